@@ -0,0 +1,182 @@
+//! This crate implements a [`BlobStore`] using Azure Blob Storage.
+//!
+//! Combined with [`BlobStorage`], history segments and snapshots are stored as blobs keyed by
+//! client and version ID, while client and version metadata remain in a small local index such as
+//! a SQLite database:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_core::InMemoryStorage;
+//! use taskchampion_sync_server_storage_azure::{AzureBlobStore, AzureStorage};
+//! let blobs = AzureBlobStore::new("myaccount", "taskchampion", "sv=...&sig=...")?;
+//! let storage: AzureStorage<_> = AzureStorage::new(InMemoryStorage::new(), blobs);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use azure_core::StatusCode;
+use azure_storage::StorageCredentials;
+use azure_storage_blobs::prelude::{BlobServiceClient, ContainerClient};
+use taskchampion_sync_server_core::runtime::run;
+use taskchampion_sync_server_core::{BlobStorage, BlobStore};
+
+/// Storage with history segments and snapshots in Azure Blob Storage, and metadata in `S`.
+pub type AzureStorage<S> = BlobStorage<S, AzureBlobStore>;
+
+/// Get the HTTP status of an error response from Azure, if any.
+fn status(err: &azure_core::Error) -> Option<StatusCode> {
+    err.as_http_error().map(|e| e.status())
+}
+
+/// A [`BlobStore`] storing each blob as a block blob in an Azure Blob Storage container.
+pub struct AzureBlobStore {
+    container: ContainerClient,
+    prefix: String,
+}
+
+impl AzureBlobStore {
+    /// Create a new instance using the given storage account and container, authenticating with
+    /// a SAS token. The container is created if it does not already exist.
+    pub fn new(account: &str, container: &str, sas_token: &str) -> anyhow::Result<Self> {
+        let credentials = StorageCredentials::sas_token(sas_token).context("Invalid SAS token")?;
+        let container = BlobServiceClient::new(account, credentials).container_client(container);
+        let client = container.clone();
+        run(async move {
+            if !client.exists().await? {
+                log::info!("Creating Azure container {}", client.container_name());
+                match client.create().await {
+                    // another server may have created the container concurrently
+                    Err(err) if status(&err) == Some(StatusCode::Conflict) => {}
+                    res => res?,
+                }
+            }
+            Ok::<_, azure_core::Error>(())
+        })?
+        .context("Error creating Azure container")?;
+        Ok(Self {
+            container,
+            prefix: String::new(),
+        })
+    }
+
+    /// Prefix all blob names with the given string, allowing several servers to share a
+    /// container. The prefix should typically end with `/`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn blob_name(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl BlobStore for AzureBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let name = self.blob_name(key);
+        let blob = self.container.blob_client(&name);
+        let data = data.to_vec();
+        run(async move { blob.put_block_blob(data).await })?
+            .with_context(|| format!("Error writing Azure blob {name}"))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let name = self.blob_name(key);
+        let blob = self.container.blob_client(&name);
+        match run(async move { blob.get_content().await })? {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if status(&err) == Some(StatusCode::NotFound) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Error reading Azure blob {name}")),
+        }
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let name = self.blob_name(key);
+        let blob = self.container.blob_client(&name);
+        match run(async move { blob.delete().await })? {
+            Ok(_) => Ok(()),
+            Err(err) if status(&err) == Some(StatusCode::NotFound) => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Error deleting Azure blob {name}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a store in a fresh container in the account given by `TEST_AZURE_ACCOUNT`, or
+    /// return `None` if that variable is not set, in which case the test should be skipped.
+    fn blob_store() -> anyhow::Result<Option<AzureBlobStore>> {
+        let Ok(account) = std::env::var("TEST_AZURE_ACCOUNT") else {
+            return Ok(None);
+        };
+        let sas_token = std::env::var("TEST_AZURE_SAS_TOKEN")?;
+        let container = format!("test-{}", Uuid::new_v4());
+        Ok(Some(AzureBlobStore::new(&account, &container, &sas_token)?))
+    }
+
+    #[test]
+    fn test_container_created() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        let container = store.container.clone();
+        assert!(run(async move { container.exists().await })??);
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_get_delete() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        let store = store.with_prefix("server1/");
+        assert_eq!(store.get("a")?, None);
+        store.put("a", b"data")?;
+        assert_eq!(store.get("a")?, Some(b"data".to_vec()));
+        store.delete("a")?;
+        assert_eq!(store.get("a")?, None);
+        // deleting a missing blob is not an error
+        store.delete("a")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        let storage = AzureStorage::new(InMemoryStorage::new(), store);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(store) = blob_store()? else {
+                return Ok(());
+            };
+            test(&AzureStorage::new(InMemoryStorage::new(), store))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The behavior of [`SnapshotAdmissionStorage`] when a snapshot upload arrives while the limit on
+/// in-flight snapshot uploads is already reached.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AdmissionPolicy {
+    /// Reject the upload immediately with [`StorageError::TryAgainLater`].
+    Reject,
+
+    /// Wait up to the given duration for another upload to finish, then reject the upload with
+    /// [`StorageError::TryAgainLater`].
+    Queue(Duration),
+}
+
+/// A storage wrapper limiting the number of concurrent `set_snapshot` operations across all
+/// clients.
+///
+/// A transaction is counted as in-flight from its first call to `set_snapshot` until it is
+/// dropped, so the commit of the snapshot data is included. Transactions which do not set a
+/// snapshot, such as reads and version additions, are not affected.
+pub struct SnapshotAdmissionStorage<S: Storage> {
+    inner: S,
+    gate: Gate,
+}
+
+impl<S: Storage> SnapshotAdmissionStorage<S> {
+    /// Wrap `inner`, allowing at most `max_in_flight` concurrent snapshot uploads.
+    pub fn new(inner: S, max_in_flight: usize, policy: AdmissionPolicy) -> Self {
+        Self {
+            inner,
+            gate: Gate {
+                max_in_flight,
+                policy,
+                in_flight: Mutex::new(0),
+                released: Condvar::new(),
+            },
+        }
+    }
+
+    /// Get the number of snapshot uploads currently in flight.
+    pub fn in_flight(&self) -> usize {
+        *self.gate.in_flight.lock().expect("poisoned lock")
+    }
+}
+
+impl<S: Storage> Storage for SnapshotAdmissionStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(AdmissionTxn {
+            inner: self.inner.txn(client_id)?,
+            gate: &self.gate,
+            admitted: false,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+/// Counter of in-flight snapshot uploads.
+struct Gate {
+    max_in_flight: usize,
+    policy: AdmissionPolicy,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Gate {
+    /// Admit a new snapshot upload, or fail with [`StorageError::TryAgainLater`].
+    fn admit(&self) -> anyhow::Result<()> {
+        let mut in_flight = self.in_flight.lock().expect("poisoned lock");
+        if let AdmissionPolicy::Queue(timeout) = self.policy {
+            (in_flight, _) = self
+                .released
+                .wait_timeout_while(in_flight, timeout, |n| *n >= self.max_in_flight)
+                .expect("poisoned lock");
+        }
+        if *in_flight >= self.max_in_flight {
+            log::info!(
+                "rejecting snapshot upload: {} already in flight",
+                *in_flight
+            );
+            return Err(StorageError::TryAgainLater.into());
+        }
+        *in_flight += 1;
+        Ok(())
+    }
+
+    /// Release a previously admitted snapshot upload.
+    fn release(&self) {
+        let mut in_flight = self.in_flight.lock().expect("poisoned lock");
+        *in_flight -= 1;
+        self.released.notify_one();
+    }
+}
+
+struct AdmissionTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+    gate: &'a Gate,
+    admitted: bool,
+}
+
+impl StorageTxn for AdmissionTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        if !self.admitted {
+            self.gate.admit()?;
+            self.admitted = true;
+        }
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        if !self.admitted {
+            self.gate.admit()?;
+            self.admitted = true;
+        }
+        self.inner.set_snapshot_from_reader(snapshot, data)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        // recording a sync only updates client metadata, so it is not subject to admission
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.inner.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version(version_id)
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, history_segment)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+impl Drop for AdmissionTxn<'_> {
+    fn drop(&mut self) {
+        if self.admitted {
+            self.gate.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use std::collections::HashMap;
+    use std::thread;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&SnapshotAdmissionStorage::new(
+            InMemoryStorage::new(),
+            2,
+            AdmissionPolicy::Reject
+        )));
+    }
+
+    /// A storage with an independent [`InMemoryStorage`] per client, so that transactions for
+    /// different clients can be open concurrently.
+    struct PerClientStorage(HashMap<Uuid, InMemoryStorage>);
+
+    impl PerClientStorage {
+        fn new(client_ids: &[Uuid]) -> anyhow::Result<Self> {
+            let mut map = HashMap::new();
+            for client_id in client_ids {
+                let storage = InMemoryStorage::new();
+                {
+                    let mut txn = storage.txn(*client_id)?;
+                    txn.new_client(NIL_VERSION_ID)?;
+                    txn.commit()?;
+                }
+                map.insert(*client_id, storage);
+            }
+            Ok(Self(map))
+        }
+    }
+
+    impl Storage for PerClientStorage {
+        fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+            self.0[&client_id].txn(client_id)
+        }
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            versions_since: 0,
+        }
+    }
+
+    fn is_try_again_later(err: &StorageError) -> bool {
+        matches!(err, StorageError::TryAgainLater)
+    }
+
+    #[test]
+    fn reject_over_limit() -> anyhow::Result<()> {
+        let clients: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let storage = SnapshotAdmissionStorage::new(
+            PerClientStorage::new(&clients)?,
+            2,
+            AdmissionPolicy::Reject,
+        );
+
+        let mut txn0 = storage.txn(clients[0])?;
+        txn0.set_snapshot(snapshot(), vec![1].into())?;
+        let mut txn1 = storage.txn(clients[1])?;
+        txn1.set_snapshot(snapshot(), vec![2].into())?;
+        assert_eq!(storage.in_flight(), 2);
+
+        // the limit is saturated, so a third upload is rejected
+        let mut txn2 = storage.txn(clients[2])?;
+        let err = txn2.set_snapshot(snapshot(), vec![3].into()).unwrap_err();
+        assert!(is_try_again_later(&err));
+        drop(txn2);
+
+        // finishing one upload makes room for another
+        txn0.commit()?;
+        drop(txn0);
+        assert_eq!(storage.in_flight(), 1);
+        let mut txn2 = storage.txn(clients[2])?;
+        txn2.set_snapshot(snapshot(), vec![3].into())?;
+        txn2.commit()?;
+        drop(txn2);
+
+        txn1.commit()?;
+        drop(txn1);
+        assert_eq!(storage.in_flight(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_and_versions_unaffected() -> anyhow::Result<()> {
+        let clients: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+        let storage = SnapshotAdmissionStorage::new(
+            PerClientStorage::new(&clients)?,
+            1,
+            AdmissionPolicy::Reject,
+        );
+
+        let mut txn0 = storage.txn(clients[0])?;
+        txn0.set_snapshot(snapshot(), vec![1].into())?;
+
+        // with the limit saturated, other operations proceed as usual
+        let mut txn1 = storage.txn(clients[1])?;
+        assert!(txn1.get_client()?.unwrap().snapshot.is_none());
+        let version_id = Uuid::new_v4();
+        txn1.add_version(version_id, NIL_VERSION_ID, vec![1, 2].into())?;
+        assert!(txn1.get_version(version_id)?.is_some());
+        txn1.commit()?;
+        drop(txn1);
+
+        txn0.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn queue_until_released() -> anyhow::Result<()> {
+        let clients: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+        let storage = SnapshotAdmissionStorage::new(
+            PerClientStorage::new(&clients)?,
+            1,
+            AdmissionPolicy::Queue(Duration::from_secs(60)),
+        );
+
+        let mut txn0 = storage.txn(clients[0])?;
+        txn0.set_snapshot(snapshot(), vec![1].into())?;
+
+        thread::scope(|s| {
+            let queued = s.spawn(|| {
+                let mut txn1 = storage.txn(clients[1])?;
+                txn1.set_snapshot(snapshot(), vec![2].into())?;
+                txn1.commit()?;
+                Ok::<_, anyhow::Error>(())
+            });
+
+            // the queued upload can only complete once this one is finished
+            thread::sleep(Duration::from_millis(50));
+            assert!(!queued.is_finished());
+            txn0.commit()?;
+            drop(txn0);
+
+            queued.join().unwrap()
+        })?;
+
+        assert_eq!(storage.in_flight(), 0);
+        assert!(storage
+            .txn(clients[1])?
+            .get_client()?
+            .unwrap()
+            .snapshot
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn queue_timeout() -> anyhow::Result<()> {
+        let clients: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+        let storage = SnapshotAdmissionStorage::new(
+            PerClientStorage::new(&clients)?,
+            1,
+            AdmissionPolicy::Queue(Duration::from_millis(10)),
+        );
+
+        let mut txn0 = storage.txn(clients[0])?;
+        txn0.set_snapshot(snapshot(), vec![1].into())?;
+
+        let mut txn1 = storage.txn(clients[1])?;
+        let err = txn1.set_snapshot(snapshot(), vec![2].into()).unwrap_err();
+        assert!(is_try_again_later(&err));
+
+        txn0.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn capabilities_propagated() -> anyhow::Result<()> {
+        let storage = SnapshotAdmissionStorage::new(
+            InMemoryStorage::new_lenient(),
+            1,
+            AdmissionPolicy::Reject,
+        );
+        assert_eq!(
+            storage.capabilities(),
+            InMemoryStorage::new_lenient().capabilities()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::StorageError;
+use crate::storage::{Client, Snapshot, Storage, StorageCapabilities, StorageTxn, Version};
+use async_trait::async_trait;
+use bytes::Bytes;
+use uuid::Uuid;
+
+/// An asynchronous transaction in the storage backend, corresponding to [`StorageTxn`].
+///
+/// The semantics of each method, and of the transaction as a whole, are the same as for
+/// [`StorageTxn`]. This allows backends which communicate over the network to wait for responses
+/// without blocking a worker thread.
+///
+/// The futures returned by these methods are not required to be `Send`, as Actix-web runs each
+/// worker on a single-threaded runtime, and the transactions of synchronous backends, wrapped by
+/// [`BlockingStorage`], are not `Send`.
+#[async_trait(?Send)]
+pub trait AsyncStorageTxn {
+    /// Get information about the client for this transaction
+    async fn get_client(&mut self) -> Result<Option<Client>, StorageError>;
+
+    /// Create the client for this transaction, with the given latest_version_id. The client must
+    /// not already exist.
+    async fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError>;
+
+    /// Set the client's most recent snapshot.
+    async fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError>;
+
+    /// Get the data for the most recent snapshot.  The version_id
+    /// is used to verify that the snapshot is for the correct version.
+    async fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError>;
+
+    /// Get a version, indexed by parent version id
+    async fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError>;
+
+    /// Get a version, indexed by its own version id
+    async fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError>;
+
+    /// Add a version (that must not already exist), and
+    ///  - update latest_version_id
+    ///  - increment snapshot.versions_since
+    async fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError>;
+
+    /// Commit any changes made in the transaction.  It is an error to call this more than
+    /// once.  It is safe to skip this call for read-only operations.
+    async fn commit(&mut self) -> Result<(), StorageError>;
+}
+
+/// An asynchronous storage backend, corresponding to [`Storage`].
+#[async_trait(?Send)]
+pub trait AsyncStorage: Send + Sync {
+    /// Begin a transaction for the given client ID.
+    async fn txn(&self, client_id: Uuid) -> Result<Box<dyn AsyncStorageTxn + '_>, StorageError>;
+
+    /// Describe the optional features this backend supports.
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::default()
+    }
+}
+
+/// An adapter allowing a synchronous [`Storage`] to be used as an [`AsyncStorage`].
+///
+/// Each operation runs the synchronous implementation directly, so it blocks the calling thread
+/// until the operation completes, exactly as the synchronous backend would. This is appropriate
+/// for backends whose operations are fast or local, such as SQLite.
+pub struct BlockingStorage<S: Storage> {
+    inner: S,
+}
+
+impl<S: Storage> BlockingStorage<S> {
+    /// Wrap a synchronous storage backend.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    /// Get the wrapped storage backend.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Storage> AsyncStorage for BlockingStorage<S> {
+    async fn txn(&self, client_id: Uuid) -> Result<Box<dyn AsyncStorageTxn + '_>, StorageError> {
+        Ok(Box::new(BlockingTxn {
+            inner: self.inner.txn(client_id)?,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+struct BlockingTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+}
+
+#[async_trait(?Send)]
+impl AsyncStorageTxn for BlockingTxn<'_> {
+    async fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    async fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    async fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    async fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    async fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    async fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version(version_id)
+    }
+
+    async fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    async fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use chrono::Utc;
+    use futures::executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn blocking_round_trip() -> anyhow::Result<()> {
+        block_on(async {
+            let storage = BlockingStorage::new(InMemoryStorage::new());
+            let client_id = Uuid::new_v4();
+            let version_id = Uuid::new_v4();
+
+            let mut txn = storage.txn(client_id).await?;
+            assert_eq!(txn.get_client().await?, None);
+            txn.new_client(Uuid::nil()).await?;
+            txn.add_version(version_id, Uuid::nil(), vec![1, 2].into())
+                .await?;
+            txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![3].into())
+                .await?;
+            txn.commit().await?;
+            drop(txn);
+
+            let mut txn = storage.txn(client_id).await?;
+            let client = txn.get_client().await?.unwrap();
+            assert_eq!(client.latest_version_id, version_id);
+            let version = txn.get_version_by_parent(Uuid::nil()).await?.unwrap();
+            assert_eq!(version.version_id, version_id);
+            assert_eq!(version.history_segment, vec![1, 2]);
+            assert_eq!(
+                txn.get_snapshot_data(version_id).await?,
+                Some(vec![3].into())
+            );
+            drop(txn);
+
+            // the wrapped storage sees the same data
+            let inner = storage.into_inner();
+            let mut txn = inner.txn(client_id)?;
+            assert!(txn.get_version(version_id)?.is_some());
+            Ok(())
+        })
+    }
+}
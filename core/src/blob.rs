@@ -0,0 +1,595 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// A store for large binary objects, such as an object-storage bucket, used by [`BlobStorage`].
+///
+/// Blob stores are not transactional; [`BlobStorage`] orders its operations so that storage never
+/// refers to a blob that does not exist.
+pub trait BlobStore: Send + Sync {
+    /// Store a blob, replacing any existing blob with the same key.
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Get a blob, if it exists.
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Delete a blob, if it exists.
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Boxed blob stores, allowing the store to be selected at runtime.
+impl<B: BlobStore + ?Sized> BlobStore for Box<B> {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        (**self).put(key, data)
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        (**self).delete(key)
+    }
+}
+
+/// A [`BlobStore`] in memory, for testing.
+#[derive(Default)]
+pub struct InMemoryBlobStore(Mutex<HashMap<String, Vec<u8>>>);
+
+impl InMemoryBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the keys of all blobs in the store, in sorted order.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .0
+            .lock()
+            .expect("poisoned lock")
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl BlobStore for InMemoryBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.0
+            .lock()
+            .expect("poisoned lock")
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.0.lock().expect("poisoned lock").get(key).cloned())
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.0.lock().expect("poisoned lock").remove(key);
+        Ok(())
+    }
+}
+
+/// Prefix identifying a reference to a blob, in place of the data itself.
+const BLOB_REF_MARKER: &[u8] = b"\0tcs-blob-ref\0";
+
+/// Encode a reference to the blob with the given key.
+fn encode_ref(key: &str) -> Bytes {
+    let mut data = BLOB_REF_MARKER.to_vec();
+    data.extend_from_slice(key.as_bytes());
+    data.into()
+}
+
+/// Decode a reference produced by [`encode_ref`], returning `None` if the data is not a
+/// reference.
+fn decode_ref(data: &[u8]) -> Option<&str> {
+    std::str::from_utf8(data.strip_prefix(BLOB_REF_MARKER)?).ok()
+}
+
+fn version_key(client_id: Uuid, version_id: Uuid) -> String {
+    format!("{client_id}/versions/{version_id}")
+}
+
+fn snapshot_key(client_id: Uuid, version_id: Uuid) -> String {
+    format!("{client_id}/snapshots/{version_id}")
+}
+
+/// A storage wrapper which stores history segments and snapshot data in a [`BlobStore`], keeping
+/// only client and version metadata, and references to the blobs, in the inner storage.
+///
+/// Blobs are written before the references to them, so the inner storage never refers to a
+/// missing blob. A transaction that is not committed may leave unreferenced blobs behind. Blobs
+/// for snapshots that are no longer retained are deleted after the replacing transaction commits.
+///
+/// History segments and snapshots stored inline in the inner storage, such as those written
+/// before this wrapper was introduced, are returned unchanged.
+pub struct BlobStorage<S: Storage, B: BlobStore> {
+    inner: S,
+    blobs: B,
+}
+
+impl<S: Storage, B: BlobStore> BlobStorage<S, B> {
+    /// Wrap `inner`, storing blobs in `blobs`.
+    pub fn new(inner: S, blobs: B) -> Self {
+        Self { inner, blobs }
+    }
+
+    /// Get a reference to the blob store.
+    pub fn blobs(&self) -> &B {
+        &self.blobs
+    }
+}
+
+impl<S: Storage, B: BlobStore> Storage for BlobStorage<S, B> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(BlobTxn {
+            client_id,
+            inner: self.inner.txn(client_id)?,
+            blobs: &self.blobs,
+            replaced: vec![],
+            savepoints: vec![],
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            // blobs are written and read in a single call
+            supports_streaming: false,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct BlobTxn<'a> {
+    client_id: Uuid,
+    inner: Box<dyn StorageTxn + 'a>,
+    blobs: &'a dyn BlobStore,
+    /// Keys of blobs no longer referenced once this transaction commits.
+    replaced: Vec<String>,
+    /// The savepoints in the inner transaction, with the length of `replaced` at each.
+    savepoints: Vec<(Savepoint, usize)>,
+}
+
+impl BlobTxn<'_> {
+    /// Resolve data from the inner storage, which may be a reference to a blob.
+    fn resolve(&self, data: Bytes) -> Result<Bytes, StorageError> {
+        match decode_ref(&data) {
+            Some(key) => self
+                .blobs
+                .get(key)?
+                .map(Bytes::from)
+                .ok_or_else(|| StorageError::Corrupt(format!("Blob {key} is missing"))),
+            None => Ok(data),
+        }
+    }
+}
+
+impl StorageTxn for BlobTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let old = self.inner.list_snapshots()?;
+        let key = snapshot_key(self.client_id, snapshot.version_id);
+        self.blobs.put(&key, &data)?;
+        self.inner.set_snapshot(snapshot, encode_ref(&key))?;
+        let retained: Vec<Uuid> = self
+            .inner
+            .list_snapshots()?
+            .into_iter()
+            .map(|s| s.version_id)
+            .collect();
+        for s in old {
+            if !retained.contains(&s.version_id) {
+                self.replaced
+                    .push(snapshot_key(self.client_id, s.version_id));
+            }
+        }
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner
+            .get_snapshot_data(version_id)?
+            .map(|data| self.resolve(data))
+            .transpose()
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version_by_parent(parent_version_id)?
+            .map(|v| -> Result<Version, StorageError> {
+                Ok(Version {
+                    history_segment: self.resolve(v.history_segment)?,
+                    ..v
+                })
+            })
+            .transpose()
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version(version_id)?
+            .map(|v| -> Result<Version, StorageError> {
+                Ok(Version {
+                    history_segment: self.resolve(v.history_segment)?,
+                    ..v
+                })
+            })
+            .transpose()
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        // the blob is written only once the version is accepted, as writing it first would
+        // overwrite the blob of an existing version with the same ID
+        let key = version_key(self.client_id, version_id);
+        self.inner
+            .add_version(version_id, parent_version_id, encode_ref(&key))?;
+        Ok(self.blobs.put(&key, &history_segment)?)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let key = version_key(self.client_id, version_id);
+        let mut data = vec![];
+        history_segment.read_to_end(&mut data)?;
+        self.inner.add_version_if_latest(
+            version_id,
+            parent_version_id,
+            &mut encode_ref(&key).as_ref(),
+        )?;
+        Ok(self.blobs.put(&key, &data)?)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let Some(version) = self.inner.get_version(version_id)? else {
+            return Ok(());
+        };
+        self.inner.delete_version(version_id)?;
+        if let Some(key) = decode_ref(&version.history_segment) {
+            self.replaced.push(key.to_string());
+        }
+        Ok(())
+    }
+
+    /// Blobs are found by following the chain of versions back from the latest version, so blobs
+    /// for versions not on that chain are left behind.
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        let savepoint = self.inner.savepoint()?;
+        self.savepoints.push((savepoint, self.replaced.len()));
+        Ok(savepoint)
+    }
+
+    /// Blobs written since the savepoint are left unreferenced, as in a transaction that is not
+    /// committed.
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)?;
+        if let Some(i) = self.savepoints.iter().rposition(|(s, _)| *s == savepoint) {
+            // the blobs replaced since the savepoint are referenced again
+            self.replaced.truncate(self.savepoints[i].1);
+            self.savepoints.truncate(i + 1);
+        }
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        let Some(client) = self.inner.get_client()? else {
+            return Ok(());
+        };
+        let mut keys = vec![];
+        for snapshot in self.inner.list_snapshots()? {
+            if let Some(data) = self.inner.get_snapshot_data(snapshot.version_id)? {
+                keys.extend(decode_ref(&data).map(str::to_string));
+            }
+        }
+        let mut version_id = client.latest_version_id;
+        while let Some(version) = self.inner.get_version(version_id)? {
+            keys.extend(decode_ref(&version.history_segment).map(str::to_string));
+            version_id = version.parent_version_id;
+        }
+        self.inner.delete_client()?;
+        self.replaced.extend(keys);
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()?;
+        for key in std::mem::take(&mut self.replaced) {
+            // the blob is no longer referenced, so failing to delete it is not fatal
+            if let Err(err) = self.blobs.delete(&key) {
+                log::warn!("Failed to delete replaced blob {key}: {err:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&BlobStorage::new(
+            InMemoryStorage::new(),
+            InMemoryBlobStore::new()
+        )));
+    }
+
+    #[test]
+    fn ref_round_trip() {
+        let data = encode_ref("abc/versions/def");
+        assert_eq!(decode_ref(&data), Some("abc/versions/def"));
+        assert_eq!(decode_ref(b"inline data"), None);
+    }
+
+    #[test]
+    fn versions_stored_as_blobs() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(InMemoryStorage::new(), InMemoryBlobStore::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(
+            storage.blobs().keys(),
+            vec![version_key(client_id, version_id)]
+        );
+        // the inner storage holds only a reference
+        let inner_version = storage.inner.txn(client_id)?.get_version(version_id)?;
+        assert_eq!(
+            inner_version.unwrap().history_segment,
+            encode_ref(&version_key(client_id, version_id))
+        );
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .unwrap()
+                .history_segment,
+            vec![1, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn boxed_storage_and_blobs() -> anyhow::Result<()> {
+        let inner: Box<dyn Storage> = Box::new(InMemoryStorage::new());
+        let blobs: Box<dyn BlobStore> = Box::new(InMemoryBlobStore::new());
+        let storage = BlobStorage::new(inner, blobs);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        assert!(storage
+            .blobs()
+            .get(&version_key(client_id, version_id))?
+            .is_some());
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            vec![1, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn inline_data_returned_unchanged() -> anyhow::Result<()> {
+        let inner = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = inner.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![4, 5].into())?;
+            txn.commit()?;
+        }
+
+        let storage = BlobStorage::new(inner, InMemoryBlobStore::new());
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            vec![4, 5]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replaced_snapshot_deleted_on_commit() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(InMemoryStorage::new_lenient(), InMemoryBlobStore::new());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_snapshot(Snapshot::new(v1, Utc::now()), vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        // an uncommitted replacement does not delete the old snapshot
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.set_snapshot(Snapshot::new(v2, Utc::now()), vec![2].into())?;
+        }
+        assert!(storage
+            .blobs()
+            .keys()
+            .contains(&snapshot_key(client_id, v1)));
+
+        let mut txn = storage.txn(client_id)?;
+        txn.set_snapshot(Snapshot::new(v2, Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        assert_eq!(txn.get_snapshot_data(v2)?, Some(vec![2].into()));
+        assert_eq!(storage.blobs().keys(), vec![snapshot_key(client_id, v2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn retained_snapshots_kept() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(
+            InMemoryStorage::new().with_snapshot_retention(2),
+            InMemoryBlobStore::new(),
+        );
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        for (i, vid) in vids.iter().enumerate() {
+            txn.set_snapshot(Snapshot::new(*vid, Utc::now()), Bytes::from(vec![i as u8]))?;
+        }
+        txn.commit()?;
+
+        // the oldest snapshot is no longer retained, so its blob is deleted
+        assert_eq!(txn.get_snapshot_data(vids[1])?, Some(vec![1].into()));
+        let mut expected = vec![
+            snapshot_key(client_id, vids[1]),
+            snapshot_key(client_id, vids[2]),
+        ];
+        expected.sort();
+        assert_eq!(storage.blobs().keys(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_version_blob_deleted() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(InMemoryStorage::new_lenient(), InMemoryBlobStore::new());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version_if_latest(v1, NIL_VERSION_ID, &mut &[1u8][..])?;
+        let err = txn
+            .add_version_if_latest(Uuid::new_v4(), NIL_VERSION_ID, &mut &[2u8][..])
+            .unwrap_err();
+        assert_eq!(err.conflict(), Some(v1));
+        assert_eq!(storage.blobs().keys().len(), 1);
+        assert_eq!(txn.get_version(v1)?.unwrap().history_segment, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_client_deletes_blobs() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(InMemoryStorage::new_lenient(), InMemoryBlobStore::new());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.add_version(v2, v1, vec![2].into())?;
+        txn.set_snapshot(Snapshot::new(v2, Utc::now()), vec![3].into())?;
+        txn.commit()?;
+        drop(txn);
+        assert_eq!(storage.blobs().keys().len(), 3);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(storage.blobs().keys(), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_blob_is_error() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(InMemoryStorage::new(), InMemoryBlobStore::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        storage
+            .blobs()
+            .delete(&version_key(client_id, version_id))?;
+        assert!(storage.txn(client_id)?.get_version(version_id).is_err());
+        Ok(())
+    }
+}
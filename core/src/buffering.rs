@@ -0,0 +1,896 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::StorageError;
+use crate::storage::{Client, Quota, Snapshot, Storage, StorageCapabilities, StorageTxn, Version};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Thresholds at which [`BufferingStorage`] flushes buffered changes to the inner storage.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FlushPolicy {
+    /// Flush when the buffered snapshot and history segment data exceeds this many bytes.
+    pub max_buffered_bytes: usize,
+
+    /// Flush when this much time has passed since the last flush.
+    pub max_delay: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            max_buffered_bytes: 1024 * 1024,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A storage wrapper which buffers committed changes in memory, writing them to the inner
+/// storage in batches.
+///
+/// Committed changes are held in memory until the buffer exceeds the thresholds in the
+/// [`FlushPolicy`], checked at each commit, or until [`BufferingStorage::flush`] is called. Reads
+/// see buffered changes overlaid on the inner storage, so buffering is not visible to callers.
+/// Buffered changes are flushed when the storage is dropped.
+///
+/// Changes that are committed but not yet flushed are lost if the process exits abruptly, so this
+/// trades a small window of durability for write throughput.
+pub struct BufferingStorage<S: Storage> {
+    inner: S,
+    policy: FlushPolicy,
+    clock: Arc<dyn Clock>,
+    buffer: Mutex<Buffer>,
+}
+
+/// Changes committed to a [`BufferingStorage`] but not yet flushed.
+struct Buffer {
+    clients: HashMap<Uuid, ClientBuffer>,
+    size: usize,
+    last_flush: DateTime<Utc>,
+}
+
+/// A single change, replayed against the inner storage on flush.
+enum Change {
+    NewClient(Uuid),
+    SetSnapshot(Snapshot, Bytes),
+    RecordSync(DateTime<Utc>, Option<String>),
+    SetQuota(Quota),
+    SetDeleted(Option<DateTime<Utc>>),
+    AddVersion(Version),
+    /// A version was deleted; the version is kept so that lookups by parent can be hidden.
+    DeleteVersion(Version),
+    DeleteClient,
+}
+
+impl Change {
+    fn size(&self) -> usize {
+        match self {
+            Change::NewClient(_)
+            | Change::RecordSync(..)
+            | Change::SetQuota(_)
+            | Change::SetDeleted(_)
+            | Change::DeleteVersion(_)
+            | Change::DeleteClient => 0,
+            Change::SetSnapshot(_, data) => data.len(),
+            Change::AddVersion(version) => version.history_segment.len(),
+        }
+    }
+}
+
+/// Changes to a single client, in the order they were made.
+#[derive(Default)]
+struct ClientBuffer {
+    /// The state of the client after these changes, if any changes have been made.
+    client: Option<Client>,
+    changes: Vec<Change>,
+    /// Whether the client was deleted before these changes, hiding any older data.
+    deleted: bool,
+}
+
+/// Lookups in a [`ClientBuffer`] return `Some(None)` if the value is hidden by a deletion, and
+/// `None` if older data must be consulted.
+impl ClientBuffer {
+    fn hidden<T>(&self) -> Option<Option<T>> {
+        self.deleted.then_some(None)
+    }
+
+    fn find_client(&self) -> Option<Option<Client>> {
+        match &self.client {
+            Some(client) => Some(Some(client.clone())),
+            None => self.hidden(),
+        }
+    }
+
+    /// Get the snapshots set in these changes, most recent first.
+    fn snapshots(&self) -> impl Iterator<Item = (&Snapshot, &Bytes)> {
+        self.changes.iter().rev().filter_map(|c| match c {
+            Change::SetSnapshot(snapshot, data) => Some((snapshot, data)),
+            _ => None,
+        })
+    }
+
+    fn find_version<P: Fn(&Version) -> bool>(&self, pred: P) -> Option<Option<&Version>> {
+        self.changes
+            .iter()
+            .rev()
+            .find_map(|c| match c {
+                Change::AddVersion(version) if pred(version) => Some(Some(version)),
+                Change::DeleteVersion(version) if pred(version) => Some(None),
+                _ => None,
+            })
+            .or_else(|| self.hidden())
+    }
+}
+
+impl<S: Storage> BufferingStorage<S> {
+    /// Wrap `inner`, flushing according to `policy`.
+    pub fn new(inner: S, policy: FlushPolicy) -> Self {
+        Self::with_clock(inner, policy, Arc::new(SystemClock))
+    }
+
+    /// Wrap `inner`, using the given clock to determine when to flush.
+    pub fn with_clock(inner: S, policy: FlushPolicy, clock: Arc<dyn Clock>) -> Self {
+        let last_flush = clock.now();
+        Self {
+            inner,
+            policy,
+            clock,
+            buffer: Mutex::new(Buffer {
+                clients: HashMap::new(),
+                size: 0,
+                last_flush,
+            }),
+        }
+    }
+
+    /// Write all buffered changes to the inner storage.
+    ///
+    /// Each client's changes are written in a single inner transaction. If writing fails, the
+    /// changes for that client and any not yet written remain buffered.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let mut buffer = self.buffer.lock().expect("poisoned lock");
+        self.flush_locked(&mut buffer)
+    }
+
+    fn flush_locked(&self, buffer: &mut Buffer) -> Result<(), StorageError> {
+        let client_ids: Vec<Uuid> = buffer.clients.keys().copied().collect();
+        for client_id in client_ids {
+            let client_buffer = &buffer.clients[&client_id];
+            let mut txn = self.inner.txn(client_id)?;
+            for change in &client_buffer.changes {
+                match change {
+                    Change::NewClient(latest_version_id) => txn.new_client(*latest_version_id)?,
+                    Change::SetSnapshot(snapshot, data) => {
+                        txn.set_snapshot(snapshot.clone(), data.clone())?
+                    }
+                    Change::RecordSync(timestamp, user_agent) => {
+                        txn.record_sync(*timestamp, user_agent.clone())?
+                    }
+                    Change::SetQuota(quota) => txn.set_quota(*quota)?,
+                    Change::SetDeleted(deleted_at) => txn.set_deleted(*deleted_at)?,
+                    Change::AddVersion(version) => txn.add_version(
+                        version.version_id,
+                        version.parent_version_id,
+                        version.history_segment.clone(),
+                    )?,
+                    Change::DeleteVersion(version) => txn.delete_version(version.version_id)?,
+                    Change::DeleteClient => txn.delete_client()?,
+                }
+            }
+            txn.commit()?;
+            let client_buffer = buffer.clients.remove(&client_id).unwrap();
+            buffer.size -= client_buffer
+                .changes
+                .iter()
+                .map(Change::size)
+                .sum::<usize>();
+        }
+        buffer.last_flush = self.clock.now();
+        Ok(())
+    }
+
+    /// Add the changes from a committed transaction to the buffer, flushing if necessary.
+    fn commit_changes(&self, client_id: Uuid, changes: ClientBuffer) -> Result<(), StorageError> {
+        let mut buffer = self.buffer.lock().expect("poisoned lock");
+        if changes.deleted {
+            // the deletion supersedes any buffered changes
+            if let Some(old) = buffer.clients.remove(&client_id) {
+                buffer.size -= old.changes.iter().map(Change::size).sum::<usize>();
+            }
+        }
+        buffer.size += changes.changes.iter().map(Change::size).sum::<usize>();
+        let client_buffer = buffer.clients.entry(client_id).or_default();
+        if changes.client.is_some() || changes.deleted {
+            client_buffer.client = changes.client;
+        }
+        client_buffer.deleted |= changes.deleted;
+        client_buffer.changes.extend(changes.changes);
+
+        let elapsed = (self.clock.now() - buffer.last_flush)
+            .to_std()
+            .unwrap_or_default();
+        if buffer.size > self.policy.max_buffered_bytes || elapsed >= self.policy.max_delay {
+            self.flush_locked(&mut buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Drop for BufferingStorage<S> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("Failed to flush buffered changes: {err:?}");
+        }
+    }
+}
+
+impl<S: Storage> Storage for BufferingStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(BufferingTxn {
+            client_id,
+            storage: self,
+            local: ClientBuffer::default(),
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            // uncommitted changes are never added to the buffer
+            supports_rollback: true,
+            // buffered snapshot data is held in memory
+            supports_streaming: false,
+            // changes are collected without savepoints
+            supports_savepoints: false,
+            ..self.inner.capabilities()
+        }
+    }
+
+    /// Buffered changes are flushed before consulting the inner storage.
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.flush()?;
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        BufferingStorage::flush(self)?;
+        self.inner.flush()
+    }
+}
+
+struct BufferingTxn<'a, S: Storage> {
+    client_id: Uuid,
+    storage: &'a BufferingStorage<S>,
+    /// Changes made in this transaction, added to the storage's buffer on commit.
+    local: ClientBuffer,
+}
+
+impl<S: Storage> BufferingTxn<'_, S> {
+    /// Look up a value in this transaction's changes, then in the buffer. This returns
+    /// `Some(None)` if the value is hidden by a deletion, and `None` if the inner storage must be
+    /// consulted.
+    fn buffered<T, B>(&self, in_buffer: B) -> Option<Option<T>>
+    where
+        B: Fn(&ClientBuffer) -> Option<Option<T>>,
+    {
+        in_buffer(&self.local).or_else(|| {
+            let buffer = self.storage.buffer.lock().expect("poisoned lock");
+            buffer.clients.get(&self.client_id).and_then(&in_buffer)
+        })
+    }
+
+    /// Get the retained snapshots, most recent first, with the data for those which are buffered.
+    /// The second value is true if any snapshot is buffered, in which case any older snapshot not
+    /// returned is no longer retained.
+    #[allow(clippy::type_complexity)]
+    fn retained_snapshots(&self) -> Result<(Vec<(Snapshot, Option<Bytes>)>, bool), StorageError> {
+        let mut snapshots: Vec<(Snapshot, Option<Bytes>)> = vec![];
+        let mut collect = |b: &ClientBuffer| {
+            for (snapshot, data) in b.snapshots() {
+                snapshots.push((snapshot.clone(), Some(data.clone())));
+            }
+            b.deleted
+        };
+        let mut hidden = collect(&self.local);
+        if !hidden {
+            let buffer = self.storage.buffer.lock().expect("poisoned lock");
+            if let Some(client_buffer) = buffer.clients.get(&self.client_id) {
+                hidden = collect(client_buffer);
+            }
+        }
+        let any_buffered = !snapshots.is_empty();
+        if !hidden {
+            // As in `lookup`, the inner transaction is not held open.
+            let mut txn = self.storage.inner.txn(self.client_id)?;
+            snapshots.extend(txn.list_snapshots()?.into_iter().map(|s| (s, None)));
+        }
+
+        let retention = self.storage.inner.capabilities().max_snapshot_retention;
+        let mut seen = HashSet::new();
+        snapshots.retain(|(s, _)| seen.insert(s.version_id));
+        snapshots.truncate(retention.max(1) as usize);
+        Ok((snapshots, any_buffered))
+    }
+
+    /// Look up a value in the buffers, falling back to the inner storage.
+    fn lookup<T, B, I>(&self, in_buffer: B, in_inner: I) -> Result<Option<T>, StorageError>
+    where
+        B: Fn(&ClientBuffer) -> Option<Option<T>>,
+        I: FnOnce(&mut dyn StorageTxn) -> Result<Option<T>, StorageError>,
+    {
+        if let Some(value) = self.buffered(in_buffer) {
+            return Ok(value);
+        }
+        // The inner transaction is only used for this read, and is not held open, as holding it
+        // could block a concurrent flush.
+        let mut txn = self.storage.inner.txn(self.client_id)?;
+        in_inner(txn.as_mut())
+    }
+}
+
+impl<S: Storage> StorageTxn for BufferingTxn<'_, S> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.lookup(ClientBuffer::find_client, |txn| txn.get_client())
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        if self.get_client()?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already exists",
+                self.client_id
+            )));
+        }
+        self.local.client = Some(Client::new(latest_version_id, self.storage.clock.now()));
+        self.local
+            .changes
+            .push(Change::NewClient(latest_version_id));
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let Some(mut client) = self.get_client()? else {
+            return Err(StorageError::NotFound);
+        };
+        client.snapshot = Some(snapshot.clone());
+        self.local.client = Some(client);
+        self.local.changes.push(Change::SetSnapshot(snapshot, data));
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        let Some(mut client) = self.get_client()? else {
+            return Ok(());
+        };
+        client.last_sync_at = Some(timestamp);
+        client.user_agent = user_agent.clone();
+        self.local.client = Some(client);
+        self.local
+            .changes
+            .push(Change::RecordSync(timestamp, user_agent));
+        Ok(())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.quota = quota;
+        self.local.client = Some(client);
+        self.local.changes.push(Change::SetQuota(quota));
+        Ok(())
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.deleted_at = deleted_at;
+        self.local.client = Some(client);
+        self.local.changes.push(Change::SetDeleted(deleted_at));
+        Ok(())
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        let (snapshots, any_buffered) = self.retained_snapshots()?;
+        match snapshots
+            .into_iter()
+            .find(|(s, _)| s.version_id == version_id)
+        {
+            Some((_, Some(data))) => Ok(Some(data)),
+            // A buffered snapshot replaces older snapshots beyond the retention, so if the
+            // requested version is not retained, it no longer exists.
+            None if any_buffered => Ok(None),
+            _ => self
+                .storage
+                .inner
+                .txn(self.client_id)?
+                .get_snapshot_data(version_id),
+        }
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        let mut snapshots: Vec<Snapshot> = self
+            .retained_snapshots()?
+            .0
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect();
+        // the most recent snapshot's `versions_since` is kept up to date in the client
+        if let (Some(first), Some(snapshot)) = (
+            snapshots.first_mut(),
+            self.get_client()?.and_then(|c| c.snapshot),
+        ) {
+            *first = snapshot;
+        }
+        Ok(snapshots)
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.lookup(
+            |b| {
+                b.find_version(|v| v.parent_version_id == parent_version_id)
+                    .map(Option::<&Version>::cloned)
+            },
+            |txn| txn.get_version_by_parent(parent_version_id),
+        )
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.lookup(
+            |b| {
+                b.find_version(|v| v.version_id == version_id)
+                    .map(Option::<&Version>::cloned)
+            },
+            |txn| txn.get_version(version_id),
+        )
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.check_new_version_id(version_id)?;
+        let Some(mut client) = self.get_client()? else {
+            return Err(StorageError::NotFound);
+        };
+        if self.get_version_by_parent(parent_version_id)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a child for {}",
+                self.client_id,
+                parent_version_id
+            )));
+        }
+        if self.get_version(version_id)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a version {}",
+                self.client_id,
+                version_id
+            )));
+        }
+        client.latest_version_id = version_id;
+        if let Some(ref mut snap) = client.snapshot {
+            snap.versions_since = snap.versions_since.saturating_add(1);
+        }
+        self.local.client = Some(client);
+        // the inner storage records its own timestamp when the version is flushed
+        self.local.changes.push(Change::AddVersion(Version {
+            version_id,
+            parent_version_id,
+            history_segment,
+            created_at: Some(self.storage.clock.now()),
+        }));
+        Ok(())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        if let Some(version) = self.get_version(version_id)? {
+            self.local.changes.push(Change::DeleteVersion(version));
+        }
+        Ok(())
+    }
+
+    /// The client is deleted when the transaction commits.
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.local = ClientBuffer {
+            client: None,
+            changes: vec![Change::DeleteClient],
+            deleted: true,
+        };
+        Ok(())
+    }
+
+    /// Buffered changes are flushed before consulting the inner storage.
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.storage.flush()?;
+        // one extra, in case this transaction's client is removed below
+        let mut client_ids = self
+            .storage
+            .inner
+            .txn(self.client_id)?
+            .list_clients(after, limit.saturating_add(1))?;
+        let client_id = self.client_id;
+        if self.local.client.is_some() {
+            // include this transaction's client if it was created in this transaction
+            if after.is_none_or(|after| client_id > after) && !client_ids.contains(&client_id) {
+                let i = client_ids.partition_point(|id| *id < client_id);
+                client_ids.insert(i, client_id);
+            }
+        } else if self.local.deleted {
+            client_ids.retain(|id| *id != client_id);
+        }
+        client_ids.truncate(limit);
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        let local = std::mem::take(&mut self.local);
+        self.storage.commit_changes(self.client_id, local)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&BufferingStorage::new(
+            InMemoryStorage::new(),
+            FlushPolicy::default()
+        )));
+    }
+
+    fn clock() -> Arc<ManualClock> {
+        Arc::new(ManualClock::new(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        ))
+    }
+
+    /// A policy which never flushes automatically.
+    fn never() -> FlushPolicy {
+        FlushPolicy {
+            max_buffered_bytes: usize::MAX,
+            max_delay: Duration::MAX,
+        }
+    }
+
+    #[test]
+    fn reads_see_buffered_changes() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2].into())?;
+        txn.commit()?;
+
+        // nothing has been written to the inner storage
+        assert_eq!(storage.inner.txn(client_id)?.get_client()?, None);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(client.snapshot, None);
+        let version = Version {
+            version_id,
+            parent_version_id: NIL_VERSION_ID,
+            history_segment: vec![1, 2].into(),
+            created_at: Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+        };
+        assert_eq!(txn.get_version(version_id)?, Some(version.clone()));
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, Some(version));
+        Ok(())
+    }
+
+    #[test]
+    fn flush_persists() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, now), vec![3].into())?;
+        txn.commit()?;
+
+        storage.flush()?;
+
+        let mut txn = storage.inner.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(client.snapshot, Some(Snapshot::new(version_id, now)));
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![3].into()));
+        assert!(txn.get_version(version_id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn flush_through_wrapper() -> anyhow::Result<()> {
+        let storage = Box::new(BufferingStorage::with_clock(
+            InMemoryStorage::new(),
+            never(),
+            clock(),
+        ));
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+
+        // flushing the boxed storage flushes the buffer
+        Storage::flush(&storage)?;
+        assert!(storage.inner.txn(client_id)?.get_client()?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn flush_on_threshold() -> anyhow::Result<()> {
+        let clock = clock();
+        let policy = FlushPolicy {
+            max_buffered_bytes: 10,
+            max_delay: Duration::from_secs(1),
+        };
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), policy, clock.clone());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        assert_eq!(storage.inner.txn(client_id)?.get_client()?, None);
+
+        // exceed the time threshold
+        clock.advance(Duration::from_secs(1));
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(v2, v1, vec![2].into())?;
+        txn.commit()?;
+        assert_eq!(
+            storage
+                .inner
+                .txn(client_id)?
+                .get_client()?
+                .unwrap()
+                .latest_version_id,
+            v2
+        );
+
+        // exceed the size threshold
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(Uuid::new_v4(), v2, vec![0; 11].into())?;
+        txn.commit()?;
+        assert!(storage
+            .inner
+            .txn(client_id)?
+            .get_version_by_parent(v2)?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn newest_value_wins() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, Bytes::new())?;
+        txn.set_snapshot(Snapshot::new(v1, now), vec![1].into())?;
+        txn.commit()?;
+        storage.flush()?;
+
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(v2, v1, Bytes::new())?;
+        txn.set_snapshot(Snapshot::new(v2, now), vec![2].into())?;
+        txn.commit()?;
+
+        // both layers have a client and a snapshot; the buffered values are returned
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, v2);
+        assert_eq!(client.snapshot.unwrap().version_id, v2);
+        assert_eq!(txn.get_snapshot_data(v2)?, Some(vec![2].into()));
+        assert_eq!(txn.get_snapshot_data(v1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn retained_snapshots_buffered() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(
+            InMemoryStorage::new().with_snapshot_retention(2),
+            never(),
+            clock(),
+        );
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_snapshot(Snapshot::new(vids[0], now), vec![0].into())?;
+        txn.commit()?;
+        storage.flush()?;
+
+        let mut txn = storage.txn(client_id)?;
+        txn.set_snapshot(Snapshot::new(vids[1], now), vec![1].into())?;
+        txn.commit()?;
+
+        // one snapshot is buffered and one is in the inner storage
+        let mut txn = storage.txn(client_id)?;
+        let snapshots: Vec<Uuid> = txn.list_snapshots()?.iter().map(|s| s.version_id).collect();
+        assert_eq!(snapshots, vec![vids[1], vids[0]]);
+        assert_eq!(txn.get_snapshot_data(vids[1])?, Some(vec![1].into()));
+        assert_eq!(txn.get_snapshot_data(vids[0])?, Some(vec![0].into()));
+
+        // a third snapshot displaces the oldest
+        txn.set_snapshot(Snapshot::new(vids[2], now), vec![2].into())?;
+        txn.commit()?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_snapshot_data(vids[0])?, None);
+        storage.flush()?;
+        let snapshots: Vec<Uuid> = txn.list_snapshots()?.iter().map(|s| s.version_id).collect();
+        assert_eq!(snapshots, vec![vids[2], vids[1]]);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_client_buffered() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        storage.flush()?;
+
+        // the deletion hides the flushed data, and the client can be created again
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(v1)?, None);
+        txn.commit()?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v2, NIL_VERSION_ID, vec![2].into())?;
+        txn.commit()?;
+        assert!(storage.inner.txn(client_id)?.get_version(v1)?.is_some());
+
+        storage.flush()?;
+        let mut txn = storage.inner.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+        assert_eq!(txn.get_version(v1)?, None);
+        assert!(txn.get_version(v2)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions_buffered() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.add_version(v2, v1, vec![2].into())?;
+        txn.set_snapshot(Snapshot::new(v1, Utc::now()), vec![3].into())?;
+        txn.commit()?;
+        storage.flush()?;
+
+        // the pruned version is hidden before it reaches the inner storage
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.prune_versions(v1)?, 1);
+        txn.commit()?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(v1)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        assert!(txn.get_version(v2)?.is_some());
+        assert!(storage.inner.txn(client_id)?.get_version(v1)?.is_some());
+
+        storage.flush()?;
+        let mut txn = storage.inner.txn(client_id)?;
+        assert_eq!(txn.get_version(v1)?, None);
+        assert!(txn.get_version(v2)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn record_sync_buffered() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+
+        storage.flush()?;
+        let client = storage.inner.txn(client_id)?.get_client()?.unwrap();
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn set_quota_buffered() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+        let quota = Quota {
+            max_bytes: Some(1024),
+            max_versions: Some(10),
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_quota(quota)?;
+        txn.commit()?;
+
+        assert_eq!(storage.txn(client_id)?.get_quota()?, quota);
+        storage.flush()?;
+        assert_eq!(storage.inner.txn(client_id)?.get_quota()?, quota);
+        Ok(())
+    }
+
+    #[test]
+    fn uncommitted_not_buffered() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            // dropped without commit
+        }
+
+        assert_eq!(storage.txn(client_id)?.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_conflict() -> anyhow::Result<()> {
+        let storage = BufferingStorage::with_clock(InMemoryStorage::new(), never(), clock());
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, Bytes::new())?;
+        assert!(txn
+            .add_version(Uuid::new_v4(), NIL_VERSION_ID, Bytes::new())
+            .is_err());
+        txn.commit()?;
+        Ok(())
+    }
+}
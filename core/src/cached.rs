@@ -0,0 +1,571 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// The key of an entry in the cache.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum CacheKey {
+    /// A client, by client ID.
+    Client(Uuid),
+    /// A version, by client ID and version ID.
+    Version(Uuid, Uuid),
+    /// A version, by client ID and parent version ID.
+    Child(Uuid, Uuid),
+}
+
+impl CacheKey {
+    fn client_id(&self) -> Uuid {
+        match self {
+            CacheKey::Client(client_id)
+            | CacheKey::Version(client_id, _)
+            | CacheKey::Child(client_id, _) => *client_id,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CacheValue {
+    Client(Client),
+    Version(Version),
+}
+
+/// A least-recently-used cache of committed data.
+struct Cache {
+    capacity: usize,
+    /// Entries, with the tick at which each was last used.
+    entries: HashMap<CacheKey, (CacheValue, u64)>,
+    /// Entries by the tick at which they were last used, oldest first.
+    by_use: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    /// A counter for each client, incremented whenever the client's cached data is invalidated.
+    generations: HashMap<Uuid, u64>,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            tick: 0,
+            generations: HashMap::new(),
+        }
+    }
+
+    fn generation(&self, client_id: Uuid) -> u64 {
+        self.generations.get(&client_id).copied().unwrap_or(0)
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<CacheValue> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.by_use.remove(used);
+        *used = self.tick;
+        self.by_use.insert(self.tick, *key);
+        Some(value.clone())
+    }
+
+    /// Insert an entry, unless the client's data has been invalidated since `generation`, in which
+    /// case the value may be stale.
+    fn insert(&mut self, key: CacheKey, value: CacheValue, generation: u64) {
+        if self.capacity == 0 || self.generation(key.client_id()) != generation {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key, (value, self.tick)) {
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.by_use.pop_first().expect("entries are in by_use");
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn invalidate(&mut self, keys: &[CacheKey]) {
+        for key in keys {
+            *self.generations.entry(key.client_id()).or_default() += 1;
+            if let Some((_, used)) = self.entries.remove(key) {
+                self.by_use.remove(&used);
+            }
+        }
+    }
+
+    /// Invalidate all of a client's entries.
+    fn invalidate_client(&mut self, client_id: Uuid) {
+        let keys: Vec<CacheKey> = self
+            .entries
+            .keys()
+            .filter(|key| key.client_id() == client_id)
+            .copied()
+            .collect();
+        *self.generations.entry(client_id).or_default() += 1;
+        self.invalidate(&keys);
+    }
+}
+
+/// A storage wrapper which caches clients and versions in memory, to reduce reads from the inner
+/// storage.
+///
+/// The cache holds up to a fixed number of clients and versions, evicting the least recently used.
+/// Only data which exists is cached. Once a transaction has modified a client, its reads bypass
+/// the cache, and the client's modified entries are invalidated when the transaction commits.
+///
+/// The cache is correct only if all changes to the inner storage are made through this wrapper, so
+/// it is not suitable for several servers sharing one storage.
+pub struct CachedStorage<S: Storage> {
+    inner: S,
+    cache: Mutex<Cache>,
+}
+
+impl<S: Storage> CachedStorage<S> {
+    /// Wrap `inner`, caching up to `capacity` clients and versions.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(Cache::new(capacity)),
+        }
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(CachedTxn {
+            client_id,
+            inner: self.inner.txn(client_id)?,
+            cache: &self.cache,
+            invalidated: vec![],
+            written: false,
+            deleted: false,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct CachedTxn<'a> {
+    client_id: Uuid,
+    inner: Box<dyn StorageTxn + 'a>,
+    cache: &'a Mutex<Cache>,
+    /// Cache entries to invalidate when this transaction commits.
+    invalidated: Vec<CacheKey>,
+    /// Whether this transaction has made any changes.
+    written: bool,
+    /// Whether this transaction has deleted the client, invalidating all of its entries.
+    deleted: bool,
+}
+
+impl CachedTxn<'_> {
+    /// Look up `key` in the cache, or read it with `read` and cache the result.
+    fn cached<T, R, F, I>(
+        &mut self,
+        key: CacheKey,
+        from_cache: F,
+        into_cache: I,
+        read: R,
+    ) -> Result<Option<T>, StorageError>
+    where
+        R: FnOnce(&mut dyn StorageTxn) -> Result<Option<T>, StorageError>,
+        F: FnOnce(CacheValue) -> Option<T>,
+        I: FnOnce(&T) -> CacheValue,
+    {
+        // the cache does not reflect this transaction's changes
+        if self.written {
+            return read(self.inner.as_mut());
+        }
+        let generation = {
+            let mut cache = self.cache.lock().expect("poisoned lock");
+            if let Some(value) = cache.get(&key).and_then(from_cache) {
+                return Ok(Some(value));
+            }
+            cache.generation(self.client_id)
+        };
+        let value = read(self.inner.as_mut())?;
+        if let Some(value) = &value {
+            self.cache
+                .lock()
+                .expect("poisoned lock")
+                .insert(key, into_cache(value), generation);
+        }
+        Ok(value)
+    }
+
+    /// Note that this transaction has changed the given cache entries.
+    fn write(&mut self, keys: impl IntoIterator<Item = CacheKey>) {
+        self.written = true;
+        self.invalidated.extend(keys);
+    }
+}
+
+impl StorageTxn for CachedTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.cached(
+            CacheKey::Client(self.client_id),
+            |value| match value {
+                CacheValue::Client(client) => Some(client),
+                _ => None,
+            },
+            |client| CacheValue::Client(client.clone()),
+            |txn| txn.get_client(),
+        )
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner.set_snapshot_from_reader(snapshot, data)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.inner.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.cached(
+            CacheKey::Child(self.client_id, parent_version_id),
+            |value| match value {
+                CacheValue::Version(version) => Some(version),
+                _ => None,
+            },
+            |version| CacheValue::Version(version.clone()),
+            |txn| txn.get_version_by_parent(parent_version_id),
+        )
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.cached(
+            CacheKey::Version(self.client_id, version_id),
+            |value| match value {
+                CacheValue::Version(version) => Some(version),
+                _ => None,
+            },
+            |version| CacheValue::Version(version.clone()),
+            |txn| txn.get_version(version_id),
+        )
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        // readers are not cached
+        self.inner.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        // missing versions are not cached, so only the client changes
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        // missing versions are not cached, so only the client changes
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.write([CacheKey::Client(self.client_id)]);
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, history_segment)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let parent_version_id = self
+            .inner
+            .get_version(version_id)?
+            .map(|v| v.parent_version_id);
+        self.write([CacheKey::Version(self.client_id, version_id)]);
+        if let Some(parent_version_id) = parent_version_id {
+            self.invalidated
+                .push(CacheKey::Child(self.client_id, parent_version_id));
+        }
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    /// Entries invalidated by the rolled-back changes are still invalidated on commit, which is
+    /// harmless.
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.write([]);
+        self.deleted = true;
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        let invalidated = std::mem::take(&mut self.invalidated);
+        let (cache, client_id, deleted) = (self.cache, self.client_id, self.deleted);
+        let invalidate = || {
+            let mut cache = cache.lock().expect("poisoned lock");
+            cache.invalidate(&invalidated);
+            if deleted {
+                cache.invalidate_client(client_id);
+            }
+        };
+        // Invalidate before committing, so that no other transaction caches the old values, and
+        // after, in case another transaction cached them while this one was committing.
+        invalidate();
+        self.inner.commit()?;
+        invalidate();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&CachedStorage::new(
+            InMemoryStorage::new(),
+            10
+        )));
+    }
+
+    fn version(version_id: Uuid) -> Version {
+        Version {
+            version_id,
+            parent_version_id: NIL_VERSION_ID,
+            history_segment: vec![1].into(),
+            created_at: None,
+        }
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let mut cache = Cache::new(2);
+        let client_id = Uuid::new_v4();
+        let keys: Vec<CacheKey> = (0..3)
+            .map(|_| CacheKey::Version(client_id, Uuid::new_v4()))
+            .collect();
+        cache.insert(keys[0], CacheValue::Version(version(Uuid::new_v4())), 0);
+        cache.insert(keys[1], CacheValue::Version(version(Uuid::new_v4())), 0);
+        // using the first entry makes the second the least recently used
+        assert!(cache.get(&keys[0]).is_some());
+        cache.insert(keys[2], CacheValue::Version(version(Uuid::new_v4())), 0);
+        assert!(cache.get(&keys[0]).is_some());
+        assert!(cache.get(&keys[1]).is_none());
+        assert!(cache.get(&keys[2]).is_some());
+        assert_eq!(cache.entries.len(), cache.by_use.len());
+    }
+
+    #[test]
+    fn stale_insert_ignored() {
+        let mut cache = Cache::new(2);
+        let client_id = Uuid::new_v4();
+        let key = CacheKey::Client(client_id);
+        let generation = cache.generation(client_id);
+        cache.invalidate(&[key]);
+        let client = Client::new(NIL_VERSION_ID, Utc::now());
+        cache.insert(key, CacheValue::Client(client), generation);
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn reads_cached() -> anyhow::Result<()> {
+        let storage = CachedStorage::new(InMemoryStorage::new(), 10);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.get_client()?.is_some());
+        assert!(txn.get_version(version_id)?.is_some());
+        assert!(txn.get_version_by_parent(NIL_VERSION_ID)?.is_some());
+        drop(txn);
+
+        let mut cache = storage.cache.lock().unwrap();
+        assert!(cache.get(&CacheKey::Client(client_id)).is_some());
+        assert!(cache
+            .get(&CacheKey::Version(client_id, version_id))
+            .is_some());
+        assert!(cache
+            .get(&CacheKey::Child(client_id, NIL_VERSION_ID))
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_invalidate() -> anyhow::Result<()> {
+        let storage = CachedStorage::new(InMemoryStorage::new(), 10);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+        // reads after a write in the same transaction see the write
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(client.snapshot.unwrap().version_id, version_id);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_client_invalidates() -> anyhow::Result<()> {
+        let storage = CachedStorage::new(InMemoryStorage::new(), 10);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+            txn.commit()?;
+        }
+        {
+            let mut txn = storage.txn(client_id)?;
+            assert!(txn.get_client()?.is_some());
+            assert!(txn.get_version(version_id)?.is_some());
+            assert!(txn.get_version_by_parent(NIL_VERSION_ID)?.is_some());
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        assert!(storage.cache.lock().unwrap().entries.is_empty());
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn zero_capacity() -> anyhow::Result<()> {
+        let storage = CachedStorage::new(InMemoryStorage::new(), 0);
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        assert!(storage.txn(client_id)?.get_client()?.is_some());
+        assert!(storage.cache.lock().unwrap().entries.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,679 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A change to storage, as published by [`ChangeStreamStorage`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum ChangeEvent {
+    /// A new client was created.
+    ClientCreated {
+        client_id: Uuid,
+        /// Time at which the client was created.
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A new version was added.
+    VersionAdded {
+        client_id: Uuid,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        /// Size of the history segment, in bytes.
+        size: usize,
+        /// Time at which the version was added.
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A new snapshot was set.
+    SnapshotSet {
+        client_id: Uuid,
+        version_id: Uuid,
+        /// Size of the snapshot data, in bytes.
+        size: usize,
+        /// Time at which the snapshot was set.
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A client was deleted, along with its versions and snapshot.
+    ClientDeleted {
+        client_id: Uuid,
+        /// Time at which the client was deleted.
+        timestamp: DateTime<Utc>,
+    },
+}
+
+impl ChangeEvent {
+    /// The client whose data changed.
+    pub fn client_id(&self) -> Uuid {
+        match self {
+            ChangeEvent::ClientCreated { client_id, .. }
+            | ChangeEvent::VersionAdded { client_id, .. }
+            | ChangeEvent::SnapshotSet { client_id, .. }
+            | ChangeEvent::ClientDeleted { client_id, .. } => *client_id,
+        }
+    }
+}
+
+/// A destination for change events, such as a Kafka topic or NATS subject.
+pub trait ChangePublisher: Send + Sync {
+    /// Publish the events from a single committed transaction, in order.
+    fn publish(&self, events: &[ChangeEvent]) -> anyhow::Result<()>;
+}
+
+/// A storage wrapper which publishes a [`ChangeEvent`] for each client created, version added,
+/// snapshot set, and client deleted.
+///
+/// Events are buffered in the transaction and published only after the transaction commits
+/// successfully, so changes that are never committed produce no events. Commits and their
+/// publication are serialized for each client, so events for each client are published in the
+/// order the changes were made. Different clients commit and publish concurrently, so a slow
+/// publisher only delays commits for the clients it is publishing.
+///
+/// Publication is at-most-once: if publishing fails, the error is logged but the commit is still
+/// reported as successful, since the changes have already been stored.
+pub struct ChangeStreamStorage<S: Storage, P: ChangePublisher> {
+    inner: S,
+    publisher: P,
+    clock: Arc<dyn Clock>,
+    commit_locks: CommitLocks,
+}
+
+/// A lock for each client with a commit in progress, serializing commits and their publication
+/// for each client.
+#[derive(Default)]
+struct CommitLocks(Mutex<HashMap<Uuid, Arc<Mutex<()>>>>);
+
+impl CommitLocks {
+    /// Get the lock for the given client, which must be passed to [`CommitLocks::release`] when
+    /// the commit is complete.
+    fn get(&self, client_id: Uuid) -> Arc<Mutex<()>> {
+        let mut locks = self.0.lock().expect("poisoned lock");
+        locks.entry(client_id).or_default().clone()
+    }
+
+    /// Release the lock for the given client, forgetting it if no other commit is using it.
+    fn release(&self, client_id: Uuid, lock: Arc<Mutex<()>>) {
+        let mut locks = self.0.lock().expect("poisoned lock");
+        drop(lock);
+        // clones are only made with the map locked, so the count cannot increase here
+        if locks
+            .get(&client_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&client_id);
+        }
+    }
+}
+
+impl<S: Storage, P: ChangePublisher> ChangeStreamStorage<S, P> {
+    /// Wrap `inner`, publishing changes to `publisher`.
+    pub fn new(inner: S, publisher: P) -> Self {
+        Self::with_clock(inner, publisher, Arc::new(SystemClock))
+    }
+
+    /// Wrap `inner`, using the given clock to timestamp events.
+    pub fn with_clock(inner: S, publisher: P, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            publisher,
+            clock,
+            commit_locks: CommitLocks::default(),
+        }
+    }
+
+    /// Get a reference to the publisher.
+    pub fn publisher(&self) -> &P {
+        &self.publisher
+    }
+}
+
+impl<S: Storage, P: ChangePublisher> Storage for ChangeStreamStorage<S, P> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(ChangeStreamTxn {
+            client_id,
+            inner: self.inner.txn(client_id)?,
+            publisher: &self.publisher,
+            clock: self.clock.as_ref(),
+            commit_locks: &self.commit_locks,
+            events: vec![],
+            savepoints: vec![],
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+/// A reader which counts the bytes read through it, to report the size of streamed data.
+struct CountingReader<'a> {
+    inner: &'a mut dyn Read,
+    count: usize,
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+}
+
+struct ChangeStreamTxn<'a> {
+    client_id: Uuid,
+    inner: Box<dyn StorageTxn + 'a>,
+    publisher: &'a dyn ChangePublisher,
+    clock: &'a dyn Clock,
+    commit_locks: &'a CommitLocks,
+    /// Events for changes made in this transaction, not yet published.
+    events: Vec<ChangeEvent>,
+    /// The savepoints in the inner transaction, with the length of `events` at each.
+    savepoints: Vec<(Savepoint, usize)>,
+}
+
+impl StorageTxn for ChangeStreamTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)?;
+        self.events.push(ChangeEvent::ClientCreated {
+            client_id: self.client_id,
+            timestamp: self.clock.now(),
+        });
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let event = ChangeEvent::SnapshotSet {
+            client_id: self.client_id,
+            version_id: snapshot.version_id,
+            size: data.len(),
+            timestamp: self.clock.now(),
+        };
+        self.inner.set_snapshot(snapshot, data)?;
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let version_id = snapshot.version_id;
+        let timestamp = self.clock.now();
+        let mut counting = CountingReader {
+            inner: data,
+            count: 0,
+        };
+        self.inner
+            .set_snapshot_from_reader(snapshot, &mut counting)?;
+        self.events.push(ChangeEvent::SnapshotSet {
+            client_id: self.client_id,
+            version_id,
+            size: counting.count,
+            timestamp,
+        });
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.inner.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version(version_id)
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        let event = ChangeEvent::VersionAdded {
+            client_id: self.client_id,
+            version_id,
+            parent_version_id,
+            size: history_segment.len(),
+            timestamp: self.clock.now(),
+        };
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)?;
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let timestamp = self.clock.now();
+        let mut counting = CountingReader {
+            inner: history_segment,
+            count: 0,
+        };
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, &mut counting)?;
+        self.events.push(ChangeEvent::VersionAdded {
+            client_id: self.client_id,
+            version_id,
+            parent_version_id,
+            size: counting.count,
+            timestamp,
+        });
+        Ok(())
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let timestamp = self.clock.now();
+        let mut counting = CountingReader {
+            inner: history_segment,
+            count: 0,
+        };
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, &mut counting)?;
+        self.events.push(ChangeEvent::VersionAdded {
+            client_id: self.client_id,
+            version_id,
+            parent_version_id,
+            size: counting.count,
+            timestamp,
+        });
+        Ok(())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        let savepoint = self.inner.savepoint()?;
+        self.savepoints.push((savepoint, self.events.len()));
+        Ok(savepoint)
+    }
+
+    /// Events for the rolled-back changes are not published.
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)?;
+        if let Some(i) = self.savepoints.iter().rposition(|(s, _)| *s == savepoint) {
+            self.events.truncate(self.savepoints[i].1);
+            self.savepoints.truncate(i + 1);
+        }
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()?;
+        self.events.push(ChangeEvent::ClientDeleted {
+            client_id: self.client_id,
+            timestamp: self.clock.now(),
+        });
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        let lock = self.commit_locks.get(self.client_id);
+        let res = {
+            let _guard = lock.lock().expect("poisoned lock");
+            self.commit_and_publish()
+        };
+        self.commit_locks.release(self.client_id, lock);
+        res
+    }
+}
+
+impl ChangeStreamTxn<'_> {
+    /// Commit the inner transaction and publish its events. The caller must hold the client's
+    /// commit lock.
+    fn commit_and_publish(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()?;
+        let events = std::mem::take(&mut self.events);
+        if !events.is_empty() {
+            if let Err(err) = self.publisher.publish(&events) {
+                log::error!(
+                    "Failed to publish {} change events for client {}: {err:?}",
+                    events.len(),
+                    self.client_id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&ChangeStreamStorage::new(
+            InMemoryStorage::new(),
+            VecPublisher::default()
+        )));
+    }
+
+    /// A publisher which collects events in memory.
+    #[derive(Default)]
+    struct VecPublisher(Mutex<Vec<ChangeEvent>>);
+
+    impl ChangePublisher for VecPublisher {
+        fn publish(&self, events: &[ChangeEvent]) -> anyhow::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    impl VecPublisher {
+        fn events(&self) -> Vec<ChangeEvent> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn storage() -> (
+        ChangeStreamStorage<InMemoryStorage, VecPublisher>,
+        DateTime<Utc>,
+    ) {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let storage = ChangeStreamStorage::with_clock(
+            InMemoryStorage::new_lenient(),
+            VecPublisher::default(),
+            Arc::new(ManualClock::new(now)),
+        );
+        (storage, now)
+    }
+
+    #[test]
+    fn committed_events_in_order() -> anyhow::Result<()> {
+        let (storage, now) = storage();
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.add_version(v2, v1, vec![4].into())?;
+        txn.set_snapshot(Snapshot::new(v2, now), vec![5, 6].into())?;
+
+        // nothing is published until commit
+        assert!(storage.publisher().events().is_empty());
+        txn.commit()?;
+
+        assert_eq!(
+            storage.publisher().events(),
+            vec![
+                ChangeEvent::ClientCreated {
+                    client_id,
+                    timestamp: now,
+                },
+                ChangeEvent::VersionAdded {
+                    client_id,
+                    version_id: v1,
+                    parent_version_id: NIL_VERSION_ID,
+                    size: 3,
+                    timestamp: now,
+                },
+                ChangeEvent::VersionAdded {
+                    client_id,
+                    version_id: v2,
+                    parent_version_id: v1,
+                    size: 1,
+                    timestamp: now,
+                },
+                ChangeEvent::SnapshotSet {
+                    client_id,
+                    version_id: v2,
+                    size: 2,
+                    timestamp: now,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_sizes() -> anyhow::Result<()> {
+        let (storage, now) = storage();
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.set_snapshot_from_reader(Snapshot::new(v1, now), &mut &[1u8, 2, 3, 4][..])?;
+        let v2 = Uuid::new_v4();
+        txn.add_version_from_reader(v2, v1, &mut &[5u8, 6][..])?;
+        txn.commit()?;
+
+        let events = storage.publisher().events();
+        assert_eq!(
+            events[2..],
+            [
+                ChangeEvent::SnapshotSet {
+                    client_id,
+                    version_id: v1,
+                    size: 4,
+                    timestamp: now,
+                },
+                ChangeEvent::VersionAdded {
+                    client_id,
+                    version_id: v2,
+                    parent_version_id: v1,
+                    size: 2,
+                    timestamp: now,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_publishes_nothing() -> anyhow::Result<()> {
+        let (storage, _) = storage();
+        let client_id = Uuid::new_v4();
+
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+            // dropped without commit
+        }
+
+        assert!(storage.publisher().events().is_empty());
+        assert!(storage.txn(client_id)?.get_client()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_write_not_published() -> anyhow::Result<()> {
+        let (storage, _) = storage();
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        // no client exists, so this fails
+        assert!(txn
+            .add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())
+            .is_err());
+        txn.commit()?;
+
+        assert!(storage.publisher().events().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn commit_locks_per_client() {
+        let locks = CommitLocks::default();
+        let (client1, client2) = (Uuid::new_v4(), Uuid::new_v4());
+        let lock1 = locks.get(client1);
+        let guard1 = lock1.lock().unwrap();
+
+        // another client's lock is independent
+        let lock2 = locks.get(client2);
+        assert!(lock2.try_lock().is_ok());
+
+        // the same client shares the lock
+        let lock1b = locks.get(client1);
+        assert!(lock1b.try_lock().is_err());
+
+        // locks are forgotten when no longer in use
+        locks.release(client2, lock2);
+        locks.release(client1, lock1b);
+        assert_eq!(locks.0.lock().unwrap().len(), 1);
+        drop(guard1);
+        locks.release(client1, lock1);
+        assert!(locks.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn commit_lock_released_after_commit() -> anyhow::Result<()> {
+        let (storage, _) = storage();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        assert!(storage.commit_locks.0.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn client_deleted() -> anyhow::Result<()> {
+        let (storage, now) = storage();
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+
+        assert_eq!(
+            storage.publisher().events(),
+            vec![
+                ChangeEvent::ClientCreated {
+                    client_id,
+                    timestamp: now,
+                },
+                ChangeEvent::ClientDeleted {
+                    client_id,
+                    timestamp: now,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialized() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let event = ChangeEvent::ClientDeleted {
+            client_id,
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        };
+        assert_eq!(event.client_id(), client_id);
+        assert_eq!(
+            serde_json::to_value(&event)?,
+            serde_json::json!({
+                "type": "client_deleted",
+                "client_id": client_id,
+                "timestamp": "2024-01-01T00:00:00Z",
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use uuid::Uuid;
+
+/// Prefix identifying checksummed data, followed by the SHA-256 of the data and the data itself.
+const CHECKSUM_MARKER: &[u8] = b"\0tcs-sha256\0";
+
+/// Overhead added to each checksummed value: the marker and the digest.
+const OVERHEAD: usize = CHECKSUM_MARKER.len() + 32;
+
+/// Prefix data with its checksum.
+fn add_checksum(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(OVERHEAD + data.len());
+    result.extend_from_slice(CHECKSUM_MARKER);
+    result.extend_from_slice(&Sha256::digest(data));
+    result.extend_from_slice(data);
+    result
+}
+
+/// Verify and strip the checksum added by [`add_checksum`], failing with
+/// [`StorageError::Corrupt`] if it does not match. Data without a checksum, such as data written
+/// before checksums were enabled, is returned unchanged.
+fn verify_checksum(what: &str, data: Bytes) -> Result<Bytes, StorageError> {
+    let Some(rest) = data.strip_prefix(CHECKSUM_MARKER) else {
+        return Ok(data);
+    };
+    if rest.len() < 32 {
+        return Err(StorageError::Corrupt(format!(
+            "Checksum of {what} is truncated"
+        )));
+    }
+    let (digest, payload) = rest.split_at(32);
+    if Sha256::digest(payload).as_slice() != digest {
+        return Err(StorageError::Corrupt(format!(
+            "Checksum mismatch in {what}"
+        )));
+    }
+    Ok(data.slice_ref(payload))
+}
+
+/// A storage wrapper which stores a SHA-256 checksum with each history segment and snapshot, and
+/// verifies it when the data is read.
+///
+/// Data whose checksum does not match fails with [`StorageError::Corrupt`], rather than being
+/// returned to clients. This protects against silent corruption in backends such as files and
+/// object stores which do not verify data themselves. Data without a checksum, such as data
+/// written before this wrapper was introduced, is returned unchanged.
+///
+/// When combined with [`crate::CompressedStorage`] or [`crate::EncryptedStorage`], this wrapper
+/// should be innermost, so that the checksum covers the data as stored.
+pub struct ChecksummedStorage<S: Storage> {
+    inner: S,
+}
+
+impl<S: Storage> ChecksummedStorage<S> {
+    /// Wrap `inner`.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: Storage> Storage for ChecksummedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(ChecksummedTxn {
+            inner: self.inner.txn(client_id)?,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let inner = self.inner.capabilities();
+        StorageCapabilities {
+            max_history_segment_len: inner.max_history_segment_len.saturating_sub(OVERHEAD),
+            // snapshot data is verified in memory
+            supports_streaming: false,
+            ..inner
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct ChecksummedTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+}
+
+fn verify_version(version: Version) -> Result<Version, StorageError> {
+    let what = format!("version {}", version.version_id);
+    Ok(Version {
+        history_segment: verify_checksum(&what, version.history_segment)?,
+        ..version
+    })
+}
+
+impl StorageTxn for ChecksummedTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.inner
+            .set_snapshot(snapshot, add_checksum(&data).into())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner
+            .get_snapshot_data(version_id)?
+            .map(|data| verify_checksum(&format!("snapshot {version_id}"), data))
+            .transpose()
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version_by_parent(parent_version_id)?
+            .map(verify_version)
+            .transpose()
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version(version_id)?
+            .map(verify_version)
+            .transpose()
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.inner.add_version(
+            version_id,
+            parent_version_id,
+            add_checksum(&history_segment).into(),
+        )
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut data = vec![];
+        history_segment.read_to_end(&mut data)?;
+        let data = add_checksum(&data);
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, &mut data.as_slice())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&ChecksummedStorage::new(
+            InMemoryStorage::new()
+        )));
+    }
+
+    #[test]
+    fn checksum_round_trip() -> anyhow::Result<()> {
+        let data = add_checksum(b"abc");
+        assert_eq!(data.len(), 3 + OVERHEAD);
+        assert_eq!(verify_checksum("test", data.into())?, b"abc".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_data_unchanged() -> anyhow::Result<()> {
+        assert_eq!(verify_checksum("test", Bytes::new())?, Vec::<u8>::new());
+        assert_eq!(
+            verify_checksum("test", vec![1, 2, 3].into())?,
+            vec![1, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_data() {
+        let mut data = add_checksum(b"abc");
+        *data.last_mut().unwrap() ^= 1;
+        let err = verify_checksum("test", data.into()).unwrap_err();
+        assert!(matches!(err, StorageError::Corrupt(_)));
+
+        let truncated = add_checksum(b"abc")[..CHECKSUM_MARKER.len() + 5].to_vec();
+        let err = verify_checksum("test", truncated.into()).unwrap_err();
+        assert!(matches!(err, StorageError::Corrupt(_)));
+    }
+
+    #[test]
+    fn storage_round_trip() -> anyhow::Result<()> {
+        let storage = ChecksummedStorage::new(InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![4, 5].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let stored = storage
+            .inner
+            .txn(client_id)?
+            .get_version(version_id)?
+            .unwrap()
+            .history_segment;
+        assert_eq!(stored, add_checksum(&[1, 2, 3]));
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .unwrap()
+                .history_segment,
+            vec![1, 2, 3]
+        );
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![4, 5].into()));
+        Ok(())
+    }
+
+    #[test]
+    fn storage_detects_corruption() -> anyhow::Result<()> {
+        let storage = ChecksummedStorage::new(InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            // write a checksummed segment whose contents do not match, directly to the inner
+            // storage
+            let mut data = add_checksum(&[1, 2, 3]);
+            *data.last_mut().unwrap() = 9;
+            let mut txn = storage.inner.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, data.into())?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        let err = txn.get_version(version_id).unwrap_err();
+        assert!(matches!(err, StorageError::Corrupt(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A source of the current time, and a way to wait for time to pass.
+///
+/// Implementations other than [`SystemClock`] are useful for testing time-dependent behavior
+/// without actually waiting.
+pub trait Clock: Send + Sync {
+    /// Get the current time.
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Block the current thread for the given duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real system clock.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// A clock which only advances when told to, for testing.
+///
+/// Sleeping on this clock returns immediately, after advancing the clock by the requested
+/// duration.
+pub struct ManualClock(Mutex<DateTime<Utc>>);
+
+impl ManualClock {
+    /// Create a new clock, set to the given time.
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    /// Advance the clock by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.0.lock().expect("poisoned lock");
+        *now += chrono::Duration::from_std(duration).expect("duration out of range");
+    }
+
+    /// Set the clock to the given time.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().expect("poisoned lock") = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().expect("poisoned lock")
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn manual_clock_sleep_advances() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let clock = ManualClock::new(start);
+        clock.sleep(Duration::from_secs(90));
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(90));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use uuid::Uuid;
+
+/// Byte preceding compressed data.
+const COMPRESSED_MARKER: u8 = 0xc5;
+
+/// The magic number beginning every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The default zstd compression level.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Compress data, returning it unchanged if compression would not make it smaller.
+fn compress(data: Bytes, level: i32) -> Result<Bytes, StorageError> {
+    let compressed = zstd::bulk::compress(&data, level)?;
+    if compressed.len() + 1 >= data.len() {
+        return Ok(data);
+    }
+    let mut result = Vec::with_capacity(compressed.len() + 1);
+    result.push(COMPRESSED_MARKER);
+    result.extend_from_slice(&compressed);
+    Ok(result.into())
+}
+
+/// Decompress data produced by [`compress`]. Data which is not compressed, such as data written
+/// before compression was enabled, is returned unchanged.
+fn decompress(data: Bytes) -> Result<Bytes, StorageError> {
+    match data.split_first() {
+        Some((&COMPRESSED_MARKER, rest)) if rest.starts_with(&ZSTD_MAGIC) => {
+            Ok(zstd::stream::decode_all(rest)?.into())
+        }
+        _ => Ok(data),
+    }
+}
+
+/// A storage wrapper which compresses history segments and snapshot data with zstd before
+/// storing them in the inner storage.
+///
+/// Compressed data is stored with a marker byte, and data which would not be made smaller is
+/// stored unchanged. Data without the marker, such as data written before this wrapper was
+/// introduced, is returned unchanged.
+pub struct CompressedStorage<S: Storage> {
+    inner: S,
+    level: i32,
+}
+
+impl<S: Storage> CompressedStorage<S> {
+    /// Wrap `inner`, compressing with [`DEFAULT_COMPRESSION_LEVEL`].
+    pub fn new(inner: S) -> Self {
+        Self::with_level(inner, DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Wrap `inner`, compressing with the given zstd level, from 1 (fastest) to 22 (smallest).
+    pub fn with_level(inner: S, level: i32) -> Self {
+        Self { inner, level }
+    }
+}
+
+impl<S: Storage> Storage for CompressedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(CompressedTxn {
+            inner: self.inner.txn(client_id)?,
+            level: self.level,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            // snapshot data is compressed in memory
+            supports_streaming: false,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct CompressedTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+    level: i32,
+}
+
+fn decompress_version(version: Version) -> Result<Version, StorageError> {
+    Ok(Version {
+        history_segment: decompress(version.history_segment)?,
+        ..version
+    })
+}
+
+impl StorageTxn for CompressedTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.inner
+            .set_snapshot(snapshot, compress(data, self.level)?)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner
+            .get_snapshot_data(version_id)?
+            .map(decompress)
+            .transpose()
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version_by_parent(parent_version_id)?
+            .map(decompress_version)
+            .transpose()
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version(version_id)?
+            .map(decompress_version)
+            .transpose()
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.inner.add_version(
+            version_id,
+            parent_version_id,
+            compress(history_segment, self.level)?,
+        )
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut data = vec![];
+        history_segment.read_to_end(&mut data)?;
+        let data = compress(data.into(), self.level)?;
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, &mut data.as_ref())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&CompressedStorage::new(
+            InMemoryStorage::new()
+        )));
+    }
+
+    #[test]
+    fn compress_round_trip() -> anyhow::Result<()> {
+        let data = Bytes::from(b"abcd".repeat(1000));
+        let compressed = compress(data.clone(), DEFAULT_COMPRESSION_LEVEL)?;
+        assert_eq!(compressed[0], COMPRESSED_MARKER);
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(compressed)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn incompressible_stored_unchanged() -> anyhow::Result<()> {
+        let data = Bytes::from(vec![1, 2, 3]);
+        assert_eq!(compress(data.clone(), DEFAULT_COMPRESSION_LEVEL)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_data_unchanged() -> anyhow::Result<()> {
+        assert_eq!(decompress(Bytes::new())?, Bytes::new());
+        assert_eq!(decompress(vec![1, 2, 3].into())?, vec![1, 2, 3]);
+        // the marker alone does not indicate compressed data
+        assert_eq!(
+            decompress(vec![COMPRESSED_MARKER, 1, 2].into())?,
+            vec![COMPRESSED_MARKER, 1, 2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn storage_round_trip() -> anyhow::Result<()> {
+        let storage = CompressedStorage::with_level(InMemoryStorage::new(), 10);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let segment = Bytes::from(b"history ".repeat(500));
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, segment.clone())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), segment.clone())?;
+        txn.commit()?;
+        drop(txn);
+
+        let stored = storage
+            .inner
+            .txn(client_id)?
+            .get_version(version_id)?
+            .unwrap()
+            .history_segment;
+        assert!(stored.len() < segment.len());
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            segment
+        );
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .unwrap()
+                .history_segment,
+            segment
+        );
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(segment));
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+//! A suite of tests of the behavior required of a [`Storage`] implementation, for use by storage
+//! backends, including those outside this repository.
+//!
+//! Each test is a function taking a newly-created, empty storage. The
+//! [`storage_conformance_tests!`](crate::storage_conformance_tests) macro defines a `#[test]` for
+//! each of them. Tests of optional features are skipped unless the storage's
+//! [`StorageCapabilities`](crate::StorageCapabilities) claim support for the feature.
+//!
+//! ```ignore
+//! mod conformance {
+//!     use super::*;
+//!
+//!     fn with_storage(
+//!         test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>,
+//!     ) -> anyhow::Result<()> {
+//!         let tmp_dir = tempfile::TempDir::new()?;
+//!         test(&MyStorage::new(tmp_dir.path())?)
+//!     }
+//!
+//!     taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+//! }
+//! ```
+use crate::error::StorageError;
+use crate::storage::{Snapshot, Storage};
+use bytes::Bytes;
+use chrono::{TimeZone, Utc};
+use uuid::Uuid;
+
+/// A conformance test, given an empty storage.
+pub type ConformanceTest = fn(&dyn Storage) -> anyhow::Result<()>;
+
+/// Run a conformance test with a storage created by `with_storage`, which calls the given test
+/// with a new, empty storage and returns its result.
+pub fn run<W>(with_storage: W, test: ConformanceTest) -> anyhow::Result<()>
+where
+    W: FnOnce(&dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()>,
+{
+    with_storage(&test)
+}
+
+/// Define a `#[test]` for each of the conformance tests in [`crate::conformance`].
+///
+/// The argument is a function or closure which creates a new, empty storage and calls the
+/// `&dyn Fn(&dyn Storage) -> anyhow::Result<()>` it is given with that storage, returning its
+/// result. This allows the storage to depend on resources, such as a temporary directory, which
+/// must outlive it.
+///
+/// ```ignore
+/// storage_conformance_tests!(|test| test(&InMemoryStorage::new()));
+/// ```
+#[macro_export]
+macro_rules! storage_conformance_tests {
+    ($with_storage:expr) => {
+        $crate::storage_conformance_tests!(
+            $with_storage;
+            client_lifecycle,
+            clients_isolated,
+            version_chain,
+            invalid_version_ids,
+            add_version_if_latest,
+            snapshots,
+            commit_visible,
+            rollback,
+            savepoints,
+            list_clients,
+            delete_version,
+            soft_delete,
+        );
+    };
+    ($with_storage:expr; $($test:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $test() -> anyhow::Result<()> {
+                $crate::conformance::run($with_storage, $crate::conformance::$test)
+            }
+        )*
+    };
+}
+
+/// A client can be created, read, and deleted if the storage supports it.
+pub fn client_lifecycle(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id = Uuid::new_v4();
+    let latest_version_id = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    assert_eq!(txn.get_client()?, None);
+    txn.new_client(latest_version_id)?;
+    let client = txn.get_client()?.expect("client was created");
+    assert_eq!(client.latest_version_id, latest_version_id);
+    assert_eq!(client.snapshot, None);
+    txn.commit()?;
+    drop(txn);
+
+    let mut txn = storage.txn(client_id)?;
+    let client = txn.get_client()?.expect("client was committed");
+    assert_eq!(client.latest_version_id, latest_version_id);
+    if txn.delete_client().is_ok() {
+        assert_eq!(txn.get_client()?, None);
+        txn.commit()?;
+        drop(txn);
+        assert_eq!(storage.txn(client_id)?.get_client()?, None);
+    }
+    Ok(())
+}
+
+/// One client's data is not visible to another client.
+pub fn clients_isolated(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id1 = Uuid::new_v4();
+    let client_id2 = Uuid::new_v4();
+    let version_id = Uuid::new_v4();
+    let mut txn = storage.txn(client_id1)?;
+    txn.new_client(Uuid::nil())?;
+    txn.add_version(version_id, Uuid::nil(), vec![1].into())?;
+    txn.commit()?;
+    drop(txn);
+
+    let mut txn = storage.txn(client_id2)?;
+    assert_eq!(txn.get_client()?, None);
+    assert_eq!(txn.get_version(version_id)?, None);
+    assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+    Ok(())
+}
+
+/// Versions form a chain, each found by its ID and by its parent's ID, and adding a version
+/// updates the client's latest version.
+pub fn version_chain(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    txn.new_client(Uuid::nil())?;
+
+    let version_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+    let mut parent_version_id = Uuid::nil();
+    for (i, version_id) in version_ids.iter().enumerate() {
+        txn.add_version(
+            *version_id,
+            parent_version_id,
+            Bytes::from(vec![i as u8; 3]),
+        )?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, *version_id);
+        parent_version_id = *version_id;
+    }
+    txn.commit()?;
+    drop(txn);
+
+    let mut txn = storage.txn(client_id)?;
+    let mut parent_version_id = Uuid::nil();
+    for (i, version_id) in version_ids.iter().enumerate() {
+        let version = txn
+            .get_version(*version_id)?
+            .expect("version was committed");
+        assert_eq!(version.version_id, *version_id);
+        assert_eq!(version.parent_version_id, parent_version_id);
+        assert_eq!(version.history_segment, vec![i as u8; 3]);
+        let child = txn
+            .get_version_by_parent(parent_version_id)?
+            .expect("child is indexed");
+        assert_eq!(child.version_id, *version_id);
+        parent_version_id = *version_id;
+    }
+    assert_eq!(txn.get_version_by_parent(parent_version_id)?, None);
+    assert_eq!(txn.get_version(Uuid::new_v4())?, None);
+    Ok(())
+}
+
+/// A version cannot be added with the nil ID or the ID of an existing version.
+pub fn invalid_version_ids(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id = Uuid::new_v4();
+    let v1 = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    txn.new_client(Uuid::nil())?;
+    txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+
+    for version_id in [v1, Uuid::nil()] {
+        let err = txn.add_version(version_id, v1, vec![2].into()).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                StorageError::InvalidVersionId { version_id: v } if v == version_id
+            ),
+            "unexpected error {err:#}"
+        );
+    }
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+    assert_eq!(txn.get_version(v1)?.unwrap().history_segment, vec![1]);
+    txn.commit()?;
+    Ok(())
+}
+
+/// A version is only added by `add_version_if_latest` if its parent is the latest version.
+pub fn add_version_if_latest(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    txn.new_client(Uuid::nil())?;
+    let v1 = Uuid::new_v4();
+    txn.add_version_if_latest(v1, Uuid::nil(), &mut &[1u8][..])?;
+    assert!(txn
+        .add_version_if_latest(Uuid::new_v4(), Uuid::nil(), &mut &[2u8][..])
+        .is_err());
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+    txn.commit()?;
+    Ok(())
+}
+
+/// A snapshot is stored with its data, and `versions_since` counts the versions added after it.
+pub fn snapshots(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    txn.new_client(Uuid::nil())?;
+    let v1 = Uuid::new_v4();
+    txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+
+    let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let snapshot = Snapshot::new(v1, timestamp);
+    txn.set_snapshot(snapshot.clone(), vec![4, 5, 6].into())?;
+    assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snapshot.clone()));
+    txn.commit()?;
+    drop(txn);
+
+    let mut txn = storage.txn(client_id)?;
+    let client = txn.get_client()?.unwrap();
+    assert_eq!(client.snapshot, Some(snapshot));
+    assert_eq!(txn.get_snapshot_data(v1)?, Some(vec![4, 5, 6].into()));
+
+    txn.add_version(Uuid::new_v4(), v1, vec![2].into())?;
+    let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+    assert_eq!(snapshot.versions_since, 1);
+    assert_eq!(snapshot.version_id, v1);
+    txn.commit()?;
+    Ok(())
+}
+
+/// Committed changes are visible to later transactions.
+pub fn commit_visible(storage: &dyn Storage) -> anyhow::Result<()> {
+    let client_id = Uuid::new_v4();
+    let v1 = Uuid::new_v4();
+    {
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.commit()?;
+    }
+    {
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+        txn.commit()?;
+    }
+    let mut txn = storage.txn(client_id)?;
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+    assert!(txn.get_version(v1)?.is_some());
+    Ok(())
+}
+
+/// A transaction dropped without being committed has no effect, if the storage supports rollback.
+pub fn rollback(storage: &dyn Storage) -> anyhow::Result<()> {
+    if !storage.capabilities().supports_rollback {
+        return Ok(());
+    }
+    let client_id = Uuid::new_v4();
+    {
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.commit()?;
+    }
+    let v1 = Uuid::new_v4();
+    {
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+    }
+    let mut txn = storage.txn(client_id)?;
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, Uuid::nil());
+    assert_eq!(txn.get_version(v1)?, None);
+    assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+    Ok(())
+}
+
+/// Changes since a savepoint can be rolled back, if the storage supports savepoints.
+pub fn savepoints(storage: &dyn Storage) -> anyhow::Result<()> {
+    if !storage.capabilities().supports_savepoints {
+        return Ok(());
+    }
+    let client_id = Uuid::new_v4();
+    let v1 = Uuid::new_v4();
+    let v2 = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    txn.new_client(Uuid::nil())?;
+    let outer = txn.savepoint()?;
+    txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+    let inner = txn.savepoint()?;
+    txn.add_version(v2, v1, vec![2].into())?;
+
+    txn.rollback_to(inner)?;
+    assert_eq!(txn.get_version(v2)?, None);
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+    txn.rollback_to(outer)?;
+    assert_eq!(txn.get_version(v1)?, None);
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, Uuid::nil());
+    txn.commit()?;
+    drop(txn);
+
+    // the client, created before the savepoints, was committed
+    assert!(storage.txn(client_id)?.get_client()?.is_some());
+    Ok(())
+}
+
+/// Clients are listed in order of their IDs, if the storage supports client enumeration.
+pub fn list_clients(storage: &dyn Storage) -> anyhow::Result<()> {
+    if !storage.capabilities().supports_client_enumeration {
+        return Ok(());
+    }
+    let mut client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+    for client_id in &client_ids {
+        let mut txn = storage.txn(*client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.commit()?;
+    }
+    client_ids.sort();
+
+    let mut txn = storage.txn(Uuid::nil())?;
+    assert_eq!(txn.list_clients(None, 10)?, client_ids);
+    assert_eq!(txn.list_clients(None, 2)?, client_ids[..2]);
+    assert_eq!(txn.list_clients(Some(client_ids[0]), 10)?, client_ids[1..]);
+    Ok(())
+}
+
+/// A deleted version is no longer found by its ID or its parent's ID, if the storage supports
+/// compaction.
+pub fn delete_version(storage: &dyn Storage) -> anyhow::Result<()> {
+    if !storage.capabilities().supports_compaction {
+        return Ok(());
+    }
+    let client_id = Uuid::new_v4();
+    let v1 = Uuid::new_v4();
+    let v2 = Uuid::new_v4();
+    let mut txn = storage.txn(client_id)?;
+    txn.new_client(Uuid::nil())?;
+    txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+    txn.add_version(v2, v1, vec![2].into())?;
+    txn.delete_version(v1)?;
+    txn.commit()?;
+    drop(txn);
+
+    let mut txn = storage.txn(client_id)?;
+    assert_eq!(txn.get_version(v1)?, None);
+    assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+    assert_eq!(txn.get_version_by_parent(v1)?.unwrap().version_id, v2);
+    assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+    // deleting a version that does not exist has no effect
+    txn.delete_version(v1)?;
+    Ok(())
+}
+
+/// A client can be marked as deleted and restored, if the storage supports soft deletion.
+pub fn soft_delete(storage: &dyn Storage) -> anyhow::Result<()> {
+    if !storage.capabilities().supports_soft_delete {
+        return Ok(());
+    }
+    let client_id = Uuid::new_v4();
+    let deleted_at = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let mut txn = storage.txn(client_id)?;
+    assert!(txn.set_deleted(Some(deleted_at)).is_err());
+    txn.new_client(Uuid::nil())?;
+    txn.set_deleted(Some(deleted_at))?;
+    txn.commit()?;
+    drop(txn);
+
+    let mut txn = storage.txn(client_id)?;
+    assert_eq!(txn.get_client()?.unwrap().deleted_at, Some(deleted_at));
+    txn.set_deleted(None)?;
+    txn.commit()?;
+    drop(txn);
+
+    assert_eq!(
+        storage.txn(client_id)?.get_client()?.unwrap().deleted_at,
+        None
+    );
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use crate::blob::BlobStore;
+use crate::error::StorageError;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+fn ref_key(key: &str) -> String {
+    format!("refs/{key}")
+}
+
+fn content_key(hash: &str) -> String {
+    format!("content/{hash}")
+}
+
+fn refcount_key(hash: &str) -> String {
+    format!("refcounts/{hash}")
+}
+
+/// A [`BlobStore`] wrapper which stores each distinct blob only once, keyed by the SHA-256 hash
+/// of its content.
+///
+/// Each key refers to a content blob by hash, and each content blob has a reference count. When a
+/// key is replaced or deleted, the count is decremented, and content that is no longer referenced
+/// is deleted. Used beneath [`BlobStorage`](crate::BlobStorage), identical history segments and
+/// snapshots, such as those uploaded by several clients restored from the same backup, are stored
+/// once.
+///
+/// Reference counts are updated under a lock, so only one process may use the inner store at a
+/// time. Operations are ordered so that an interruption may leak a content blob, but never
+/// deletes content which is still referenced.
+pub struct DedupBlobStore<B: BlobStore> {
+    inner: B,
+    lock: Mutex<()>,
+}
+
+impl<B: BlobStore> DedupBlobStore<B> {
+    /// Wrap `inner`, which should not contain any blobs not written by this wrapper.
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Get the hash of the content to which a key refers, if any.
+    fn get_ref(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.inner
+            .get(&ref_key(key))?
+            .map(|hash| Ok(String::from_utf8(hash)?))
+            .transpose()
+    }
+
+    fn refcount(&self, hash: &str) -> anyhow::Result<u64> {
+        match self.inner.get(&refcount_key(hash))? {
+            Some(count) => Ok(u64::from_be_bytes(count.as_slice().try_into().map_err(
+                |_| anyhow::anyhow!("Invalid reference count for content {hash}"),
+            )?)),
+            None => Ok(0),
+        }
+    }
+
+    /// Decrement the reference count of content, deleting it if it is no longer referenced.
+    fn release(&self, hash: &str) -> anyhow::Result<()> {
+        let count = self.refcount(hash)?;
+        if count > 1 {
+            return self
+                .inner
+                .put(&refcount_key(hash), &(count - 1).to_be_bytes());
+        }
+        // with the count deleted first, a later put of the same content rewrites it
+        self.inner.delete(&refcount_key(hash))?;
+        self.inner.delete(&content_key(hash))
+    }
+}
+
+impl<B: BlobStore> BlobStore for DedupBlobStore<B> {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let hash = format!("{:x}", Sha256::digest(data));
+        let _guard = self.lock.lock().expect("poisoned lock");
+        let old = self.get_ref(key)?;
+        if old.as_deref() == Some(hash.as_str()) {
+            return Ok(());
+        }
+
+        // reference the content before releasing the old content, so that nothing referenced is
+        // ever deleted
+        let count = self.refcount(&hash)?;
+        if count == 0 {
+            self.inner.put(&content_key(&hash), data)?;
+        }
+        self.inner
+            .put(&refcount_key(&hash), &(count + 1).to_be_bytes())?;
+        self.inner.put(&ref_key(key), hash.as_bytes())?;
+        if let Some(old) = old {
+            self.release(&old)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(hash) = self.get_ref(key)? else {
+            return Ok(None);
+        };
+        match self.inner.get(&content_key(&hash))? {
+            Some(data) => Ok(Some(data)),
+            None => Err(
+                StorageError::Corrupt(format!("Content {hash} of blob {key} is missing")).into(),
+            ),
+        }
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().expect("poisoned lock");
+        let Some(hash) = self.get_ref(key)? else {
+            return Ok(());
+        };
+        self.inner.delete(&ref_key(key))?;
+        self.release(&hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blob::{BlobStorage, InMemoryBlobStore};
+    use crate::inmemory::InMemoryStorage;
+    use crate::storage::{Snapshot, Storage};
+    use crate::{Bytes, NIL_VERSION_ID};
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    fn content_keys(store: &DedupBlobStore<InMemoryBlobStore>) -> Vec<String> {
+        store
+            .inner
+            .keys()
+            .into_iter()
+            .filter(|k| k.starts_with("content/"))
+            .collect()
+    }
+
+    #[test]
+    fn identical_content_stored_once() -> anyhow::Result<()> {
+        let store = DedupBlobStore::new(InMemoryBlobStore::new());
+        store.put("a", b"data")?;
+        store.put("b", b"data")?;
+        store.put("c", b"other")?;
+        assert_eq!(content_keys(&store).len(), 2);
+        assert_eq!(store.get("a")?, Some(b"data".to_vec()));
+        assert_eq!(store.get("b")?, Some(b"data".to_vec()));
+        assert_eq!(store.get("c")?, Some(b"other".to_vec()));
+        assert_eq!(store.get("d")?, None);
+
+        let hash = store.get_ref("a")?.unwrap();
+        assert_eq!(store.refcount(&hash)?, 2);
+        // putting the same content again does not add a reference
+        store.put("a", b"data")?;
+        assert_eq!(store.refcount(&hash)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn unreferenced_content_deleted() -> anyhow::Result<()> {
+        let store = DedupBlobStore::new(InMemoryBlobStore::new());
+        store.put("a", b"data")?;
+        store.put("b", b"data")?;
+
+        store.delete("a")?;
+        assert_eq!(store.get("a")?, None);
+        assert_eq!(store.get("b")?, Some(b"data".to_vec()));
+        assert_eq!(content_keys(&store).len(), 1);
+
+        // replacing the last reference deletes the old content
+        store.put("b", b"new")?;
+        assert_eq!(store.get("b")?, Some(b"new".to_vec()));
+        assert_eq!(content_keys(&store).len(), 1);
+
+        store.delete("b")?;
+        store.delete("b")?;
+        assert_eq!(store.inner.keys(), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_deduplicated_across_clients() -> anyhow::Result<()> {
+        let storage = BlobStorage::new(
+            InMemoryStorage::new_lenient(),
+            DedupBlobStore::new(InMemoryBlobStore::new()),
+        );
+        let snapshot = Bytes::from_static(b"restored snapshot");
+        let client_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for client_id in client_ids {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.set_snapshot(Snapshot::new(Uuid::new_v4(), Utc::now()), snapshot.clone())?;
+            txn.commit()?;
+        }
+        assert_eq!(content_keys(storage.blobs()).len(), 1);
+
+        // once both clients replace their snapshots, the shared content is deleted
+        for client_id in client_ids {
+            let mut txn = storage.txn(client_id)?;
+            txn.set_snapshot(
+                Snapshot::new(Uuid::new_v4(), Utc::now()),
+                client_id.as_bytes().to_vec().into(),
+            )?;
+            txn.commit()?;
+        }
+        let hash = format!("{:x}", Sha256::digest(&snapshot));
+        assert!(!content_keys(storage.blobs()).contains(&content_key(&hash)));
+        assert_eq!(content_keys(storage.blobs()).len(), 2);
+        Ok(())
+    }
+}
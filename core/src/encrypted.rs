@@ -0,0 +1,546 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use uuid::Uuid;
+
+/// Prefix identifying encrypted data, followed by the key ID, nonce, and ciphertext.
+const ENCRYPTED_MARKER: &[u8] = b"\0tcs-aes-gcm\0";
+
+/// Length of an AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+
+/// Overhead added to each encrypted value: the marker, key ID, nonce, and authentication tag.
+const OVERHEAD: usize = ENCRYPTED_MARKER.len() + 4 + NONCE_LEN + 16;
+
+/// The kind of data being encrypted, included in the associated data so that data of one kind
+/// cannot be substituted for another.
+#[derive(Clone, Copy)]
+enum Kind {
+    Snapshot = b's' as isize,
+    Version = b'v' as isize,
+}
+
+/// The keys used by [`EncryptedStorage`].
+struct Keyring {
+    /// The ID of the key used to encrypt new data.
+    current: u32,
+    keys: HashMap<u32, Aes256Gcm>,
+}
+
+impl Keyring {
+    /// Get the associated data binding a ciphertext to its client and version.
+    fn aad(kind: Kind, client_id: Uuid, version_id: Uuid) -> Vec<u8> {
+        let mut aad = vec![kind as u8];
+        aad.extend_from_slice(client_id.as_bytes());
+        aad.extend_from_slice(version_id.as_bytes());
+        aad
+    }
+
+    fn encrypt(
+        &self,
+        kind: Kind,
+        client_id: Uuid,
+        version_id: Uuid,
+        plaintext: &[u8],
+    ) -> Result<Bytes, StorageError> {
+        let cipher = &self.keys[&self.current];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = Self::aad(kind, client_id, version_id);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+        let mut data = Vec::with_capacity(OVERHEAD + plaintext.len());
+        data.extend_from_slice(ENCRYPTED_MARKER);
+        data.extend_from_slice(&self.current.to_be_bytes());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data.into())
+    }
+
+    /// Get the ID of the key with which data was encrypted, or `None` if it is not encrypted.
+    fn key_id(data: &[u8]) -> Option<u32> {
+        let rest = data.strip_prefix(ENCRYPTED_MARKER)?;
+        Some(u32::from_be_bytes(rest.get(..4)?.try_into().ok()?))
+    }
+
+    /// Decrypt data produced by [`Keyring::encrypt`]. Data which is not encrypted, such as data
+    /// written before encryption was enabled, is returned unchanged.
+    fn decrypt(
+        &self,
+        kind: Kind,
+        client_id: Uuid,
+        version_id: Uuid,
+        data: Bytes,
+    ) -> Result<Bytes, StorageError> {
+        let Some(key_id) = Self::key_id(&data) else {
+            return Ok(data);
+        };
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or_else(|| anyhow::anyhow!("Data is encrypted with unknown key {key_id}"))?;
+        let rest = &data[ENCRYPTED_MARKER.len() + 4..];
+        if rest.len() < NONCE_LEN {
+            return Err(StorageError::Corrupt("Encrypted data is truncated".into()));
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let aad = Self::aad(kind, client_id, version_id);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                StorageError::Corrupt("Decryption failed; data may have been modified".into())
+            })
+            .map(Bytes::from)
+    }
+}
+
+/// A storage wrapper which encrypts history segments and snapshot data with AES-256-GCM before
+/// storing them in the inner storage.
+///
+/// Each encrypted value records the ID of the key used to encrypt it, and is bound to its client
+/// and version so that it cannot be moved elsewhere undetected. Data which is not encrypted, such
+/// as data written before this wrapper was introduced, is returned unchanged.
+///
+/// To rotate keys, create the storage with the new key, adding the old key with
+/// [`EncryptedStorage::with_old_key`], and call [`EncryptedStorage::rotate_client`] for each
+/// client. Once all clients are rotated, the old key is no longer needed.
+pub struct EncryptedStorage<S: Storage> {
+    inner: S,
+    keys: Keyring,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    /// Wrap `inner`, encrypting new data with the given 256-bit key, identified by `key_id`.
+    pub fn new(inner: S, key_id: u32, key: [u8; 32]) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+        Self {
+            inner,
+            keys: Keyring {
+                current: key_id,
+                keys,
+            },
+        }
+    }
+
+    /// Add a key used only to decrypt existing data.
+    pub fn with_old_key(mut self, key_id: u32, key: [u8; 32]) -> Self {
+        // the current key is never replaced
+        self.keys
+            .keys
+            .entry(key_id)
+            .or_insert_with(|| Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+        self
+    }
+
+    /// Re-encrypt a client's snapshot and history segments with the current key, returning the
+    /// number of values re-encrypted.
+    ///
+    /// History segments are rewritten by removing and re-adding the versions reachable from the
+    /// client's latest version, so the inner storage must support
+    /// [`StorageTxn::delete_version`]. This is done in a single transaction.
+    pub fn rotate_client(&self, client_id: Uuid) -> Result<usize, StorageError> {
+        let mut txn = self.inner.txn(client_id)?;
+        let Some(client) = txn.get_client()? else {
+            return Ok(0);
+        };
+        let needs_rotation = |data: &[u8]| Keyring::key_id(data) != Some(self.keys.current);
+
+        // Find the versions reachable from the latest version, newest first.
+        let mut versions = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = client.latest_version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = txn.get_version(vid)? else {
+                break;
+            };
+            vid = version.parent_version_id;
+            versions.push(version);
+        }
+
+        let mut rotated = 0;
+        let rewrite_versions = versions.iter().any(|v| needs_rotation(&v.history_segment));
+        if rewrite_versions {
+            for version in &versions {
+                txn.delete_version(version.version_id)?;
+            }
+            // re-adding the versions oldest-first leaves the latest version unchanged
+            for version in versions.into_iter().rev() {
+                let history_segment = if needs_rotation(&version.history_segment) {
+                    rotated += 1;
+                    let plaintext = self.keys.decrypt(
+                        Kind::Version,
+                        client_id,
+                        version.version_id,
+                        version.history_segment,
+                    )?;
+                    self.keys
+                        .encrypt(Kind::Version, client_id, version.version_id, &plaintext)?
+                } else {
+                    version.history_segment
+                };
+                txn.add_version(
+                    version.version_id,
+                    version.parent_version_id,
+                    history_segment,
+                )?;
+            }
+        }
+
+        // Re-adding versions increments versions_since, so in that case the snapshot is rewritten
+        // even if its data need not be.
+        if let Some(snapshot) = client.snapshot {
+            let data = txn.get_snapshot_data(snapshot.version_id)?;
+            if let Some(data) = data.filter(|d| rewrite_versions || needs_rotation(d)) {
+                let data = if needs_rotation(&data) {
+                    rotated += 1;
+                    let plaintext =
+                        self.keys
+                            .decrypt(Kind::Snapshot, client_id, snapshot.version_id, data)?;
+                    self.keys
+                        .encrypt(Kind::Snapshot, client_id, snapshot.version_id, &plaintext)?
+                } else {
+                    data
+                };
+                txn.set_snapshot(snapshot, data)?;
+            }
+        }
+        txn.commit()?;
+        log::debug!("re-encrypted {rotated} values for client {client_id}");
+        Ok(rotated)
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(EncryptedTxn {
+            client_id,
+            inner: self.inner.txn(client_id)?,
+            keys: &self.keys,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let inner = self.inner.capabilities();
+        StorageCapabilities {
+            max_history_segment_len: inner.max_history_segment_len.saturating_sub(OVERHEAD),
+            // snapshot data is encrypted in memory
+            supports_streaming: false,
+            ..inner
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct EncryptedTxn<'a> {
+    client_id: Uuid,
+    inner: Box<dyn StorageTxn + 'a>,
+    keys: &'a Keyring,
+}
+
+impl EncryptedTxn<'_> {
+    fn decrypt_version(&self, version: Version) -> Result<Version, StorageError> {
+        Ok(Version {
+            history_segment: self.keys.decrypt(
+                Kind::Version,
+                self.client_id,
+                version.version_id,
+                version.history_segment,
+            )?,
+            ..version
+        })
+    }
+}
+
+impl StorageTxn for EncryptedTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let data = self
+            .keys
+            .encrypt(Kind::Snapshot, self.client_id, snapshot.version_id, &data)?;
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner
+            .get_snapshot_data(version_id)?
+            .map(|data| {
+                self.keys
+                    .decrypt(Kind::Snapshot, self.client_id, version_id, data)
+            })
+            .transpose()
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version_by_parent(parent_version_id)?
+            .map(|v| self.decrypt_version(v))
+            .transpose()
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner
+            .get_version(version_id)?
+            .map(|v| self.decrypt_version(v))
+            .transpose()
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        let history_segment =
+            self.keys
+                .encrypt(Kind::Version, self.client_id, version_id, &history_segment)?;
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut data = vec![];
+        history_segment.read_to_end(&mut data)?;
+        let data = self
+            .keys
+            .encrypt(Kind::Version, self.client_id, version_id, &data)?;
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, &mut data.as_ref())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&EncryptedStorage::new(
+            InMemoryStorage::new(),
+            1,
+            KEY1
+        )));
+    }
+
+    const KEY1: [u8; 32] = [1; 32];
+    const KEY2: [u8; 32] = [2; 32];
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let storage = EncryptedStorage::new(InMemoryStorage::new(), 1, KEY1);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![4, 5].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        // the inner storage holds only ciphertext
+        let mut inner = storage.inner.txn(client_id)?;
+        let stored = inner.get_version(version_id)?.unwrap().history_segment;
+        assert_eq!(Keyring::key_id(&stored), Some(1));
+        assert_eq!(stored.len(), 3 + OVERHEAD);
+        drop(inner);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .unwrap()
+                .history_segment,
+            vec![1, 2, 3]
+        );
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![4, 5].into()));
+        Ok(())
+    }
+
+    #[test]
+    fn bound_to_version() -> anyhow::Result<()> {
+        let storage = EncryptedStorage::new(InMemoryStorage::new(), 1, KEY1);
+        let client_id = Uuid::new_v4();
+        let data = storage
+            .keys
+            .encrypt(Kind::Version, client_id, Uuid::new_v4(), b"abc")?;
+        assert!(storage
+            .keys
+            .decrypt(Kind::Version, client_id, Uuid::new_v4(), data.clone())
+            .is_err());
+        assert!(storage
+            .keys
+            .decrypt(Kind::Snapshot, client_id, Uuid::new_v4(), data)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn plaintext_returned_unchanged() -> anyhow::Result<()> {
+        let storage = EncryptedStorage::new(InMemoryStorage::new(), 1, KEY1);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.inner.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![7].into())?;
+            txn.commit()?;
+        }
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_version(version_id)?.unwrap().history_segment,
+            vec![7]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_key() -> anyhow::Result<()> {
+        let old = EncryptedStorage::new(InMemoryStorage::new(), 1, KEY1);
+        let data = old
+            .keys
+            .encrypt(Kind::Version, Uuid::nil(), Uuid::nil(), b"abc")?;
+        let new = EncryptedStorage::new(InMemoryStorage::new(), 2, KEY2);
+        assert!(new
+            .keys
+            .decrypt(Kind::Version, Uuid::nil(), Uuid::nil(), data)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_client() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let snapshot = Snapshot::new(vids[1], Utc::now()).with_versions_since(1);
+
+        let storage = EncryptedStorage::new(InMemoryStorage::new(), 1, KEY1);
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            let mut parent_version_id = NIL_VERSION_ID;
+            for vid in &vids {
+                txn.add_version(*vid, parent_version_id, vid.as_bytes().to_vec().into())?;
+                parent_version_id = *vid;
+            }
+            txn.set_snapshot(snapshot.clone(), vec![9].into())?;
+            txn.commit()?;
+        }
+
+        let storage = EncryptedStorage::new(storage.inner, 2, KEY2).with_old_key(1, KEY1);
+        assert_eq!(storage.rotate_client(client_id)?, 4);
+        // rotating again has nothing to do
+        assert_eq!(storage.rotate_client(client_id)?, 0);
+
+        // the old key is no longer needed
+        let storage = EncryptedStorage::new(storage.inner, 2, KEY2);
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, vids[2]);
+        assert_eq!(client.snapshot, Some(snapshot));
+        assert_eq!(txn.get_snapshot_data(vids[1])?, Some(vec![9].into()));
+        let mut parent_version_id = NIL_VERSION_ID;
+        for vid in &vids {
+            let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
+            assert_eq!(version.version_id, *vid);
+            assert_eq!(version.history_segment, vid.as_bytes().to_vec());
+            parent_version_id = *vid;
+        }
+        Ok(())
+    }
+}
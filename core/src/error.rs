@@ -1,3 +1,5 @@
+use uuid::Uuid;
+
 /// An error from the [`crate::Server`] type.
 ///
 /// This type represents only circumstances outside the realm of the protocol, and not the specific
@@ -8,6 +10,233 @@ pub enum ServerError {
     #[error("No such client")]
     NoSuchClient,
 
+    /// The client has been deleted, and can be restored until its data is purged.
+    #[error("Client has been deleted")]
+    ClientDeleted,
+
+    /// The server cannot handle this request right now, and the client should retry later. This
+    /// includes transient storage failures which persisted after the server retried them.
+    #[error("Try again later")]
+    TryAgainLater,
+
+    /// The request would exceed the client's quota.
+    #[error("Client quota exceeded")]
+    QuotaExceeded,
+
+    /// A version could not be added because its ID is nil or already in use.
+    #[error("Invalid version ID {0}")]
+    InvalidVersionId(Uuid),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ServerError {
+    fn from(err: anyhow::Error) -> Self {
+        ServerError::Other(err)
+    }
+}
+
+impl From<StorageError> for ServerError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            err if err.is_transient() => ServerError::TryAgainLater,
+            StorageError::QuotaExceeded => ServerError::QuotaExceeded,
+            StorageError::NotFound => ServerError::NoSuchClient,
+            StorageError::InvalidVersionId { version_id } => {
+                ServerError::InvalidVersionId(version_id)
+            }
+            StorageError::Backend(err) => ServerError::Other(err),
+            err => ServerError::Other(err.into()),
+        }
+    }
+}
+
+/// An error from a [`crate::Storage`] implementation.
+///
+/// Each variant other than [`StorageError::Backend`] has a meaning the [`crate::Server`]
+/// understands, and is mapped to a [`ServerError`] without matching on error messages. Any other
+/// failure of the storage backend itself is a [`StorageError::Backend`].
+///
+/// Lower-level interfaces such as [`crate::KvTxn`] and [`crate::BlobStore`] return
+/// `anyhow::Error`. Converting such an error to a `StorageError` recovers a `StorageError` wrapped
+/// in it; use [`StorageError::of`] to recognize one without converting.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    /// The storage is too busy to perform this operation, and it should be retried later.
+    #[error("Try again later")]
+    TryAgainLater,
+
+    /// A transient failure, such as a dropped connection, which may succeed if retried.
+    #[error("Transient storage error: {0}")]
+    Retryable(anyhow::Error),
+
+    /// A version could not be added because its parent is not the client's latest version, such
+    /// as when another replica added a version first.
+    #[error("Parent version conflict; latest version is {latest_version_id}")]
+    Conflict { latest_version_id: Uuid },
+
+    /// A write would exceed the client's [`Quota`](crate::Quota).
+    #[error("Client quota exceeded")]
+    QuotaExceeded,
+
+    /// A version could not be added because its ID is nil or a version with that ID already
+    /// exists.
+    #[error("Version ID {version_id} is nil or already exists")]
+    InvalidVersionId { version_id: Uuid },
+
+    /// The operation requires a client which does not exist.
+    #[error("No such client")]
+    NotFound,
+
+    /// Stored data is invalid, such as a malformed value or a missing blob.
+    #[error("Corrupt data: {0}")]
+    Corrupt(String),
+
+    /// Any other failure of the storage backend.
     #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    Backend(anyhow::Error),
+}
+
+impl From<anyhow::Error> for StorageError {
+    fn from(err: anyhow::Error) -> Self {
+        // A backend failure keeps its context, rather than unwrapping to the inner error.
+        match err.downcast_ref::<StorageError>() {
+            None | Some(StorageError::Backend(_)) => StorageError::Backend(err),
+            Some(_) => err.downcast().expect("checked by downcast_ref"),
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Backend(err.into())
+    }
+}
+
+impl StorageError {
+    /// Get the [`StorageError`] wrapped in the given error, if any.
+    pub fn of(err: &anyhow::Error) -> Option<&StorageError> {
+        err.downcast_ref::<StorageError>()
+    }
+
+    /// Determine whether this error is transient, such as a lock timeout or a dropped connection,
+    /// so that the operation may succeed if retried. Other errors, such as constraint
+    /// violations, are permanent.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            StorageError::TryAgainLater | StorageError::Retryable(_)
+        )
+    }
+
+    /// Determine whether this error is a [`StorageError::Retryable`].
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, StorageError::Retryable(_))
+    }
+
+    /// Get the client's latest version, if this error is a [`StorageError::Conflict`].
+    pub fn conflict(&self) -> Option<Uuid> {
+        match self {
+            StorageError::Conflict { latest_version_id } => Some(*latest_version_id),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn try_again_later() {
+        let err = StorageError::TryAgainLater;
+        assert!(matches!(ServerError::from(err), ServerError::TryAgainLater));
+    }
+
+    #[test]
+    fn retryable() {
+        let err = StorageError::Retryable(anyhow::anyhow!("dropped"));
+        assert!(err.is_retryable());
+        assert!(!StorageError::TryAgainLater.is_retryable());
+        assert!(matches!(ServerError::from(err), ServerError::TryAgainLater));
+    }
+
+    #[test]
+    fn is_transient() {
+        assert!(StorageError::TryAgainLater.is_transient());
+        assert!(StorageError::Retryable(anyhow::anyhow!("dropped")).is_transient());
+        assert!(!StorageError::QuotaExceeded.is_transient());
+        assert!(!StorageError::NotFound.is_transient());
+        assert!(!StorageError::Backend(anyhow::anyhow!("uhoh")).is_transient());
+    }
+
+    #[test]
+    fn conflict() {
+        let latest_version_id = Uuid::new_v4();
+        let err = StorageError::Conflict { latest_version_id };
+        assert_eq!(err.conflict(), Some(latest_version_id));
+        assert_eq!(StorageError::NotFound.conflict(), None);
+    }
+
+    #[test]
+    fn quota_exceeded() {
+        let err = StorageError::QuotaExceeded;
+        assert!(matches!(ServerError::from(err), ServerError::QuotaExceeded));
+    }
+
+    #[test]
+    fn not_found() {
+        let err = StorageError::NotFound;
+        assert!(matches!(ServerError::from(err), ServerError::NoSuchClient));
+    }
+
+    #[test]
+    fn corrupt() {
+        let err = StorageError::Corrupt("bad".into());
+        assert!(matches!(ServerError::from(err), ServerError::Other(_)));
+    }
+
+    #[test]
+    fn invalid_version_id() {
+        let version_id = Uuid::new_v4();
+        let err = StorageError::InvalidVersionId { version_id };
+        assert!(matches!(
+            ServerError::from(err),
+            ServerError::InvalidVersionId(v) if v == version_id
+        ));
+    }
+
+    #[test]
+    fn backend() {
+        let err = StorageError::Backend(anyhow::anyhow!("uhoh"));
+        assert!(matches!(ServerError::from(err), ServerError::Other(e) if e.to_string() == "uhoh"));
+    }
+
+    #[test]
+    fn from_anyhow() {
+        let err: anyhow::Error = StorageError::NotFound.into();
+        assert!(matches!(
+            StorageError::of(&err),
+            Some(StorageError::NotFound)
+        ));
+        assert!(matches!(StorageError::from(err), StorageError::NotFound));
+
+        let err = anyhow::Error::from(StorageError::QuotaExceeded).context("adding version");
+        assert!(matches!(
+            StorageError::from(err),
+            StorageError::QuotaExceeded
+        ));
+
+        let err = anyhow::Error::from(StorageError::Backend(anyhow::anyhow!("uhoh")))
+            .context("getting client");
+        let StorageError::Backend(err) = StorageError::from(err) else {
+            panic!("expected a backend error");
+        };
+        assert_eq!(format!("{err:#}"), "getting client: uhoh");
+
+        let err = anyhow::anyhow!("uhoh");
+        assert!(StorageError::of(&err).is_none());
+        assert!(matches!(StorageError::from(err), StorageError::Backend(_)));
+    }
 }
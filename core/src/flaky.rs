@@ -0,0 +1,444 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Configuration for the faults injected by [`FlakyStorage`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FaultConfig {
+    /// Fraction of operations, between 0.0 and 1.0, which fail with
+    /// [`StorageError::Retryable`]. This includes beginning a transaction and committing it.
+    pub failure_rate: f64,
+
+    /// If true, committing a transaction which has performed a write always fails, and the
+    /// transaction's changes are not committed.
+    pub fail_commits_after_write: bool,
+
+    /// Time to wait before each operation.
+    pub latency: Duration,
+
+    /// Seed for the pseudo-random choice of failing operations, so that failures are
+    /// reproducible.
+    pub seed: u64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            failure_rate: 0.0,
+            fail_commits_after_write: false,
+            latency: Duration::ZERO,
+            seed: 0,
+        }
+    }
+}
+
+/// A storage wrapper which injects failures and latency, for testing how callers handle
+/// unreliable storage.
+///
+/// Injected failures are [`StorageError::Retryable`]. When a commit fails, the inner transaction
+/// is dropped without being committed, so the inner storage must tolerate that (for example,
+/// [`InMemoryStorage::new_lenient`](crate::InMemoryStorage::new_lenient)).
+pub struct FlakyStorage<S: Storage> {
+    inner: S,
+    config: FaultConfig,
+    clock: Arc<dyn Clock>,
+    rng: Mutex<u64>,
+    failures: AtomicU64,
+}
+
+impl<S: Storage> FlakyStorage<S> {
+    /// Wrap `inner`, injecting faults according to `config`.
+    pub fn new(inner: S, config: FaultConfig) -> Self {
+        Self::with_clock(inner, config, Arc::new(SystemClock))
+    }
+
+    /// Wrap `inner`, using the given clock to inject latency.
+    pub fn with_clock(inner: S, config: FaultConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            config,
+            clock,
+            rng: Mutex::new(config.seed),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the wrapped storage.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The number of failures injected so far.
+    pub fn injected_failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the configured latency, then decide whether this operation fails.
+    fn fault(&self, op: &str) -> Result<(), StorageError> {
+        if !self.config.latency.is_zero() {
+            self.clock.sleep(self.config.latency);
+        }
+        if self.config.failure_rate > 0.0 && self.next_random() < self.config.failure_rate {
+            return Err(self.fail(op));
+        }
+        Ok(())
+    }
+
+    /// Generate the error for an injected failure of `op`.
+    fn fail(&self, op: &str) -> StorageError {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        log::debug!("injecting failure in {op}");
+        StorageError::Retryable(anyhow::anyhow!("injected failure in {op}"))
+    }
+
+    /// Generate a pseudo-random number in [0, 1), using splitmix64.
+    fn next_random(&self) -> f64 {
+        let mut state = self.rng.lock().expect("poisoned lock");
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl<S: Storage> Storage for FlakyStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        self.fault("txn")?;
+        Ok(Box::new(FlakyTxn {
+            inner: self.inner.txn(client_id)?,
+            storage: self,
+            wrote: false,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.fault("max_versions_since_snapshot")?;
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.fault("flush")?;
+        self.inner.flush()
+    }
+}
+
+struct FlakyTxn<'a, S: Storage> {
+    inner: Box<dyn StorageTxn + 'a>,
+    storage: &'a FlakyStorage<S>,
+    wrote: bool,
+}
+
+impl<S: Storage> FlakyTxn<'_, S> {
+    /// Inject a fault into a read operation.
+    fn read(&self, op: &str) -> Result<(), StorageError> {
+        self.storage.fault(op)
+    }
+
+    /// Inject a fault into a write operation, and note that the transaction has written.
+    fn write(&mut self, op: &str) -> Result<(), StorageError> {
+        self.storage.fault(op)?;
+        self.wrote = true;
+        Ok(())
+    }
+}
+
+impl<S: Storage> StorageTxn for FlakyTxn<'_, S> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.read("get_client")?;
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.write("new_client")?;
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.write("set_snapshot")?;
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.write("set_snapshot_from_reader")?;
+        self.inner.set_snapshot_from_reader(snapshot, data)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.write("record_sync")?;
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn get_quota(&mut self) -> Result<Quota, StorageError> {
+        self.read("get_quota")?;
+        self.inner.get_quota()
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.write("set_quota")?;
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.write("set_deleted")?;
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.read("get_snapshot_data")?;
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.read("get_snapshot_reader")?;
+        self.inner.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.read("list_snapshots")?;
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.read("get_version_by_parent")?;
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.read("get_version")?;
+        self.inner.get_version(version_id)
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.read("get_version_reader_by_parent")?;
+        self.inner.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.read("get_version_reader")?;
+        self.inner.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.write("add_version")?;
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.write("add_version_from_reader")?;
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.write("add_version_if_latest")?;
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, history_segment)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.write("delete_version")?;
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.read("savepoint")?;
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.read("rollback_to")?;
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.write("delete_client")?;
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.read("list_clients")?;
+        self.inner.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.read("commit")?;
+        if self.wrote && self.storage.config.fail_commits_after_write {
+            return Err(self.storage.fail("commit"));
+        }
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn no_faults() -> anyhow::Result<()> {
+        let storage = FlakyStorage::new(InMemoryStorage::new(), FaultConfig::default());
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        assert_eq!(storage.injected_failures(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn always_fail() -> anyhow::Result<()> {
+        let config = FaultConfig {
+            failure_rate: 1.0,
+            ..FaultConfig::default()
+        };
+        let storage = FlakyStorage::new(InMemoryStorage::new(), config);
+
+        let err = storage.txn(Uuid::new_v4()).err().unwrap();
+        assert!(err.is_retryable());
+        assert_eq!(storage.injected_failures(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn failure_rate() -> anyhow::Result<()> {
+        let config = FaultConfig {
+            failure_rate: 0.25,
+            seed: 42,
+            ..FaultConfig::default()
+        };
+        let storage = FlakyStorage::new(InMemoryStorage::new_lenient(), config);
+
+        // each iteration is a single operation, so about a quarter of them fail
+        for _ in 0..1000 {
+            let _ = storage.txn(Uuid::new_v4());
+        }
+        let failures = storage.injected_failures();
+        assert!(failures > 200 && failures < 350, "{failures} failures");
+        Ok(())
+    }
+
+    #[test]
+    fn failures_reproducible() -> anyhow::Result<()> {
+        let config = FaultConfig {
+            failure_rate: 0.5,
+            seed: 7,
+            ..FaultConfig::default()
+        };
+        let outcomes = || {
+            let storage = FlakyStorage::new(InMemoryStorage::new_lenient(), config);
+            (0..20)
+                .map(|_| storage.txn(Uuid::new_v4()).is_ok())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(outcomes(), outcomes());
+        Ok(())
+    }
+
+    #[test]
+    fn fail_commits_after_write() -> anyhow::Result<()> {
+        let config = FaultConfig {
+            fail_commits_after_write: true,
+            ..FaultConfig::default()
+        };
+        let storage = FlakyStorage::new(InMemoryStorage::new_lenient(), config);
+        let client_id = Uuid::new_v4();
+
+        // A read-only transaction commits.
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let err = txn.commit().unwrap_err();
+        assert!(err.is_retryable());
+        drop(txn);
+
+        // The write was not committed.
+        let mut txn = storage.inner().txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn latency() -> anyhow::Result<()> {
+        let clock = Arc::new(ManualClock::new(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        ));
+        let start = clock.now();
+        let config = FaultConfig {
+            latency: Duration::from_millis(5),
+            ..FaultConfig::default()
+        };
+        let storage = FlakyStorage::with_clock(InMemoryStorage::new(), config, clock.clone());
+
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.get_client()?;
+        txn.commit()?;
+        assert_eq!(clock.now() - start, chrono::Duration::milliseconds(15));
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
-use super::{Client, Snapshot, Storage, StorageTxn, Version};
+use super::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::error::StorageError;
+#[cfg(feature = "serde")]
+use anyhow::Context;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::{Mutex, MutexGuard};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
 use uuid::Uuid;
 
 struct Inner {
@@ -8,7 +20,10 @@ struct Inner {
     clients: HashMap<Uuid, Client>,
 
     /// Snapshot data, indexed by client id
-    snapshots: HashMap<Uuid, Vec<u8>>,
+    snapshots: HashMap<Uuid, Bytes>,
+
+    /// Snapshots older than the client's latest snapshot, most recent first, indexed by client id
+    retained: HashMap<Uuid, Vec<(Snapshot, Bytes)>>,
 
     /// Versions, indexed by (client_id, version_id)
     versions: HashMap<(Uuid, Uuid), Version>,
@@ -17,90 +32,543 @@ struct Inner {
     children: HashMap<(Uuid, Uuid), Uuid>,
 }
 
+#[cfg(feature = "serde")]
+/// The serialized form of a client, in a persisted [`InMemoryStorage`].
+#[derive(Serialize, Deserialize)]
+struct PersistedClient {
+    client_id: Uuid,
+    latest_version_id: Uuid,
+    snapshot: Option<PersistedSnapshot>,
+    snapshot_data: Option<Vec<u8>>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_sync_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    retained_snapshots: Vec<PersistedRetainedSnapshot>,
+    #[serde(default)]
+    quota_max_bytes: Option<u64>,
+    #[serde(default)]
+    quota_max_versions: Option<u64>,
+    #[serde(default)]
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct PersistedSnapshot {
+    version_id: Uuid,
+    timestamp: DateTime<Utc>,
+    versions_since: u32,
+}
+
+#[cfg(feature = "serde")]
+impl From<&Snapshot> for PersistedSnapshot {
+    fn from(s: &Snapshot) -> Self {
+        PersistedSnapshot {
+            version_id: s.version_id,
+            timestamp: s.timestamp,
+            versions_since: s.versions_since,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<PersistedSnapshot> for Snapshot {
+    fn from(s: PersistedSnapshot) -> Self {
+        Snapshot {
+            version_id: s.version_id,
+            timestamp: s.timestamp,
+            versions_since: s.versions_since,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct PersistedRetainedSnapshot {
+    snapshot: PersistedSnapshot,
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct PersistedVersion {
+    client_id: Uuid,
+    version_id: Uuid,
+    parent_version_id: Uuid,
+    history_segment: Vec<u8>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "serde")]
+/// The serialized form of a persisted [`InMemoryStorage`].
+#[derive(Serialize, Deserialize)]
+struct Persisted {
+    clients: Vec<PersistedClient>,
+    versions: Vec<PersistedVersion>,
+}
+
+impl Inner {
+    fn empty() -> Self {
+        Inner {
+            clients: HashMap::new(),
+            snapshots: HashMap::new(),
+            retained: HashMap::new(),
+            versions: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::empty()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Error opening `{}`", path.display()))
+            }
+        };
+        let persisted: Persisted = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Error reading `{}`", path.display()))?;
+
+        let mut inner = Self::empty();
+        for c in persisted.clients {
+            inner.clients.insert(
+                c.client_id,
+                Client {
+                    latest_version_id: c.latest_version_id,
+                    snapshot: c.snapshot.map(Snapshot::from),
+                    created_at: c.created_at,
+                    last_sync_at: c.last_sync_at,
+                    user_agent: c.user_agent,
+                    quota: Quota {
+                        max_bytes: c.quota_max_bytes,
+                        max_versions: c.quota_max_versions,
+                    },
+                    deleted_at: c.deleted_at,
+                },
+            );
+            if let Some(data) = c.snapshot_data {
+                inner.snapshots.insert(c.client_id, data.into());
+            }
+            if !c.retained_snapshots.is_empty() {
+                inner.retained.insert(
+                    c.client_id,
+                    c.retained_snapshots
+                        .into_iter()
+                        .map(|r| (r.snapshot.into(), r.data.into()))
+                        .collect(),
+                );
+            }
+        }
+        for v in persisted.versions {
+            inner
+                .children
+                .insert((v.client_id, v.parent_version_id), v.version_id);
+            inner.versions.insert(
+                (v.client_id, v.version_id),
+                Version {
+                    version_id: v.version_id,
+                    parent_version_id: v.parent_version_id,
+                    history_segment: v.history_segment.into(),
+                    created_at: v.created_at,
+                },
+            );
+        }
+        Ok(inner)
+    }
+
+    /// Write the contents to `path`, atomically replacing any existing file.
+    #[cfg(feature = "serde")]
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let persisted = Persisted {
+            clients: self
+                .clients
+                .iter()
+                .map(|(client_id, client)| PersistedClient {
+                    client_id: *client_id,
+                    latest_version_id: client.latest_version_id,
+                    snapshot: client.snapshot.as_ref().map(PersistedSnapshot::from),
+                    snapshot_data: self.snapshots.get(client_id).map(|data| data.to_vec()),
+                    created_at: client.created_at,
+                    last_sync_at: client.last_sync_at,
+                    user_agent: client.user_agent.clone(),
+                    retained_snapshots: self
+                        .retained
+                        .get(client_id)
+                        .into_iter()
+                        .flatten()
+                        .map(|(snapshot, data)| PersistedRetainedSnapshot {
+                            snapshot: snapshot.into(),
+                            data: data.to_vec(),
+                        })
+                        .collect(),
+                    quota_max_bytes: client.quota.max_bytes,
+                    quota_max_versions: client.quota.max_versions,
+                    deleted_at: client.deleted_at,
+                })
+                .collect(),
+            versions: self
+                .versions
+                .iter()
+                .map(|((client_id, _), version)| PersistedVersion {
+                    client_id: *client_id,
+                    version_id: version.version_id,
+                    parent_version_id: version.parent_version_id,
+                    history_segment: version.history_segment.to_vec(),
+                    created_at: version.created_at,
+                })
+                .collect(),
+        };
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(&tmp_path)
+                .with_context(|| format!("Error creating `{}`", tmp_path.display()))?,
+        );
+        serde_json::to_writer(&mut file, &persisted)?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Error replacing `{}`", path.display()))?;
+        Ok(())
+    }
+}
+
 /// In-memory storage for testing and experimentation.
 ///
 /// This is not for production use, but supports testing of sync server implementations.
 ///
-/// NOTE: this panics if changes were made in a transaction that is later dropped without being
-/// committed, as this likely represents a bug that should be exposed in tests.
-pub struct InMemoryStorage(Mutex<Inner>);
+/// NOTE: by default, this panics if changes were made in a transaction that is later dropped
+/// without being committed, as this likely represents a bug that should be exposed in tests. Use
+/// [`InMemoryStorage::new_lenient`] to instead log an error and roll back the changes.
+pub struct InMemoryStorage {
+    inner: Mutex<Inner>,
+    lenient: bool,
+    /// The file to which the storage is written after each commit, if any.
+    path: Option<PathBuf>,
+    /// The number of snapshots retained for each client.
+    snapshot_retention: u32,
+    /// The clock used to timestamp new clients and versions.
+    clock: Arc<dyn Clock>,
+}
 
 impl InMemoryStorage {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self(Mutex::new(Inner {
-            clients: HashMap::new(),
-            snapshots: HashMap::new(),
-            versions: HashMap::new(),
-            children: HashMap::new(),
-        }))
+        Self::with_lenience(false)
+    }
+
+    /// Create a new instance which rolls back, rather than panicking, when a transaction with
+    /// changes is dropped without being committed.
+    pub fn new_lenient() -> Self {
+        Self::with_lenience(true)
+    }
+
+    fn with_lenience(lenient: bool) -> Self {
+        Self {
+            inner: Mutex::new(Inner::empty()),
+            lenient,
+            path: None,
+            snapshot_retention: 1,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Create an instance persisted to the given file, loading its contents if it exists.
+    ///
+    /// The entire storage is written to the file after each commit that makes changes, so this is
+    /// suitable only for small deployments, such as a single user. Transactions dropped without
+    /// committing are rolled back, as for [`InMemoryStorage::new_lenient`].
+    #[cfg(feature = "serde")]
+    pub fn with_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            inner: Mutex::new(Inner::load(path)?),
+            lenient: true,
+            path: Some(path.to_path_buf()),
+            snapshot_retention: 1,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Retain up to `retention` snapshots for each client, rather than only the most recent, so
+    /// that a client can recover from an older snapshot if a newer one is corrupt.
+    pub fn with_snapshot_retention(mut self, retention: u32) -> Self {
+        self.snapshot_retention = retention.max(1);
+        self
+    }
+
+    /// Use the given clock to timestamp new clients and versions, rather than the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
     }
 }
 
+/// A record of the previous value of an entry modified in a transaction, used to roll back.
+enum Undo {
+    Client(Option<Client>),
+    Snapshot(Option<Bytes>),
+    Retained(Option<Vec<(Snapshot, Bytes)>>),
+    Version(Uuid, Option<Version>),
+    Child(Uuid, Option<Uuid>),
+}
+
 struct InnerTxn<'a> {
     client_id: Uuid,
     guard: MutexGuard<'a, Inner>,
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    path: Option<&'a Path>,
+    undo: Vec<Undo>,
+    lenient: bool,
+    snapshot_retention: u32,
+    clock: &'a dyn Clock,
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
     written: bool,
     committed: bool,
 }
 
 impl Storage for InMemoryStorage {
-    fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
         Ok(Box::new(InnerTxn {
             client_id,
-            guard: self.0.lock().expect("poisoned lock"),
+            guard: self.inner.lock().expect("poisoned lock"),
+            path: self.path.as_deref(),
+            undo: vec![],
+            lenient: self.lenient,
+            snapshot_retention: self.snapshot_retention,
+            clock: self.clock.as_ref(),
             written: false,
             committed: false,
         }))
     }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_client_enumeration: true,
+            supports_rollback: self.lenient,
+            supports_compaction: true,
+            supports_savepoints: true,
+            max_snapshot_retention: self.snapshot_retention,
+            supports_soft_delete: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        Ok(inner
+            .clients
+            .iter()
+            .filter_map(|(client_id, client)| {
+                client
+                    .snapshot
+                    .as_ref()
+                    .map(|snap| (*client_id, snap.versions_since))
+            })
+            .max_by_key(|(_, versions_since)| *versions_since))
+    }
+}
+
+impl InnerTxn<'_> {
+    /// Record the current state of this client, before modifying it.
+    fn save_client(&mut self) {
+        let client = self.guard.clients.get(&self.client_id).cloned();
+        self.undo.push(Undo::Client(client));
+    }
+
+    /// Revert all changes made in this transaction.
+    fn rollback(&mut self) {
+        self.rollback_undo(0);
+    }
+
+    /// Revert the changes made in this transaction after the first `len` entries in the undo log.
+    fn rollback_undo(&mut self, len: usize) {
+        let client_id = self.client_id;
+        while self.undo.len() > len {
+            let undo = self.undo.pop().unwrap();
+            match undo {
+                Undo::Client(Some(client)) => {
+                    self.guard.clients.insert(client_id, client);
+                }
+                Undo::Client(None) => {
+                    self.guard.clients.remove(&client_id);
+                }
+                Undo::Snapshot(Some(data)) => {
+                    self.guard.snapshots.insert(client_id, data);
+                }
+                Undo::Snapshot(None) => {
+                    self.guard.snapshots.remove(&client_id);
+                }
+                Undo::Retained(Some(retained)) => {
+                    self.guard.retained.insert(client_id, retained);
+                }
+                Undo::Retained(None) => {
+                    self.guard.retained.remove(&client_id);
+                }
+                Undo::Version(version_id, Some(version)) => {
+                    self.guard.versions.insert((client_id, version_id), version);
+                }
+                Undo::Version(version_id, None) => {
+                    self.guard.versions.remove(&(client_id, version_id));
+                }
+                Undo::Child(parent_version_id, Some(version_id)) => {
+                    self.guard
+                        .children
+                        .insert((client_id, parent_version_id), version_id);
+                }
+                Undo::Child(parent_version_id, None) => {
+                    self.guard.children.remove(&(client_id, parent_version_id));
+                }
+            }
+        }
+    }
 }
 
 impl StorageTxn for InnerTxn<'_> {
-    fn get_client(&mut self) -> anyhow::Result<Option<Client>> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
         Ok(self.guard.clients.get(&self.client_id).cloned())
     }
 
-    fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
         if self.guard.clients.contains_key(&self.client_id) {
-            return Err(anyhow::anyhow!("Client {} already exists", self.client_id));
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already exists",
+                self.client_id
+            )));
         }
+        self.save_client();
         self.guard.clients.insert(
             self.client_id,
-            Client {
-                latest_version_id,
-                snapshot: None,
-            },
+            Client::new(latest_version_id, self.clock.now()),
         );
         self.written = true;
         Ok(())
     }
 
-    fn set_snapshot(&mut self, snapshot: Snapshot, data: Vec<u8>) -> anyhow::Result<()> {
-        let client = self
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let total_versions = self
             .guard
+            .versions
+            .keys()
+            .filter(|(client_id, _)| *client_id == self.client_id)
+            .count();
+        snapshot.validate(total_versions as u64)?;
+        if !self.guard.clients.contains_key(&self.client_id) {
+            return Err(StorageError::NotFound);
+        }
+        self.save_client();
+        let client = self.guard.clients.get_mut(&self.client_id).unwrap();
+        let old_snapshot = client.snapshot.replace(snapshot.clone());
+        let old_data = self.guard.snapshots.insert(self.client_id, data);
+        self.undo.push(Undo::Snapshot(old_data.clone()));
+
+        if self.snapshot_retention > 1 {
+            let old_retained = self.guard.retained.get(&self.client_id).cloned();
+            let mut retained = old_retained.clone().unwrap_or_default();
+            if let (Some(old_snapshot), Some(old_data)) = (old_snapshot, old_data) {
+                retained.insert(0, (old_snapshot, old_data));
+            }
+            retained.retain(|(s, _)| s.version_id != snapshot.version_id);
+            retained.truncate(self.snapshot_retention as usize - 1);
+            self.guard.retained.insert(self.client_id, retained);
+            self.undo.push(Undo::Retained(old_retained));
+        }
+        self.written = true;
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        if !self.guard.clients.contains_key(&self.client_id) {
+            return Ok(());
+        }
+        self.save_client();
+        let client = self.guard.clients.get_mut(&self.client_id).unwrap();
+        client.last_sync_at = Some(timestamp);
+        client.user_agent = user_agent;
+        self.written = true;
+        Ok(())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        if !self.guard.clients.contains_key(&self.client_id) {
+            return Err(StorageError::NotFound);
+        }
+        self.save_client();
+        self.guard.clients.get_mut(&self.client_id).unwrap().quota = quota;
+        self.written = true;
+        Ok(())
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        if !self.guard.clients.contains_key(&self.client_id) {
+            return Err(StorageError::NotFound);
+        }
+        self.save_client();
+        self.guard
             .clients
             .get_mut(&self.client_id)
-            .ok_or_else(|| anyhow::anyhow!("no such client"))?;
-        client.snapshot = Some(snapshot);
-        self.guard.snapshots.insert(self.client_id, data);
+            .unwrap()
+            .deleted_at = deleted_at;
         self.written = true;
         Ok(())
     }
 
-    fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
         // sanity check
         let client = self.guard.clients.get(&self.client_id);
-        let client = client.ok_or_else(|| anyhow::anyhow!("no such client"))?;
-        if Some(&version_id) != client.snapshot.as_ref().map(|snap| &snap.version_id) {
-            return Err(anyhow::anyhow!("unexpected snapshot_version_id"));
+        let client = client.ok_or(StorageError::NotFound)?;
+        if Some(&version_id) == client.snapshot.as_ref().map(|snap| &snap.version_id) {
+            return Ok(self.guard.snapshots.get(&self.client_id).cloned());
+        }
+        let retained = self
+            .guard
+            .retained
+            .get(&self.client_id)
+            .into_iter()
+            .flatten();
+        for (snapshot, data) in retained {
+            if snapshot.version_id == version_id {
+                return Ok(Some(data.clone()));
+            }
         }
-        Ok(self.guard.snapshots.get(&self.client_id).cloned())
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "unexpected snapshot_version_id"
+        )))
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        let Some(client) = self.guard.clients.get(&self.client_id) else {
+            return Ok(vec![]);
+        };
+        let retained = self
+            .guard
+            .retained
+            .get(&self.client_id)
+            .into_iter()
+            .flatten();
+        Ok(client
+            .snapshot
+            .iter()
+            .cloned()
+            .chain(retained.map(|(snapshot, _)| snapshot.clone()))
+            .collect())
     }
 
     fn get_version_by_parent(
         &mut self,
         parent_version_id: Uuid,
-    ) -> anyhow::Result<Option<Version>> {
+    ) -> Result<Option<Version>, StorageError> {
         if let Some(parent_version_id) = self
             .guard
             .children
@@ -116,7 +584,7 @@ impl StorageTxn for InnerTxn<'_> {
         }
     }
 
-    fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
         Ok(self
             .guard
             .versions
@@ -128,53 +596,147 @@ impl StorageTxn for InnerTxn<'_> {
         &mut self,
         version_id: Uuid,
         parent_version_id: Uuid,
-        history_segment: Vec<u8>,
-    ) -> anyhow::Result<()> {
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.check_new_version_id(version_id)?;
         let version = Version {
             version_id,
             parent_version_id,
             history_segment,
+            created_at: Some(self.clock.now()),
         };
 
+        self.save_client();
         if let Some(client) = self.guard.clients.get_mut(&self.client_id) {
             client.latest_version_id = version_id;
             if let Some(ref mut snap) = client.snapshot {
-                snap.versions_since += 1;
+                snap.versions_since = snap.versions_since.saturating_add(1);
             }
         } else {
-            anyhow::bail!("Client {} does not exist", self.client_id);
+            return Err(StorageError::NotFound);
         }
 
-        if self
+        let old_child = self
             .guard
             .children
-            .insert((self.client_id, parent_version_id), version_id)
-            .is_some()
-        {
-            anyhow::bail!(
+            .insert((self.client_id, parent_version_id), version_id);
+        let conflict = old_child.is_some();
+        self.undo.push(Undo::Child(parent_version_id, old_child));
+        if conflict {
+            return Err(StorageError::Backend(anyhow::anyhow!(
                 "Client {} already has a child for {}",
                 self.client_id,
                 parent_version_id
-            );
+            )));
         }
-        if self
+        let old_version = self
             .guard
             .versions
-            .insert((self.client_id, version_id), version)
-            .is_some()
-        {
-            anyhow::bail!(
+            .insert((self.client_id, version_id), version);
+        let conflict = old_version.is_some();
+        self.undo.push(Undo::Version(version_id, old_version));
+        if conflict {
+            return Err(StorageError::Backend(anyhow::anyhow!(
                 "Client {} already has a version {}",
                 self.client_id,
                 version_id
-            );
+            )));
         }
 
         self.written = true;
         Ok(())
     }
 
-    fn commit(&mut self) -> anyhow::Result<()> {
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let Some(version) = self.guard.versions.remove(&(self.client_id, version_id)) else {
+            return Ok(());
+        };
+        let parent_key = (self.client_id, version.parent_version_id);
+        self.undo.push(Undo::Version(version_id, Some(version)));
+        if self.guard.children.get(&parent_key) == Some(&version_id) {
+            self.guard.children.remove(&parent_key);
+            self.undo.push(Undo::Child(parent_key.1, Some(version_id)));
+        }
+        self.written = true;
+        Ok(())
+    }
+
+    /// A savepoint is a position in the undo log.
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        Ok(Savepoint(self.undo.len()))
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        if savepoint.0 > self.undo.len() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Savepoint has been discarded"
+            )));
+        }
+        self.rollback_undo(savepoint.0);
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        let client_id = self.client_id;
+        self.save_client();
+        self.guard.clients.remove(&client_id);
+        let old_data = self.guard.snapshots.remove(&client_id);
+        self.undo.push(Undo::Snapshot(old_data));
+        let old_retained = self.guard.retained.remove(&client_id);
+        self.undo.push(Undo::Retained(old_retained));
+        let version_ids: Vec<Uuid> = self
+            .guard
+            .versions
+            .keys()
+            .filter(|(c, _)| *c == client_id)
+            .map(|(_, v)| *v)
+            .collect();
+        for version_id in version_ids {
+            let version = self.guard.versions.remove(&(client_id, version_id));
+            self.undo.push(Undo::Version(version_id, version));
+        }
+        let parent_version_ids: Vec<Uuid> = self
+            .guard
+            .children
+            .keys()
+            .filter(|(c, _)| *c == client_id)
+            .map(|(_, p)| *p)
+            .collect();
+        for parent_version_id in parent_version_ids {
+            let child = self.guard.children.remove(&(client_id, parent_version_id));
+            self.undo.push(Undo::Child(parent_version_id, child));
+        }
+        self.written = true;
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let mut client_ids: Vec<Uuid> = self
+            .guard
+            .clients
+            .keys()
+            .filter(|client_id| after.is_none_or(|after| **client_id > after))
+            .copied()
+            .collect();
+        client_ids.sort_unstable();
+        client_ids.truncate(limit);
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        #[cfg(feature = "serde")]
+        if let (Some(path), true) = (self.path, self.written) {
+            if let Err(err) = self.guard.save(path) {
+                // the changes were not persisted, so they are not committed
+                self.rollback();
+                self.committed = true;
+                return Err(err.into());
+            }
+        }
         self.committed = true;
         Ok(())
     }
@@ -182,16 +744,43 @@ impl StorageTxn for InnerTxn<'_> {
 
 impl Drop for InnerTxn<'_> {
     fn drop(&mut self) {
-        if self.written && !self.committed {
+        if self.committed {
+            return;
+        }
+        if self.lenient {
+            if !self.undo.is_empty() {
+                log::error!(
+                    "Uncommitted InMemoryStorage transaction dropped without commiting; rolling back"
+                );
+                self.rollback();
+            }
+        } else if self.written {
             panic!("Uncommitted InMemoryStorage transaction dropped without commiting");
         }
     }
 }
 
+#[cfg(test)]
+impl InMemoryStorage {
+    /// Set the `versions_since` of a client's snapshot directly, bypassing
+    /// [`Snapshot::validate`], as for a client which has synced for a very long time.
+    pub(crate) fn set_versions_since(&self, client_id: Uuid, versions_since: u32) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let client = inner.clients.get_mut(&client_id).expect("no such client");
+        client
+            .snapshot
+            .as_mut()
+            .expect("no snapshot")
+            .versions_since = versions_since;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::Utc;
+    use crate::clock::ManualClock;
+    use crate::NIL_VERSION_ID;
+    use chrono::TimeZone;
 
     #[test]
     fn test_get_client_empty() -> anyhow::Result<()> {
@@ -216,7 +805,7 @@ mod test {
         assert!(client.snapshot.is_none());
 
         let latest_version_id = Uuid::new_v4();
-        txn.add_version(latest_version_id, Uuid::new_v4(), vec![1, 1])?;
+        txn.add_version(latest_version_id, Uuid::new_v4(), vec![1, 1].into())?;
 
         let client = txn.get_client()?.unwrap();
         assert_eq!(client.latest_version_id, latest_version_id);
@@ -225,9 +814,9 @@ mod test {
         let snap = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: Utc::now(),
-            versions_since: 4,
+            versions_since: 1,
         };
-        txn.set_snapshot(snap.clone(), vec![1, 2, 3])?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3].into())?;
 
         let client = txn.get_client()?.unwrap();
         assert_eq!(client.latest_version_id, latest_version_id);
@@ -237,6 +826,74 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new_lenient();
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        for client_id in [client_id, other_client_id] {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.add_version(version_id, Uuid::nil(), vec![1].into())?;
+            txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![2].into())?;
+            txn.commit()?;
+        }
+
+        // an uncommitted deletion is rolled back
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.delete_client()?;
+            assert_eq!(txn.get_client()?, None);
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.get_client()?.is_some());
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id)?, None);
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+        // the client can be created again
+        txn.new_client(Uuid::nil())?;
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![2].into()));
+        assert!(txn.get_version(version_id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut client_ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        assert_eq!(txn.list_clients(None, 10)?, client_ids);
+        assert_eq!(txn.list_clients(None, 2)?, client_ids[..2].to_vec());
+        assert_eq!(
+            txn.list_clients(Some(client_ids[1]), 2)?,
+            client_ids[2..4].to_vec()
+        );
+        assert_eq!(
+            txn.list_clients(Some(client_ids[4]), 2)?,
+            Vec::<Uuid>::new()
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_gvbp_empty() -> anyhow::Result<()> {
         let storage = InMemoryStorage::new();
@@ -249,13 +906,14 @@ mod test {
 
     #[test]
     fn test_add_version_and_get_version() -> anyhow::Result<()> {
-        let storage = InMemoryStorage::new();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let storage = InMemoryStorage::new().with_clock(Arc::new(ManualClock::new(now)));
         let client_id = Uuid::new_v4();
         let mut txn = storage.txn(client_id)?;
 
         let version_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
-        let history_segment = b"abc".to_vec();
+        let history_segment = Bytes::from_static(b"abc");
 
         txn.new_client(parent_version_id)?;
         txn.add_version(version_id, parent_version_id, history_segment.clone())?;
@@ -264,6 +922,7 @@ mod test {
             version_id,
             parent_version_id,
             history_segment,
+            created_at: Some(now),
         };
 
         let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
@@ -276,6 +935,17 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_add_version_no_client() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new_lenient();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let err = txn
+            .add_version(Uuid::new_v4(), Uuid::nil(), vec![1].into())
+            .unwrap_err();
+        assert!(matches!(err, StorageError::NotFound));
+        Ok(())
+    }
+
     #[test]
     fn test_add_version_exists() -> anyhow::Result<()> {
         let storage = InMemoryStorage::new();
@@ -284,7 +954,7 @@ mod test {
 
         let version_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
-        let history_segment = b"abc".to_vec();
+        let history_segment = Bytes::from_static(b"abc");
 
         txn.new_client(parent_version_id)?;
         txn.add_version(version_id, parent_version_id, history_segment.clone())?;
@@ -295,6 +965,26 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_set_snapshot_validates() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::nil())?;
+        txn.add_version(Uuid::new_v4(), Uuid::nil(), Bytes::new())?;
+        let snap = Snapshot::new(Uuid::new_v4(), Utc::now());
+        assert!(txn
+            .set_snapshot(snap.clone().with_versions_since(2), vec![1].into())
+            .is_err());
+        txn.set_snapshot(snap.with_versions_since(1), vec![1].into())?;
+        let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+        assert_eq!(snapshot.versions_since, 1);
+
+        txn.commit()?;
+        Ok(())
+    }
+
     #[test]
     fn test_snapshots() -> anyhow::Result<()> {
         let storage = InMemoryStorage::new();
@@ -307,9 +997,9 @@ mod test {
         let snap = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: Utc::now(),
-            versions_since: 3,
+            versions_since: 0,
         };
-        txn.set_snapshot(snap.clone(), vec![9, 8, 9])?;
+        txn.set_snapshot(snap.clone(), vec![9, 8, 9].into())?;
 
         assert_eq!(
             txn.get_snapshot_data(snap.version_id)?.unwrap(),
@@ -320,9 +1010,9 @@ mod test {
         let snap2 = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: Utc::now(),
-            versions_since: 10,
+            versions_since: 0,
         };
-        txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6])?;
+        txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6].into())?;
 
         assert_eq!(
             txn.get_snapshot_data(snap2.version_id)?.unwrap(),
@@ -336,4 +1026,376 @@ mod test {
         txn.commit()?;
         Ok(())
     }
+
+    #[test]
+    #[should_panic(expected = "dropped without commiting")]
+    fn test_strict_drop_uncommitted_panics() {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4()).unwrap();
+        txn.new_client(Uuid::new_v4()).unwrap();
+        drop(txn);
+    }
+
+    #[test]
+    fn test_lenient_drop_uncommitted_rolls_back() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new_lenient();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        let version_id = Uuid::new_v4();
+        txn.add_version(version_id, Uuid::nil(), vec![1, 2, 3].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![4, 5, 6].into())?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.get_client()?.is_none());
+        assert!(txn.get_version(version_id)?.is_none());
+        assert!(txn.get_version_by_parent(Uuid::nil())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_rollback_restores_previous() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new_lenient();
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let snap = Snapshot::new(v1, Utc::now());
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+            txn.set_snapshot(snap.clone(), vec![1, 1].into())?;
+            txn.commit()?;
+        }
+
+        {
+            let mut txn = storage.txn(client_id)?;
+            let v2 = Uuid::new_v4();
+            txn.add_version(v2, v1, vec![2].into())?;
+            txn.set_snapshot(Snapshot::new(v2, Utc::now()), vec![2, 2].into())?;
+            // dropped without commit
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, v1);
+        assert_eq!(client.snapshot, Some(snap));
+        assert_eq!(txn.get_snapshot_data(v1)?, Some(vec![1, 1].into()));
+        assert!(txn.get_version_by_parent(v1)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_versions_since_snapshot() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        assert_eq!(storage.max_versions_since_snapshot()?, None);
+
+        let mut clients = vec![];
+        for versions_since in [3, 17, 5] {
+            let client_id = Uuid::new_v4();
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            let mut parent_version_id = Uuid::nil();
+            for _ in 0..versions_since {
+                let version_id = Uuid::new_v4();
+                txn.add_version(version_id, parent_version_id, Bytes::new())?;
+                parent_version_id = version_id;
+            }
+            txn.set_snapshot(
+                Snapshot::new(Uuid::new_v4(), Utc::now()).with_versions_since(versions_since),
+                vec![1].into(),
+            )?;
+            txn.commit()?;
+            clients.push(client_id);
+        }
+        // a client without a snapshot is not considered
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(
+            storage.max_versions_since_snapshot()?,
+            Some((clients[1], 17))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions_since_saturates() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.set_snapshot(Snapshot::new(Uuid::new_v4(), Utc::now()), vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+        storage.set_versions_since(client_id, u32::MAX - 1);
+
+        let mut txn = storage.txn(client_id)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.add_version(Uuid::new_v4(), v1, Bytes::new())?;
+
+        let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+        assert_eq!(snapshot.versions_since, u32::MAX);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_sync() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        // recording a sync for a nonexistent client has no effect
+        txn.record_sync(Utc::now(), None)?;
+        assert_eq!(txn.get_client()?, None);
+
+        let before = Utc::now();
+        txn.new_client(NIL_VERSION_ID)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.created_at.unwrap() >= before);
+        assert_eq!(client.last_sync_at, None);
+        assert_eq!(client.user_agent, None);
+
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_quota() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        let quota = Quota {
+            max_bytes: Some(1024),
+            max_versions: None,
+        };
+
+        assert!(txn.set_quota(quota).is_err());
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_quota()?, Quota::default());
+        txn.set_quota(quota)?;
+        assert_eq!(txn.get_quota()?, quota);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_deleted() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        assert!(txn.set_deleted(Some(timestamp)).is_err());
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_deleted(Some(timestamp))?;
+        assert_eq!(txn.get_client()?.unwrap().deleted_at, Some(timestamp));
+        txn.set_deleted(None)?;
+        assert_eq!(txn.get_client()?.unwrap().deleted_at, None);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_persisted() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let path = tmp_dir.path().join("storage.json");
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let storage = InMemoryStorage::with_file(&path)?.with_snapshot_retention(2);
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.set_snapshot(Snapshot::new(NIL_VERSION_ID, Utc::now()), vec![0].into())?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2].into())?;
+            txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![3].into())?;
+            txn.record_sync(Utc::now(), Some("tc/1.0".into()))?;
+            txn.set_quota(Quota {
+                max_bytes: None,
+                max_versions: Some(10),
+            })?;
+            txn.commit()?;
+            drop(txn);
+
+            // uncommitted changes are not persisted
+            let mut txn = storage.txn(client_id)?;
+            txn.add_version(Uuid::new_v4(), version_id, vec![4].into())?;
+        }
+
+        let storage = InMemoryStorage::with_file(&path)?.with_snapshot_retention(2);
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(txn.list_snapshots()?.len(), 2);
+        assert_eq!(txn.get_snapshot_data(NIL_VERSION_ID)?, Some(vec![0].into()));
+        assert_eq!(
+            client.snapshot.map(|s| (s.version_id, s.versions_since)),
+            Some((version_id, 0))
+        );
+        assert!(client.created_at.is_some());
+        assert!(client.last_sync_at.is_some());
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+        assert_eq!(client.quota.max_versions, Some(10));
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![3].into()));
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1, 2]);
+        assert!(version.created_at.is_some());
+        assert_eq!(txn.get_version_by_parent(version_id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_retention() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new().with_snapshot_retention(2);
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let mut parent_version_id = NIL_VERSION_ID;
+        for (i, vid) in vids.iter().enumerate() {
+            txn.add_version(*vid, parent_version_id, Bytes::from(vec![i as u8]))?;
+            txn.set_snapshot(Snapshot::new(*vid, Utc::now()), Bytes::from(vec![i as u8]))?;
+            parent_version_id = *vid;
+        }
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let snapshots: Vec<Uuid> = txn
+            .list_snapshots()?
+            .into_iter()
+            .map(|s| s.version_id)
+            .collect();
+        assert_eq!(snapshots, vec![vids[2], vids[1]]);
+        assert_eq!(txn.get_snapshot_data(vids[2])?, Some(vec![2].into()));
+        assert_eq!(txn.get_snapshot_data(vids[1])?, Some(vec![1].into()));
+        assert!(txn.get_snapshot_data(vids[0]).is_err());
+
+        // versions after the oldest retained snapshot cannot be pruned
+        assert!(txn.prune_versions(vids[2]).is_err());
+        assert_eq!(txn.prune_versions(vids[1])?, 2);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_retention_rollback() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new_lenient().with_snapshot_retention(3);
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(vids[0], NIL_VERSION_ID, Bytes::new())?;
+        txn.add_version(vids[1], vids[0], Bytes::new())?;
+        txn.set_snapshot(Snapshot::new(vids[0], Utc::now()), vec![0].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.set_snapshot(Snapshot::new(vids[1], Utc::now()), vec![1].into())?;
+        assert_eq!(txn.list_snapshots()?.len(), 2);
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let snapshots = txn.list_snapshots()?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].version_id, vids[0]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_persist_failure_rolls_back() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        // a path in a directory which does not exist cannot be written
+        let storage = InMemoryStorage::with_file(tmp_dir.path().join("missing/storage.json"))?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert!(txn.commit().is_err());
+        drop(txn);
+        assert!(storage.txn(client_id)?.get_client()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoints() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let v3 = Uuid::new_v4();
+
+        let outer = txn.savepoint()?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        let inner = txn.savepoint()?;
+        txn.add_version(v2, v1, vec![2].into())?;
+
+        txn.rollback_to(inner)?;
+        assert_eq!(txn.get_version(v2)?, None);
+        assert_eq!(txn.get_version_by_parent(v1)?, None);
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+
+        // the savepoint remains valid after rolling back to it
+        txn.add_version(v3, v1, vec![3].into())?;
+        txn.rollback_to(inner)?;
+        assert_eq!(txn.get_version(v3)?, None);
+
+        txn.rollback_to(outer)?;
+        assert_eq!(txn.get_version(v1)?, None);
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
+        // `inner` was discarded by rolling back to `outer`
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = InMemoryStorage::new().capabilities();
+        assert!(caps.supports_client_enumeration);
+        assert!(!caps.supports_rollback);
+        assert!(!caps.supports_streaming);
+        assert!(caps.supports_compaction);
+        assert!(!caps.supports_read_only_txn);
+        assert_eq!(caps.max_snapshot_retention, 1);
+        assert_eq!(
+            InMemoryStorage::new()
+                .with_snapshot_retention(3)
+                .capabilities()
+                .max_snapshot_retention,
+            3
+        );
+
+        assert!(
+            InMemoryStorage::new_lenient()
+                .capabilities()
+                .supports_rollback
+        );
+    }
+
+    mod conformance {
+        use super::*;
+
+        crate::storage_conformance_tests!(|test| test(&InMemoryStorage::new()));
+    }
+
+    mod conformance_lenient {
+        use super::*;
+
+        crate::storage_conformance_tests!(|test| test(&InMemoryStorage::new_lenient()));
+    }
 }
@@ -0,0 +1,355 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::StorageError;
+use crate::metrics::MetricsRegistry;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Name of the counter of storage operations, labeled with `operation` and `result` (`ok` or
+/// `error`).
+pub const STORAGE_OPERATIONS: &str = "storage_operations_total";
+
+/// Name of the histogram of storage operation latency in seconds, labeled with `operation`.
+pub const STORAGE_OPERATION_DURATION: &str = "storage_operation_duration_seconds";
+
+/// A storage wrapper which records the count and latency of each storage operation in a
+/// [`MetricsRegistry`].
+///
+/// Comparing these metrics with request latency shows whether slowness comes from storage or
+/// from elsewhere.
+pub struct InstrumentedStorage<S: Storage> {
+    inner: S,
+    registry: Arc<MetricsRegistry>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S: Storage> InstrumentedStorage<S> {
+    /// Wrap `inner`, recording metrics in `registry`.
+    pub fn new(inner: S, registry: Arc<MetricsRegistry>) -> Self {
+        Self::with_clock(inner, registry, Arc::new(SystemClock))
+    }
+
+    /// Wrap `inner`, using the given clock to measure latency.
+    pub fn with_clock(inner: S, registry: Arc<MetricsRegistry>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            registry,
+            clock,
+        }
+    }
+}
+
+/// Call `f`, recording its result and latency as `operation`.
+fn measure<T>(
+    registry: &MetricsRegistry,
+    clock: &dyn Clock,
+    operation: &str,
+    f: impl FnOnce() -> Result<T, StorageError>,
+) -> Result<T, StorageError> {
+    let start = clock.now();
+    let res = f();
+    let elapsed = (clock.now() - start)
+        .to_std()
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    let result = if res.is_ok() { "ok" } else { "error" };
+    registry.inc_counter(
+        STORAGE_OPERATIONS,
+        &[("operation", operation), ("result", result)],
+    );
+    registry.observe(
+        STORAGE_OPERATION_DURATION,
+        &[("operation", operation)],
+        elapsed,
+    );
+    res
+}
+
+impl<S: Storage> Storage for InstrumentedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        let inner = measure(&self.registry, self.clock.as_ref(), "txn", || {
+            self.inner.txn(client_id)
+        })?;
+        Ok(Box::new(InstrumentedTxn {
+            inner,
+            registry: &self.registry,
+            clock: self.clock.as_ref(),
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        measure(
+            &self.registry,
+            self.clock.as_ref(),
+            "max_versions_since_snapshot",
+            || self.inner.max_versions_since_snapshot(),
+        )
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        measure(&self.registry, self.clock.as_ref(), "flush", || {
+            self.inner.flush()
+        })
+    }
+}
+
+struct InstrumentedTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+    registry: &'a MetricsRegistry,
+    clock: &'a dyn Clock,
+}
+
+impl InstrumentedTxn<'_> {
+    /// Call `f` on the inner transaction, recording its result and latency as `operation`.
+    fn measure<T, F>(&mut self, operation: &str, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&mut dyn StorageTxn) -> Result<T, StorageError>,
+    {
+        let inner = self.inner.as_mut();
+        measure(self.registry, self.clock, operation, || f(inner))
+    }
+}
+
+impl StorageTxn for InstrumentedTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.measure("get_client", |txn| txn.get_client())
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.measure("new_client", |txn| txn.new_client(latest_version_id))
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.measure("set_snapshot", |txn| txn.set_snapshot(snapshot, data))
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.measure("set_snapshot_from_reader", |txn| {
+            txn.set_snapshot_from_reader(snapshot, data)
+        })
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.measure("record_sync", |txn| txn.record_sync(timestamp, user_agent))
+    }
+
+    fn get_quota(&mut self) -> Result<Quota, StorageError> {
+        self.measure("get_quota", |txn| txn.get_quota())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.measure("set_quota", |txn| txn.set_quota(quota))
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.measure("set_deleted", |txn| txn.set_deleted(deleted_at))
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.measure("get_snapshot_data", |txn| txn.get_snapshot_data(version_id))
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.measure("get_snapshot_reader", |txn| {
+            txn.get_snapshot_reader(version_id)
+        })
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.measure("list_snapshots", |txn| txn.list_snapshots())
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.measure("get_version_by_parent", |txn| {
+            txn.get_version_by_parent(parent_version_id)
+        })
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.measure("get_version", |txn| txn.get_version(version_id))
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.measure("get_version_reader_by_parent", |txn| {
+            txn.get_version_reader_by_parent(parent_version_id)
+        })
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.measure("get_version_reader", |txn| {
+            txn.get_version_reader(version_id)
+        })
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.measure("add_version", |txn| {
+            txn.add_version(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.measure("add_version_from_reader", |txn| {
+            txn.add_version_from_reader(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.measure("add_version_if_latest", |txn| {
+            txn.add_version_if_latest(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.measure("delete_version", |txn| txn.delete_version(version_id))
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.measure("savepoint", |txn| txn.savepoint())
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.measure("rollback_to", |txn| txn.rollback_to(savepoint))
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.measure("delete_client", |txn| txn.delete_client())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.measure("list_clients", |txn| txn.list_clients(after, limit))
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.measure("commit", |txn| txn.commit())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::flaky::{FaultConfig, FlakyStorage};
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&InstrumentedStorage::new(
+            InMemoryStorage::new(),
+            Arc::new(MetricsRegistry::new())
+        )));
+    }
+
+    #[test]
+    fn counts_operations() -> anyhow::Result<()> {
+        let registry = Arc::new(MetricsRegistry::new());
+        let storage = InstrumentedStorage::new(InMemoryStorage::new(), registry.clone());
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
+        txn.commit()?;
+
+        let ok = |op| registry.counter(STORAGE_OPERATIONS, &[("operation", op), ("result", "ok")]);
+        assert_eq!(ok("txn"), 1);
+        assert_eq!(ok("get_client"), 2);
+        assert_eq!(ok("new_client"), 1);
+        assert_eq!(ok("commit"), 1);
+        assert_eq!(
+            registry
+                .histogram(STORAGE_OPERATION_DURATION, &[("operation", "get_client")])
+                .unwrap()
+                .count,
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn records_errors_and_latency() -> anyhow::Result<()> {
+        let clock = Arc::new(ManualClock::new(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        ));
+        let registry = Arc::new(MetricsRegistry::new());
+        let flaky = FlakyStorage::with_clock(
+            InMemoryStorage::new(),
+            FaultConfig {
+                failure_rate: 1.0,
+                latency: Duration::from_millis(20),
+                ..FaultConfig::default()
+            },
+            clock.clone(),
+        );
+        let storage = InstrumentedStorage::with_clock(flaky, registry.clone(), clock);
+
+        assert!(storage.txn(Uuid::new_v4()).is_err());
+
+        assert_eq!(
+            registry.counter(
+                STORAGE_OPERATIONS,
+                &[("operation", "txn"), ("result", "error")]
+            ),
+            1
+        );
+        let hist = registry
+            .histogram(STORAGE_OPERATION_DURATION, &[("operation", "txn")])
+            .unwrap();
+        assert_eq!(hist.count, 1);
+        assert!((hist.sum - 0.02).abs() < 1e-9);
+        Ok(())
+    }
+}
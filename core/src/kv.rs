@@ -0,0 +1,1131 @@
+use crate::error::StorageError;
+use crate::storage::{Client, Quota, Snapshot, Storage, StorageCapabilities, StorageTxn, Version};
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// A simple transactional key-value store, over which [`KvStorage`] implements [`Storage`].
+///
+/// Implementing this trait is much simpler than implementing [`Storage`] directly.
+pub trait KvBackend: Send + Sync {
+    /// Begin a transaction. Transactions must be sequentially consistent, as described for
+    /// [`StorageTxn`].
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>>;
+}
+
+/// A transaction in a [`KvBackend`].
+///
+/// Changes in a transaction that is dropped without calling `commit` must not appear in any other
+/// transaction.
+pub trait KvTxn {
+    /// Get the value for a key.
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Set the value for a key.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()>;
+
+    /// Delete a key, if it exists.
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()>;
+
+    /// Get all keys beginning with the given prefix, with their values, in key order.
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Commit any changes made in the transaction.
+    fn commit(&mut self) -> anyhow::Result<()>;
+}
+
+/// Encoding of the storage data model into keys and values.
+///
+/// Each key begins with a one-byte tag identifying the logical map, followed by the client ID and,
+/// for per-version maps, a version ID. Backends may use the tag to store each map separately.
+pub mod keys {
+    use uuid::Uuid;
+
+    /// Client latest version and snapshot, keyed by client ID.
+    pub const CLIENT: u8 = b'c';
+    /// Snapshot data, keyed by client ID.
+    pub const SNAPSHOT: u8 = b's';
+    /// Versions, keyed by client ID and version ID.
+    pub const VERSION: u8 = b'v';
+    /// Child version IDs, keyed by client ID and parent version ID.
+    pub const CHILD: u8 = b'p';
+    /// Client creation and sync metadata, keyed by client ID.
+    pub const CLIENT_METADATA: u8 = b'm';
+    /// Snapshots older than the client's latest snapshot, keyed by client ID and version ID.
+    pub const RETAINED_SNAPSHOT: u8 = b'r';
+    /// The times at which versions were added, keyed by client ID and version ID.
+    pub const VERSION_TIMESTAMP: u8 = b't';
+
+    /// Encode a key for a per-client map.
+    pub(crate) fn client_key(tag: u8, client_id: Uuid) -> Vec<u8> {
+        let mut key = Vec::with_capacity(17);
+        key.push(tag);
+        key.extend_from_slice(client_id.as_bytes());
+        key
+    }
+
+    /// Encode a key for a per-version map.
+    pub(crate) fn version_key(tag: u8, client_id: Uuid, version_id: Uuid) -> Vec<u8> {
+        let mut key = client_key(tag, client_id);
+        key.extend_from_slice(version_id.as_bytes());
+        key
+    }
+
+    /// Decode a key, returning the tag, client ID, and version ID if present.
+    pub(crate) fn decode(key: &[u8]) -> Option<(u8, Uuid, Option<Uuid>)> {
+        match key.len() {
+            17 => Some((key[0], Uuid::from_slice(&key[1..17]).ok()?, None)),
+            33 => Some((
+                key[0],
+                Uuid::from_slice(&key[1..17]).ok()?,
+                Some(Uuid::from_slice(&key[17..33]).ok()?),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Encode a client's latest version and snapshot as a value.
+fn encode_client(client: &Client) -> Vec<u8> {
+    let mut value = client.latest_version_id.as_bytes().to_vec();
+    if let Some(snap) = &client.snapshot {
+        value.extend_from_slice(snap.version_id.as_bytes());
+        value.extend_from_slice(&snap.timestamp.timestamp().to_be_bytes());
+        value.extend_from_slice(&snap.versions_since.to_be_bytes());
+    }
+    value
+}
+
+/// Decode a value produced by [`encode_client`].
+fn decode_client(value: &[u8]) -> anyhow::Result<Client> {
+    let bad = || StorageError::Corrupt("Invalid client value".into());
+    let latest_version_id = Uuid::from_slice(value.get(0..16).ok_or_else(bad)?)?;
+    let snapshot = match value.len() {
+        16 => None,
+        44 => Some(Snapshot {
+            version_id: Uuid::from_slice(&value[16..32])?,
+            timestamp: Utc
+                .timestamp_opt(i64::from_be_bytes(value[32..40].try_into()?), 0)
+                .single()
+                .ok_or_else(bad)?,
+            versions_since: u32::from_be_bytes(value[40..44].try_into()?),
+        }),
+        _ => return Err(bad().into()),
+    };
+    Ok(Client {
+        latest_version_id,
+        snapshot,
+        created_at: None,
+        last_sync_at: None,
+        user_agent: None,
+        quota: Quota::default(),
+        deleted_at: None,
+    })
+}
+
+/// Client metadata, stored separately from the client value so that values written before it
+/// existed remain valid.
+#[derive(Default)]
+struct ClientMetadata {
+    created_at: Option<DateTime<Utc>>,
+    last_sync_at: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    quota: Quota,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Encode client metadata as a value: a byte of flags indicating which fields are present, the
+/// two timestamps (zero if absent), the quota limits and deletion time (only if present), and the
+/// user agent.
+fn encode_client_metadata(metadata: &ClientMetadata) -> Vec<u8> {
+    let flags = u8::from(metadata.created_at.is_some())
+        | u8::from(metadata.last_sync_at.is_some()) << 1
+        | u8::from(metadata.user_agent.is_some()) << 2
+        | u8::from(metadata.quota.max_bytes.is_some()) << 3
+        | u8::from(metadata.quota.max_versions.is_some()) << 4
+        | u8::from(metadata.deleted_at.is_some()) << 5;
+    let mut value = vec![flags];
+    for timestamp in [metadata.created_at, metadata.last_sync_at] {
+        let seconds = timestamp.map(|t| t.timestamp()).unwrap_or(0);
+        value.extend_from_slice(&seconds.to_be_bytes());
+    }
+    for limit in [metadata.quota.max_bytes, metadata.quota.max_versions]
+        .into_iter()
+        .flatten()
+    {
+        value.extend_from_slice(&limit.to_be_bytes());
+    }
+    if let Some(deleted_at) = metadata.deleted_at {
+        value.extend_from_slice(&deleted_at.timestamp().to_be_bytes());
+    }
+    if let Some(user_agent) = &metadata.user_agent {
+        value.extend_from_slice(user_agent.as_bytes());
+    }
+    value
+}
+
+/// Decode a value produced by [`encode_client_metadata`].
+fn decode_client_metadata(value: &[u8]) -> anyhow::Result<ClientMetadata> {
+    let bad = || StorageError::Corrupt("Invalid client metadata value".into());
+    if value.len() < 17 {
+        return Err(bad().into());
+    }
+    let flags = value[0];
+    let timestamp = |present: bool, bytes: &[u8]| -> anyhow::Result<Option<DateTime<Utc>>> {
+        if !present {
+            return Ok(None);
+        }
+        let seconds = i64::from_be_bytes(bytes.try_into()?);
+        Ok(Some(
+            Utc.timestamp_opt(seconds, 0).single().ok_or_else(bad)?,
+        ))
+    };
+    let mut rest = &value[17..];
+    let mut limit = |present: bool| -> anyhow::Result<Option<u64>> {
+        if !present {
+            return Ok(None);
+        }
+        let (bytes, tail) = rest.split_at_checked(8).ok_or_else(bad)?;
+        rest = tail;
+        Ok(Some(u64::from_be_bytes(bytes.try_into()?)))
+    };
+    let quota = Quota {
+        max_bytes: limit(flags & 8 != 0)?,
+        max_versions: limit(flags & 16 != 0)?,
+    };
+    let deleted_at = limit(flags & 32 != 0)?
+        .map(|seconds| {
+            Utc.timestamp_opt(seconds as i64, 0)
+                .single()
+                .ok_or_else(bad)
+        })
+        .transpose()?;
+    Ok(ClientMetadata {
+        created_at: timestamp(flags & 1 != 0, &value[1..9])?,
+        last_sync_at: timestamp(flags & 2 != 0, &value[9..17])?,
+        user_agent: if flags & 4 != 0 {
+            Some(String::from_utf8(rest.to_vec())?)
+        } else {
+            None
+        },
+        quota,
+        deleted_at,
+    })
+}
+
+/// A snapshot older than the client's latest snapshot, retained with its data.
+struct RetainedSnapshot {
+    /// The order in which the snapshot was retained, increasing with each retained snapshot.
+    seq: u64,
+    snapshot: Snapshot,
+    data: Vec<u8>,
+}
+
+/// Encode a retained snapshot as a value: the sequence number, the snapshot's timestamp and
+/// versions since, then the data. The version ID is in the key.
+fn encode_retained_snapshot(retained: &RetainedSnapshot) -> Vec<u8> {
+    let mut value = retained.seq.to_be_bytes().to_vec();
+    value.extend_from_slice(&retained.snapshot.timestamp.timestamp().to_be_bytes());
+    value.extend_from_slice(&retained.snapshot.versions_since.to_be_bytes());
+    value.extend_from_slice(&retained.data);
+    value
+}
+
+/// Decode a value produced by [`encode_retained_snapshot`].
+fn decode_retained_snapshot(version_id: Uuid, value: &[u8]) -> anyhow::Result<RetainedSnapshot> {
+    let bad = || StorageError::Corrupt("Invalid retained snapshot value".into());
+    if value.len() < 20 {
+        return Err(bad().into());
+    }
+    Ok(RetainedSnapshot {
+        seq: u64::from_be_bytes(value[0..8].try_into()?),
+        snapshot: Snapshot {
+            version_id,
+            timestamp: Utc
+                .timestamp_opt(i64::from_be_bytes(value[8..16].try_into()?), 0)
+                .single()
+                .ok_or_else(bad)?,
+            versions_since: u32::from_be_bytes(value[16..20].try_into()?),
+        },
+        data: value[20..].to_vec(),
+    })
+}
+
+/// An implementation of [`Storage`] over any [`KvBackend`].
+pub struct KvStorage<K: KvBackend> {
+    backend: K,
+    snapshot_retention: u32,
+}
+
+impl<K: KvBackend> KvStorage<K> {
+    pub fn new(backend: K) -> Self {
+        Self {
+            backend,
+            snapshot_retention: 1,
+        }
+    }
+
+    /// Retain up to `retention` snapshots for each client, rather than only the most recent, so
+    /// that a client can recover from an older snapshot if a newer one is corrupt.
+    pub fn with_snapshot_retention(mut self, retention: u32) -> Self {
+        self.snapshot_retention = retention.max(1);
+        self
+    }
+}
+
+impl<K: KvBackend> Storage for KvStorage<K> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(KvStorageTxn {
+            client_id,
+            kv: self.backend.txn()?,
+            snapshot_retention: self.snapshot_retention,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_client_enumeration: true,
+            supports_rollback: true,
+            supports_compaction: true,
+            max_snapshot_retention: self.snapshot_retention,
+            supports_soft_delete: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let mut kv = self.backend.txn()?;
+        let mut max = None;
+        for (key, value) in kv.scan_prefix(&[keys::CLIENT])? {
+            let Some((_, client_id, None)) = keys::decode(&key) else {
+                return Err(StorageError::Backend(anyhow::anyhow!("Invalid client key")));
+            };
+            if let Some(snap) = decode_client(&value)?.snapshot {
+                let is_max = match max {
+                    Some((_, versions_since)) => snap.versions_since > versions_since,
+                    None => true,
+                };
+                if is_max {
+                    max = Some((client_id, snap.versions_since));
+                }
+            }
+        }
+        Ok(max)
+    }
+}
+
+struct KvStorageTxn<'a> {
+    client_id: Uuid,
+    kv: Box<dyn KvTxn + 'a>,
+    snapshot_retention: u32,
+}
+
+impl KvStorageTxn<'_> {
+    fn put_client(&mut self, client: &Client) -> Result<(), StorageError> {
+        self.kv.put(
+            &keys::client_key(keys::CLIENT, self.client_id),
+            &encode_client(client),
+        )?;
+        Ok(())
+    }
+
+    fn get_client_metadata(&mut self) -> anyhow::Result<Option<ClientMetadata>> {
+        self.kv
+            .get(&keys::client_key(keys::CLIENT_METADATA, self.client_id))?
+            .map(|value| decode_client_metadata(&value))
+            .transpose()
+    }
+
+    fn put_client_metadata(&mut self, metadata: &ClientMetadata) -> Result<(), StorageError> {
+        self.kv.put(
+            &keys::client_key(keys::CLIENT_METADATA, self.client_id),
+            &encode_client_metadata(metadata),
+        )?;
+        Ok(())
+    }
+
+    /// Get the retained snapshots, most recent first.
+    fn retained_snapshots(&mut self) -> anyhow::Result<Vec<RetainedSnapshot>> {
+        let mut retained = vec![];
+        for (key, value) in self
+            .kv
+            .scan_prefix(&keys::client_key(keys::RETAINED_SNAPSHOT, self.client_id))?
+        {
+            let Some((_, _, Some(version_id))) = keys::decode(&key) else {
+                anyhow::bail!("Invalid retained snapshot key");
+            };
+            retained.push(decode_retained_snapshot(version_id, &value)?);
+        }
+        retained.sort_by_key(|r| std::cmp::Reverse(r.seq));
+        Ok(retained)
+    }
+
+    /// Retain the client's current snapshot, if any, before it is replaced by `replacement`, and
+    /// discard retained snapshots beyond the retention.
+    fn retain_snapshot(&mut self, client: &Client, replacement: Uuid) -> anyhow::Result<()> {
+        let mut retained = self.retained_snapshots()?;
+        if let Some(snapshot) = &client.snapshot {
+            let data = self
+                .kv
+                .get(&keys::client_key(keys::SNAPSHOT, self.client_id))?;
+            if let (Some(data), true) = (data, snapshot.version_id != replacement) {
+                let seq = retained.first().map(|r| r.seq + 1).unwrap_or(0);
+                retained.insert(
+                    0,
+                    RetainedSnapshot {
+                        seq,
+                        snapshot: snapshot.clone(),
+                        data,
+                    },
+                );
+            }
+        }
+
+        let keep = self.snapshot_retention as usize - 1;
+        let mut kept = 0;
+        for r in retained {
+            let key = keys::version_key(
+                keys::RETAINED_SNAPSHOT,
+                self.client_id,
+                r.snapshot.version_id,
+            );
+            if r.snapshot.version_id != replacement && kept < keep {
+                self.kv.put(&key, &encode_retained_snapshot(&r))?;
+                kept += 1;
+            } else {
+                self.kv.delete(&key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageTxn for KvStorageTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        let Some(value) = self
+            .kv
+            .get(&keys::client_key(keys::CLIENT, self.client_id))?
+        else {
+            return Ok(None);
+        };
+        let mut client = decode_client(&value)?;
+        if let Some(metadata) = self.get_client_metadata()? {
+            client.created_at = metadata.created_at;
+            client.last_sync_at = metadata.last_sync_at;
+            client.user_agent = metadata.user_agent;
+            client.quota = metadata.quota;
+            client.deleted_at = metadata.deleted_at;
+        }
+        Ok(Some(client))
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        if self.get_client()?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already exists",
+                self.client_id
+            )));
+        }
+        let client = Client::new(latest_version_id, Utc::now());
+        self.put_client(&client)?;
+        self.put_client_metadata(&ClientMetadata {
+            created_at: client.created_at,
+            ..ClientMetadata::default()
+        })
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        let Some(client) = self.get_client()? else {
+            return Ok(());
+        };
+        self.put_client_metadata(&ClientMetadata {
+            created_at: client.created_at,
+            last_sync_at: Some(timestamp),
+            user_agent,
+            quota: client.quota,
+            deleted_at: client.deleted_at,
+        })
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        self.put_client_metadata(&ClientMetadata {
+            created_at: client.created_at,
+            last_sync_at: client.last_sync_at,
+            user_agent: client.user_agent,
+            quota,
+            deleted_at: client.deleted_at,
+        })
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        self.put_client_metadata(&ClientMetadata {
+            created_at: client.created_at,
+            last_sync_at: client.last_sync_at,
+            user_agent: client.user_agent,
+            quota: client.quota,
+            deleted_at,
+        })
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if self.snapshot_retention > 1 {
+            self.retain_snapshot(&client, snapshot.version_id)?;
+        }
+        client.snapshot = Some(snapshot);
+        self.put_client(&client)?;
+        self.kv
+            .put(&keys::client_key(keys::SNAPSHOT, self.client_id), &data)?;
+        Ok(())
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if Some(version_id) == client.snapshot.map(|snap| snap.version_id) {
+            return Ok(self
+                .kv
+                .get(&keys::client_key(keys::SNAPSHOT, self.client_id))?
+                .map(Bytes::from));
+        }
+        let Some(value) = self.kv.get(&keys::version_key(
+            keys::RETAINED_SNAPSHOT,
+            self.client_id,
+            version_id,
+        ))?
+        else {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "unexpected snapshot_version_id"
+            )));
+        };
+        Ok(Some(
+            decode_retained_snapshot(version_id, &value)?.data.into(),
+        ))
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        let Some(client) = self.get_client()? else {
+            return Ok(vec![]);
+        };
+        let retained = self.retained_snapshots()?;
+        Ok(client
+            .snapshot
+            .into_iter()
+            .chain(retained.into_iter().map(|r| r.snapshot))
+            .collect())
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        let Some(child) = self.kv.get(&keys::version_key(
+            keys::CHILD,
+            self.client_id,
+            parent_version_id,
+        ))?
+        else {
+            return Ok(None);
+        };
+        let version_id = Uuid::from_slice(&child)
+            .map_err(|_| StorageError::Corrupt("Invalid child version value".into()))?;
+        self.get_version(version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        let Some(value) = self.kv.get(&keys::version_key(
+            keys::VERSION,
+            self.client_id,
+            version_id,
+        ))?
+        else {
+            return Ok(None);
+        };
+        if value.len() < 16 {
+            return Err(StorageError::Corrupt("Invalid version value".into()));
+        }
+        let created_at = self
+            .kv
+            .get(&keys::version_key(
+                keys::VERSION_TIMESTAMP,
+                self.client_id,
+                version_id,
+            ))?
+            .map(|value| -> anyhow::Result<DateTime<Utc>> {
+                let seconds = i64::from_be_bytes(value.as_slice().try_into()?);
+                Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| {
+                    StorageError::Corrupt("Invalid version timestamp value".into()).into()
+                })
+            })
+            .transpose()?;
+        Ok(Some(Version {
+            version_id,
+            parent_version_id: Uuid::from_slice(&value[..16])
+                .map_err(|_| StorageError::Corrupt("Invalid version value".into()))?,
+            history_segment: value[16..].to_vec().into(),
+            created_at,
+        }))
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.check_new_version_id(version_id)?;
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+
+        let child_key = keys::version_key(keys::CHILD, self.client_id, parent_version_id);
+        if self.kv.get(&child_key)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a child for {}",
+                self.client_id,
+                parent_version_id
+            )));
+        }
+        let version_key = keys::version_key(keys::VERSION, self.client_id, version_id);
+        if self.kv.get(&version_key)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a version {}",
+                self.client_id,
+                version_id
+            )));
+        }
+
+        let mut value = parent_version_id.as_bytes().to_vec();
+        value.extend_from_slice(&history_segment);
+        self.kv.put(&version_key, &value)?;
+        self.kv.put(&child_key, version_id.as_bytes())?;
+        self.kv.put(
+            &keys::version_key(keys::VERSION_TIMESTAMP, self.client_id, version_id),
+            &Utc::now().timestamp().to_be_bytes(),
+        )?;
+
+        client.latest_version_id = version_id;
+        if let Some(ref mut snap) = client.snapshot {
+            snap.versions_since = snap.versions_since.saturating_add(1);
+        }
+        self.put_client(&client)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let Some(version) = self.get_version(version_id)? else {
+            return Ok(());
+        };
+        for tag in [keys::VERSION, keys::VERSION_TIMESTAMP] {
+            self.kv
+                .delete(&keys::version_key(tag, self.client_id, version_id))?;
+        }
+        let child_key = keys::version_key(keys::CHILD, self.client_id, version.parent_version_id);
+        if self.kv.get(&child_key)?.as_deref() == Some(version_id.as_bytes().as_slice()) {
+            self.kv.delete(&child_key)?;
+        }
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        for tag in [
+            keys::CLIENT,
+            keys::CLIENT_METADATA,
+            keys::SNAPSHOT,
+            keys::RETAINED_SNAPSHOT,
+            keys::VERSION,
+            keys::VERSION_TIMESTAMP,
+            keys::CHILD,
+        ] {
+            for (key, _) in self
+                .kv
+                .scan_prefix(&keys::client_key(tag, self.client_id))?
+            {
+                self.kv.delete(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        // the KvTxn trait has no range scans, so this scans all client keys, which are in order
+        let mut client_ids = vec![];
+        for (key, _) in self.kv.scan_prefix(&[keys::CLIENT])? {
+            let Some((_, client_id, None)) = keys::decode(&key) else {
+                return Err(StorageError::Backend(anyhow::anyhow!("Invalid client key")));
+            };
+            if after.is_none_or(|after| client_id > after) {
+                client_ids.push(client_id);
+                if client_ids.len() >= limit {
+                    break;
+                }
+            }
+        }
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        Ok(self.kv.commit()?)
+    }
+}
+
+/// An in-memory [`KvBackend`], for testing.
+///
+/// Changes are buffered in each transaction and applied on commit, so a transaction dropped
+/// without committing has no effect.
+#[derive(Default)]
+pub struct InMemoryKv(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl InMemoryKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvBackend for InMemoryKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(InMemoryKvTxn {
+            guard: self.0.lock().expect("poisoned lock"),
+            pending: BTreeMap::new(),
+        }))
+    }
+}
+
+struct InMemoryKvTxn<'a> {
+    guard: MutexGuard<'a, BTreeMap<Vec<u8>, Vec<u8>>>,
+    /// Uncommitted changes, with `None` representing a deletion.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl KvTxn for InMemoryKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        Ok(self.guard.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.pending.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut result: BTreeMap<Vec<u8>, Vec<u8>> = self
+            .guard
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (k, v) in self
+            .pending
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+        {
+            match v {
+                Some(v) => result.insert(k.clone(), v.clone()),
+                None => result.remove(k),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        for (k, v) in std::mem::take(&mut self.pending) {
+            match v {
+                Some(v) => self.guard.insert(k, v),
+                None => self.guard.remove(&k),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn key_round_trip() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        assert_eq!(
+            keys::decode(&keys::client_key(keys::CLIENT, client_id)),
+            Some((keys::CLIENT, client_id, None))
+        );
+        assert_eq!(
+            keys::decode(&keys::version_key(keys::VERSION, client_id, version_id)),
+            Some((keys::VERSION, client_id, Some(version_id)))
+        );
+        assert_eq!(keys::decode(b"short"), None);
+    }
+
+    #[test]
+    fn client_value_round_trip() -> anyhow::Result<()> {
+        let client = Client {
+            latest_version_id: Uuid::new_v4(),
+            snapshot: None,
+            created_at: None,
+            last_sync_at: None,
+            user_agent: None,
+            quota: Quota::default(),
+            deleted_at: None,
+        };
+        assert_eq!(decode_client(&encode_client(&client))?, client);
+
+        let client = Client {
+            latest_version_id: Uuid::new_v4(),
+            snapshot: Some(
+                Snapshot::new(
+                    Uuid::new_v4(),
+                    Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                )
+                .with_versions_since(12),
+            ),
+            created_at: None,
+            last_sync_at: None,
+            user_agent: None,
+            quota: Quota::default(),
+            deleted_at: None,
+        };
+        assert_eq!(decode_client(&encode_client(&client))?, client);
+        Ok(())
+    }
+
+    #[test]
+    fn client_metadata_value_round_trip() -> anyhow::Result<()> {
+        let metadata = decode_client_metadata(&encode_client_metadata(&ClientMetadata::default()))?;
+        assert_eq!(metadata.created_at, None);
+        assert_eq!(metadata.last_sync_at, None);
+        assert_eq!(metadata.user_agent, None);
+
+        let timestamp = Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap();
+        let metadata = decode_client_metadata(&encode_client_metadata(&ClientMetadata {
+            created_at: Some(timestamp),
+            last_sync_at: Some(timestamp),
+            user_agent: Some("tc/1.0".into()),
+            quota: Quota::default(),
+            deleted_at: Some(timestamp),
+        }))?;
+        assert_eq!(metadata.created_at, Some(timestamp));
+        assert_eq!(metadata.last_sync_at, Some(timestamp));
+        assert_eq!(metadata.user_agent.as_deref(), Some("tc/1.0"));
+        assert_eq!(metadata.deleted_at, Some(timestamp));
+
+        let quota = Quota {
+            max_bytes: None,
+            max_versions: Some(100),
+        };
+        let metadata = decode_client_metadata(&encode_client_metadata(&ClientMetadata {
+            user_agent: Some("tc/1.0".into()),
+            quota,
+            ..ClientMetadata::default()
+        }))?;
+        assert_eq!(metadata.quota, quota);
+        assert_eq!(metadata.user_agent.as_deref(), Some("tc/1.0"));
+
+        assert!(decode_client_metadata(b"short").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix() -> anyhow::Result<()> {
+        let kv = InMemoryKv::new();
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"aa", b"1")?;
+            txn.put(b"ab", b"2")?;
+            txn.put(b"b", b"3")?;
+            txn.commit()?;
+        }
+        let mut txn = kv.txn()?;
+        // pending changes are overlaid on committed data
+        txn.put(b"ac", b"4")?;
+        txn.delete(b"aa")?;
+        assert_eq!(
+            txn.scan_prefix(b"a")?,
+            vec![
+                (b"ab".to_vec(), b"2".to_vec()),
+                (b"ac".to_vec(), b"4".to_vec())
+            ]
+        );
+        assert!(txn.scan_prefix(b"c")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let kv = InMemoryKv::new();
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"k", b"v")?;
+        }
+        assert_eq!(kv.txn()?.get(b"k")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn storage_client() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.get_client()?.is_none());
+
+        let latest_version_id = Uuid::new_v4();
+        txn.new_client(latest_version_id)?;
+        assert!(txn.new_client(latest_version_id).is_err());
+
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert!(client.snapshot.is_none());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn storage_record_sync() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.record_sync(Utc::now(), None)?;
+        assert!(txn.get_client()?.is_none());
+
+        txn.new_client(Uuid::nil())?;
+        assert!(txn.get_client()?.unwrap().created_at.is_some());
+
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.add_version(Uuid::new_v4(), Uuid::nil(), vec![1].into())?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn storage_set_quota() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let quota = Quota {
+            max_bytes: Some(1 << 20),
+            max_versions: Some(100),
+        };
+        assert!(txn.set_quota(quota).is_err());
+
+        txn.new_client(Uuid::nil())?;
+        txn.set_quota(quota)?;
+        // recording a sync leaves the quota unchanged
+        txn.record_sync(Utc::now(), Some("tc/1.0".into()))?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.quota, quota);
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn storage_set_deleted() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        assert!(txn.set_deleted(Some(timestamp)).is_err());
+
+        txn.new_client(Uuid::nil())?;
+        txn.set_deleted(Some(timestamp))?;
+        // recording a sync leaves the deletion unchanged
+        txn.record_sync(Utc::now(), None)?;
+        assert_eq!(txn.get_client()?.unwrap().deleted_at, Some(timestamp));
+        txn.set_deleted(None)?;
+        assert_eq!(txn.get_client()?.unwrap().deleted_at, None);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn storage_delete_client() -> anyhow::Result<()> {
+        let kv = InMemoryKv::new();
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = KvStorage::new(kv).with_snapshot_retention(2);
+        for client_id in [client_id, other_client_id] {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.set_snapshot(Snapshot::new(Uuid::nil(), Utc::now()), vec![0].into())?;
+            txn.add_version(version_id, Uuid::nil(), vec![1].into())?;
+            txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![2].into())?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        // only the other client's keys remain
+        let mut kv_txn = storage.backend.txn()?;
+        let remaining = kv_txn.scan_prefix(&[])?;
+        assert_eq!(remaining.len(), 7);
+        for (key, _) in remaining {
+            assert_eq!(keys::decode(&key).unwrap().1, other_client_id);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn retained_snapshot_value_round_trip() -> anyhow::Result<()> {
+        let version_id = Uuid::new_v4();
+        let retained = RetainedSnapshot {
+            seq: 3,
+            snapshot: Snapshot::new(
+                version_id,
+                Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            )
+            .with_versions_since(7),
+            data: vec![1, 2, 3],
+        };
+        let decoded = decode_retained_snapshot(version_id, &encode_retained_snapshot(&retained))?;
+        assert_eq!(decoded.seq, 3);
+        assert_eq!(decoded.snapshot, retained.snapshot);
+        assert_eq!(decoded.data, vec![1, 2, 3]);
+        assert!(decode_retained_snapshot(version_id, &[0; 19]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn storage_snapshot_retention() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new()).with_snapshot_retention(3);
+        assert_eq!(storage.capabilities().max_snapshot_retention, 3);
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        for (i, vid) in vids.iter().enumerate() {
+            txn.set_snapshot(Snapshot::new(*vid, Utc::now()), Bytes::from(vec![i as u8]))?;
+        }
+        // setting the same snapshot again does not retain a duplicate
+        txn.set_snapshot(Snapshot::new(vids[3], Utc::now()), vec![3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let snapshots: Vec<Uuid> = txn.list_snapshots()?.iter().map(|s| s.version_id).collect();
+        assert_eq!(snapshots, vec![vids[3], vids[2], vids[1]]);
+        assert_eq!(txn.get_snapshot_data(vids[3])?, Some(vec![3].into()));
+        assert_eq!(txn.get_snapshot_data(vids[1])?, Some(vec![1].into()));
+        assert!(txn.get_snapshot_data(vids[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn storage_list_clients() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let mut client_ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(Uuid::new_v4())?;
+            txn.set_snapshot(Snapshot::new(Uuid::new_v4(), Utc::now()), vec![1].into())?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+
+        let mut txn = storage.txn(client_ids[0])?;
+        assert_eq!(txn.list_clients(None, 10)?, client_ids);
+        assert_eq!(
+            txn.list_clients(Some(client_ids[1]), 2)?,
+            client_ids[2..4].to_vec()
+        );
+        assert_eq!(
+            txn.list_clients(Some(client_ids[4]), 2)?,
+            Vec::<Uuid>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn storage_versions() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let version_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        txn.new_client(parent_version_id)?;
+        let before = Utc::now().timestamp();
+        txn.add_version(version_id, parent_version_id, b"abc".to_vec().into())?;
+
+        let created_at = txn.get_version(version_id)?.unwrap().created_at;
+        assert!(created_at.unwrap().timestamp() >= before);
+        let expected = Version {
+            version_id,
+            parent_version_id,
+            history_segment: b"abc".to_vec().into(),
+            created_at,
+        };
+        assert_eq!(
+            txn.get_version_by_parent(parent_version_id)?,
+            Some(expected.clone())
+        );
+        assert_eq!(txn.get_version(version_id)?, Some(expected));
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        assert!(txn
+            .add_version(version_id, parent_version_id, b"abc".to_vec().into())
+            .is_err());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn storage_snapshots() -> anyhow::Result<()> {
+        let storage = KvStorage::new(InMemoryKv::new());
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::new_v4())?;
+
+        let snap = Snapshot::new(
+            Uuid::new_v4(),
+            Utc.with_ymd_and_hms(2013, 10, 8, 12, 0, 9).unwrap(),
+        )
+        .with_versions_since(3);
+        txn.set_snapshot(snap.clone(), vec![9, 8, 9].into())?;
+        assert_eq!(
+            txn.get_snapshot_data(snap.version_id)?,
+            Some(vec![9, 8, 9].into())
+        );
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+        assert!(txn.get_snapshot_data(Uuid::new_v4()).is_err());
+
+        // adding a version increments versions_since
+        txn.add_version(Uuid::new_v4(), Uuid::new_v4(), Bytes::new())?;
+        assert_eq!(
+            txn.get_client()?.unwrap().snapshot.unwrap().versions_since,
+            4
+        );
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(storage.max_versions_since_snapshot()?, Some((client_id, 4)));
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        crate::storage_conformance_tests!(|test| test(&KvStorage::new(InMemoryKv::new())));
+    }
+}
@@ -12,12 +12,62 @@
 //! To use, create a new [`Server`] instance and call the relevant protocol API methods. The
 //! arguments and return values correspond closely to the protocol documentation.
 
+mod admission;
+mod async_storage;
+mod blob;
+mod buffering;
+mod cached;
+mod change_stream;
+mod checksummed;
+mod clock;
+mod compressed;
+pub mod conformance;
+mod dedup;
+mod encrypted;
 mod error;
+mod flaky;
 mod inmemory;
+mod instrumented;
+mod kv;
+mod metrics;
+mod mirrored;
+mod quota;
+mod read_retry;
+#[cfg(feature = "runtime")]
+pub mod runtime;
 mod server;
+mod sharded;
 mod storage;
+mod tiered;
+#[cfg(feature = "tracing")]
+mod traced;
 
+pub use admission::*;
+pub use async_storage::*;
+pub use blob::*;
+pub use buffering::*;
+/// History segments and snapshot data are reference-counted [`Bytes`], so that they can be passed
+/// between the HTTP layer, the server, and storage, and retried, without copying.
+pub use bytes::Bytes;
+pub use cached::*;
+pub use change_stream::*;
+pub use checksummed::*;
+pub use clock::*;
+pub use compressed::*;
+pub use dedup::*;
+pub use encrypted::*;
 pub use error::*;
+pub use flaky::*;
 pub use inmemory::*;
+pub use instrumented::*;
+pub use kv::{keys as kv_keys, InMemoryKv, KvBackend, KvStorage, KvTxn};
+pub use metrics::*;
+pub use mirrored::*;
+pub use quota::*;
+pub use read_retry::*;
 pub use server::*;
+pub use sharded::*;
 pub use storage::*;
+pub use tiered::*;
+#[cfg(feature = "tracing")]
+pub use traced::*;
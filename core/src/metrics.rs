@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Upper bounds, in seconds, of the buckets of latency histograms.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds, in bytes, of the buckets of size histograms.
+pub const SIZE_BUCKETS: &[f64] = &[
+    100.0,
+    1000.0,
+    10_000.0,
+    100_000.0,
+    1_000_000.0,
+    10_000_000.0,
+    100_000_000.0,
+];
+
+/// Labels identifying one series of a metric, as (name, value) pairs.
+pub type Labels = Vec<(String, String)>;
+
+/// A histogram of observed values.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Histogram {
+    /// The upper bound of each bucket, and the number of observations less than or equal to it.
+    pub buckets: Vec<(f64, u64)>,
+
+    /// The total number of observations.
+    pub count: u64,
+
+    /// The sum of all observations.
+    pub sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            buckets: bounds.iter().map(|b| (*b, 0)).collect(),
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter_mut() {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// A registry of counters, gauges, and histograms, each identified by a metric name and a set of
+/// labels.
+///
+/// Counters and histograms are only ever added to, and gauges are replaced, so a registry is safe
+/// to share between threads and to read at any time.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    counters: Mutex<BTreeMap<(String, Labels), u64>>,
+    gauges: Mutex<BTreeMap<(String, Labels), f64>>,
+    histograms: Mutex<BTreeMap<(String, Labels), Histogram>>,
+}
+
+fn key(name: &str, labels: &[(&str, &str)]) -> (String, Labels) {
+    (
+        name.to_string(),
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    )
+}
+
+impl MetricsRegistry {
+    /// Create a new, empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increment a counter by one.
+    pub fn inc_counter(&self, name: &str, labels: &[(&str, &str)]) {
+        let mut counters = self.counters.lock().expect("poisoned lock");
+        *counters.entry(key(name, labels)).or_insert(0) += 1;
+    }
+
+    /// Set the value of a gauge.
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut gauges = self.gauges.lock().expect("poisoned lock");
+        gauges.insert(key(name, labels), value);
+    }
+
+    /// Record an observation in a histogram with the [`LATENCY_BUCKETS`].
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.observe_with_buckets(name, labels, LATENCY_BUCKETS, value)
+    }
+
+    /// Record an observation in a histogram, creating it with the given bucket upper bounds if it
+    /// does not exist.
+    pub fn observe_with_buckets(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        bounds: &[f64],
+        value: f64,
+    ) {
+        let mut histograms = self.histograms.lock().expect("poisoned lock");
+        histograms
+            .entry(key(name, labels))
+            .or_insert_with(|| Histogram::new(bounds))
+            .observe(value);
+    }
+
+    /// Get the value of a counter, which is zero if it has never been incremented.
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let counters = self.counters.lock().expect("poisoned lock");
+        counters.get(&key(name, labels)).copied().unwrap_or(0)
+    }
+
+    /// Get the value of a gauge, if it has been set.
+    pub fn gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        let gauges = self.gauges.lock().expect("poisoned lock");
+        gauges.get(&key(name, labels)).copied()
+    }
+
+    /// Get a histogram, if any values have been observed in it.
+    pub fn histogram(&self, name: &str, labels: &[(&str, &str)]) -> Option<Histogram> {
+        let histograms = self.histograms.lock().expect("poisoned lock");
+        histograms.get(&key(name, labels)).cloned()
+    }
+
+    /// Get all counters, sorted by name and labels.
+    pub fn counters(&self) -> Vec<(String, Labels, u64)> {
+        let counters = self.counters.lock().expect("poisoned lock");
+        counters
+            .iter()
+            .map(|((name, labels), value)| (name.clone(), labels.clone(), *value))
+            .collect()
+    }
+
+    /// Get all histograms, sorted by name and labels.
+    pub fn histograms(&self) -> Vec<(String, Labels, Histogram)> {
+        let histograms = self.histograms.lock().expect("poisoned lock");
+        histograms
+            .iter()
+            .map(|((name, labels), hist)| (name.clone(), labels.clone(), hist.clone()))
+            .collect()
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut last_name = None;
+        let mut type_line = |out: &mut String, name: &str, kind: &str| {
+            if last_name.as_deref() != Some(name) {
+                out.push_str(&format!("# TYPE {name} {kind}\n"));
+                last_name = Some(name.to_string());
+            }
+        };
+
+        for (name, labels, value) in self.counters() {
+            type_line(&mut out, &name, "counter");
+            out.push_str(&format!("{name}{} {value}\n", format_labels(&labels, None)));
+        }
+        let gauges = self.gauges.lock().expect("poisoned lock").clone();
+        for ((name, labels), value) in gauges {
+            type_line(&mut out, &name, "gauge");
+            out.push_str(&format!("{name}{} {value}\n", format_labels(&labels, None)));
+        }
+        for (name, labels, hist) in self.histograms() {
+            type_line(&mut out, &name, "histogram");
+            for (bound, count) in &hist.buckets {
+                let labels = format_labels(&labels, Some(&bound.to_string()));
+                out.push_str(&format!("{name}_bucket{labels} {count}\n"));
+            }
+            let inf_labels = format_labels(&labels, Some("+Inf"));
+            out.push_str(&format!("{name}_bucket{inf_labels} {}\n", hist.count));
+            let labels = format_labels(&labels, None);
+            out.push_str(&format!("{name}_sum{labels} {}\n", hist.sum));
+            out.push_str(&format!("{name}_count{labels} {}\n", hist.count));
+        }
+        out
+    }
+}
+
+/// Format labels as `{name="value",...}`, with an additional `le` label for histogram buckets, or
+/// as an empty string if there are none.
+fn format_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let escape = |v: &str| {
+        v.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    };
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{le}\""));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn counters() {
+        let registry = MetricsRegistry::new();
+        registry.inc_counter("requests", &[("path", "/a")]);
+        registry.inc_counter("requests", &[("path", "/a")]);
+        registry.inc_counter("requests", &[("path", "/b")]);
+        assert_eq!(registry.counter("requests", &[("path", "/a")]), 2);
+        assert_eq!(registry.counter("requests", &[("path", "/b")]), 1);
+        assert_eq!(registry.counter("requests", &[("path", "/c")]), 0);
+        assert_eq!(registry.counters().len(), 2);
+    }
+
+    #[test]
+    fn histogram() {
+        let registry = MetricsRegistry::new();
+        assert_eq!(registry.histogram("latency", &[]), None);
+        registry.observe("latency", &[], 0.003);
+        registry.observe("latency", &[], 0.2);
+        let hist = registry.histogram("latency", &[]).unwrap();
+        assert_eq!(hist.count, 2);
+        assert!((hist.sum - 0.203).abs() < 1e-9);
+        assert_eq!(hist.buckets[0], (0.001, 0));
+        assert_eq!(hist.buckets[2], (0.005, 1));
+        assert_eq!(hist.buckets[7], (0.25, 2));
+        assert_eq!(hist.buckets.last().unwrap(), &(10.0, 2));
+    }
+
+    #[test]
+    fn gauges() {
+        let registry = MetricsRegistry::new();
+        assert_eq!(registry.gauge("clients", &[]), None);
+        registry.set_gauge("clients", &[], 3.0);
+        registry.set_gauge("clients", &[], 2.0);
+        assert_eq!(registry.gauge("clients", &[]), Some(2.0));
+    }
+
+    #[test]
+    fn render_prometheus() {
+        let registry = MetricsRegistry::new();
+        registry.inc_counter("requests_total", &[("path", "/a")]);
+        registry.inc_counter("requests_total", &[("path", "say \"hi\"")]);
+        registry.set_gauge("clients", &[], 2.0);
+        registry.observe_with_buckets("size_bytes", &[], &[10.0, 100.0], 50.0);
+        assert_eq!(
+            registry.render_prometheus(),
+            [
+                "# TYPE requests_total counter",
+                "requests_total{path=\"/a\"} 1",
+                "requests_total{path=\"say \\\"hi\\\"\"} 1",
+                "# TYPE clients gauge",
+                "clients 2",
+                "# TYPE size_bytes histogram",
+                "size_bytes_bucket{le=\"10\"} 0",
+                "size_bytes_bucket{le=\"100\"} 1",
+                "size_bytes_bucket{le=\"+Inf\"} 1",
+                "size_bytes_sum 50",
+                "size_bytes_count 1",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
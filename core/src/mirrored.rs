@@ -0,0 +1,344 @@
+use crate::error::StorageError;
+use crate::storage::{Client, Quota, Snapshot, Storage, StorageCapabilities, StorageTxn, Version};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use uuid::Uuid;
+
+/// A storage wrapper which writes every change to two storages, reading only from the primary.
+///
+/// This allows live migration from the primary to the secondary storage, or keeps a second copy
+/// of the data for durability.
+///
+/// Changes are made in the secondary storage after the primary, and an error from either fails
+/// the operation. The primary is committed first, and is authoritative: if the secondary then
+/// fails to commit, the error is logged and the commit succeeds, leaving the secondary behind.
+/// [`StorageTxn::client_state_hash`] can be used to find clients for which the storages differ.
+///
+/// Only clients which exist in the secondary storage, or which are created through this wrapper,
+/// are mirrored. Existing clients must be copied to the secondary storage separately.
+pub struct MirroredStorage<P: Storage, S: Storage> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P: Storage, S: Storage> MirroredStorage<P, S> {
+    /// Wrap `primary`, mirroring changes to `secondary`.
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+impl<P: Storage, S: Storage> Storage for MirroredStorage<P, S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(MirroredTxn {
+            client_id,
+            primary: self.primary.txn(client_id)?,
+            secondary: self.secondary.txn(client_id)?,
+            mirrored: None,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let primary = self.primary.capabilities();
+        let secondary = self.secondary.capabilities();
+        StorageCapabilities {
+            supports_rollback: primary.supports_rollback && secondary.supports_rollback,
+            supports_soft_delete: primary.supports_soft_delete && secondary.supports_soft_delete,
+            // changes in the two storages cannot be rolled back together
+            supports_savepoints: false,
+            max_history_segment_len: primary
+                .max_history_segment_len
+                .min(secondary.max_history_segment_len),
+            // snapshot data is held in memory to write it to both storages
+            supports_streaming: false,
+            ..primary
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.primary.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.primary.flush()?;
+        self.secondary.flush()
+    }
+}
+
+struct MirroredTxn<'a> {
+    client_id: Uuid,
+    primary: Box<dyn StorageTxn + 'a>,
+    secondary: Box<dyn StorageTxn + 'a>,
+    /// Whether this client is mirrored to the secondary storage, once determined.
+    mirrored: Option<bool>,
+}
+
+impl MirroredTxn<'_> {
+    /// Get the secondary transaction, if this client is mirrored.
+    fn secondary(&mut self) -> anyhow::Result<Option<&mut dyn StorageTxn>> {
+        let mirrored = match self.mirrored {
+            Some(mirrored) => mirrored,
+            None => {
+                let mirrored = self.secondary.get_client()?.is_some();
+                if !mirrored {
+                    log::debug!(
+                        "client {} is not in secondary storage; not mirroring",
+                        self.client_id
+                    );
+                }
+                self.mirrored = Some(mirrored);
+                mirrored
+            }
+        };
+        Ok(mirrored.then_some(self.secondary.as_mut()))
+    }
+}
+
+impl StorageTxn for MirroredTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.primary.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.primary.new_client(latest_version_id)?;
+        // a new client is always mirrored, unless it was already in the secondary storage
+        if self.secondary.get_client()?.is_none() {
+            self.secondary.new_client(latest_version_id)?;
+        }
+        self.mirrored = Some(true);
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.primary.set_snapshot(snapshot.clone(), data.clone())?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.set_snapshot(snapshot, data)?;
+        }
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.primary.record_sync(timestamp, user_agent.clone())?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.record_sync(timestamp, user_agent)?;
+        }
+        Ok(())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.primary.set_quota(quota)?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.set_quota(quota)?;
+        }
+        Ok(())
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.primary.set_deleted(deleted_at)?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.set_deleted(deleted_at)?;
+        }
+        Ok(())
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.primary.get_snapshot_data(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.primary.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.primary.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.primary.get_version(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.primary
+            .add_version(version_id, parent_version_id, history_segment.clone())?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.add_version(version_id, parent_version_id, history_segment)?;
+        }
+        Ok(())
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut data = vec![];
+        history_segment.read_to_end(&mut data)?;
+        self.primary
+            .add_version_if_latest(version_id, parent_version_id, &mut data.as_slice())?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.add_version(version_id, parent_version_id, data.into())?;
+        }
+        Ok(())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.primary.delete_version(version_id)?;
+        if let Some(secondary) = self.secondary()? {
+            secondary.delete_version(version_id)?;
+        }
+        Ok(())
+    }
+
+    /// The client is deleted from the secondary storage even if it was not mirrored, so that a
+    /// stale copy does not remain there.
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.primary.delete_client()?;
+        self.secondary.delete_client()?;
+        self.mirrored = Some(false);
+        Ok(())
+    }
+
+    /// Clients are listed from the primary storage.
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.primary.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.primary.commit()?;
+        if let Err(err) = self.secondary.commit() {
+            log::error!(
+                "Failed to commit client {} to secondary storage: {err:?}",
+                self.client_id
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&MirroredStorage::new(
+            InMemoryStorage::new(),
+            InMemoryStorage::new()
+        )));
+    }
+
+    #[test]
+    fn changes_mirrored() -> anyhow::Result<()> {
+        let storage = MirroredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut primary = storage.primary.txn(client_id)?;
+        let mut secondary = storage.secondary.txn(client_id)?;
+        // each storage records its own creation time, so only the other fields are compared
+        let (primary_client, secondary_client) = (
+            primary.get_client()?.unwrap(),
+            secondary.get_client()?.unwrap(),
+        );
+        assert_eq!(
+            primary_client.latest_version_id,
+            secondary_client.latest_version_id
+        );
+        assert_eq!(primary_client.snapshot, secondary_client.snapshot);
+        assert_eq!(
+            secondary.get_version(version_id)?.unwrap().history_segment,
+            vec![1, 2]
+        );
+        assert_eq!(
+            secondary.get_snapshot_data(version_id)?,
+            Some(vec![3].into())
+        );
+        assert_eq!(primary.client_state_hash()?, secondary.client_state_hash()?);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_from_primary() -> anyhow::Result<()> {
+        let storage = MirroredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.primary.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        assert!(txn.get_version(version_id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn existing_client_not_mirrored() -> anyhow::Result<()> {
+        let storage = MirroredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        {
+            let mut txn = storage.primary.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(storage.secondary.txn(client_id)?.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_client_mirrored() -> anyhow::Result<()> {
+        let storage = MirroredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(storage.primary.txn(client_id)?.get_client()?, None);
+        assert_eq!(storage.secondary.txn(client_id)?.get_client()?, None);
+        Ok(())
+    }
+}
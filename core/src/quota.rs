@@ -0,0 +1,425 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, ClientStats, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn,
+    Version, VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::{self, Read};
+use uuid::Uuid;
+
+/// A storage wrapper enforcing each client's [`Quota`].
+///
+/// Adding versions fails with [`StorageError::QuotaExceeded`] if the client would then have more
+/// than `max_versions` versions, or if its history segments and snapshot data would then total
+/// more than `max_bytes`. Setting a snapshot replaces the existing snapshot, so only the new
+/// snapshot data is counted. Clients without limits are not affected, and do not incur the cost
+/// of calculating their [`ClientStats`].
+pub struct QuotaStorage<S: Storage> {
+    inner: S,
+}
+
+impl<S: Storage> QuotaStorage<S> {
+    /// Wrap `inner`, enforcing the quotas it stores.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: Storage> Storage for QuotaStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(QuotaTxn {
+            inner: self.inner.txn(client_id)?,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct QuotaTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+}
+
+impl QuotaTxn<'_> {
+    /// Get the client's quota and current usage, or `None` if the client has no limits or does
+    /// not exist.
+    fn usage(&mut self) -> Result<Option<(Quota, ClientStats)>, StorageError> {
+        let quota = self.inner.get_quota()?;
+        if quota.is_unlimited() {
+            return Ok(None);
+        }
+        Ok(self.inner.get_client_stats()?.map(|stats| (quota, stats)))
+    }
+
+    /// Check that adding `count` versions does not exceed the quota, returning the number of
+    /// bytes of history segments which may be added, if limited.
+    fn check_versions(&mut self, count: u64) -> Result<Option<u64>, StorageError> {
+        let Some((quota, stats)) = self.usage()? else {
+            return Ok(None);
+        };
+        if let Some(max_versions) = quota.max_versions {
+            if stats.version_count + count > max_versions {
+                return Err(StorageError::QuotaExceeded);
+            }
+        }
+        Ok(quota
+            .max_bytes
+            .map(|max| max.saturating_sub(stats.history_bytes + stats.snapshot_bytes)))
+    }
+
+    /// Get the number of bytes of snapshot data which may be set, if limited.
+    fn snapshot_headroom(&mut self) -> Result<Option<u64>, StorageError> {
+        let Some((quota, stats)) = self.usage()? else {
+            return Ok(None);
+        };
+        Ok(quota
+            .max_bytes
+            .map(|max| max.saturating_sub(stats.history_bytes)))
+    }
+
+    /// Call `f` with `reader`, limited to `headroom` bytes if given, translating an overrun into
+    /// [`StorageError::QuotaExceeded`].
+    fn with_limit(
+        &mut self,
+        headroom: Option<u64>,
+        reader: &mut dyn Read,
+        f: impl FnOnce(&mut dyn StorageTxn, &mut dyn Read) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        let Some(remaining) = headroom else {
+            return f(self.inner.as_mut(), reader);
+        };
+        let mut limited = LimitedReader {
+            inner: reader,
+            remaining,
+            exceeded: false,
+        };
+        let res = f(self.inner.as_mut(), &mut limited);
+        if limited.exceeded {
+            return Err(StorageError::QuotaExceeded);
+        }
+        res
+    }
+}
+
+/// A reader failing once more than `remaining` bytes have been read.
+struct LimitedReader<'a> {
+    inner: &'a mut dyn Read,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl Read for LimitedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::other("client quota exceeded"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl StorageTxn for QuotaTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        if let Some(headroom) = self.snapshot_headroom()? {
+            if data.len() as u64 > headroom {
+                return Err(StorageError::QuotaExceeded);
+            }
+        }
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let headroom = self.snapshot_headroom()?;
+        self.with_limit(headroom, data, |txn, data| {
+            txn.set_snapshot_from_reader(snapshot, data)
+        })
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn get_quota(&mut self) -> Result<Quota, StorageError> {
+        self.inner.get_quota()
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.inner.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version(version_id)
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        if let Some(headroom) = self.check_versions(1)? {
+            if history_segment.len() as u64 > headroom {
+                return Err(StorageError::QuotaExceeded);
+            }
+        }
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let headroom = self.check_versions(1)?;
+        self.with_limit(headroom, history_segment, |txn, history_segment| {
+            txn.add_version_from_reader(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let headroom = self.check_versions(1)?;
+        self.with_limit(headroom, history_segment, |txn, history_segment| {
+            txn.add_version_if_latest(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn add_versions(&mut self, versions: Vec<(Uuid, Uuid, Bytes)>) -> Result<(), StorageError> {
+        if let Some(headroom) = self.check_versions(versions.len() as u64)? {
+            let bytes: u64 = versions.iter().map(|(_, _, seg)| seg.len() as u64).sum();
+            if bytes > headroom {
+                return Err(StorageError::QuotaExceeded);
+            }
+        }
+        self.inner.add_versions(versions)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.inner.list_clients(after, limit)
+    }
+
+    fn get_client_stats(&mut self) -> Result<Option<ClientStats>, StorageError> {
+        self.inner.get_client_stats()
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&QuotaStorage::new(InMemoryStorage::new())));
+    }
+
+    fn is_quota_exceeded(err: &StorageError) -> bool {
+        matches!(err, StorageError::QuotaExceeded)
+    }
+
+    fn storage_with_quota(quota: Quota) -> anyhow::Result<(QuotaStorage<InMemoryStorage>, Uuid)> {
+        let storage = QuotaStorage::new(InMemoryStorage::new_lenient());
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_quota(quota)?;
+        txn.commit()?;
+        drop(txn);
+        Ok((storage, client_id))
+    }
+
+    #[test]
+    fn unlimited() -> anyhow::Result<()> {
+        let (storage, client_id) = storage_with_quota(Quota::default())?;
+        let mut txn = storage.txn(client_id)?;
+        let mut parent = NIL_VERSION_ID;
+        for _ in 0..10 {
+            let version_id = Uuid::new_v4();
+            txn.add_version(version_id, parent, vec![0; 1000].into())?;
+            parent = version_id;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn max_versions() -> anyhow::Result<()> {
+        let (storage, client_id) = storage_with_quota(Quota {
+            max_versions: Some(2),
+            ..Quota::default()
+        })?;
+        let mut txn = storage.txn(client_id)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        let v2 = Uuid::new_v4();
+        txn.add_version_if_latest(v2, v1, &mut [2u8].as_slice())?;
+
+        let err = txn
+            .add_version(Uuid::new_v4(), v2, vec![3].into())
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+        let err = txn
+            .add_versions(vec![(Uuid::new_v4(), v2, vec![3].into())])
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+        Ok(())
+    }
+
+    #[test]
+    fn max_bytes_versions() -> anyhow::Result<()> {
+        let (storage, client_id) = storage_with_quota(Quota {
+            max_bytes: Some(10),
+            ..Quota::default()
+        })?;
+        let mut txn = storage.txn(client_id)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, NIL_VERSION_ID, vec![0; 6].into())?;
+
+        let err = txn
+            .add_version(Uuid::new_v4(), v1, vec![0; 5].into())
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+        let err = txn
+            .add_version_from_reader(Uuid::new_v4(), v1, &mut [0u8; 5].as_slice())
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+
+        // exactly reaching the quota is allowed
+        let v2 = Uuid::new_v4();
+        txn.add_version_from_reader(v2, v1, &mut [0u8; 4].as_slice())?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+        Ok(())
+    }
+
+    #[test]
+    fn max_bytes_snapshot() -> anyhow::Result<()> {
+        let (storage, client_id) = storage_with_quota(Quota {
+            max_bytes: Some(10),
+            ..Quota::default()
+        })?;
+        let mut txn = storage.txn(client_id)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, NIL_VERSION_ID, vec![0; 4].into())?;
+
+        let snapshot = Snapshot::new(v1, Utc::now());
+        let err = txn
+            .set_snapshot(snapshot.clone(), vec![0; 7].into())
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+        let err = txn
+            .set_snapshot_from_reader(snapshot.clone(), &mut [0u8; 7].as_slice())
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+
+        // the existing snapshot does not count against its replacement
+        txn.set_snapshot(snapshot.clone(), vec![0; 6].into())?;
+        txn.set_snapshot_from_reader(snapshot, &mut [0u8; 6].as_slice())?;
+
+        // but does count against new versions
+        let err = txn
+            .add_version(Uuid::new_v4(), v1, vec![0; 1].into())
+            .unwrap_err();
+        assert!(is_quota_exceeded(&err));
+        Ok(())
+    }
+}
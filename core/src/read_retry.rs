@@ -0,0 +1,422 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Configuration for retries in [`ReadRetryStorage`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts for each read, including the first.
+    pub max_attempts: u32,
+
+    /// Time to wait before the first retry. This doubles on each subsequent retry.
+    pub initial_backoff: Duration,
+
+    /// Maximum time to wait between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A storage wrapper which retries reads that fail with [`StorageError::Retryable`].
+///
+/// Only methods which do not modify storage are retried. Errors from mutating methods, including
+/// `commit`, are returned to the caller immediately, to avoid applying a write twice.
+pub struct ReadRetryStorage<S: Storage> {
+    inner: S,
+    policy: RetryPolicy,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S: Storage> ReadRetryStorage<S> {
+    /// Wrap `inner`, retrying reads according to `policy`.
+    pub fn new(inner: S, policy: RetryPolicy) -> Self {
+        Self::with_clock(inner, policy, Arc::new(SystemClock))
+    }
+
+    /// Wrap `inner`, using the given clock to wait between retries.
+    pub fn with_clock(inner: S, policy: RetryPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            policy,
+            clock,
+        }
+    }
+}
+
+impl<S: Storage> Storage for ReadRetryStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(ReadRetryTxn {
+            inner: self.inner.txn(client_id)?,
+            policy: &self.policy,
+            clock: self.clock.as_ref(),
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.inner.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+}
+
+struct ReadRetryTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+    policy: &'a RetryPolicy,
+    clock: &'a dyn Clock,
+}
+
+impl ReadRetryTxn<'_> {
+    /// Call `f`, retrying on retryable errors according to the policy.
+    fn retry<T, F>(&mut self, mut f: F) -> Result<T, StorageError>
+    where
+        F: FnMut(&mut dyn StorageTxn) -> Result<T, StorageError>,
+    {
+        let mut backoff = self.policy.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match f(self.inner.as_mut()) {
+                Err(err) if attempt < self.policy.max_attempts && err.is_retryable() => {
+                    log::debug!("retrying read after attempt {attempt} failed: {err}");
+                    self.clock.sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, self.policy.max_backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl StorageTxn for ReadRetryTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.retry(|txn| txn.get_client())
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner.set_snapshot_from_reader(snapshot, data)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.retry(|txn| txn.get_snapshot_data(version_id))
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.retry(|txn| txn.get_snapshot_reader(version_id))
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.retry(|txn| txn.list_snapshots())
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.retry(|txn| txn.get_version_by_parent(parent_version_id))
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.retry(|txn| txn.get_version(version_id))
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.retry(|txn| txn.get_version_reader_by_parent(parent_version_id))
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.retry(|txn| txn.get_version_reader(version_id))
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, history_segment)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.retry(|txn| txn.list_clients(after, limit))
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::inmemory::InMemoryStorage;
+    use crate::NIL_VERSION_ID;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&ReadRetryStorage::new(
+            InMemoryStorage::new(),
+            RetryPolicy::default()
+        )));
+    }
+
+    /// A storage which fails the first `failures` reads in each transaction, and every write,
+    /// with a retryable error.
+    struct FlakyStorage {
+        inner: InMemoryStorage,
+        failures: u32,
+        reads: AtomicU32,
+        writes: AtomicU32,
+    }
+
+    impl FlakyStorage {
+        fn new(failures: u32) -> Self {
+            Self {
+                inner: InMemoryStorage::new(),
+                failures,
+                reads: AtomicU32::new(0),
+                writes: AtomicU32::new(0),
+            }
+        }
+    }
+
+    impl Storage for FlakyStorage {
+        fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+            Ok(Box::new(FlakyTxn {
+                inner: self.inner.txn(client_id)?,
+                storage: self,
+                failed: 0,
+            }))
+        }
+    }
+
+    struct FlakyTxn<'a> {
+        inner: Box<dyn StorageTxn + 'a>,
+        storage: &'a FlakyStorage,
+        failed: u32,
+    }
+
+    impl FlakyTxn<'_> {
+        fn read(&mut self) -> anyhow::Result<()> {
+            self.storage.reads.fetch_add(1, Ordering::SeqCst);
+            if self.failed < self.storage.failures {
+                self.failed += 1;
+                return Err(StorageError::Retryable(anyhow::anyhow!("flaky read")).into());
+            }
+            Ok(())
+        }
+
+        fn write(&mut self) -> anyhow::Result<()> {
+            self.storage.writes.fetch_add(1, Ordering::SeqCst);
+            Err(StorageError::Retryable(anyhow::anyhow!("flaky write")).into())
+        }
+    }
+
+    impl StorageTxn for FlakyTxn<'_> {
+        fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+            self.read()?;
+            self.inner.get_client()
+        }
+
+        fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+            self.inner.new_client(latest_version_id)
+        }
+
+        fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.set_snapshot(snapshot, data)
+        }
+
+        fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+            self.read()?;
+            self.inner.get_snapshot_data(version_id)
+        }
+
+        fn get_version_by_parent(
+            &mut self,
+            parent_version_id: Uuid,
+        ) -> Result<Option<Version>, StorageError> {
+            self.read()?;
+            self.inner.get_version_by_parent(parent_version_id)
+        }
+
+        fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+            self.read()?;
+            self.inner.get_version(version_id)
+        }
+
+        fn add_version(
+            &mut self,
+            version_id: Uuid,
+            parent_version_id: Uuid,
+            history_segment: Bytes,
+        ) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner
+                .add_version(version_id, parent_version_id, history_segment)
+        }
+
+        fn commit(&mut self) -> Result<(), StorageError> {
+            self.inner.commit()
+        }
+    }
+
+    fn clock() -> Arc<ManualClock> {
+        Arc::new(ManualClock::new(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn read_succeeds_after_retries() -> anyhow::Result<()> {
+        let clock = clock();
+        let start = clock.now();
+        let storage = ReadRetryStorage::with_clock(
+            FlakyStorage::new(2),
+            RetryPolicy::default(),
+            clock.clone(),
+        );
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(storage.inner.reads.load(Ordering::SeqCst), 3);
+
+        // backoff was 10ms, then 20ms
+        assert_eq!(clock.now() - start, chrono::Duration::milliseconds(30));
+        Ok(())
+    }
+
+    #[test]
+    fn read_fails_after_max_attempts() -> anyhow::Result<()> {
+        let storage =
+            ReadRetryStorage::with_clock(FlakyStorage::new(5), RetryPolicy::default(), clock());
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        let err = txn.get_version(Uuid::new_v4()).unwrap_err();
+        assert!(err.is_retryable());
+        assert_eq!(storage.inner.reads.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn write_not_retried() -> anyhow::Result<()> {
+        let storage =
+            ReadRetryStorage::with_clock(FlakyStorage::new(0), RetryPolicy::default(), clock());
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert!(txn
+            .add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())
+            .is_err());
+        assert_eq!(storage.inner.writes.load(Ordering::SeqCst), 1);
+        txn.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+//! A shared runtime for storage backends built on asynchronous client libraries.
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+
+/// The runtime on which storage backends perform asynchronous operations.
+///
+/// The storage traits are synchronous, and may be called from within another async runtime, such
+/// as actix-web's. Asynchronous operations are spawned on this runtime, and the calling thread
+/// blocks until they complete. It is never dropped, as dropping a runtime from within another
+/// runtime panics.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .thread_name("taskchampion-storage")
+            .enable_all()
+            .build()
+            .expect("Could not create runtime")
+    })
+}
+
+/// Run a future on the [`runtime`], blocking until it completes.
+pub fn run<F>(fut: F) -> anyhow::Result<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Ok(futures::executor::block_on(runtime().spawn(fut))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_outside_runtime() -> anyhow::Result<()> {
+        assert_eq!(run(async { 1 + 1 })?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn run_within_runtime() -> anyhow::Result<()> {
+        // actix-web handlers run within a current-thread runtime
+        let other = tokio::runtime::Builder::new_current_thread().build()?;
+        assert_eq!(other.block_on(async { run(async { 1 + 1 }) })?, 2);
+        Ok(())
+    }
+}
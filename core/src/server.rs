@@ -1,6 +1,14 @@
-use crate::error::ServerError;
-use crate::storage::{Snapshot, Storage, StorageTxn};
-use chrono::Utc;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{ServerError, StorageError};
+use crate::storage::{
+    AddVersionCheck, Client, ClientStats, Snapshot, Storage, StorageCapabilities, StorageStats,
+    StorageTxn, Version,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 /// The distinguished value for "no version"
@@ -11,7 +19,11 @@ pub const NIL_VERSION_ID: VersionId = Uuid::nil();
 /// than this will be rejected.
 const SNAPSHOT_SEARCH_LEN: i32 = 5;
 
-pub type HistorySegment = Vec<u8>;
+/// Time to wait before retrying an operation after a transient storage error. This is multiplied
+/// by the number of the retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+pub type HistorySegment = Bytes;
 pub type ClientId = Uuid;
 pub type VersionId = Uuid;
 
@@ -22,6 +34,14 @@ pub struct ServerConfig {
 
     /// Target number of versions between snapshots.
     pub snapshot_versions: u32,
+
+    /// Number of times to retry an operation which fails with a transient storage error, before
+    /// failing with [`ServerError::TryAgainLater`].
+    pub transient_retries: u32,
+
+    /// Number of days after a client is deleted before its data is purged. Until then, the
+    /// deletion can be undone.
+    pub deletion_grace_days: i64,
 }
 
 impl Default for ServerConfig {
@@ -29,19 +49,24 @@ impl Default for ServerConfig {
         ServerConfig {
             snapshot_days: 14,
             snapshot_versions: 100,
+            transient_retries: 2,
+            deletion_grace_days: 30,
         }
     }
 }
 
 /// Response to get_child_version.  See the protocol documentation.
+///
+/// The history segment is a [`HistorySegment`], or a reader for one when returned from
+/// [`Server::get_child_version_reader`].
 #[derive(Clone, PartialEq, Debug)]
-pub enum GetVersionResult {
+pub enum GetVersionResult<H = HistorySegment> {
     NotFound,
     Gone,
     Success {
         version_id: Uuid,
         parent_version_id: Uuid,
-        history_segment: HistorySegment,
+        history_segment: H,
     },
 }
 
@@ -79,9 +104,14 @@ impl SnapshotUrgency {
         }
     }
 
-    /// Calculate the urgency for a snapshot based on its age in versions
+    /// Calculate the urgency for a snapshot based on its age in versions.
+    ///
+    /// The count saturates at `u32::MAX`, so that value indicates an unknown but very large
+    /// number of versions.
     fn for_versions_since(config: &ServerConfig, versions_since: u32) -> Self {
-        if versions_since >= config.snapshot_versions * 3 / 2 {
+        if versions_since == u32::MAX
+            || versions_since >= config.snapshot_versions.saturating_mul(3) / 2
+        {
             SnapshotUrgency::High
         } else if versions_since >= config.snapshot_versions {
             SnapshotUrgency::Low
@@ -95,32 +125,103 @@ impl SnapshotUrgency {
 pub struct Server {
     config: ServerConfig,
     storage: Box<dyn Storage>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Server {
     pub fn new<ST: Storage + 'static>(config: ServerConfig, storage: ST) -> Self {
+        Self::with_clock(config, storage, Arc::new(SystemClock))
+    }
+
+    /// Create a server using the given clock for snapshot timestamps, snapshot urgency, and
+    /// waiting between retries.
+    pub fn with_clock<ST: Storage + 'static>(
+        config: ServerConfig,
+        storage: ST,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             config,
             storage: Box::new(storage),
+            clock,
         }
     }
 
+    /// Get the server's configuration.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Get the capabilities of the server's storage.
+    pub fn capabilities(&self) -> StorageCapabilities {
+        self.storage.capabilities()
+    }
+
     /// Implementation of the GetChildVersion protocol transaction.
     pub fn get_child_version(
         &self,
         client_id: ClientId,
         parent_version_id: VersionId,
     ) -> Result<GetVersionResult, ServerError> {
+        self.retry(|| {
+            self.get_child_version_with(client_id, parent_version_id, |txn| {
+                Ok(txn
+                    .get_version_by_parent(parent_version_id)?
+                    .map(|version| {
+                        (
+                            version.version_id,
+                            version.parent_version_id,
+                            version.history_segment,
+                        )
+                    }))
+            })
+        })
+    }
+
+    /// Implementation of the GetChildVersion protocol transaction, returning a reader for the
+    /// history segment rather than the segment itself. With a backend supporting streaming, the
+    /// segment is not held in memory.
+    pub fn get_child_version_reader(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+    ) -> Result<GetVersionResult<Box<dyn Read + Send>>, ServerError> {
+        self.retry(|| {
+            self.get_child_version_with(client_id, parent_version_id, |txn| {
+                Ok(txn
+                    .get_version_reader_by_parent(parent_version_id)?
+                    .map(|version| {
+                        (
+                            version.version_id,
+                            version.parent_version_id,
+                            version.history_segment,
+                        )
+                    }))
+            })
+        })
+    }
+
+    /// Get the child version with `get`, which returns its version ID, parent version ID, and
+    /// history segment, or determine why there is none.
+    fn get_child_version_with<H, F>(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+        get: F,
+    ) -> Result<GetVersionResult<H>, ServerError>
+    where
+        F: FnOnce(&mut dyn StorageTxn) -> Result<Option<(Uuid, Uuid, H)>, StorageError>,
+    {
         let mut txn = self.storage.txn(client_id)?;
-        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        let client = active_client(txn.as_mut())?;
 
         // If a version with parentVersionId equal to the requested parentVersionId exists, it is
         // returned.
-        if let Some(version) = txn.get_version_by_parent(parent_version_id)? {
+        if let Some((version_id, parent_version_id, history_segment)) = get(txn.as_mut())? {
             return Ok(GetVersionResult::Success {
-                version_id: version.version_id,
-                parent_version_id: version.parent_version_id,
-                history_segment: version.history_segment,
+                version_id,
+                parent_version_id,
+                history_segment,
             });
         }
 
@@ -141,77 +242,228 @@ impl Server {
         )
     }
 
+    /// Get up to `limit` versions following `parent_version_id`, in order, so that a replica can
+    /// catch up without a GetChildVersion request per version.
+    ///
+    /// An empty result means that the parent version has no child. In that case,
+    /// `get_child_version` distinguishes a parent which is up to date from one which is gone.
+    pub fn get_versions_since(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+        limit: usize,
+    ) -> Result<Vec<Version>, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            active_client(txn.as_mut())?;
+            Ok(txn.get_versions_since(parent_version_id, limit)?)
+        })
+    }
+
+    /// Check whether an AddVersion with the given parent and history segment length would be
+    /// accepted, without modifying anything. This allows rejecting a request before reading its
+    /// body. The result is only advisory, as another request may intervene.
+    pub fn check_add_version(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+        segment_len: usize,
+    ) -> Result<AddVersionCheck, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            Ok(txn.check_add_version(parent_version_id, segment_len)?)
+        })
+    }
+
     /// Implementation of the AddVersion protocol transaction
     pub fn add_version(
         &self,
         client_id: ClientId,
         parent_version_id: VersionId,
         history_segment: HistorySegment,
+    ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
+        self.retry(|| {
+            self.add_version_from_reader(
+                client_id,
+                parent_version_id,
+                &mut history_segment.as_ref(),
+            )
+        })
+    }
+
+    /// Implementation of the AddVersion protocol transaction, reading the history segment from
+    /// `history_segment`. With a backend supporting streaming, the segment is not held in memory.
+    ///
+    /// Transient storage errors are not retried, as the history segment may have been consumed.
+    pub fn add_version_from_reader(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+        history_segment: &mut dyn Read,
     ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
         log::debug!("add_version(client_id: {client_id}, parent_version_id: {parent_version_id})");
 
         let mut txn = self.storage.txn(client_id)?;
-        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        let client = active_client(txn.as_mut())?;
 
-        // check if this version is acceptable, under the protection of the transaction
+        // invent a version ID
+        let version_id = Uuid::new_v4();
+
+        // the storage adds the version only if its parent is the latest version
+        match txn.add_version_if_latest(version_id, parent_version_id, history_segment) {
+            Ok(()) => {}
+            Err(StorageError::Conflict { latest_version_id }) => {
+                log::debug!("add_version request rejected: mismatched latest_version_id");
+                return Ok((
+                    AddVersionResult::ExpectedParentVersion(latest_version_id),
+                    SnapshotUrgency::None,
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        }
+        log::debug!("add_version request accepted: new version_id: {version_id}");
+        txn.commit()?;
+
+        Ok((
+            AddVersionResult::Ok(version_id),
+            self.snapshot_urgency(client.snapshot.as_ref(), 0),
+        ))
+    }
+
+    /// Add a sequence of versions in a single transaction, such as when a replica catches up
+    /// after a long offline period. Each tuple contains a version ID, its parent version ID, and
+    /// its history segment.
+    ///
+    /// The first version must be acceptable to AddVersion, and each following version must be a
+    /// child of the version before it. If the first version is not acceptable, nothing is added
+    /// and the expected parent version is returned. Otherwise all of the versions are added, and
+    /// the result contains the ID of the last one.
+    ///
+    /// If the storage supports savepoints and a version other than the first cannot be added, such
+    /// as when it would exceed the client's quota, that version is rolled back and the versions
+    /// before it are kept, so the result contains the ID of the last version added. The client can
+    /// then retry the remainder.
+    pub fn add_versions(
+        &self,
+        client_id: ClientId,
+        versions: Vec<(VersionId, VersionId, HistorySegment)>,
+    ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
+        self.retry(|| self.add_versions_once(client_id, versions.clone()))
+    }
+
+    /// Make a single attempt at [`Server::add_versions`].
+    fn add_versions_once(
+        &self,
+        client_id: ClientId,
+        versions: Vec<(VersionId, VersionId, HistorySegment)>,
+    ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
+        log::debug!(
+            "add_versions(client_id: {client_id}, {} versions)",
+            versions.len()
+        );
+
+        let (Some(&(_, parent_version_id, _)), Some(&(last_version_id, _, _))) =
+            (versions.first(), versions.last())
+        else {
+            return Err(ServerError::Other(anyhow::anyhow!("No versions to add")));
+        };
+        let mut expected_parent = parent_version_id;
+        for (version_id, parent_version_id, _) in &versions {
+            if *parent_version_id != expected_parent {
+                return Err(ServerError::Other(anyhow::anyhow!(
+                    "Version {version_id} is not a child of {expected_parent}"
+                )));
+            }
+            if *version_id == NIL_VERSION_ID {
+                return Err(ServerError::InvalidVersionId(*version_id));
+            }
+            expected_parent = *version_id;
+        }
+
+        let mut txn = self.storage.txn(client_id)?;
+        let client = active_client(txn.as_mut())?;
+
+        // check if the first version is acceptable, under the protection of the transaction
         if client.latest_version_id != NIL_VERSION_ID
             && parent_version_id != client.latest_version_id
         {
-            log::debug!("add_version request rejected: mismatched latest_version_id");
+            log::debug!("add_versions request rejected: mismatched latest_version_id");
             return Ok((
                 AddVersionResult::ExpectedParentVersion(client.latest_version_id),
                 SnapshotUrgency::None,
             ));
         }
 
-        // invent a version ID
-        let version_id = Uuid::new_v4();
-        log::debug!("add_version request accepted: new version_id: {version_id}");
-
-        // update the DB
-        txn.add_version(version_id, parent_version_id, history_segment)?;
-        txn.commit()?;
-
-        // calculate the urgency
-        let time_urgency = match client.snapshot {
-            None => SnapshotUrgency::High,
-            Some(Snapshot { timestamp, .. }) => {
-                SnapshotUrgency::for_days(&self.config, (Utc::now() - timestamp).num_days())
-            }
-        };
-
-        let version_urgency = match client.snapshot {
-            None => SnapshotUrgency::High,
-            Some(Snapshot { versions_since, .. }) => {
-                SnapshotUrgency::for_versions_since(&self.config, versions_since)
-            }
+        let (added, last_version_id) = if self.storage.capabilities().supports_savepoints {
+            add_versions_until_error(txn.as_mut(), versions)?
+        } else {
+            let added = versions.len() as u32;
+            txn.add_versions(versions)?;
+            (added, last_version_id)
         };
+        txn.commit()?;
+        log::debug!("add_versions request accepted: latest version_id: {last_version_id}");
 
         Ok((
-            AddVersionResult::Ok(version_id),
-            std::cmp::max(time_urgency, version_urgency),
+            AddVersionResult::Ok(last_version_id),
+            self.snapshot_urgency(client.snapshot.as_ref(), added.saturating_sub(1)),
         ))
     }
 
-    /// Implementation of the AddSnapshot protocol transaction
+    /// Implementation of the AddSnapshot protocol transaction. This returns true if the snapshot
+    /// was accepted, and false if it was ignored, such as when a newer snapshot exists.
     pub fn add_snapshot(
         &self,
         client_id: ClientId,
         version_id: VersionId,
-        data: Vec<u8>,
-    ) -> Result<(), ServerError> {
+        data: Bytes,
+    ) -> Result<bool, ServerError> {
+        self.retry(|| {
+            self.add_snapshot_with(client_id, version_id, |txn, snapshot| {
+                txn.set_snapshot(snapshot, data.clone())
+            })
+        })
+    }
+
+    /// Implementation of the AddSnapshot protocol transaction, reading the snapshot data from
+    /// `data`. With a backend supporting streaming, the data is not held in memory. If the
+    /// snapshot is rejected, `data` is not read.
+    ///
+    /// Transient storage errors are not retried, as the data may have been consumed. As for
+    /// [`Server::add_snapshot`], this returns whether the snapshot was accepted.
+    pub fn add_snapshot_from_reader(
+        &self,
+        client_id: ClientId,
+        version_id: VersionId,
+        data: &mut dyn Read,
+    ) -> Result<bool, ServerError> {
+        self.add_snapshot_with(client_id, version_id, |txn, snapshot| {
+            txn.set_snapshot_from_reader(snapshot, data)
+        })
+    }
+
+    /// Decide whether to accept a snapshot, and if so, set it with `set`.
+    fn add_snapshot_with<F>(
+        &self,
+        client_id: ClientId,
+        version_id: VersionId,
+        set: F,
+    ) -> Result<bool, ServerError>
+    where
+        F: FnOnce(&mut dyn StorageTxn, Snapshot) -> Result<(), StorageError>,
+    {
         log::debug!("add_snapshot(client_id: {client_id}, version_id: {version_id})");
 
         let mut txn = self.storage.txn(client_id)?;
-        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        let client = active_client(txn.as_mut())?;
 
         // NOTE: if the snapshot is rejected, this function logs about it and returns
-        // Ok(()), as there's no reason to report an errot to the client / user.
+        // Ok(false), as there's no reason to report an errot to the client / user.
 
         let last_snapshot = client.snapshot.map(|snap| snap.version_id);
         if Some(version_id) == last_snapshot {
             log::debug!("rejecting snapshot for version {version_id}: already exists");
-            return Ok(());
+            return Ok(false);
         }
 
         // look for this version in the history of this client, starting at the latest version, and
@@ -228,14 +480,14 @@ impl Server {
             if Some(vid) == last_snapshot {
                 // the new snapshot is older than the last snapshot, so ignore it
                 log::debug!("rejecting snapshot for version {version_id}: newer snapshot already exists or no such version");
-                return Ok(());
+                return Ok(false);
             }
 
             search_len -= 1;
             if search_len <= 0 || vid == NIL_VERSION_ID {
                 // this should not happen in normal operation, so warn about it
                 log::warn!("rejecting snapshot for version {version_id}: version is too old or no such version");
-                return Ok(());
+                return Ok(false);
             }
 
             // get the parent version ID
@@ -245,48 +497,347 @@ impl Server {
                 // this version does not exist; "this should not happen" but if it does,
                 // we don't need a snapshot earlier than the missing version.
                 log::warn!("rejecting snapshot for version {version_id}: newer versions have already been deleted");
-                return Ok(());
+                return Ok(false);
             }
         }
 
         log::debug!("accepting snapshot for version {version_id}");
-        txn.set_snapshot(
-            Snapshot {
-                version_id,
-                timestamp: Utc::now(),
-                versions_since: 0,
-            },
-            data,
-        )?;
+        set(txn.as_mut(), Snapshot::new(version_id, self.clock.now()))?;
         txn.commit()?;
-        Ok(())
+        Ok(true)
     }
 
     /// Implementation of the GetSnapshot protocol transaction
-    pub fn get_snapshot(
+    pub fn get_snapshot(&self, client_id: ClientId) -> Result<Option<(Uuid, Bytes)>, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            let client = active_client(txn.as_mut())?;
+
+            Ok(if let Some(snap) = client.snapshot {
+                txn.get_snapshot_data(snap.version_id)?
+                    .map(|data| (snap.version_id, data))
+            } else {
+                None
+            })
+        })
+    }
+
+    /// Record that the client has synced, at the current time, along with the user agent it
+    /// reported. This has no effect if the client does not exist.
+    pub fn record_sync(
         &self,
         client_id: ClientId,
-    ) -> Result<Option<(Uuid, Vec<u8>)>, ServerError> {
-        let mut txn = self.storage.txn(client_id)?;
-        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        user_agent: Option<String>,
+    ) -> Result<(), ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            txn.record_sync(self.clock.now(), user_agent.clone())?;
+            txn.commit()?;
+            Ok(())
+        })
+    }
 
-        Ok(if let Some(snap) = client.snapshot {
-            txn.get_snapshot_data(snap.version_id)?
-                .map(|data| (snap.version_id, data))
-        } else {
-            None
+    /// Implementation of the GetSnapshot protocol transaction, returning a reader for the snapshot
+    /// data rather than the data itself. With a backend supporting streaming, the data is not
+    /// held in memory.
+    #[allow(clippy::type_complexity)]
+    pub fn get_snapshot_reader(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<(Uuid, Box<dyn Read + Send>)>, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            let client = active_client(txn.as_mut())?;
+
+            Ok(if let Some(snap) = client.snapshot {
+                txn.get_snapshot_reader(snap.version_id)?
+                    .map(|reader| (snap.version_id, reader))
+            } else {
+                None
+            })
+        })
+    }
+
+    /// Delete the client's versions added before the given time, along with all of their
+    /// ancestors, returning the number of versions deleted. A client syncing from before the
+    /// oldest remaining version must then use a snapshot.
+    pub fn expire_versions_older_than(
+        &self,
+        client_id: ClientId,
+        timestamp: DateTime<Utc>,
+    ) -> Result<usize, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            if txn.get_client()?.is_none() {
+                return Err(ServerError::NoSuchClient);
+            }
+            let expired = txn.expire_versions_older_than(timestamp)?;
+            txn.commit()?;
+            Ok(expired)
+        })
+    }
+
+    /// Mark a client as deleted. Its data is retained, and the deletion can be undone with
+    /// [`Server::undelete_client`], until it is purged by [`Server::purge_deleted_clients`] after
+    /// the configured grace period. Meanwhile, protocol requests for the client fail with
+    /// [`ServerError::ClientDeleted`].
+    ///
+    /// If the backend does not support soft deletion, as reported by
+    /// [`StorageCapabilities::supports_soft_delete`], the client is removed immediately and
+    /// cannot be restored.
+    pub fn delete_client(&self, client_id: ClientId) -> Result<(), ServerError> {
+        let soft_delete = self.storage.capabilities().supports_soft_delete;
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+            if !soft_delete {
+                txn.delete_client()?;
+                txn.commit()?;
+            } else if client.deleted_at.is_none() {
+                txn.set_deleted(Some(self.clock.now()))?;
+                txn.commit()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Restore a client deleted with [`Server::delete_client`] which has not yet been purged.
+    pub fn undelete_client(&self, client_id: ClientId) -> Result<(), ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+            if client.deleted_at.is_some() {
+                txn.set_deleted(None)?;
+                txn.commit()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Permanently remove the data of clients which were deleted more than the configured grace
+    /// period ago, returning the number of clients purged. Each client is purged in a separate
+    /// transaction.
+    ///
+    /// This requires a backend supporting client enumeration and deletion.
+    pub fn purge_deleted_clients(&self) -> Result<usize, ServerError> {
+        const PAGE_LEN: usize = 100;
+        let cutoff = self.clock.now() - chrono::Duration::days(self.config.deletion_grace_days);
+        let mut purged = 0;
+        let mut after = None;
+        loop {
+            let page = self
+                .storage
+                .txn(NIL_VERSION_ID)?
+                .list_clients(after, PAGE_LEN)?;
+            for client_id in &page {
+                let mut txn = self.storage.txn(*client_id)?;
+                let Some(client) = txn.get_client()? else {
+                    continue;
+                };
+                if client.deleted_at.is_some_and(|t| t <= cutoff) {
+                    txn.delete_client()?;
+                    txn.commit()?;
+                    log::info!("purged deleted client {client_id}");
+                    purged += 1;
+                }
+            }
+            if page.len() < PAGE_LEN {
+                return Ok(purged);
+            }
+            after = page.last().copied();
+        }
+    }
+
+    /// Get the latest version ID of an active client, and its snapshot if any, so that a replica
+    /// can check whether it is up to date without reading a version.
+    pub fn get_latest_version(
+        &self,
+        client_id: ClientId,
+    ) -> Result<(VersionId, Option<Snapshot>), ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            let client = active_client(txn.as_mut())?;
+            Ok((client.latest_version_id, client.snapshot))
+        })
+    }
+
+    /// Get a client, including one which has been deleted but not yet purged.
+    pub fn get_client(&self, client_id: ClientId) -> Result<Client, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            txn.get_client()?.ok_or(ServerError::NoSuchClient)
+        })
+    }
+
+    /// List up to `limit` client IDs, in order, beginning after `after` if given.
+    ///
+    /// This requires a backend supporting client enumeration.
+    pub fn list_clients(
+        &self,
+        after: Option<ClientId>,
+        limit: usize,
+    ) -> Result<Vec<ClientId>, ServerError> {
+        self.retry(|| {
+            Ok(self
+                .storage
+                .txn(NIL_VERSION_ID)?
+                .list_clients(after, limit)?)
+        })
+    }
+
+    /// Get up to `limit` versions of the client's history, beginning with its latest version and
+    /// following parent links back toward its first version. The chain ends early if a parent
+    /// version has been deleted, such as by expiry.
+    pub fn get_version_chain(
+        &self,
+        client_id: ClientId,
+        limit: usize,
+    ) -> Result<Vec<Version>, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+            let mut chain = Vec::new();
+            let mut version_id = client.latest_version_id;
+            while version_id != NIL_VERSION_ID && chain.len() < limit {
+                let Some(version) = txn.get_version(version_id)? else {
+                    break;
+                };
+                version_id = version.parent_version_id;
+                chain.push(version);
+            }
+            Ok(chain)
         })
     }
 
+    /// Get statistics about the space used by a client.
+    pub fn get_client_stats(&self, client_id: ClientId) -> Result<ClientStats, ServerError> {
+        self.retry(|| {
+            let mut txn = self.storage.txn(client_id)?;
+            txn.get_client_stats()?.ok_or(ServerError::NoSuchClient)
+        })
+    }
+
+    /// Get statistics about the space used by all clients. This reads every client, in a separate
+    /// transaction for each, so the result is not a consistent snapshot of the storage.
+    ///
+    /// This requires a backend supporting client enumeration.
+    pub fn get_storage_stats(&self) -> Result<StorageStats, ServerError> {
+        const PAGE_LEN: usize = 100;
+        let mut stats = StorageStats::default();
+        let mut after = None;
+        loop {
+            let page = self
+                .storage
+                .txn(NIL_VERSION_ID)?
+                .list_clients(after, PAGE_LEN)?;
+            for client_id in &page {
+                // a client deleted since it was listed is skipped
+                if let Some(client_stats) = self.storage.txn(*client_id)?.get_client_stats()? {
+                    stats.add(&client_stats);
+                }
+            }
+            if page.len() < PAGE_LEN {
+                return Ok(stats);
+            }
+            after = page.last().copied();
+        }
+    }
+
+    /// Call `f`, retrying it up to `transient_retries` times while it fails with
+    /// [`ServerError::TryAgainLater`], which includes transient storage errors.
+    fn retry<T, F>(&self, mut f: F) -> Result<T, ServerError>
+    where
+        F: FnMut() -> Result<T, ServerError>,
+    {
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(ServerError::TryAgainLater) if retries < self.config.transient_retries => {
+                    retries += 1;
+                    log::debug!("retrying after transient storage error (retry {retries})");
+                    self.clock.sleep(RETRY_BACKOFF * retries);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Calculate the urgency of a snapshot for a client with the given snapshot, after adding
+    /// versions. `added` is the number of versions added beyond the first, which is not yet
+    /// counted in the urgency.
+    fn snapshot_urgency(&self, snapshot: Option<&Snapshot>, added: u32) -> SnapshotUrgency {
+        let Some(snapshot) = snapshot else {
+            return SnapshotUrgency::High;
+        };
+        let time_urgency = SnapshotUrgency::for_days(
+            &self.config,
+            (self.clock.now() - snapshot.timestamp).num_days(),
+        );
+        let version_urgency = SnapshotUrgency::for_versions_since(
+            &self.config,
+            snapshot.versions_since.saturating_add(added),
+        );
+        std::cmp::max(time_urgency, version_urgency)
+    }
+
+    /// Check that the storage is reachable, by reading a client in a new transaction. This is not
+    /// retried, so that a failing storage is reported promptly.
+    pub fn check_ready(&self) -> Result<(), ServerError> {
+        let mut txn = self.storage.txn(Uuid::new_v4())?;
+        txn.get_client()?;
+        Ok(())
+    }
+
+    /// Write any changes buffered in memory to the storage, such as before the process exits.
+    pub fn flush(&self) -> Result<(), ServerError> {
+        Ok(self.storage.flush()?)
+    }
+
     /// Convenience method to get a transaction for the embedded storage.
     pub fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, ServerError> {
         Ok(self.storage.txn(client_id)?)
     }
 }
 
+/// Get the client, failing if it does not exist or has been deleted.
+fn active_client(txn: &mut dyn StorageTxn) -> Result<Client, ServerError> {
+    let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+    if client.deleted_at.is_some() {
+        return Err(ServerError::ClientDeleted);
+    }
+    Ok(client)
+}
+
+/// Add versions one at a time, each under a savepoint, stopping at the first version that cannot
+/// be added. Returns the number of versions added and the ID of the last, or the error if the
+/// first version cannot be added.
+fn add_versions_until_error(
+    txn: &mut dyn StorageTxn,
+    versions: Vec<(VersionId, VersionId, HistorySegment)>,
+) -> Result<(u32, VersionId), StorageError> {
+    let mut added = 0;
+    let mut last_version_id = NIL_VERSION_ID;
+    for (version_id, parent_version_id, history_segment) in versions {
+        let savepoint = txn.savepoint()?;
+        if let Err(err) = txn.add_version(version_id, parent_version_id, history_segment) {
+            txn.rollback_to(savepoint)?;
+            if added == 0 {
+                return Err(err);
+            }
+            log::debug!("add_versions stopped after {added} versions: {err:#}");
+            break;
+        }
+        added += 1;
+        last_version_id = version_id;
+    }
+    Ok((added, last_version_id))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::flaky::{FaultConfig, FlakyStorage};
     use crate::inmemory::InMemoryStorage;
     use crate::storage::{Snapshot, Storage, StorageTxn};
     use chrono::{Duration, TimeZone, Utc};
@@ -328,7 +879,7 @@ mod test {
                     version_id,
                     parent_version_id,
                     // Generate some unique data for this version.
-                    vec![0, 0, vnum as u8],
+                    Bytes::from(vec![0, 0, vnum as u8]),
                 )?;
                 if Some(vnum) == snapshot_version {
                     txn.set_snapshot(
@@ -338,7 +889,7 @@ mod test {
                             timestamp: Utc::now() - Duration::days(snapshot_days_ago.unwrap_or(0)),
                         },
                         // Generate some unique data for this snapshot.
-                        vec![vnum as u8],
+                        Bytes::from(vec![vnum as u8]),
                     )?;
                 }
             }
@@ -354,7 +905,7 @@ mod test {
         client_id: Uuid,
         existing_versions: &[Uuid],
         (add_version_result, snapshot_urgency): (AddVersionResult, SnapshotUrgency),
-        expected_history: Vec<u8>,
+        expected_history: HistorySegment,
         expected_urgency: SnapshotUrgency,
     ) -> anyhow::Result<()> {
         if let AddVersionResult::Ok(new_version_id) = add_version_result {
@@ -426,6 +977,48 @@ mod test {
         );
     }
 
+    #[test]
+    fn snapshot_urgency_for_versions_since_saturated() {
+        let config = ServerConfig {
+            snapshot_versions: u32::MAX,
+            ..ServerConfig::default()
+        };
+        assert_eq!(
+            SnapshotUrgency::for_versions_since(&config, u32::MAX),
+            SnapshotUrgency::High
+        );
+        assert_eq!(
+            SnapshotUrgency::for_versions_since(&config, 10),
+            SnapshotUrgency::None
+        );
+    }
+
+    #[test]
+    fn add_version_saturated_versions_since() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, Bytes::new())?;
+            txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![1].into())?;
+            txn.commit()?;
+        }
+        storage.set_versions_since(client_id, u32::MAX);
+        let server = Server::new(ServerConfig::default(), storage);
+
+        let (result, urgency) = server.add_version(client_id, version_id, vec![1, 2, 3].into())?;
+        assert!(matches!(result, AddVersionResult::Ok(_)));
+        assert_eq!(urgency, SnapshotUrgency::High);
+
+        // the count does not wrap around to zero
+        let mut txn = server.txn(client_id)?;
+        let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+        assert_eq!(snapshot.versions_since, u32::MAX);
+        Ok(())
+    }
+
     #[test]
     fn get_child_version_not_found_initial_nil() -> anyhow::Result<()> {
         let (server, client_id) = setup(|txn, client_id| {
@@ -464,7 +1057,7 @@ mod test {
             // add a parent version, but not the requested child version
             let parent_version_id = Uuid::new_v4();
             txn.new_client(parent_version_id)?;
-            txn.add_version(parent_version_id, NIL_VERSION_ID, vec![])?;
+            txn.add_version(parent_version_id, NIL_VERSION_ID, Bytes::new())?;
 
             Ok((client_id, parent_version_id))
         })?;
@@ -482,7 +1075,7 @@ mod test {
             // Add a parent version, but not the requested parent version
             let parent_version_id = Uuid::new_v4();
             txn.new_client(parent_version_id)?;
-            txn.add_version(parent_version_id, NIL_VERSION_ID, vec![])?;
+            txn.add_version(parent_version_id, NIL_VERSION_ID, Bytes::new())?;
 
             Ok(client_id)
         })?;
@@ -500,7 +1093,7 @@ mod test {
             setup(|txn, client_id| {
                 let version_id = Uuid::new_v4();
                 let parent_version_id = Uuid::new_v4();
-                let history_segment = b"abcd".to_vec();
+                let history_segment = Bytes::from_static(b"abcd");
 
                 txn.new_client(version_id)?;
                 txn.add_version(version_id, parent_version_id, history_segment.clone())?;
@@ -518,13 +1111,121 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn get_versions_since() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+
+        let result = server.get_versions_since(client_id, NIL_VERSION_ID, 2)?;
+        assert_eq!(
+            result.iter().map(|v| v.version_id).collect::<Vec<_>>(),
+            &versions[0..2]
+        );
+        assert_eq!(result[1].parent_version_id, versions[0]);
+        assert_eq!(result[1].history_segment, vec![0, 0, 1]);
+
+        assert_eq!(
+            server.get_versions_since(client_id, versions[2], 2)?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_versions_since_no_client() -> anyhow::Result<()> {
+        let (server, _) = setup(|_, _| Ok(()))?;
+        assert!(matches!(
+            server.get_versions_since(Uuid::new_v4(), NIL_VERSION_ID, 2),
+            Err(ServerError::NoSuchClient)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn record_sync() -> anyhow::Result<()> {
+        let (server, client_id, _) = av_setup(1, None, None)?;
+        server.record_sync(client_id, Some("tc/1.0".into()))?;
+        let client = server.txn(client_id)?.get_client()?.unwrap();
+        assert!(client.last_sync_at.is_some());
+        assert_eq!(client.user_agent, Some("tc/1.0".into()));
+
+        // a nonexistent client is not created
+        let other_client_id = Uuid::new_v4();
+        server.record_sync(other_client_id, None)?;
+        assert_eq!(server.txn(other_client_id)?.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn expire_versions_older_than() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        assert_eq!(
+            server.expire_versions_older_than(client_id, Utc::now() - Duration::days(1))?,
+            0
+        );
+        assert_eq!(
+            server.expire_versions_older_than(client_id, Utc::now() + Duration::days(1))?,
+            3
+        );
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_version(versions[0])?, None);
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[2]);
+        drop(txn);
+
+        assert!(matches!(
+            server.expire_versions_older_than(Uuid::new_v4(), Utc::now()),
+            Err(ServerError::NoSuchClient)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_stats() -> anyhow::Result<()> {
+        let (server, client_id, _) = av_setup(3, Some(1), None)?;
+        let stats = server.get_client_stats(client_id)?;
+        assert_eq!(stats.version_count, 3);
+        assert_eq!(stats.history_bytes, 9);
+        assert_eq!(stats.snapshot_bytes, 1);
+        assert!(stats.snapshot_timestamp.is_some());
+        assert!(stats.oldest_version_at.is_some());
+        assert!(stats.oldest_version_at <= stats.latest_version_at);
+
+        assert!(matches!(
+            server.get_client_stats(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_storage_stats() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        {
+            let mut txn = server.txn(Uuid::new_v4())?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+            txn.commit()?;
+        }
+        server.add_snapshot(client_id, versions[2], vec![1, 2].into())?;
+
+        let stats = server.get_storage_stats()?;
+        assert_eq!(stats.client_count, 2);
+        assert_eq!(stats.version_count, 4);
+        assert_eq!(stats.history_bytes, 10);
+        assert_eq!(stats.snapshot_bytes, 2);
+        assert!(stats.oldest_snapshot_timestamp.is_some());
+        assert!(stats.latest_version_at.is_some());
+        Ok(())
+    }
+
     #[test]
     fn add_version_conflict() -> anyhow::Result<()> {
         let (server, client_id, versions) = av_setup(3, None, None)?;
 
         // try to add a child of a version other than the latest
         assert_eq!(
-            server.add_version(client_id, versions[1], vec![3, 6, 9])?.0,
+            server
+                .add_version(client_id, versions[1], vec![3, 6, 9].into())?
+                .0,
             AddVersionResult::ExpectedParentVersion(versions[2])
         );
 
@@ -540,14 +1241,14 @@ mod test {
     fn add_version_with_existing_history() -> anyhow::Result<()> {
         let (server, client_id, versions) = av_setup(1, None, None)?;
 
-        let result = server.add_version(client_id, versions[0], vec![3, 6, 9])?;
+        let result = server.add_version(client_id, versions[0], vec![3, 6, 9].into())?;
 
         av_success_check(
             &server,
             client_id,
             &versions,
             result,
-            vec![3, 6, 9],
+            vec![3, 6, 9].into(),
             // urgency=high because there are no snapshots yet
             SnapshotUrgency::High,
         )?;
@@ -560,14 +1261,14 @@ mod test {
         let (server, client_id, versions) = av_setup(0, None, None)?;
 
         let parent_version_id = Uuid::nil();
-        let result = server.add_version(client_id, parent_version_id, vec![3, 6, 9])?;
+        let result = server.add_version(client_id, parent_version_id, vec![3, 6, 9].into())?;
 
         av_success_check(
             &server,
             client_id,
             &versions,
             result,
-            vec![3, 6, 9],
+            vec![3, 6, 9].into(),
             // urgency=high because there are no snapshots yet
             SnapshotUrgency::High,
         )?;
@@ -575,18 +1276,44 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn add_version_from_reader() -> anyhow::Result<()> {
+        let (server, client_id, _) = av_setup(0, None, None)?;
+
+        let (result, _) =
+            server.add_version_from_reader(client_id, NIL_VERSION_ID, &mut &[3u8, 6, 9][..])?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("version not added");
+        };
+
+        let GetVersionResult::Success {
+            version_id: got_version_id,
+            parent_version_id,
+            mut history_segment,
+        } = server.get_child_version_reader(client_id, NIL_VERSION_ID)?
+        else {
+            panic!("version not found");
+        };
+        assert_eq!(got_version_id, version_id);
+        assert_eq!(parent_version_id, NIL_VERSION_ID);
+        let mut data = vec![];
+        history_segment.read_to_end(&mut data)?;
+        assert_eq!(data, vec![3, 6, 9]);
+        Ok(())
+    }
+
     #[test]
     fn add_version_success_recent_snapshot() -> anyhow::Result<()> {
         let (server, client_id, versions) = av_setup(1, Some(0), None)?;
 
-        let result = server.add_version(client_id, versions[0], vec![1, 2, 3])?;
+        let result = server.add_version(client_id, versions[0], vec![1, 2, 3].into())?;
 
         av_success_check(
             &server,
             client_id,
             &versions,
             result,
-            vec![1, 2, 3],
+            vec![1, 2, 3].into(),
             // no snapshot request since the previous version has a snapshot
             SnapshotUrgency::None,
         )?;
@@ -599,14 +1326,14 @@ mod test {
         // one snapshot, but it was 50 days ago
         let (server, client_id, versions) = av_setup(1, Some(0), Some(50))?;
 
-        let result = server.add_version(client_id, versions[0], vec![1, 2, 3])?;
+        let result = server.add_version(client_id, versions[0], vec![1, 2, 3].into())?;
 
         av_success_check(
             &server,
             client_id,
             &versions,
             result,
-            vec![1, 2, 3],
+            vec![1, 2, 3].into(),
             // urgency=high due to days since the snapshot
             SnapshotUrgency::High,
         )?;
@@ -620,14 +1347,14 @@ mod test {
         let (mut server, client_id, versions) = av_setup(50, Some(0), None)?;
         server.config.snapshot_versions = 30;
 
-        let result = server.add_version(client_id, versions[49], vec![1, 2, 3])?;
+        let result = server.add_version(client_id, versions[49], vec![1, 2, 3].into())?;
 
         av_success_check(
             &server,
             client_id,
             &versions,
             result,
-            vec![1, 2, 3],
+            vec![1, 2, 3].into(),
             // urgency=high due to number of versions since the snapshot
             SnapshotUrgency::High,
         )?;
@@ -635,6 +1362,124 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn add_versions_success() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, Some(0), None)?;
+
+        let new_versions: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let batch = vec![
+            (new_versions[0], versions[0], vec![1].into()),
+            (new_versions[1], new_versions[0], vec![2].into()),
+            (new_versions[2], new_versions[1], vec![3].into()),
+        ];
+        assert_eq!(
+            server.add_versions(client_id, batch)?,
+            (AddVersionResult::Ok(new_versions[2]), SnapshotUrgency::None)
+        );
+
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, new_versions[2]);
+        assert_eq!(client.snapshot.unwrap().versions_since, 3);
+        let version = txn.get_version_by_parent(new_versions[0])?.unwrap();
+        assert_eq!(version.version_id, new_versions[1]);
+        assert_eq!(version.history_segment, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn add_versions_partial() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        // the last version cannot be added, because a version with its ID already exists
+        let batch = vec![
+            (v1, versions[0], vec![1].into()),
+            (v2, v1, vec![2].into()),
+            (versions[0], v2, vec![3].into()),
+        ];
+        assert_eq!(
+            server.add_versions(client_id, batch)?.0,
+            AddVersionResult::Ok(v2)
+        );
+
+        {
+            let mut txn = server.txn(client_id)?;
+            assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+            assert_eq!(txn.get_version_by_parent(v2)?, None);
+            assert_eq!(
+                txn.get_version(versions[0])?.unwrap().history_segment,
+                vec![0, 0, 0]
+            );
+        }
+
+        // if the first version cannot be added, nothing is added
+        let batch = vec![(versions[0], v2, vec![3].into())];
+        assert!(server.add_versions(client_id, batch).is_err());
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+        assert_eq!(txn.get_version_by_parent(v2)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn add_versions_urgency_counts_batch() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(1, Some(0), None)?;
+        server.config.snapshot_versions = 2;
+
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let v3 = Uuid::new_v4();
+        let batch = vec![
+            (v1, versions[0], vec![1].into()),
+            (v2, v1, vec![2].into()),
+            (v3, v2, vec![3].into()),
+        ];
+        // a single add_version would not request a snapshot, but the batch of three does
+        assert_eq!(
+            server.add_versions(client_id, batch)?.1,
+            SnapshotUrgency::Low
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn add_versions_conflict() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+
+        let v1 = Uuid::new_v4();
+        let batch = vec![(v1, versions[1], vec![1].into())];
+        assert_eq!(
+            server.add_versions(client_id, batch)?.0,
+            AddVersionResult::ExpectedParentVersion(versions[2])
+        );
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[2]);
+        assert_eq!(txn.get_version(v1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn add_versions_not_a_chain() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let batch = vec![
+            (v1, versions[0], vec![1].into()),
+            (v2, versions[0], vec![2].into()),
+        ];
+        assert!(server.add_versions(client_id, batch).is_err());
+        assert!(server.add_versions(client_id, vec![]).is_err());
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[0]);
+        assert_eq!(txn.get_version(v1)?, None);
+        Ok(())
+    }
+
     #[test]
     fn add_snapshot_success_latest() -> anyhow::Result<()> {
         let (server, (client_id, version_id)) = setup(|txn, client_id| {
@@ -642,12 +1487,12 @@ mod test {
 
             // set up a task DB with one version in it
             txn.new_client(version_id)?;
-            txn.add_version(version_id, NIL_VERSION_ID, vec![])?;
+            txn.add_version(version_id, NIL_VERSION_ID, Bytes::new())?;
 
             // add a snapshot for that version
             Ok((client_id, version_id))
         })?;
-        server.add_snapshot(client_id, version_id, vec![1, 2, 3])?;
+        assert!(server.add_snapshot(client_id, version_id, vec![1, 2, 3].into())?);
 
         // verify the snapshot
         let mut txn = server.txn(client_id)?;
@@ -657,12 +1502,30 @@ mod test {
         assert_eq!(snapshot.versions_since, 0);
         assert_eq!(
             txn.get_snapshot_data(version_id).unwrap(),
-            Some(vec![1, 2, 3])
+            Some(vec![1, 2, 3].into())
         );
 
         Ok(())
     }
 
+    #[test]
+    fn add_snapshot_from_reader() -> anyhow::Result<()> {
+        let (server, (client_id, version_id)) = setup(|txn, client_id| {
+            let version_id = Uuid::new_v4();
+            txn.new_client(version_id)?;
+            txn.add_version(version_id, NIL_VERSION_ID, Bytes::new())?;
+            Ok((client_id, version_id))
+        })?;
+        server.add_snapshot_from_reader(client_id, version_id, &mut &[1u8, 2, 3][..])?;
+
+        let (snapshot_version_id, mut reader) = server.get_snapshot_reader(client_id)?.unwrap();
+        assert_eq!(snapshot_version_id, version_id);
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        assert_eq!(data, vec![1, 2, 3]);
+        Ok(())
+    }
+
     #[test]
     fn add_snapshot_success_older() -> anyhow::Result<()> {
         let (server, (client_id, version_id_1)) = setup(|txn, client_id| {
@@ -671,13 +1534,13 @@ mod test {
 
             // set up a task DB with two versions in it
             txn.new_client(version_id_2)?;
-            txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
-            txn.add_version(version_id_2, version_id_1, vec![])?;
+            txn.add_version(version_id_1, NIL_VERSION_ID, Bytes::new())?;
+            txn.add_version(version_id_2, version_id_1, Bytes::new())?;
 
             Ok((client_id, version_id_1))
         })?;
         // add a snapshot for version 1
-        server.add_snapshot(client_id, version_id_1, vec![1, 2, 3])?;
+        server.add_snapshot(client_id, version_id_1, vec![1, 2, 3].into())?;
 
         // verify the snapshot
         let mut txn = server.txn(client_id)?;
@@ -687,7 +1550,7 @@ mod test {
         assert_eq!(snapshot.versions_since, 0);
         assert_eq!(
             txn.get_snapshot_data(version_id_1).unwrap(),
-            Some(vec![1, 2, 3])
+            Some(vec![1, 2, 3].into())
         );
 
         Ok(())
@@ -701,15 +1564,15 @@ mod test {
 
             // set up a task DB with two versions in it
             txn.new_client(version_id_2)?;
-            txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
-            txn.add_version(version_id_2, version_id_1, vec![])?;
+            txn.add_version(version_id_1, NIL_VERSION_ID, Bytes::new())?;
+            txn.add_version(version_id_2, version_id_1, Bytes::new())?;
 
             // add a snapshot for unknown version
             Ok(client_id)
         })?;
 
         let version_id_unk = Uuid::new_v4();
-        server.add_snapshot(client_id, version_id_unk, vec![1, 2, 3])?;
+        assert!(!server.add_snapshot(client_id, version_id_unk, vec![1, 2, 3].into())?);
 
         // verify the snapshot does not exist
         let mut txn = server.txn(client_id)?;
@@ -729,7 +1592,7 @@ mod test {
             // set up a task DB with 10 versions in it (oldest to newest)
             txn.new_client(Uuid::nil())?;
             for _ in 0..10 {
-                txn.add_version(version_id, parent_version_id, vec![])?;
+                txn.add_version(version_id, parent_version_id, Bytes::new())?;
                 version_ids.push(version_id);
                 parent_version_id = version_id;
                 version_id = Uuid::new_v4();
@@ -738,7 +1601,7 @@ mod test {
             // add a snapshot for the earliest of those
             Ok((client_id, version_ids))
         })?;
-        server.add_snapshot(client_id, version_ids[0], vec![1, 2, 3])?;
+        server.add_snapshot(client_id, version_ids[0], vec![1, 2, 3].into())?;
 
         // verify the snapshot does not exist
         let mut txn = server.txn(client_id)?;
@@ -759,7 +1622,7 @@ mod test {
             // middle one
             txn.new_client(Uuid::nil())?;
             for _ in 0..5 {
-                txn.add_version(version_id, parent_version_id, vec![])?;
+                txn.add_version(version_id, parent_version_id, Bytes::new())?;
                 version_ids.push(version_id);
                 parent_version_id = version_id;
                 version_id = Uuid::new_v4();
@@ -770,14 +1633,14 @@ mod test {
                     versions_since: 2,
                     timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
                 },
-                vec![1, 2, 3],
+                vec![1, 2, 3].into(),
             )?;
 
             // add a snapshot for the earliest of those
             Ok((client_id, version_ids))
         })?;
 
-        server.add_snapshot(client_id, version_ids[0], vec![9, 9, 9])?;
+        server.add_snapshot(client_id, version_ids[0], vec![9, 9, 9].into())?;
 
         // verify the snapshot was not replaced
         let mut txn = server.txn(client_id)?;
@@ -787,7 +1650,7 @@ mod test {
         assert_eq!(snapshot.versions_since, 2);
         assert_eq!(
             txn.get_snapshot_data(version_ids[2]).unwrap(),
-            Some(vec![1, 2, 3])
+            Some(vec![1, 2, 3].into())
         );
 
         Ok(())
@@ -803,7 +1666,7 @@ mod test {
             Ok(client_id)
         })?;
 
-        server.add_snapshot(client_id, NIL_VERSION_ID, vec![9, 9, 9])?;
+        server.add_snapshot(client_id, NIL_VERSION_ID, vec![9, 9, 9].into())?;
 
         // verify the snapshot does not exist
         let mut txn = server.txn(client_id)?;
@@ -816,14 +1679,14 @@ mod test {
     #[test]
     fn get_snapshot_found() -> anyhow::Result<()> {
         let (server, (client_id, data, snapshot_version_id)) = setup(|txn, client_id| {
-            let data = vec![1, 2, 3];
+            let data = Bytes::from(vec![1, 2, 3]);
             let snapshot_version_id = Uuid::new_v4();
 
             txn.new_client(snapshot_version_id)?;
             txn.set_snapshot(
                 Snapshot {
                     version_id: snapshot_version_id,
-                    versions_since: 3,
+                    versions_since: 0,
                     timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
                 },
                 data.clone(),
@@ -846,7 +1709,252 @@ mod test {
         })?;
 
         assert_eq!(server.get_snapshot(client_id)?, None);
+        assert!(server.get_snapshot_reader(client_id)?.is_none());
+
+        Ok(())
+    }
+
+    /// Create a server with a client, over storage which fails with the given rate.
+    fn flaky_setup(failure_rate: f64, transient_retries: u32) -> anyhow::Result<(Server, Uuid)> {
+        let storage = InMemoryStorage::new_lenient();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+        let config = ServerConfig {
+            transient_retries,
+            ..ServerConfig::default()
+        };
+        let storage = FlakyStorage::new(
+            storage,
+            FaultConfig {
+                failure_rate,
+                seed: 1,
+                ..FaultConfig::default()
+            },
+        );
+        Ok((Server::new(config, storage), client_id))
+    }
+
+    #[test]
+    fn transient_errors_retried() -> anyhow::Result<()> {
+        let (server, client_id) = flaky_setup(0.3, 30)?;
+        assert_eq!(server.get_snapshot(client_id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn transient_errors_exhaust_retries() -> anyhow::Result<()> {
+        let (server, client_id) = flaky_setup(1.0, 2)?;
+        assert!(matches!(
+            server.get_snapshot(client_id),
+            Err(ServerError::TryAgainLater)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn check_ready() -> anyhow::Result<()> {
+        let server = Server::new(ServerConfig::default(), InMemoryStorage::new());
+        server.check_ready()?;
+
+        let flaky = FlakyStorage::new(
+            InMemoryStorage::new(),
+            FaultConfig {
+                failure_rate: 1.0,
+                ..FaultConfig::default()
+            },
+        );
+        let server = Server::new(ServerConfig::default(), flaky);
+        assert!(matches!(
+            server.check_ready(),
+            Err(ServerError::TryAgainLater)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn soft_delete_client() -> anyhow::Result<()> {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        for id in [client_id, other_client_id] {
+            let mut txn = storage.txn(id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+        let server = Server::with_clock(ServerConfig::default(), storage, clock.clone());
+
+        server.delete_client(client_id)?;
+        assert!(matches!(
+            server.get_child_version(client_id, NIL_VERSION_ID),
+            Err(ServerError::ClientDeleted)
+        ));
+        assert!(matches!(
+            server.add_version(client_id, NIL_VERSION_ID, vec![1].into()),
+            Err(ServerError::ClientDeleted)
+        ));
+        assert!(matches!(
+            server.delete_client(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        // the deletion can be undone
+        server.undelete_client(client_id)?;
+        server.add_version(client_id, NIL_VERSION_ID, vec![1].into())?;
+
+        // deleted clients are purged only after the grace period
+        server.delete_client(client_id)?;
+        clock.set(start + Duration::days(29));
+        assert_eq!(server.purge_deleted_clients()?, 0);
+        clock.set(start + Duration::days(30));
+        assert_eq!(server.purge_deleted_clients()?, 1);
+        assert_eq!(server.txn(client_id)?.get_client()?, None);
+        assert!(server.txn(other_client_id)?.get_client()?.is_some());
+        Ok(())
+    }
+
+    /// A storage which does not report support for soft deletion.
+    struct HardDeleteStorage(InMemoryStorage);
 
+    impl Storage for HardDeleteStorage {
+        fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+            self.0.txn(client_id)
+        }
+    }
+
+    #[test]
+    fn delete_client_without_soft_delete() -> anyhow::Result<()> {
+        let storage = HardDeleteStorage(InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+        let server = Server::new(ServerConfig::default(), storage);
+
+        // the client is removed immediately, rather than failing
+        server.delete_client(client_id)?;
+        assert_eq!(server.txn(client_id)?.get_client()?, None);
+        assert!(matches!(
+            server.undelete_client(client_id),
+            Err(ServerError::NoSuchClient)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_latest_version() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+            txn.commit()?;
+        }
+        let server = Server::new(ServerConfig::default(), storage);
+
+        assert_eq!(server.get_latest_version(client_id)?, (version_id, None));
+        server.add_snapshot(client_id, version_id, vec![2].into())?;
+        let (latest, snapshot) = server.get_latest_version(client_id)?;
+        assert_eq!(latest, version_id);
+        assert_eq!(snapshot.unwrap().version_id, version_id);
+
+        assert!(matches!(
+            server.get_latest_version(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+        server.delete_client(client_id)?;
+        assert!(matches!(
+            server.get_latest_version(client_id),
+            Err(ServerError::ClientDeleted)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn admin_queries() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let (v1, v2, v3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+            txn.add_version(v2, v1, vec![2].into())?;
+            txn.add_version(v3, v2, vec![3].into())?;
+            txn.commit()?;
+        }
+        let server = Server::new(ServerConfig::default(), storage);
+
+        assert_eq!(server.list_clients(None, 10)?, vec![client_id]);
+        assert_eq!(
+            server.list_clients(Some(client_id), 10)?,
+            Vec::<Uuid>::new()
+        );
+        assert_eq!(server.get_client(client_id)?.latest_version_id, v3);
+        assert!(matches!(
+            server.get_client(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        let chain = server.get_version_chain(client_id, 10)?;
+        let ids: Vec<_> = chain.iter().map(|v| v.version_id).collect();
+        assert_eq!(ids, vec![v3, v2, v1]);
+        let chain = server.get_version_chain(client_id, 2)?;
+        assert_eq!(chain.len(), 2);
+
+        // deleted clients can still be inspected
+        server.delete_client(client_id)?;
+        assert!(server.get_client(client_id)?.deleted_at.is_some());
+        assert_eq!(server.get_version_chain(client_id, 10)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_urgency_ages_with_clock() -> anyhow::Result<()> {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, Bytes::new())?;
+            txn.commit()?;
+        }
+        let server = Server::with_clock(ServerConfig::default(), storage, clock.clone());
+        server.add_snapshot(client_id, version_id, vec![1].into())?;
+        {
+            let mut txn = server.txn(client_id)?;
+            assert_eq!(
+                txn.get_client()?.unwrap().snapshot.unwrap().timestamp,
+                start
+            );
+        }
+
+        let mut parent_version_id = version_id;
+        let mut add = || -> anyhow::Result<SnapshotUrgency> {
+            let (result, urgency) =
+                server.add_version(client_id, parent_version_id, Bytes::new())?;
+            let AddVersionResult::Ok(version_id) = result else {
+                anyhow::bail!("version not added");
+            };
+            parent_version_id = version_id;
+            Ok(urgency)
+        };
+        assert_eq!(add()?, SnapshotUrgency::None);
+        clock.set(start + Duration::days(14));
+        assert_eq!(add()?, SnapshotUrgency::Low);
+        clock.set(start + Duration::days(21));
+        assert_eq!(add()?, SnapshotUrgency::High);
         Ok(())
     }
 }
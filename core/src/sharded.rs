@@ -0,0 +1,402 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use uuid::Uuid;
+
+/// The number of points on the hash ring for each shard. More points distribute clients more
+/// evenly.
+const POINTS_PER_SHARD: u32 = 64;
+
+/// Hash arbitrary bytes to a position on the hash ring.
+fn ring_position(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+/// A storage which distributes clients among several inner storages.
+///
+/// Each client is assigned to a shard by consistent hashing of its client ID, using the names of
+/// the shards. Adding a shard moves only about `1/n` of the clients to it, while removing one
+/// moves only its clients, but in either case the moved clients' data must be copied to their
+/// new shards separately. Renaming a shard moves clients just as removing and re-adding it would.
+pub struct ShardedStorage<S: Storage> {
+    shards: Vec<(String, S)>,
+    /// The hash ring, mapping positions to indexes in `shards`.
+    ring: BTreeMap<u64, usize>,
+}
+
+impl<S: Storage> ShardedStorage<S> {
+    /// Create a new instance with the given named shards. Names must be unique and there must be
+    /// at least one shard.
+    pub fn new(shards: Vec<(String, S)>) -> anyhow::Result<Self> {
+        if shards.is_empty() {
+            anyhow::bail!("At least one shard is required");
+        }
+        let mut names = HashSet::new();
+        let mut ring = BTreeMap::new();
+        for (i, (name, _)) in shards.iter().enumerate() {
+            if !names.insert(name) {
+                anyhow::bail!("Duplicate shard name {name}");
+            }
+            for point in 0..POINTS_PER_SHARD {
+                ring.insert(ring_position(format!("{name}#{point}").as_bytes()), i);
+            }
+        }
+        Ok(Self { shards, ring })
+    }
+
+    fn shard_index(&self, client_id: Uuid) -> usize {
+        let position = ring_position(client_id.as_bytes());
+        let (_, &i) = self
+            .ring
+            .range(position..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("ring is not empty");
+        i
+    }
+
+    /// Get the name of the shard storing the given client.
+    pub fn shard_name(&self, client_id: Uuid) -> &str {
+        &self.shards[self.shard_index(client_id)].0
+    }
+
+    /// Get the shard storing the given client.
+    pub fn shard(&self, client_id: Uuid) -> &S {
+        &self.shards[self.shard_index(client_id)].1
+    }
+}
+
+impl<S: Storage> Storage for ShardedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        let shard_index = self.shard_index(client_id);
+        Ok(Box::new(ShardedTxn {
+            storage: self,
+            client_id,
+            shard_index,
+            inner: self.shards[shard_index].1.txn(client_id)?,
+        }))
+    }
+
+    /// Capabilities are those supported by every shard.
+    fn capabilities(&self) -> StorageCapabilities {
+        let mut shards = self.shards.iter().map(|(_, s)| s.capabilities());
+        let first = shards.next().expect("there is at least one shard");
+        shards.fold(first, |a, b| StorageCapabilities {
+            supports_client_enumeration: a.supports_client_enumeration
+                && b.supports_client_enumeration,
+            supports_rollback: a.supports_rollback && b.supports_rollback,
+            supports_streaming: a.supports_streaming && b.supports_streaming,
+            supports_compaction: a.supports_compaction && b.supports_compaction,
+            supports_read_only_txn: a.supports_read_only_txn && b.supports_read_only_txn,
+            supports_savepoints: a.supports_savepoints && b.supports_savepoints,
+            supports_soft_delete: a.supports_soft_delete && b.supports_soft_delete,
+            max_snapshot_retention: a.max_snapshot_retention.min(b.max_snapshot_retention),
+            max_history_segment_len: a.max_history_segment_len.min(b.max_history_segment_len),
+        })
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let mut max = None;
+        for (_, shard) in &self.shards {
+            if let Some((client_id, versions_since)) = shard.max_versions_since_snapshot()? {
+                let is_max = match max {
+                    Some((_, max_versions_since)) => versions_since > max_versions_since,
+                    None => true,
+                };
+                if is_max {
+                    max = Some((client_id, versions_since));
+                }
+            }
+        }
+        Ok(max)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        for (_, shard) in &self.shards {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+}
+
+struct ShardedTxn<'a, S: Storage> {
+    storage: &'a ShardedStorage<S>,
+    client_id: Uuid,
+    shard_index: usize,
+    /// The transaction in the client's shard.
+    inner: Box<dyn StorageTxn + 'a>,
+}
+
+impl<S: Storage> StorageTxn for ShardedTxn<'_, S> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.inner.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.inner.set_snapshot(snapshot, data)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner.set_snapshot_from_reader(snapshot, data)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.inner.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.inner.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.inner.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.inner.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.inner.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.inner.get_version(version_id)
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.inner.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_version_if_latest(version_id, parent_version_id, history_segment)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.inner.delete_version(version_id)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.inner.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.inner.rollback_to(savepoint)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.inner.delete_client()
+    }
+
+    /// Clients are listed from every shard, each in its own transaction.
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let mut client_ids = vec![];
+        for (i, (_, shard)) in self.storage.shards.iter().enumerate() {
+            if i == self.shard_index {
+                client_ids.extend(self.inner.list_clients(after, limit)?);
+            } else {
+                client_ids.extend(shard.txn(self.client_id)?.list_clients(after, limit)?);
+            }
+        }
+        client_ids.sort_unstable();
+        client_ids.truncate(limit);
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::storage::Snapshot;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&shards(&["a", "b", "c"])?));
+    }
+
+    fn shards(names: &[&str]) -> anyhow::Result<ShardedStorage<InMemoryStorage>> {
+        ShardedStorage::new(
+            names
+                .iter()
+                .map(|n| (n.to_string(), InMemoryStorage::new()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn invalid_shards() {
+        assert!(shards(&[]).is_err());
+        assert!(shards(&["a", "a"]).is_err());
+    }
+
+    #[test]
+    fn clients_routed_to_shard() -> anyhow::Result<()> {
+        let storage = shards(&["a", "b", "c"])?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        for (name, shard) in &storage.shards {
+            let in_shard = shard.txn(client_id)?.get_client()?.is_some();
+            assert_eq!(in_shard, name == storage.shard_name(client_id));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn distribution_and_stability() -> anyhow::Result<()> {
+        let three = shards(&["a", "b", "c"])?;
+        let four = shards(&["a", "b", "c", "d"])?;
+        let client_ids: Vec<Uuid> = (0..3000).map(|_| Uuid::new_v4()).collect();
+
+        let mut counts = BTreeMap::new();
+        let mut moved = 0;
+        for client_id in &client_ids {
+            let name = three.shard_name(*client_id);
+            *counts.entry(name).or_insert(0) += 1;
+            let new_name = four.shard_name(*client_id);
+            if new_name != name {
+                // clients only move to the new shard
+                assert_eq!(new_name, "d");
+                moved += 1;
+            }
+        }
+        // each shard gets a reasonable share of the clients
+        for count in counts.values() {
+            assert!(*count > 500, "{counts:?}");
+        }
+        assert!(moved > 300 && moved < 1500, "{moved} clients moved");
+        Ok(())
+    }
+
+    #[test]
+    fn list_clients() -> anyhow::Result<()> {
+        let storage = shards(&["a", "b", "c"])?;
+        let mut client_ids: Vec<Uuid> = (0..20).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+
+        let mut txn = storage.txn(client_ids[0])?;
+        assert_eq!(txn.list_clients(None, 5)?, client_ids[..5].to_vec());
+        assert_eq!(
+            txn.list_clients(Some(client_ids[4]), 100)?,
+            client_ids[5..].to_vec()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_versions_since_snapshot() -> anyhow::Result<()> {
+        let storage = shards(&["a", "b"])?;
+        assert_eq!(storage.max_versions_since_snapshot()?, None);
+        let mut expected = None;
+        for versions_since in [3, 10, 5] {
+            let client_id = Uuid::new_v4();
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            let mut parent_version_id = NIL_VERSION_ID;
+            for _ in 0..versions_since {
+                let version_id = Uuid::new_v4();
+                txn.add_version(version_id, parent_version_id, Bytes::new())?;
+                parent_version_id = version_id;
+            }
+            txn.set_snapshot(
+                Snapshot::new(Uuid::new_v4(), Utc::now()).with_versions_since(versions_since),
+                Bytes::new(),
+            )?;
+            txn.commit()?;
+            if versions_since == 10 {
+                expected = Some((client_id, 10));
+            }
+        }
+        assert_eq!(storage.max_versions_since_snapshot()?, expected);
+        Ok(())
+    }
+}
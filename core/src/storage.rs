@@ -1,17 +1,72 @@
+use crate::error::StorageError;
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
 use uuid::Uuid;
 
+/// Maximum size of a history segment accepted by default: 100MB.
+pub const MAX_HISTORY_SEGMENT_LEN: usize = 100 * 1024 * 1024;
+
 /// A representation of stored metadata about a client.
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Client {
     /// The latest version for this client (may be the nil version)
     pub latest_version_id: Uuid,
     /// Data about the latest snapshot for this client
     pub snapshot: Option<Snapshot>,
+    /// The time at which the client was created, or `None` if it was created before this was
+    /// recorded
+    pub created_at: Option<DateTime<Utc>>,
+    /// The time of the client's most recent sync, as recorded by [`StorageTxn::record_sync`]
+    pub last_sync_at: Option<DateTime<Utc>>,
+    /// The user agent presented in the client's most recent sync, including its version
+    pub user_agent: Option<String>,
+    /// Limits on the client's storage, as set by [`StorageTxn::set_quota`]
+    pub quota: Quota,
+    /// The time at which the client was marked deleted by [`StorageTxn::set_deleted`], or `None`
+    /// if it is active. A deleted client's data is retained until it is purged.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+impl Client {
+    /// Create a client with the given latest version, no snapshot, and no sync recorded.
+    pub fn new(latest_version_id: Uuid, created_at: DateTime<Utc>) -> Self {
+        Client {
+            latest_version_id,
+            snapshot: None,
+            created_at: Some(created_at),
+            last_sync_at: None,
+            user_agent: None,
+            quota: Quota::default(),
+            deleted_at: None,
+        }
+    }
+}
+
+/// Limits on the storage used by a client. The default is no limits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quota {
+    /// The maximum total size of the client's history segments and snapshot data, in bytes
+    pub max_bytes: Option<u64>,
+    /// The maximum number of versions in the client's history
+    pub max_versions: Option<u64>,
+}
+
+impl Quota {
+    /// Whether this quota imposes no limits.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_versions.is_none()
+    }
 }
 
 /// Metadata about a snapshot, not including the snapshot data itself.
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Snapshot {
     /// ID of the version at which this snapshot was made
     pub version_id: Uuid,
@@ -23,14 +78,234 @@ pub struct Snapshot {
     pub versions_since: u32,
 }
 
+impl Snapshot {
+    /// Create a new snapshot for the given version, with no versions since it was made.
+    pub fn new(version_id: Uuid, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            version_id,
+            timestamp,
+            versions_since: 0,
+        }
+    }
+
+    /// Set the number of versions since this snapshot was made.
+    pub fn with_versions_since(mut self, versions_since: u32) -> Self {
+        self.versions_since = versions_since;
+        self
+    }
+
+    /// Check that this snapshot is consistent with a client having `total_versions` versions.
+    ///
+    /// This is intended for use in [`StorageTxn::set_snapshot`] implementations which can
+    /// determine the number of versions cheaply.
+    pub fn validate(&self, total_versions: u64) -> Result<(), StorageError> {
+        if u64::from(self.versions_since) > total_versions {
+            return Err(StorageError::Corrupt(format!(
+                "Snapshot has {} versions since, but client has only {} versions",
+                self.versions_since, total_versions
+            )));
+        }
+        if self.timestamp < DateTime::UNIX_EPOCH {
+            return Err(StorageError::Corrupt(format!(
+                "Snapshot timestamp {} is invalid",
+                self.timestamp
+            )));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Version {
     /// The uuid identifying this version.
     pub version_id: Uuid,
     /// The uuid identifying this version's parent.
     pub parent_version_id: Uuid,
     /// The data carried in this version.
-    pub history_segment: Vec<u8>,
+    pub history_segment: Bytes,
+    /// The time at which the version was added, or `None` if it was added before this was
+    /// recorded.
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// A version whose history segment is read incrementally, as returned by
+/// [`StorageTxn::get_version_reader`].
+pub struct VersionReader {
+    /// The uuid identifying this version.
+    pub version_id: Uuid,
+    /// The uuid identifying this version's parent.
+    pub parent_version_id: Uuid,
+    /// A reader for the data carried in this version.
+    pub history_segment: Box<dyn Read + Send>,
+}
+
+impl From<Version> for VersionReader {
+    fn from(version: Version) -> Self {
+        VersionReader {
+            version_id: version.version_id,
+            parent_version_id: version.parent_version_id,
+            history_segment: Box::new(Cursor::new(version.history_segment)),
+        }
+    }
+}
+
+/// Statistics about the space used by a client, as returned by [`StorageTxn::get_client_stats`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientStats {
+    /// The number of versions in the client's history.
+    pub version_count: u64,
+    /// The total size of those versions' history segments, in bytes.
+    pub history_bytes: u64,
+    /// The size of the snapshot data, in bytes, or zero if there is no snapshot.
+    pub snapshot_bytes: u64,
+    /// The time at which the snapshot was set, if there is one. Its age is the time since then.
+    pub snapshot_timestamp: Option<DateTime<Utc>>,
+    /// The time at which the oldest version in the history was added, if known.
+    pub oldest_version_at: Option<DateTime<Utc>>,
+    /// The time at which the latest version was added, if known. This is when the client last
+    /// added a version to the server.
+    pub latest_version_at: Option<DateTime<Utc>>,
+}
+
+/// Statistics about the space used by all clients, summing their [`ClientStats`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageStats {
+    /// The number of clients.
+    pub client_count: u64,
+    /// The total number of versions.
+    pub version_count: u64,
+    /// The total size of all history segments, in bytes.
+    pub history_bytes: u64,
+    /// The total size of all snapshot data, in bytes.
+    pub snapshot_bytes: u64,
+    /// The time at which the oldest snapshot was set, if any client has a snapshot.
+    pub oldest_snapshot_timestamp: Option<DateTime<Utc>>,
+    /// The time at which a version was most recently added by any client, if known.
+    pub latest_version_at: Option<DateTime<Utc>>,
+}
+
+impl StorageStats {
+    /// Add a client's statistics to the totals.
+    pub fn add(&mut self, stats: &ClientStats) {
+        self.client_count += 1;
+        self.version_count += stats.version_count;
+        self.history_bytes += stats.history_bytes;
+        self.snapshot_bytes += stats.snapshot_bytes;
+        if let Some(timestamp) = stats.snapshot_timestamp {
+            if self
+                .oldest_snapshot_timestamp
+                .is_none_or(|oldest| timestamp < oldest)
+            {
+                self.oldest_snapshot_timestamp = Some(timestamp);
+            }
+        }
+        if let Some(timestamp) = stats.latest_version_at {
+            if self
+                .latest_version_at
+                .is_none_or(|latest| timestamp > latest)
+            {
+                self.latest_version_at = Some(timestamp);
+            }
+        }
+    }
+}
+
+/// A problem with the integrity of a client's data, found by [`StorageTxn::check_client`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Problem {
+    /// A version in the client's history does not exist, and is more recent than the oldest
+    /// retained snapshot, so the history cannot be followed back to the first version or to a
+    /// snapshot.
+    MissingVersion { version_id: Uuid },
+
+    /// Following parent links from the latest version leads back to this version.
+    Cycle { version_id: Uuid },
+
+    /// The index of children gives `indexed` as the child of `parent_version_id`, but the client's
+    /// history gives `version_id`, which is `None` if `parent_version_id` is the latest version.
+    ChildMismatch {
+        parent_version_id: Uuid,
+        version_id: Option<Uuid>,
+        indexed: Option<Uuid>,
+    },
+
+    /// The data for the snapshot of this version does not exist.
+    MissingSnapshotData { version_id: Uuid },
+
+    /// The snapshot of this version is not of a version in the client's history.
+    SnapshotNotInHistory { version_id: Uuid },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_none = |v: &Option<Uuid>| v.map_or("none".to_string(), |v| v.to_string());
+        match self {
+            Problem::MissingVersion { version_id } => {
+                write!(f, "version {version_id} in the history does not exist")
+            }
+            Problem::Cycle { version_id } => {
+                write!(f, "version history contains a cycle at {version_id}")
+            }
+            Problem::ChildMismatch {
+                parent_version_id,
+                version_id,
+                indexed,
+            } => write!(
+                f,
+                "child of {parent_version_id} is {} but is indexed as {}",
+                or_none(version_id),
+                or_none(indexed)
+            ),
+            Problem::MissingSnapshotData { version_id } => {
+                write!(f, "data for the snapshot of {version_id} does not exist")
+            }
+            Problem::SnapshotNotInHistory { version_id } => {
+                write!(f, "snapshot of {version_id} is not in the history")
+            }
+        }
+    }
+}
+
+/// A problem found by [`Storage::check`], with the client to which it applies.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ClientProblem {
+    pub client_id: Uuid,
+    pub problem: Problem,
+}
+
+/// A copy of all of a client's data, as returned by [`Storage::export_client`] and accepted by
+/// [`Storage::import_client`], for backups and for moving clients between storages.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientArchive {
+    pub client_id: Uuid,
+    pub client: Client,
+    /// The versions in the client's history, oldest first, ending with the latest version.
+    pub versions: Vec<Version>,
+    /// The client's retained snapshots, most recent first, with their data.
+    pub snapshots: Vec<(Snapshot, Bytes)>,
+}
+
+/// A point within a transaction to which its changes can be rolled back, as returned by
+/// [`StorageTxn::savepoint`]. The value is meaningful only to the transaction which created it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Savepoint(pub usize);
+
+/// The result of [`StorageTxn::check_add_version`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddVersionCheck {
+    /// The version would be accepted.
+    Accept,
+
+    /// The version would be rejected because its parent is not the latest version, which is
+    /// given here.
+    StaleParent(Uuid),
+
+    /// The version would be rejected because its history segment is too large.
+    TooLarge,
 }
 
 /// A transaction in the storage backend.
@@ -46,44 +321,1447 @@ pub struct Version {
 /// transaction.
 pub trait StorageTxn {
     /// Get information about the client for this transaction
-    fn get_client(&mut self) -> anyhow::Result<Option<Client>>;
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError>;
 
     /// Create the client for this transaction, with the given latest_version_id. The client must
-    /// not already exist.
-    fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()>;
+    /// not already exist. The client's `created_at` is the current time.
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError>;
 
-    /// Set the client's most recent snapshot.
-    fn set_snapshot(&mut self, snapshot: Snapshot, data: Vec<u8>) -> anyhow::Result<()>;
+    /// Set the client's most recent snapshot. On backends retaining more than one snapshot, the
+    /// previous snapshot is retained, and the oldest retained snapshot is discarded if this exceeds
+    /// the backend's retention.
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError>;
 
-    /// Get the data for the most recent snapshot.  The version_id
-    /// is used to verify that the snapshot is for the correct version.
-    fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>>;
+    /// Set the client's most recent snapshot, as for `set_snapshot`, reading its data from `data`.
+    ///
+    /// The default implementation reads the data into memory and calls `set_snapshot`. Backends
+    /// supporting streaming, as indicated by [`StorageCapabilities::supports_streaming`], override
+    /// it to write the data without holding it all in memory.
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut buf = vec![];
+        data.read_to_end(&mut buf)?;
+        self.set_snapshot(snapshot, buf.into())
+    }
+
+    /// Record a sync by the client, setting its `last_sync_at` to `timestamp` and its
+    /// `user_agent`. Recording a sync for a client that does not exist has no effect.
+    ///
+    /// The default implementation does nothing, for backends which do not store client metadata.
+    fn record_sync(
+        &mut self,
+        _timestamp: DateTime<Utc>,
+        _user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Get the client's quota. A client that does not exist has no limits.
+    fn get_quota(&mut self) -> Result<Quota, StorageError> {
+        Ok(self
+            .get_client()?
+            .map(|client| client.quota)
+            .unwrap_or_default())
+    }
+
+    /// Set the client's quota. Quotas are enforced by [`QuotaStorage`](crate::QuotaStorage), and
+    /// setting a quota does not remove data which already exceeds it. It is an error if the
+    /// client does not exist.
+    ///
+    /// The default implementation returns an error, for backends which do not store client
+    /// metadata.
+    fn set_quota(&mut self, _quota: Quota) -> Result<(), StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "quotas are not supported by this storage backend"
+        )))
+    }
+
+    /// Mark the client as deleted at the given time, or restore it if `deleted_at` is `None`. The
+    /// client's data is not removed; see [`StorageTxn::delete_client`]. It is an error if the
+    /// client does not exist.
+    ///
+    /// The default implementation returns an error, for backends which do not store client
+    /// metadata. Backends overriding it report
+    /// [`StorageCapabilities::supports_soft_delete`].
+    fn set_deleted(&mut self, _deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "soft deletion is not supported by this storage backend"
+        )))
+    }
+
+    /// Get the data for the snapshot at the given version.  This is the most recent snapshot or,
+    /// on backends retaining more than one snapshot, any snapshot returned by `list_snapshots`.
+    /// It is an error if no snapshot is retained for the version.
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError>;
+
+    /// Get a reader for the data of the snapshot at the given version, as for `get_snapshot_data`.
+    /// The reader does not borrow the transaction, and continues to read the same data even if the
+    /// snapshot is replaced after the transaction ends.
+    ///
+    /// The default implementation reads the data into memory with `get_snapshot_data`. Backends
+    /// supporting streaming override it to read the data incrementally.
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        Ok(self
+            .get_snapshot_data(version_id)?
+            .map(|data| Box::new(Cursor::new(data)) as Box<dyn Read + Send>))
+    }
+
+    /// Get the client's retained snapshots, most recent first. The first is the client's
+    /// `snapshot`, and there are at most [`StorageCapabilities::max_snapshot_retention`]. The
+    /// `versions_since` of an older snapshot is its value when the snapshot was replaced.
+    ///
+    /// The default implementation returns only the most recent snapshot, for backends which retain
+    /// a single snapshot.
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        Ok(self
+            .get_client()?
+            .and_then(|c| c.snapshot)
+            .into_iter()
+            .collect())
+    }
 
     /// Get a version, indexed by parent version id
-    fn get_version_by_parent(&mut self, parent_version_id: Uuid)
-        -> anyhow::Result<Option<Version>>;
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError>;
 
     /// Get a version, indexed by its own version id
-    fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>>;
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError>;
+
+    /// Get a version with a reader for its history segment, indexed by parent version id. As for
+    /// `get_snapshot_reader`, the reader does not borrow the transaction.
+    ///
+    /// The default implementation reads the segment into memory with `get_version_by_parent`.
+    /// Backends supporting streaming override it to read the segment incrementally.
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        Ok(self
+            .get_version_by_parent(parent_version_id)?
+            .map(VersionReader::from))
+    }
+
+    /// Get a version with a reader for its history segment, indexed by its own version id.
+    ///
+    /// The default implementation reads the segment into memory with `get_version`.
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        Ok(self.get_version(version_id)?.map(VersionReader::from))
+    }
+
+    /// Get the chain of versions following `parent_version_id`: its child, that version's child,
+    /// and so on, returning at most `limit` versions. The result is empty if the parent has no
+    /// child.
+    ///
+    /// The default implementation calls `get_version_by_parent` for each version. Backends may
+    /// override it to read the chain more efficiently.
+    fn get_versions_since(
+        &mut self,
+        parent_version_id: Uuid,
+        limit: usize,
+    ) -> Result<Vec<Version>, StorageError> {
+        let mut versions: Vec<Version> = vec![];
+        let mut seen = HashSet::new();
+        let mut parent_version_id = parent_version_id;
+        while versions.len() < limit && seen.insert(parent_version_id) {
+            let Some(version) = self.get_version_by_parent(parent_version_id)? else {
+                break;
+            };
+            parent_version_id = version.version_id;
+            versions.push(version);
+        }
+        Ok(versions)
+    }
 
     /// Add a version (that must not already exist), and
     ///  - update latest_version_id
     ///  - increment snapshot.versions_since
+    ///
+    /// Implementations call [`StorageTxn::check_new_version_id`] first, so that an existing or
+    /// nil `version_id` fails with [`StorageError::InvalidVersionId`].
     fn add_version(
         &mut self,
         version_id: Uuid,
         parent_version_id: Uuid,
-        history_segment: Vec<u8>,
-    ) -> anyhow::Result<()>;
+        history_segment: Bytes,
+    ) -> Result<(), StorageError>;
+
+    /// Add a version, as for `add_version`, reading its history segment from `history_segment`.
+    ///
+    /// The default implementation reads the segment into memory and calls `add_version`. Backends
+    /// supporting streaming override it to write the segment without holding it all in memory.
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut buf = vec![];
+        history_segment.read_to_end(&mut buf)?;
+        self.add_version(version_id, parent_version_id, buf.into())
+    }
+
+    /// Add a version, as for `add_version_from_reader`, only if `parent_version_id` is the
+    /// client's latest version or the client has no versions. This is a compare-and-swap of the
+    /// client's latest version: if another version was added first, nothing is added and this
+    /// fails with [`StorageError::Conflict`].
+    ///
+    /// The default implementation checks the client before calling `add_version_from_reader`.
+    /// This is atomic for backends which serialize transactions for a client, or which fail the
+    /// commit if the client was changed concurrently. Other backends must override it to compare
+    /// and update the client atomically.
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if !client.latest_version_id.is_nil() && client.latest_version_id != parent_version_id {
+            return Err(StorageError::Conflict {
+                latest_version_id: client.latest_version_id,
+            });
+        }
+        self.add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    /// Check that `version_id` may be used for a new version, failing with
+    /// [`StorageError::InvalidVersionId`] if it is nil or a version with that ID already exists.
+    ///
+    /// The default implementation uses `get_version`, which reads nothing when, as usual, the
+    /// version does not exist.
+    fn check_new_version_id(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        if version_id.is_nil() || self.get_version(version_id)?.is_some() {
+            return Err(StorageError::InvalidVersionId { version_id });
+        }
+        Ok(())
+    }
+
+    /// Add several versions, in order, as if by calling `add_version` for each. Each version's
+    /// parent is typically the version before it.
+    ///
+    /// The default implementation calls `add_version` for each version. Since all of the versions
+    /// are written in the same transaction, this already avoids a commit per version, but backends
+    /// may override it to write the versions more efficiently.
+    fn add_versions(&mut self, versions: Vec<(Uuid, Uuid, Bytes)>) -> Result<(), StorageError> {
+        for (version_id, parent_version_id, history_segment) in versions {
+            self.add_version(version_id, parent_version_id, history_segment)?;
+        }
+        Ok(())
+    }
+
+    /// Remove a version, so that it is no longer returned by `get_version` or
+    /// `get_version_by_parent`. The client's latest version and snapshot are unchanged, and
+    /// removing a version that does not exist has no effect.
+    ///
+    /// The default implementation returns an error, for backends which cannot remove versions.
+    fn delete_version(&mut self, _version_id: Uuid) -> Result<(), StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "delete_version is not supported by this storage backend"
+        )))
+    }
+
+    /// Delete the version `up_to_version_id` and all of its ancestors, reclaiming the space used by
+    /// versions which are covered by the client's snapshots, and return the number of versions
+    /// deleted. `up_to_version_id` must be the oldest retained snapshot's version or one of its
+    /// ancestors, so that a client can start again from any retained snapshot. A client whose
+    /// latest version has been pruned is told that it is gone, and must start again from the
+    /// snapshot.
+    ///
+    /// This is implemented with [`StorageTxn::delete_version`], which also removes the deleted
+    /// versions from the index of children, so it is supported by every backend supporting that.
+    fn prune_versions(&mut self, up_to_version_id: Uuid) -> Result<usize, StorageError> {
+        let Some(snapshot) = self.list_snapshots()?.pop() else {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Versions cannot be pruned without a snapshot"
+            )));
+        };
+
+        // Walk back from the oldest snapshot, through `up_to_version_id`, to the first version or
+        // the first version already pruned.
+        let mut pruning = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = snapshot.version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = self.get_version(vid)? else {
+                break;
+            };
+            if vid == up_to_version_id || !pruning.is_empty() {
+                pruning.push(vid);
+            }
+            vid = version.parent_version_id;
+        }
+        // a version that was not found in the walk is either already pruned, or not covered
+        if pruning.is_empty() && self.get_version(up_to_version_id)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Version {up_to_version_id} is not covered by the snapshot"
+            )));
+        }
+
+        for vid in &pruning {
+            self.delete_version(*vid)?;
+        }
+        Ok(pruning.len())
+    }
+
+    /// Delete the versions added before `timestamp`, implementing a retention policy such as
+    /// "keep 90 days of history" independently of snapshots, and return the number of versions
+    /// deleted.
+    ///
+    /// Versions are deleted from the oldest end of the client's history: the newest version added
+    /// before `timestamp` is deleted along with all of its ancestors, including any whose time of
+    /// addition was not recorded. As with `prune_versions`, a client whose latest version has
+    /// been deleted is told that it is gone, and must start again from a snapshot.
+    ///
+    /// This is implemented with [`StorageTxn::delete_version`], so it is supported by every
+    /// backend supporting that.
+    fn expire_versions_older_than(
+        &mut self,
+        timestamp: DateTime<Utc>,
+    ) -> Result<usize, StorageError> {
+        let Some(client) = self.get_client()? else {
+            return Ok(0);
+        };
+
+        let mut expiring = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = client.latest_version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = self.get_version(vid)? else {
+                break;
+            };
+            if !expiring.is_empty() || version.created_at.is_some_and(|t| t < timestamp) {
+                expiring.push(vid);
+            }
+            vid = version.parent_version_id;
+        }
+
+        for vid in &expiring {
+            self.delete_version(*vid)?;
+        }
+        Ok(expiring.len())
+    }
+
+    /// Mark a savepoint in this transaction, to which later changes can be rolled back with
+    /// [`StorageTxn::rollback_to`]. Savepoints may be nested.
+    ///
+    /// The default implementation returns an error, for backends which do not support savepoints.
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "savepoint is not supported by this storage backend"
+        )))
+    }
+
+    /// Roll back the changes made in this transaction since the given savepoint, leaving the
+    /// transaction open. The savepoint remains valid, but those created after it are discarded.
+    ///
+    /// The default implementation returns an error, for backends which do not support savepoints.
+    fn rollback_to(&mut self, _savepoint: Savepoint) -> Result<(), StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "rollback_to is not supported by this storage backend"
+        )))
+    }
+
+    /// Delete this transaction's client, along with its versions and snapshot. Deleting a client
+    /// that does not exist has no effect. As with other changes, the deletion takes effect when
+    /// the transaction is committed, and the client may be created again afterward.
+    ///
+    /// The default implementation returns an error, for backends which cannot delete clients.
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "delete_client is not supported by this storage backend"
+        )))
+    }
+
+    /// List the IDs of existing clients in ascending order, beginning after `after` if it is given,
+    /// and returning at most `limit` IDs. To list all clients, call this repeatedly, passing the
+    /// last ID returned, until fewer than `limit` IDs are returned.
+    ///
+    /// The result is not limited to this transaction's client.
+    ///
+    /// The default implementation returns an error, for backends which cannot enumerate clients.
+    fn list_clients(
+        &mut self,
+        _after: Option<Uuid>,
+        _limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "list_clients is not supported by this storage backend"
+        )))
+    }
+
+    /// Determine whether a version with the given parent and history segment length would be
+    /// accepted, without modifying anything.
+    ///
+    /// A client that does not exist yet is treated as having no versions, since it will be
+    /// created before the version is added.
+    fn check_add_version(
+        &mut self,
+        parent_version_id: Uuid,
+        segment_len: usize,
+    ) -> Result<AddVersionCheck, StorageError> {
+        if segment_len > MAX_HISTORY_SEGMENT_LEN {
+            return Ok(AddVersionCheck::TooLarge);
+        }
+        if let Some(client) = self.get_client()? {
+            if !client.latest_version_id.is_nil() && client.latest_version_id != parent_version_id {
+                return Ok(AddVersionCheck::StaleParent(client.latest_version_id));
+            }
+        }
+        Ok(AddVersionCheck::Accept)
+    }
+
+    /// Get the IDs of the versions from `version_id` back to the first version, the version whose
+    /// parent is the nil version. The result begins with `version_id` and includes both ends.
+    ///
+    /// It is an error if a version along the way does not exist, or if the parent links form a
+    /// cycle.
+    fn ancestor_path(&mut self, version_id: Uuid) -> Result<Vec<Uuid>, StorageError> {
+        let mut path = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = version_id;
+        loop {
+            // A version can only be visited once, so this loop runs at most once per version.
+            if !seen.insert(vid) {
+                return Err(StorageError::Backend(anyhow::anyhow!(
+                    "Version history contains a cycle at {vid}"
+                )));
+            }
+            let Some(version) = self.get_version(vid)? else {
+                return Err(StorageError::Backend(anyhow::anyhow!(
+                    "Version {vid} does not exist"
+                )));
+            };
+            path.push(vid);
+            if version.parent_version_id.is_nil() {
+                return Ok(path);
+            }
+            vid = version.parent_version_id;
+        }
+    }
+
+    /// Compute a digest of this client's state: its latest version, the versions in its history,
+    /// and its snapshot metadata.
+    ///
+    /// The digest depends only on the stored data, and not on the order in which it was stored or
+    /// the storage backend, so it can be used to check that two backends contain the same data.
+    ///
+    /// The history consists of the versions reachable by following parent links from the latest
+    /// version, until reaching the nil version or a version that does not exist.
+    fn client_state_hash(&mut self) -> Result<[u8; 32], StorageError> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"taskchampion-client-state-v1");
+        let Some(client) = self.get_client()? else {
+            return Ok(hasher.finalize().into());
+        };
+        hasher.update(client.latest_version_id.as_bytes());
+
+        // Hash each version individually, then combine them in sorted order.
+        let mut version_hashes = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = client.latest_version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = self.get_version(vid)? else {
+                break;
+            };
+            let mut version_hasher = Sha256::new();
+            version_hasher.update(version.version_id.as_bytes());
+            version_hasher.update(version.parent_version_id.as_bytes());
+            version_hasher.update(Sha256::digest(&version.history_segment));
+            version_hashes.push(<[u8; 32]>::from(version_hasher.finalize()));
+            vid = version.parent_version_id;
+        }
+        version_hashes.sort_unstable();
+        hasher.update((version_hashes.len() as u64).to_be_bytes());
+        for version_hash in version_hashes {
+            hasher.update(version_hash);
+        }
+
+        if let Some(snapshot) = client.snapshot {
+            hasher.update([1u8]);
+            hasher.update(snapshot.version_id.as_bytes());
+            hasher.update(snapshot.versions_since.to_be_bytes());
+            hasher.update(snapshot.timestamp.timestamp().to_be_bytes());
+        } else {
+            hasher.update([0u8]);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Get statistics about the space used by this client, or `None` if the client does not
+    /// exist.
+    ///
+    /// As in `client_state_hash`, the history consists of the versions reachable by following
+    /// parent links from the latest version, so versions that are no longer reachable are not
+    /// counted.
+    fn get_client_stats(&mut self) -> Result<Option<ClientStats>, StorageError> {
+        let Some(client) = self.get_client()? else {
+            return Ok(None);
+        };
+        let mut stats = ClientStats::default();
+
+        let mut seen = HashSet::new();
+        let mut vid = client.latest_version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = self.get_version(vid)? else {
+                break;
+            };
+            stats.version_count += 1;
+            stats.history_bytes += version.history_segment.len() as u64;
+            if version.created_at.is_some() {
+                if stats.latest_version_at.is_none() {
+                    stats.latest_version_at = version.created_at;
+                }
+                stats.oldest_version_at = version.created_at;
+            }
+            vid = version.parent_version_id;
+        }
+
+        if let Some(snapshot) = client.snapshot {
+            if let Some(data) = self.get_snapshot_data(snapshot.version_id)? {
+                stats.snapshot_bytes = data.len() as u64;
+            }
+            stats.snapshot_timestamp = Some(snapshot.timestamp);
+        }
+        Ok(Some(stats))
+    }
+
+    /// Check the integrity of this client's data, returning the problems found, or an empty
+    /// vector if there are none or the client does not exist.
+    ///
+    /// This checks that the history can be followed from the latest version back to the first
+    /// version or to the oldest retained snapshot, that `get_version_by_parent` agrees with
+    /// each version's parent, and that the data for each retained snapshot exists.
+    fn check_client(&mut self) -> Result<Vec<Problem>, StorageError> {
+        let Some(client) = self.get_client()? else {
+            return Ok(vec![]);
+        };
+        let snapshots = self.list_snapshots()?;
+        let mut problems = vec![];
+
+        // versions older than the oldest retained snapshot may have been pruned
+        let mut covered = false;
+        let mut history = HashSet::new();
+        let mut child = None;
+        let mut vid = client.latest_version_id;
+        loop {
+            let indexed = self.get_version_by_parent(vid)?.map(|v| v.version_id);
+            if indexed != child {
+                problems.push(Problem::ChildMismatch {
+                    parent_version_id: vid,
+                    version_id: child,
+                    indexed,
+                });
+            }
+            if vid.is_nil() {
+                break;
+            }
+            if !history.insert(vid) {
+                problems.push(Problem::Cycle { version_id: vid });
+                break;
+            }
+            covered = covered || snapshots.last().is_some_and(|s| s.version_id == vid);
+            let Some(version) = self.get_version(vid)? else {
+                if !covered {
+                    problems.push(Problem::MissingVersion { version_id: vid });
+                }
+                break;
+            };
+            child = Some(vid);
+            vid = version.parent_version_id;
+        }
+
+        for snapshot in snapshots {
+            if !history.contains(&snapshot.version_id) {
+                problems.push(Problem::SnapshotNotInHistory {
+                    version_id: snapshot.version_id,
+                });
+            }
+            if self.get_snapshot_data(snapshot.version_id)?.is_none() {
+                problems.push(Problem::MissingSnapshotData {
+                    version_id: snapshot.version_id,
+                });
+            }
+        }
+        Ok(problems)
+    }
 
     /// Commit any changes made in the transaction.  It is an error to call this more than
     /// once.  It is safe to skip this call for read-only operations.
-    fn commit(&mut self) -> anyhow::Result<()>;
+    fn commit(&mut self) -> Result<(), StorageError>;
+}
+
+/// Optional features supported by a [`Storage`] implementation, as reported by
+/// [`Storage::capabilities`].
+///
+/// The default value describes a minimal backend, supporting only the required methods.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StorageCapabilities {
+    /// Whether the backend can enumerate clients, as required by
+    /// [`StorageTxn::list_clients`] and [`Storage::max_versions_since_snapshot`].
+    pub supports_client_enumeration: bool,
+
+    /// Whether changes in a transaction dropped without being committed are rolled back, rather
+    /// than causing a panic.
+    pub supports_rollback: bool,
+
+    /// Whether snapshot data and history segments can be streamed rather than held in memory, with
+    /// [`StorageTxn::set_snapshot_from_reader`], [`StorageTxn::add_version_from_reader`], and the
+    /// corresponding readers.
+    pub supports_streaming: bool,
+
+    /// Whether old versions can be removed to reclaim space, with [`StorageTxn::prune_versions`].
+    pub supports_compaction: bool,
+
+    /// Whether the backend supports read-only transactions, which may run concurrently.
+    pub supports_read_only_txn: bool,
+
+    /// Whether changes can be rolled back to a savepoint within a transaction, with
+    /// [`StorageTxn::savepoint`] and [`StorageTxn::rollback_to`].
+    pub supports_savepoints: bool,
+
+    /// Whether clients can be marked as deleted and later restored, with
+    /// [`StorageTxn::set_deleted`].
+    pub supports_soft_delete: bool,
+
+    /// The number of snapshots retained for each client, as returned by
+    /// [`StorageTxn::list_snapshots`].
+    pub max_snapshot_retention: u32,
+
+    /// The largest history segment the backend can store, in bytes.
+    pub max_history_segment_len: usize,
+}
+
+impl Default for StorageCapabilities {
+    fn default() -> Self {
+        StorageCapabilities {
+            supports_client_enumeration: false,
+            supports_rollback: false,
+            supports_streaming: false,
+            supports_compaction: false,
+            supports_read_only_txn: false,
+            supports_savepoints: false,
+            supports_soft_delete: false,
+            max_snapshot_retention: 1,
+            max_history_segment_len: MAX_HISTORY_SEGMENT_LEN,
+        }
+    }
 }
 
 /// A trait for objects able to act as storage.  Most of the interesting behavior is in the
 /// [`crate::storage::StorageTxn`] trait.
 pub trait Storage: Send + Sync {
     /// Begin a transaction for the given client ID.
-    fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>>;
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError>;
+
+    /// Describe the optional features this backend supports.
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::default()
+    }
+
+    /// Find the client with the most versions since its latest snapshot, returning its ID and
+    /// `versions_since`. Clients without a snapshot are not considered, so this returns `None` if
+    /// no client has a snapshot.
+    ///
+    /// The default implementation returns an error, for backends which cannot enumerate clients.
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        Err(StorageError::Backend(anyhow::anyhow!(
+            "max_versions_since_snapshot is not supported by this storage backend"
+        )))
+    }
+
+    /// Write any changes held in memory to the underlying storage, such as before the process
+    /// exits. Wrappers must flush their inner storage.
+    ///
+    /// The default implementation does nothing, for backends which do not buffer changes.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Export all of a client's data, or return `None` if the client does not exist. The data is
+    /// read in a single transaction.
+    ///
+    /// As in `get_client_stats`, the history consists of the versions reachable by following
+    /// parent links from the latest version.
+    fn export_client(&self, client_id: Uuid) -> Result<Option<ClientArchive>, StorageError> {
+        let mut txn = self.txn(client_id)?;
+        let Some(client) = txn.get_client()? else {
+            return Ok(None);
+        };
+
+        let mut versions = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = client.latest_version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = txn.get_version(vid)? else {
+                break;
+            };
+            vid = version.parent_version_id;
+            versions.push(version);
+        }
+        versions.reverse();
+
+        let mut snapshots = vec![];
+        for snapshot in txn.list_snapshots()? {
+            let Some(data) = txn.get_snapshot_data(snapshot.version_id)? else {
+                return Err(StorageError::Backend(anyhow::anyhow!(
+                    "Data for snapshot of {} of client {client_id} does not exist",
+                    snapshot.version_id
+                )));
+            };
+            snapshots.push((snapshot, data));
+        }
+
+        Ok(Some(ClientArchive {
+            client_id,
+            client,
+            versions,
+            snapshots,
+        }))
+    }
+
+    /// Import a client exported with [`Storage::export_client`], in a single transaction. The
+    /// client must not already exist.
+    ///
+    /// The client's last sync, user agent, quota, and deletion are restored, but the times at
+    /// which the client and its versions were created are those of the import.
+    fn import_client(&self, archive: ClientArchive) -> Result<(), StorageError> {
+        let ClientArchive {
+            client_id,
+            client,
+            versions,
+            snapshots,
+        } = archive;
+        let mut txn = self.txn(client_id)?;
+        if txn.get_client()?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {client_id} already exists"
+            )));
+        }
+
+        match versions.last() {
+            // a client whose history has all been deleted still has a latest version
+            None => txn.new_client(client.latest_version_id)?,
+            Some(latest) if latest.version_id == client.latest_version_id => {
+                txn.new_client(Uuid::nil())?;
+                for version in versions {
+                    txn.add_version(
+                        version.version_id,
+                        version.parent_version_id,
+                        version.history_segment,
+                    )?;
+                }
+            }
+            Some(_) => {
+                return Err(StorageError::Backend(anyhow::anyhow!(
+                    "Archive's versions do not end with the latest version"
+                )))
+            }
+        }
+        // snapshots are set oldest first, so that each replaces the one before it
+        for (snapshot, data) in snapshots.into_iter().rev() {
+            txn.set_snapshot(snapshot, data)?;
+        }
+        if let Some(last_sync_at) = client.last_sync_at {
+            txn.record_sync(last_sync_at, client.user_agent)?;
+        }
+        if !client.quota.is_unlimited() {
+            txn.set_quota(client.quota)?;
+        }
+        if client.deleted_at.is_some() {
+            txn.set_deleted(client.deleted_at)?;
+        }
+        txn.commit()
+    }
+
+    /// Check the integrity of every client's data with [`StorageTxn::check_client`], returning
+    /// the problems found. Each client is checked in a separate transaction, so the result is not
+    /// a consistent snapshot of the storage.
+    ///
+    /// This requires a backend supporting client enumeration.
+    fn check(&self) -> Result<Vec<ClientProblem>, StorageError> {
+        const PAGE_LEN: usize = 100;
+        let mut problems = vec![];
+        let mut after = None;
+        loop {
+            let page = self.txn(Uuid::nil())?.list_clients(after, PAGE_LEN)?;
+            for client_id in &page {
+                for problem in self.txn(*client_id)?.check_client()? {
+                    problems.push(ClientProblem {
+                        client_id: *client_id,
+                        problem,
+                    });
+                }
+            }
+            if page.len() < PAGE_LEN {
+                return Ok(problems);
+            }
+            after = page.last().copied();
+        }
+    }
+}
+
+/// Boxed storage, allowing the backend to be selected at runtime.
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        (**self).txn(client_id)
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        (**self).capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        (**self).max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        (**self).flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::inmemory::InMemoryStorage;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn snapshot_new() {
+        let version_id = Uuid::new_v4();
+        let timestamp = Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap();
+        assert_eq!(
+            Snapshot::new(version_id, timestamp),
+            Snapshot {
+                version_id,
+                timestamp,
+                versions_since: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn snapshot_with_versions_since() {
+        let snap = Snapshot::new(Uuid::new_v4(), Utc::now()).with_versions_since(7);
+        assert_eq!(snap.versions_since, 7);
+    }
+
+    #[test]
+    fn snapshot_validate_ok() {
+        let snap = Snapshot::new(Uuid::new_v4(), Utc::now()).with_versions_since(3);
+        assert!(snap.validate(3).is_ok());
+        assert!(snap.validate(10).is_ok());
+    }
+
+    #[test]
+    fn snapshot_validate_impossible_versions_since() {
+        let snap = Snapshot::new(Uuid::new_v4(), Utc::now()).with_versions_since(4);
+        assert!(matches!(snap.validate(3), Err(StorageError::Corrupt(_))));
+    }
+
+    #[test]
+    fn snapshot_validate_bad_timestamp() {
+        let snap = Snapshot::new(
+            Uuid::new_v4(),
+            Utc.with_ymd_and_hms(1960, 1, 1, 0, 0, 0).unwrap(),
+        );
+        assert!(matches!(snap.validate(0), Err(StorageError::Corrupt(_))));
+    }
+
+    #[test]
+    fn ancestor_path_chain() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let v3 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.add_version(v2, v1, Bytes::new())?;
+        txn.add_version(v3, v2, Bytes::new())?;
+
+        assert_eq!(txn.ancestor_path(v3)?, vec![v3, v2, v1]);
+        assert_eq!(txn.ancestor_path(v2)?, vec![v2, v1]);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn ancestor_path_single() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+
+        assert_eq!(txn.ancestor_path(v1)?, vec![v1]);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn ancestor_path_dangling() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        // v1's parent does not exist
+        txn.add_version(v1, Uuid::new_v4(), Bytes::new())?;
+        txn.add_version(v2, v1, Bytes::new())?;
+
+        assert!(txn.ancestor_path(v2).is_err());
+        assert!(txn.ancestor_path(Uuid::new_v4()).is_err());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn ancestor_path_cycle() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        txn.add_version(v1, v2, Bytes::new())?;
+        txn.add_version(v2, v1, Bytes::new())?;
+
+        assert!(txn.ancestor_path(v2).is_err());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_versions_since() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let vids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        txn.new_client(Uuid::nil())?;
+        let mut parent_version_id = Uuid::nil();
+        for vid in &vids {
+            txn.add_version(*vid, parent_version_id, Bytes::new())?;
+            parent_version_id = *vid;
+        }
+
+        let since = |versions: Vec<Version>| -> Vec<Uuid> {
+            versions.into_iter().map(|v| v.version_id).collect()
+        };
+        assert_eq!(since(txn.get_versions_since(Uuid::nil(), 10)?), vids);
+        assert_eq!(since(txn.get_versions_since(vids[0], 2)?), &vids[1..3]);
+        assert_eq!(
+            since(txn.get_versions_since(vids[3], 10)?),
+            Vec::<Uuid>::new()
+        );
+        assert_eq!(
+            since(txn.get_versions_since(vids[0], 0)?),
+            Vec::<Uuid>::new()
+        );
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_stats() -> anyhow::Result<()> {
+        let t1 = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+        let t2 = Utc.with_ymd_and_hms(2023, 6, 2, 0, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(t1));
+        let storage = InMemoryStorage::new().with_clock(clock.clone());
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        assert_eq!(txn.get_client_stats()?, None);
+
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        txn.new_client(Uuid::nil())?;
+        txn.add_version(v1, Uuid::nil(), vec![1, 2, 3].into())?;
+        clock.set(t2);
+        txn.add_version(v2, v1, vec![4, 5].into())?;
+        assert_eq!(
+            txn.get_client_stats()?,
+            Some(ClientStats {
+                version_count: 2,
+                history_bytes: 5,
+                snapshot_bytes: 0,
+                snapshot_timestamp: None,
+                oldest_version_at: Some(t1),
+                latest_version_at: Some(t2),
+            })
+        );
+
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        txn.set_snapshot(Snapshot::new(v2, timestamp), vec![0; 10].into())?;
+        let stats = txn.get_client_stats()?.unwrap();
+        assert_eq!(stats.snapshot_bytes, 10);
+        assert_eq!(stats.snapshot_timestamp, Some(timestamp));
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_client_ok() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        assert_eq!(txn.check_client()?, vec![]);
+
+        txn.new_client(Uuid::nil())?;
+        assert_eq!(txn.check_client()?, vec![]);
+
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let v3 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.add_version(v2, v1, Bytes::new())?;
+        txn.add_version(v3, v2, Bytes::new())?;
+        txn.set_snapshot(Snapshot::new(v2, Utc::now()), vec![1].into())?;
+        assert_eq!(txn.check_client()?, vec![]);
+
+        // versions covered by the snapshot may be pruned
+        txn.prune_versions(v2)?;
+        assert_eq!(txn.check_client()?, vec![]);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_client_missing_version() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        txn.new_client(Uuid::nil())?;
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.add_version(v2, v1, Bytes::new())?;
+        txn.delete_version(v1)?;
+        assert_eq!(
+            txn.check_client()?,
+            vec![Problem::MissingVersion { version_id: v1 }]
+        );
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_client_snapshot_not_in_history() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let v1 = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        txn.new_client(Uuid::nil())?;
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.set_snapshot(Snapshot::new(other, Utc::now()), vec![1].into())?;
+        assert_eq!(
+            txn.check_client()?,
+            vec![Problem::SnapshotNotInHistory { version_id: other }]
+        );
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let good_client_id = Uuid::new_v4();
+        let bad_client_id = Uuid::new_v4();
+        let missing = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(good_client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.add_version(Uuid::new_v4(), Uuid::nil(), Bytes::new())?;
+            txn.commit()?;
+        }
+        {
+            let mut txn = storage.txn(bad_client_id)?;
+            txn.new_client(missing)?;
+            txn.commit()?;
+        }
+        let problems = storage.check()?;
+        assert_eq!(
+            problems,
+            vec![ClientProblem {
+                client_id: bad_client_id,
+                problem: Problem::MissingVersion {
+                    version_id: missing
+                },
+            }]
+        );
+        assert_eq!(
+            problems[0].problem.to_string(),
+            format!("version {missing} in the history does not exist")
+        );
+        Ok(())
+    }
+
+    /// Create a client with some history, a snapshot, and metadata, returning its ID.
+    fn archive_setup(storage: &InMemoryStorage) -> anyhow::Result<Uuid> {
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        let mut parent_version_id = Uuid::nil();
+        for i in 0..3 {
+            let version_id = Uuid::new_v4();
+            txn.add_version(version_id, parent_version_id, vec![i].into())?;
+            parent_version_id = version_id;
+        }
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        txn.set_snapshot(
+            Snapshot::new(parent_version_id, timestamp),
+            vec![9, 9].into(),
+        )?;
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.set_quota(Quota {
+            max_bytes: Some(1000),
+            max_versions: None,
+        })?;
+        txn.commit()?;
+        Ok(client_id)
+    }
+
+    #[test]
+    fn export_import_client() -> anyhow::Result<()> {
+        let source = InMemoryStorage::new();
+        let client_id = archive_setup(&source)?;
+        let archive = source.export_client(client_id)?.unwrap();
+        assert_eq!(archive.client_id, client_id);
+        assert_eq!(archive.versions.len(), 3);
+        assert_eq!(archive.versions[0].parent_version_id, Uuid::nil());
+        assert_eq!(archive.snapshots[0].1, vec![9, 9]);
+
+        let dest = InMemoryStorage::new();
+        dest.import_client(archive.clone())?;
+        let mut source_txn = source.txn(client_id)?;
+        let mut dest_txn = dest.txn(client_id)?;
+        assert_eq!(
+            source_txn.client_state_hash()?,
+            dest_txn.client_state_hash()?
+        );
+        let client = dest_txn.get_client()?.unwrap();
+        assert_eq!(client.last_sync_at, archive.client.last_sync_at);
+        assert_eq!(client.user_agent, Some("tc/1.0".into()));
+        assert_eq!(client.quota, archive.client.quota);
+        assert_eq!(
+            dest_txn.get_snapshot_data(archive.snapshots[0].0.version_id)?,
+            Some(vec![9, 9].into())
+        );
+        assert_eq!(dest_txn.check_client()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn client_archive_serde() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = archive_setup(&storage)?;
+        let archive = storage.export_client(client_id)?.unwrap();
+
+        let json = serde_json::to_string(&archive)?;
+        let parsed: ClientArchive = serde_json::from_str(&json)?;
+        assert_eq!(parsed, archive);
+        Ok(())
+    }
+
+    #[test]
+    fn export_client_missing() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        assert_eq!(storage.export_client(Uuid::new_v4())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn export_import_client_pruned() -> anyhow::Result<()> {
+        let source = InMemoryStorage::new();
+        let client_id = archive_setup(&source)?;
+        {
+            let mut txn = source.txn(client_id)?;
+            let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+            txn.prune_versions(snapshot.version_id)?;
+            txn.commit()?;
+        }
+        let archive = source.export_client(client_id)?.unwrap();
+        assert_eq!(archive.versions, vec![]);
+
+        let dest = InMemoryStorage::new();
+        dest.import_client(archive.clone())?;
+        let client = dest.export_client(client_id)?.unwrap().client;
+        assert_eq!(client.latest_version_id, archive.client.latest_version_id);
+        assert_eq!(client.snapshot, archive.client.snapshot);
+        Ok(())
+    }
+
+    #[test]
+    fn import_client_exists() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = archive_setup(&storage)?;
+        let archive = storage.export_client(client_id)?.unwrap();
+        assert!(storage.import_client(archive).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn storage_stats_add() {
+        let t1 = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let t2 = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+        let mut stats = StorageStats::default();
+        stats.add(&ClientStats {
+            version_count: 2,
+            history_bytes: 5,
+            snapshot_bytes: 10,
+            snapshot_timestamp: Some(t1),
+            oldest_version_at: Some(t2),
+            latest_version_at: Some(t1),
+        });
+        stats.add(&ClientStats::default());
+        stats.add(&ClientStats {
+            version_count: 1,
+            history_bytes: 1,
+            snapshot_bytes: 3,
+            snapshot_timestamp: Some(t2),
+            oldest_version_at: Some(t2),
+            latest_version_at: Some(t2),
+        });
+        assert_eq!(
+            stats,
+            StorageStats {
+                client_count: 3,
+                version_count: 3,
+                history_bytes: 6,
+                snapshot_bytes: 13,
+                oldest_snapshot_timestamp: Some(t2),
+                latest_version_at: Some(t1),
+            }
+        );
+    }
+
+    #[test]
+    fn prune_versions() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let vids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let mut parent_version_id = Uuid::nil();
+        for vid in &vids {
+            txn.add_version(*vid, parent_version_id, Bytes::new())?;
+            parent_version_id = *vid;
+        }
+
+        // pruning requires a snapshot covering the versions
+        assert!(txn.prune_versions(vids[1]).is_err());
+        txn.set_snapshot(Snapshot::new(vids[3], Utc::now()), Bytes::new())?;
+        assert!(txn.prune_versions(vids[4]).is_err());
+
+        assert_eq!(txn.prune_versions(vids[1])?, 2);
+        assert_eq!(txn.get_version(vids[0])?, None);
+        assert_eq!(txn.get_version(vids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+        assert_eq!(txn.get_version_by_parent(vids[0])?, None);
+        assert_eq!(
+            txn.get_version_by_parent(vids[1])?.unwrap().version_id,
+            vids[2]
+        );
+
+        // pruning up to the snapshot leaves the versions after it
+        assert_eq!(txn.prune_versions(vids[3])?, 2);
+        assert_eq!(
+            txn.get_version_by_parent(vids[3])?.unwrap().version_id,
+            vids[4]
+        );
+        assert_eq!(txn.prune_versions(vids[3])?, 0);
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, vids[4]);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn expire_versions_older_than() -> anyhow::Result<()> {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let storage = InMemoryStorage::new().with_clock(clock.clone());
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+
+        // add a version each day
+        let vids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let mut parent_version_id = Uuid::nil();
+        for vid in &vids {
+            txn.add_version(*vid, parent_version_id, Bytes::new())?;
+            parent_version_id = *vid;
+            clock.advance(Duration::from_secs(86400));
+        }
+
+        assert_eq!(txn.expire_versions_older_than(start)?, 0);
+        // versions added on the first two days are expired, without requiring a snapshot
+        let cutoff = start + chrono::Duration::hours(36);
+        assert_eq!(txn.expire_versions_older_than(cutoff)?, 2);
+        assert_eq!(txn.get_version(vids[0])?, None);
+        assert_eq!(txn.get_version(vids[1])?, None);
+        assert!(txn.get_version(vids[2])?.is_some());
+        assert_eq!(txn.expire_versions_older_than(cutoff)?, 0);
+
+        // expiring everything leaves the client in place
+        assert_eq!(txn.expire_versions_older_than(clock.now())?, 3);
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, vids[4]);
+        assert_eq!(txn.get_version(vids[4])?, None);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_add_version_accept() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        // a nonexistent client accepts any parent
+        assert_eq!(
+            txn.check_add_version(Uuid::new_v4(), 10)?,
+            AddVersionCheck::Accept
+        );
+
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        let before = txn.get_client()?;
+        assert_eq!(txn.check_add_version(v1, 10)?, AddVersionCheck::Accept);
+        assert_eq!(txn.get_client()?, before);
+        assert!(txn.get_version_by_parent(v1)?.is_none());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_add_version_stale_parent() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.add_version(v2, v1, Bytes::new())?;
+        let before = txn.get_client()?;
+        assert_eq!(
+            txn.check_add_version(v1, 10)?,
+            AddVersionCheck::StaleParent(v2)
+        );
+        assert_eq!(txn.get_client()?, before);
+        assert!(txn.get_version_by_parent(v2)?.is_none());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_if_latest() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        txn.add_version_if_latest(v1, Uuid::nil(), &mut &[1u8][..])?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+
+        // a version whose parent is not the latest version is not added
+        let err = txn
+            .add_version_if_latest(v2, Uuid::nil(), &mut &[2u8][..])
+            .unwrap_err();
+        assert_eq!(err.conflict(), Some(v1));
+        assert!(txn.get_version(v2)?.is_none());
+
+        txn.add_version_if_latest(v2, v1, &mut &[2u8][..])?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_add_version_too_large() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        let before = txn.get_client()?;
+        assert_eq!(
+            txn.check_add_version(Uuid::nil(), MAX_HISTORY_SEGMENT_LEN + 1)?,
+            AddVersionCheck::TooLarge
+        );
+        assert_eq!(txn.get_client()?, before);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_state_hash_identical() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let v3 = Uuid::new_v4();
+        let snap = Snapshot::new(v2, Utc::now()).with_versions_since(1);
+
+        let storage1 = InMemoryStorage::new();
+        let mut txn1 = storage1.txn(client_id)?;
+        txn1.new_client(Uuid::nil())?;
+        txn1.add_version(v1, Uuid::nil(), vec![1].into())?;
+        txn1.add_version(v2, v1, vec![2].into())?;
+        txn1.add_version(v3, v2, vec![3].into())?;
+        txn1.set_snapshot(snap.clone(), vec![9].into())?;
+
+        // the same data, inserted in a different order
+        let storage2 = InMemoryStorage::new();
+        let mut txn2 = storage2.txn(client_id)?;
+        txn2.new_client(Uuid::nil())?;
+        txn2.add_version(v2, v1, vec![2].into())?;
+        txn2.add_version(v1, Uuid::nil(), vec![1].into())?;
+        txn2.add_version(v3, v2, vec![3].into())?;
+        txn2.set_snapshot(snap, vec![9].into())?;
+
+        assert_eq!(txn1.client_state_hash()?, txn2.client_state_hash()?);
+        txn1.commit()?;
+        txn2.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_state_hash_changes() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        let empty = txn.client_state_hash()?;
+
+        txn.new_client(Uuid::nil())?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+        let one_version = txn.client_state_hash()?;
+        assert_ne!(empty, one_version);
+
+        txn.add_version(Uuid::new_v4(), v1, vec![2].into())?;
+        let two_versions = txn.client_state_hash()?;
+        assert_ne!(one_version, two_versions);
+
+        txn.set_snapshot(Snapshot::new(v1, Utc::now()), vec![1].into())?;
+        assert_ne!(two_versions, txn.client_state_hash()?);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_state_hash_segment_contents() -> anyhow::Result<()> {
+        let v1 = Uuid::new_v4();
+        let mut hashes = vec![];
+        for segment in [vec![1], vec![2]] {
+            let storage = InMemoryStorage::new();
+            let mut txn = storage.txn(Uuid::new_v4())?;
+            txn.new_client(Uuid::nil())?;
+            txn.add_version(v1, Uuid::nil(), segment.into())?;
+            hashes.push(txn.client_state_hash()?);
+            txn.commit()?;
+        }
+        assert_ne!(hashes[0], hashes[1]);
+        Ok(())
+    }
 }
@@ -0,0 +1,413 @@
+use crate::error::StorageError;
+use crate::server::NIL_VERSION_ID;
+use crate::storage::{
+    Client, Quota, Snapshot, Storage, StorageCapabilities, StorageTxn, Version, VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::io::Read;
+use uuid::Uuid;
+
+/// A storage wrapper which keeps recent versions in a fast "hot" storage, and moves older
+/// versions to a "cold" storage.
+///
+/// Clients, snapshots, and versions are written to the hot storage. When a snapshot is set, the
+/// versions preceding the snapshot's version are copied to the cold storage and removed from the
+/// hot storage, which must support [`StorageTxn::delete_version`]. Versions not found in the hot
+/// storage are read from the cold storage, so the move is not visible to callers.
+///
+/// The two storages are not updated atomically. The cold storage is committed first, so a failed
+/// commit may leave a version in both storages, but never in neither; such a version is moved
+/// again with the next snapshot.
+pub struct TieredStorage<H: Storage, C: Storage> {
+    hot: H,
+    cold: C,
+}
+
+impl<H: Storage, C: Storage> TieredStorage<H, C> {
+    /// Wrap `hot` and `cold`, moving versions older than the latest snapshot from `hot` to
+    /// `cold`.
+    pub fn new(hot: H, cold: C) -> Self {
+        Self { hot, cold }
+    }
+}
+
+impl<H: Storage, C: Storage> Storage for TieredStorage<H, C> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(TieredTxn {
+            client_id,
+            hot: self.hot.txn(client_id)?,
+            cold_storage: &self.cold,
+            cold: None,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let hot = self.hot.capabilities();
+        let cold = self.cold.capabilities();
+        StorageCapabilities {
+            supports_rollback: hot.supports_rollback && cold.supports_rollback,
+            supports_streaming: hot.supports_streaming && cold.supports_streaming,
+            // changes in the two storages cannot be rolled back together
+            supports_savepoints: false,
+            max_history_segment_len: hot
+                .max_history_segment_len
+                .min(cold.max_history_segment_len),
+            ..hot
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        self.hot.max_versions_since_snapshot()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.hot.flush()?;
+        self.cold.flush()
+    }
+}
+
+struct TieredTxn<'a> {
+    client_id: Uuid,
+    hot: Box<dyn StorageTxn + 'a>,
+    cold_storage: &'a dyn Storage,
+    /// The transaction in the cold storage, begun when first needed.
+    cold: Option<Box<dyn StorageTxn + 'a>>,
+}
+
+impl TieredTxn<'_> {
+    fn cold(&mut self) -> Result<&mut dyn StorageTxn, StorageError> {
+        if self.cold.is_none() {
+            self.cold = Some(self.cold_storage.txn(self.client_id)?);
+        }
+        Ok(self.cold.as_mut().unwrap().as_mut())
+    }
+
+    /// Move the versions preceding `version_id` from the hot storage to the cold storage.
+    fn move_to_cold(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let Some(version) = self.hot.get_version(version_id)? else {
+            return Ok(());
+        };
+
+        // Versions are moved oldest-first, so the walk stops at the first version already moved.
+        let mut moving = vec![];
+        let mut seen = HashSet::new();
+        let mut vid = version.parent_version_id;
+        while !vid.is_nil() && seen.insert(vid) {
+            let Some(version) = self.hot.get_version(vid)? else {
+                break;
+            };
+            vid = version.parent_version_id;
+            moving.push(version);
+        }
+        if moving.is_empty() {
+            return Ok(());
+        }
+
+        log::debug!(
+            "moving {} versions of client {} to cold storage",
+            moving.len(),
+            self.client_id
+        );
+        let cold = self.cold()?;
+        if cold.get_client()?.is_none() {
+            // the cold client's latest version is not meaningful
+            cold.new_client(NIL_VERSION_ID)?;
+        }
+        for version in moving.iter().rev() {
+            // a version may already be in cold storage if an earlier move failed part-way
+            if cold.get_version(version.version_id)?.is_none() {
+                cold.add_version(
+                    version.version_id,
+                    version.parent_version_id,
+                    version.history_segment.clone(),
+                )?;
+            }
+        }
+        for version in moving {
+            self.hot.delete_version(version.version_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageTxn for TieredTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.hot.get_client()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.hot.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let version_id = snapshot.version_id;
+        self.hot.set_snapshot(snapshot, data)?;
+        self.move_to_cold(version_id)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let version_id = snapshot.version_id;
+        self.hot.set_snapshot_from_reader(snapshot, data)?;
+        self.move_to_cold(version_id)
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        // the client is stored in the hot storage
+        self.hot.record_sync(timestamp, user_agent)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.hot.set_quota(quota)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.hot.set_deleted(deleted_at)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.hot.get_snapshot_data(version_id)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.hot.get_snapshot_reader(version_id)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.hot.list_snapshots()
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        if let Some(version) = self.hot.get_version_by_parent(parent_version_id)? {
+            return Ok(Some(version));
+        }
+        self.cold()?.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        if let Some(version) = self.hot.get_version(version_id)? {
+            return Ok(Some(version));
+        }
+        self.cold()?.get_version(version_id)
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        if let Some(version) = self.hot.get_version_reader_by_parent(parent_version_id)? {
+            return Ok(Some(version));
+        }
+        self.cold()?.get_version_reader_by_parent(parent_version_id)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        if let Some(version) = self.hot.get_version_reader(version_id)? {
+            return Ok(Some(version));
+        }
+        self.cold()?.get_version_reader(version_id)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.hot
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.hot
+            .add_version_from_reader(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.hot
+            .add_version_if_latest(version_id, parent_version_id, history_segment)
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.hot.delete_version(version_id)?;
+        self.cold()?.delete_version(version_id)
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.hot.delete_client()?;
+        self.cold()?.delete_client()
+    }
+
+    /// Every client is in the hot storage, so clients are listed from there.
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.hot.list_clients(after, limit)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        if let Some(cold) = self.cold.as_mut() {
+            cold.commit()?;
+        }
+        self.hot.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use pretty_assertions::assert_eq;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&TieredStorage::new(
+            InMemoryStorage::new(),
+            InMemoryStorage::new()
+        )));
+    }
+
+    /// Add a chain of versions with the given IDs, each the child of the previous.
+    fn add_versions(txn: &mut dyn StorageTxn, version_ids: &[Uuid]) -> anyhow::Result<()> {
+        let mut parent_version_id = NIL_VERSION_ID;
+        for version_id in version_ids {
+            txn.add_version(
+                *version_id,
+                parent_version_id,
+                version_id.as_bytes().to_vec().into(),
+            )?;
+            parent_version_id = *version_id;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn versions_before_snapshot_moved_to_cold() -> anyhow::Result<()> {
+        let storage = TieredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        add_versions(txn.as_mut(), &vids)?;
+        txn.set_snapshot(Snapshot::new(vids[2], Utc::now()), vec![9].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut hot = storage.hot.txn(client_id)?;
+        let mut cold = storage.cold.txn(client_id)?;
+        for (i, vid) in vids.iter().enumerate() {
+            assert_eq!(hot.get_version(*vid)?.is_some(), i >= 2);
+            assert_eq!(cold.get_version(*vid)?.is_some(), i < 2);
+        }
+        drop((hot, cold));
+
+        // reads are unaffected by the move
+        let mut txn = storage.txn(client_id)?;
+        let mut parent_version_id = NIL_VERSION_ID;
+        for vid in &vids {
+            let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
+            assert_eq!(version.version_id, *vid);
+            assert_eq!(version.history_segment, vid.as_bytes().to_vec());
+            assert_eq!(txn.get_version(*vid)?, Some(version));
+            parent_version_id = *vid;
+        }
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, vids[3]);
+        assert_eq!(txn.get_snapshot_data(vids[2])?, Some(vec![9].into()));
+        Ok(())
+    }
+
+    #[test]
+    fn later_snapshot_moves_newer_versions() -> anyhow::Result<()> {
+        let storage = TieredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        add_versions(txn.as_mut(), &vids)?;
+        txn.set_snapshot(Snapshot::new(vids[1], Utc::now()), vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.set_snapshot(Snapshot::new(vids[4], Utc::now()), vec![4].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut hot = storage.hot.txn(client_id)?;
+        let mut cold = storage.cold.txn(client_id)?;
+        for (i, vid) in vids.iter().enumerate() {
+            assert_eq!(hot.get_version(*vid)?.is_some(), i == 4);
+            assert_eq!(cold.get_version(*vid)?.is_some(), i < 4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delete_client_from_both() -> anyhow::Result<()> {
+        let storage = TieredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        add_versions(txn.as_mut(), &vids)?;
+        txn.set_snapshot(Snapshot::new(vids[2], Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(storage.hot.txn(client_id)?.get_client()?, None);
+        assert_eq!(storage.cold.txn(client_id)?.get_client()?, None);
+        assert_eq!(storage.cold.txn(client_id)?.get_version(vids[0])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_versions() -> anyhow::Result<()> {
+        let storage = TieredStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_version(Uuid::new_v4())?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        txn.commit()?;
+        Ok(())
+    }
+}
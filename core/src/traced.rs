@@ -0,0 +1,266 @@
+use crate::error::StorageError;
+use crate::storage::{
+    Client, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageTxn, Version,
+    VersionReader,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Read;
+use tracing::field;
+use uuid::Uuid;
+
+/// A storage wrapper which records a [`tracing`] span for each storage transaction, with a child
+/// span for each operation in it.
+///
+/// With a subscriber exporting spans, such as an OpenTelemetry exporter, this shows how the time
+/// handling a request is divided between storage operations. Failed operations record their
+/// error on the span.
+pub struct TracedStorage<S: Storage> {
+    inner: S,
+}
+
+impl<S: Storage> TracedStorage<S> {
+    /// Wrap `inner`.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+/// Call `f` within a span for `operation`, recording any error on the span.
+fn trace<T>(
+    operation: &str,
+    f: impl FnOnce() -> Result<T, StorageError>,
+) -> Result<T, StorageError> {
+    let span = tracing::debug_span!(
+        "storage_operation",
+        otel.name = %format!("storage {operation}"),
+        operation,
+        error = field::Empty,
+    );
+    let _enter = span.enter();
+    let res = f();
+    if let Err(err) = &res {
+        span.record("error", field::display(err));
+    }
+    res
+}
+
+impl<S: Storage> Storage for TracedStorage<S> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        let span = tracing::debug_span!("storage_txn", client_id = %client_id);
+        let inner = {
+            let _enter = span.enter();
+            trace("txn", || self.inner.txn(client_id))?
+        };
+        Ok(Box::new(TracedTxn { inner, span }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        trace("max_versions_since_snapshot", || {
+            self.inner.max_versions_since_snapshot()
+        })
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        trace("flush", || self.inner.flush())
+    }
+}
+
+struct TracedTxn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+    /// The span covering the whole transaction, which is the parent of each operation's span.
+    span: tracing::Span,
+}
+
+impl TracedTxn<'_> {
+    /// Call `f` on the inner transaction within a span for `operation`.
+    fn trace<T, F>(&mut self, operation: &str, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&mut dyn StorageTxn) -> Result<T, StorageError>,
+    {
+        let _enter = self.span.enter();
+        let inner = self.inner.as_mut();
+        trace(operation, || f(inner))
+    }
+}
+
+impl StorageTxn for TracedTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        self.trace("get_client", |txn| txn.get_client())
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        self.trace("new_client", |txn| txn.new_client(latest_version_id))
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        self.trace("set_snapshot", |txn| txn.set_snapshot(snapshot, data))
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.trace("set_snapshot_from_reader", |txn| {
+            txn.set_snapshot_from_reader(snapshot, data)
+        })
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.trace("record_sync", |txn| txn.record_sync(timestamp, user_agent))
+    }
+
+    fn get_quota(&mut self) -> Result<Quota, StorageError> {
+        self.trace("get_quota", |txn| txn.get_quota())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        self.trace("set_quota", |txn| txn.set_quota(quota))
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        self.trace("set_deleted", |txn| txn.set_deleted(deleted_at))
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        self.trace("get_snapshot_data", |txn| txn.get_snapshot_data(version_id))
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        self.trace("get_snapshot_reader", |txn| {
+            txn.get_snapshot_reader(version_id)
+        })
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, StorageError> {
+        self.trace("list_snapshots", |txn| txn.list_snapshots())
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        self.trace("get_version_by_parent", |txn| {
+            txn.get_version_by_parent(parent_version_id)
+        })
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        self.trace("get_version", |txn| txn.get_version(version_id))
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.trace("get_version_reader_by_parent", |txn| {
+            txn.get_version_reader_by_parent(parent_version_id)
+        })
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        self.trace("get_version_reader", |txn| {
+            txn.get_version_reader(version_id)
+        })
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.trace("add_version", |txn| {
+            txn.add_version(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.trace("add_version_from_reader", |txn| {
+            txn.add_version_from_reader(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn add_version_if_latest(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.trace("add_version_if_latest", |txn| {
+            txn.add_version_if_latest(version_id, parent_version_id, history_segment)
+        })
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.trace("delete_version", |txn| txn.delete_version(version_id))
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        self.trace("savepoint", |txn| txn.savepoint())
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        self.trace("rollback_to", |txn| txn.rollback_to(savepoint))
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.trace("delete_client", |txn| txn.delete_client())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        self.trace("list_clients", |txn| txn.list_clients(after, limit))
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.trace("commit", |txn| txn.commit())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flaky::{FaultConfig, FlakyStorage};
+    use crate::inmemory::InMemoryStorage;
+
+    mod conformance {
+        use super::*;
+        crate::storage_conformance_tests!(|test| test(&TracedStorage::new(InMemoryStorage::new())));
+    }
+
+    #[test]
+    fn errors_pass_through() {
+        let storage = TracedStorage::new(FlakyStorage::new(
+            InMemoryStorage::new(),
+            FaultConfig {
+                failure_rate: 1.0,
+                ..FaultConfig::default()
+            },
+        ));
+        assert!(storage.txn(Uuid::new_v4()).is_err());
+    }
+}
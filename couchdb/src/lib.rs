@@ -0,0 +1,1107 @@
+//! This crate implements a CouchDB storage backend for the TaskChampion sync server.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_couchdb::CouchDbStorage;
+//! let storage = CouchDbStorage::new("http://localhost:5984", "taskchampion")
+//!     .with_credentials("admin", "password");
+//! storage.create_database()?;
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{json, Value};
+use std::io::Read;
+use taskchampion_sync_server_core::{
+    Bytes, Client, Quota, Snapshot, Storage, StorageCapabilities, StorageError, StorageTxn, Version,
+};
+use uuid::Uuid;
+
+/// The largest history segment stored. New history segments are stored, base64-encoded, in the
+/// client document until they are copied to their own documents, and this leaves room within
+/// CouchDB's default 8MB document limit for several of them.
+const MAX_HISTORY_SEGMENT_LEN: usize = 1024 * 1024;
+
+/// The name of the Mango index used to find the child of a version.
+const CHILDREN_INDEX: &str = "taskchampion-children";
+
+/// The name of the attachment to the client document holding the snapshot data.
+const SNAPSHOT_ATTACHMENT: &str = "snapshot";
+
+/// The ID of the document holding a client's metadata.
+fn client_doc_id(client_id: Uuid) -> String {
+    format!("client-{client_id}")
+}
+
+/// The ID of the document holding a version.
+fn version_doc_id(client_id: Uuid, version_id: Uuid) -> String {
+    format!("version-{client_id}-{version_id}")
+}
+
+fn get_str<'v>(value: &'v Value, name: &str) -> anyhow::Result<&'v str> {
+    value[name]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Document is missing string field {name}"))
+}
+
+fn get_uuid(value: &Value, name: &str) -> anyhow::Result<Uuid> {
+    Ok(Uuid::parse_str(get_str(value, name)?)?)
+}
+
+fn get_u64(value: &Value, name: &str) -> anyhow::Result<u64> {
+    value[name]
+        .as_u64()
+        .ok_or_else(|| anyhow::anyhow!("Document is missing number field {name}"))
+}
+
+fn get_i64(value: &Value, name: &str) -> anyhow::Result<i64> {
+    value[name]
+        .as_i64()
+        .ok_or_else(|| anyhow::anyhow!("Document is missing number field {name}"))
+}
+
+/// Get an optional timestamp field, in seconds since the Unix epoch.
+fn get_timestamp(value: &Value, name: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+    if value[name].is_null() {
+        return Ok(None);
+    }
+    Utc.timestamp_opt(get_i64(value, name)?, 0)
+        .single()
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp field {name}"))
+}
+
+fn encode_version(client_id: Uuid, version: &Version) -> Value {
+    json!({
+        "type": "version",
+        "client_id": client_id.to_string(),
+        "version_id": version.version_id.to_string(),
+        "parent_version_id": version.parent_version_id.to_string(),
+        "history_segment": STANDARD.encode(&version.history_segment),
+        "created_at": version.created_at.map(|t| t.timestamp()),
+    })
+}
+
+fn decode_version(value: &Value) -> anyhow::Result<Version> {
+    Ok(Version {
+        version_id: get_uuid(value, "version_id")?,
+        parent_version_id: get_uuid(value, "parent_version_id")?,
+        history_segment: STANDARD.decode(get_str(value, "history_segment")?)?.into(),
+        created_at: get_timestamp(value, "created_at")?,
+    })
+}
+
+fn decode_client(doc: &Value) -> anyhow::Result<Client> {
+    let snapshot = match &doc["snapshot"] {
+        Value::Null => None,
+        snap => Some(Snapshot {
+            version_id: get_uuid(snap, "version_id")?,
+            timestamp: get_timestamp(snap, "timestamp")?
+                .ok_or_else(|| anyhow::anyhow!("Document is missing snapshot timestamp"))?,
+            versions_since: get_u64(snap, "versions_since")? as u32,
+        }),
+    };
+    Ok(Client {
+        latest_version_id: get_uuid(doc, "latest_version_id")?,
+        snapshot,
+        created_at: get_timestamp(doc, "created_at")?,
+        last_sync_at: get_timestamp(doc, "last_sync_at")?,
+        user_agent: doc["user_agent"].as_str().map(str::to_string),
+        quota: Quota {
+            max_bytes: doc["quota_max_bytes"].as_u64(),
+            max_versions: doc["quota_max_versions"].as_u64(),
+        },
+        deleted_at: get_timestamp(doc, "deleted_at")?,
+    })
+}
+
+/// Decode the versions in a client document which have not yet been copied to their own
+/// documents.
+fn decode_pending(doc: &Value) -> anyhow::Result<Vec<Version>> {
+    match &doc["pending"] {
+        Value::Null => Ok(vec![]),
+        Value::Array(versions) => versions.iter().map(decode_version).collect(),
+        _ => anyhow::bail!("Document has invalid pending versions"),
+    }
+}
+
+/// Decode the IDs of versions in a client document which have been deleted, but whose documents
+/// have not yet been deleted.
+fn decode_removed(doc: &Value) -> anyhow::Result<Vec<Uuid>> {
+    match &doc["removed"] {
+        Value::Null => Ok(vec![]),
+        Value::Array(ids) => ids
+            .iter()
+            .map(|id| {
+                let id = id
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Document has invalid removed versions"))?;
+                Ok(Uuid::parse_str(id)?)
+            })
+            .collect(),
+        _ => anyhow::bail!("Document has invalid removed versions"),
+    }
+}
+
+/// A storage backend which uses a CouchDB database.
+///
+/// Each client has one document holding its metadata, with the snapshot data as an attachment,
+/// and each version has its own document. CouchDB has no multi-document transactions, so the
+/// client document is the only point of serialization: on commit, it is updated using the
+/// revision read in the transaction, failing with [`StorageError::TryAgainLater`] if another
+/// transaction has modified it in the meantime. New versions are included in that update, and
+/// only then copied to their own documents and removed from the client document. If that copy
+/// is interrupted, it is completed by the next transaction to commit for the client. Deleted
+/// versions are handled in the same way, listed in the client document until their own documents
+/// are deleted.
+///
+/// Similarly, deleting a client deletes the client document first, conditional on its revision,
+/// followed by the version documents. If that is interrupted, the remaining version documents
+/// are removed when the client is next deleted.
+///
+/// CouchDB replication of the database works as usual, but only one server should write to it at
+/// a time, as conflicting revisions created on different replicas are not merged.
+pub struct CouchDbStorage {
+    agent: ureq::Agent,
+    /// The URL of the database, without a trailing slash.
+    db_url: String,
+    /// The value of the `Authorization` header, if any.
+    authorization: Option<String>,
+}
+
+impl CouchDbStorage {
+    /// Create a new instance using the given database on the CouchDB server at `url`. This does
+    /// not contact the server.
+    pub fn new(url: &str, database: &str) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().build(),
+            db_url: format!("{}/{database}", url.trim_end_matches('/')),
+            authorization: None,
+        }
+    }
+
+    /// Authenticate to the server with the given username and password.
+    pub fn with_credentials(self, username: &str, password: &str) -> Self {
+        let credentials = STANDARD.encode(format!("{username}:{password}"));
+        Self {
+            authorization: Some(format!("Basic {credentials}")),
+            ..self
+        }
+    }
+
+    /// Create the database, if it does not exist, and the index used to find versions.
+    pub fn create_database(&self) -> anyhow::Result<()> {
+        match self.request("PUT", "").call() {
+            // 412 indicates the database already exists
+            Ok(_) | Err(ureq::Error::Status(412, _)) => {}
+            Err(err) => return Err(err).context("Error creating CouchDB database"),
+        }
+        self.request("POST", "/_index")
+            .send_json(json!({
+                "index": { "fields": ["type", "client_id", "parent_version_id"] },
+                "name": CHILDREN_INDEX,
+                "type": "json",
+            }))
+            .context("Error creating CouchDB index")?;
+        Ok(())
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self
+            .agent
+            .request(method, &format!("{}{path}", self.db_url));
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    /// Get a document, or None if it does not exist.
+    fn get_doc(&self, doc_id: &str) -> anyhow::Result<Option<Value>> {
+        match self.request("GET", &format!("/{doc_id}")).call() {
+            Ok(response) => Ok(Some(response.into_json()?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(err).context("Error reading from CouchDB"),
+        }
+    }
+
+    /// Write a document, returning its new revision, or None if the write conflicted with another
+    /// revision.
+    fn put_doc(&self, doc_id: &str, doc: &Value) -> anyhow::Result<Option<String>> {
+        match self.request("PUT", &format!("/{doc_id}")).send_json(doc) {
+            Ok(response) => {
+                let response: Value = response.into_json()?;
+                Ok(Some(get_str(&response, "rev")?.to_string()))
+            }
+            Err(ureq::Error::Status(409, _)) => Ok(None),
+            Err(err) => Err(err).context("Error writing to CouchDB"),
+        }
+    }
+
+    /// Copy pending versions to their own documents, and delete the documents of removed versions,
+    /// and then remove both from the client document, which has the given revision. This is safe
+    /// to repeat, and is abandoned if the client document has changed, in which case the
+    /// transaction that changed it is responsible for completing it.
+    /// Delete the documents with the given IDs, if they exist.
+    fn delete_docs(&self, doc_ids: &[String]) -> anyhow::Result<()> {
+        let response: Value = self
+            .request("POST", "/_all_docs")
+            .send_json(json!({ "keys": doc_ids }))
+            .context("Error listing CouchDB documents")?
+            .into_json()?;
+        let rows = response["rows"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Invalid response from CouchDB"))?;
+        let mut docs = vec![];
+        for row in rows {
+            // rows for missing documents have an error, and for deleted documents a flag
+            if row.get("error").is_some() || row["value"]["deleted"] == json!(true) {
+                continue;
+            }
+            docs.push(json!({
+                "_id": get_str(row, "id")?,
+                "_rev": get_str(&row["value"], "rev")?,
+                "_deleted": true,
+            }));
+        }
+        if docs.is_empty() {
+            return Ok(());
+        }
+        self.request("POST", "/_bulk_docs")
+            .send_json(json!({ "docs": docs }))
+            .context("Error deleting CouchDB documents")?;
+        Ok(())
+    }
+
+    fn flush_pending(&self, client_id: Uuid, mut doc: Value, rev: String) -> anyhow::Result<()> {
+        for version in decode_pending(&doc)? {
+            let version_doc_id = version_doc_id(client_id, version.version_id);
+            // a conflict indicates the version was already copied
+            self.put_doc(&version_doc_id, &encode_version(client_id, &version))?;
+        }
+        let removed = decode_removed(&doc)?;
+        if !removed.is_empty() {
+            let keys: Vec<String> = removed
+                .iter()
+                .map(|version_id| version_doc_id(client_id, *version_id))
+                .collect();
+            self.delete_docs(&keys)?;
+        }
+        doc["_rev"] = Value::String(rev);
+        doc["pending"] = json!([]);
+        doc["removed"] = json!([]);
+        // the snapshot data has already been written, so refer to it with a stub
+        if let Some(attachment) = doc
+            .get_mut("_attachments")
+            .and_then(|attachments| attachments.get_mut(SNAPSHOT_ATTACHMENT))
+        {
+            *attachment = json!({ "stub": true });
+        }
+        self.put_doc(&client_doc_id(client_id), &doc)?;
+        Ok(())
+    }
+}
+
+impl Storage for CouchDbStorage {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(Txn {
+            storage: self,
+            client_id,
+            stored_doc: None,
+            client: None,
+            new_versions: vec![],
+            snapshot_data: None,
+            removed: vec![],
+            deleted: false,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_client_enumeration: true,
+            supports_rollback: true,
+            supports_compaction: true,
+            max_history_segment_len: MAX_HISTORY_SEGMENT_LEN,
+            supports_soft_delete: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let mut max: Option<(Uuid, u32)> = None;
+        let mut bookmark: Option<String> = None;
+        loop {
+            let mut query = json!({
+                "selector": { "type": "client", "snapshot": { "$type": "object" } },
+                "fields": ["client_id", "snapshot"],
+                "limit": 1000,
+            });
+            if let Some(bookmark) = bookmark {
+                query["bookmark"] = Value::String(bookmark);
+            }
+            let response: Value = self
+                .request("POST", "/_find")
+                .send_json(query)
+                .context("Error querying CouchDB")?
+                .into_json()?;
+            let docs = response["docs"]
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("Invalid response from CouchDB"))?;
+            for doc in docs {
+                let client_id = get_uuid(doc, "client_id")?;
+                let versions_since = get_u64(&doc["snapshot"], "versions_since")? as u32;
+                let is_max = match max {
+                    Some((_, m)) => versions_since > m,
+                    None => true,
+                };
+                if is_max {
+                    max = Some((client_id, versions_since));
+                }
+            }
+            if docs.is_empty() {
+                return Ok(max);
+            }
+            bookmark = Some(get_str(&response, "bookmark")?.to_string());
+        }
+    }
+}
+
+struct Txn<'a> {
+    storage: &'a CouchDbStorage,
+    client_id: Uuid,
+    /// The client document as stored, once it has been read.
+    stored_doc: Option<Option<Value>>,
+    /// The client as modified in this transaction, if it has been modified.
+    client: Option<Client>,
+    /// Versions added in this transaction.
+    new_versions: Vec<Version>,
+    /// Snapshot data set in this transaction.
+    snapshot_data: Option<Bytes>,
+    /// Versions deleted in this transaction.
+    removed: Vec<Uuid>,
+    /// Whether the client's stored documents are deleted on commit, before the client is written.
+    deleted: bool,
+}
+
+impl Txn<'_> {
+    /// Get the stored client document, reading it if necessary.
+    fn stored_doc(&mut self) -> anyhow::Result<Option<&Value>> {
+        if self.stored_doc.is_none() {
+            let doc = self.storage.get_doc(&client_doc_id(self.client_id))?;
+            self.stored_doc = Some(doc);
+        }
+        Ok(self.stored_doc.as_ref().and_then(|doc| doc.as_ref()))
+    }
+
+    /// Get the versions which are committed or added in this transaction but not yet stored in
+    /// their own documents.
+    fn pending_versions(&mut self) -> anyhow::Result<Vec<Version>> {
+        let removed = self.removed_versions()?;
+        let deleted = self.deleted;
+        let mut versions = match self.stored_doc()? {
+            Some(doc) if !deleted => decode_pending(doc)?,
+            _ => vec![],
+        };
+        versions.extend(self.new_versions.iter().cloned());
+        versions.retain(|v| !removed.contains(&v.version_id));
+        Ok(versions)
+    }
+
+    /// Get the IDs of the versions which are deleted, whether committed or in this transaction,
+    /// but whose documents have not yet been deleted.
+    fn removed_versions(&mut self) -> anyhow::Result<Vec<Uuid>> {
+        let deleted = self.deleted;
+        let mut removed = match self.stored_doc()? {
+            Some(doc) if !deleted => decode_removed(doc)?,
+            _ => vec![],
+        };
+        removed.extend(self.removed.iter().copied());
+        Ok(removed)
+    }
+
+    /// Delete the stored client document, failing if it has been modified since it was read, and
+    /// then all of the client's version documents.
+    fn delete_stored(&mut self) -> anyhow::Result<()> {
+        let client_doc_id = client_doc_id(self.client_id);
+        let rev = self
+            .stored_doc()?
+            .and_then(|doc| doc["_rev"].as_str())
+            .map(|rev| rev.to_string());
+        if let Some(rev) = rev {
+            match self
+                .storage
+                .request("DELETE", &format!("/{client_doc_id}"))
+                .query("rev", &rev)
+                .call()
+            {
+                Ok(_) | Err(ureq::Error::Status(404, _)) => {}
+                Err(ureq::Error::Status(409, _)) => return Err(StorageError::TryAgainLater.into()),
+                Err(err) => return Err(err).context("Error deleting client from CouchDB"),
+            }
+        }
+        self.stored_doc = Some(None);
+
+        let prefix = format!("version-{}-", self.client_id);
+        let response: Value = self
+            .storage
+            .request("GET", "/_all_docs")
+            .query("startkey", &json!(prefix).to_string())
+            .query("endkey", &json!(format!("{prefix}\u{fff0}")).to_string())
+            .call()
+            .context("Error listing CouchDB documents")?
+            .into_json()?;
+        let doc_ids = response["rows"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Invalid response from CouchDB"))?
+            .iter()
+            .map(|row| Ok(get_str(row, "id")?.to_string()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if doc_ids.is_empty() {
+            return Ok(());
+        }
+        self.storage.delete_docs(&doc_ids)
+    }
+
+    /// Encode the client document to be written on commit.
+    fn client_doc(&mut self, client: &Client) -> anyhow::Result<Value> {
+        let pending = self.pending_versions()?;
+        let removed = self.removed_versions()?;
+        let mut doc = match self.stored_doc()? {
+            Some(doc) => doc.clone(),
+            None => json!({ "_id": client_doc_id(self.client_id) }),
+        };
+        doc["type"] = json!("client");
+        doc["client_id"] = json!(self.client_id.to_string());
+        doc["latest_version_id"] = json!(client.latest_version_id.to_string());
+        doc["snapshot"] = match &client.snapshot {
+            Some(snap) => json!({
+                "version_id": snap.version_id.to_string(),
+                "timestamp": snap.timestamp.timestamp(),
+                "versions_since": snap.versions_since,
+            }),
+            None => Value::Null,
+        };
+        doc["created_at"] = json!(client.created_at.map(|t| t.timestamp()));
+        doc["last_sync_at"] = json!(client.last_sync_at.map(|t| t.timestamp()));
+        doc["user_agent"] = json!(client.user_agent);
+        doc["quota_max_bytes"] = json!(client.quota.max_bytes);
+        doc["quota_max_versions"] = json!(client.quota.max_versions);
+        doc["deleted_at"] = json!(client.deleted_at.map(|t| t.timestamp()));
+        doc["pending"] = Value::Array(
+            pending
+                .iter()
+                .map(|version| encode_version(self.client_id, version))
+                .collect(),
+        );
+        doc["removed"] = Value::Array(removed.iter().map(|id| json!(id.to_string())).collect());
+        if let Some(data) = &self.snapshot_data {
+            doc["_attachments"] = json!({
+                SNAPSHOT_ATTACHMENT: {
+                    "content_type": "application/octet-stream",
+                    "data": STANDARD.encode(data),
+                },
+            });
+        }
+        Ok(doc)
+    }
+}
+
+impl StorageTxn for Txn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        if let Some(client) = &self.client {
+            return Ok(Some(client.clone()));
+        }
+        if self.deleted {
+            return Ok(None);
+        }
+        Ok(self.stored_doc()?.map(decode_client).transpose()?)
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        if self.get_client()?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already exists",
+                self.client_id
+            )));
+        }
+        self.client = Some(Client::new(latest_version_id, Utc::now()));
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        let Some(mut client) = self.get_client()? else {
+            return Ok(());
+        };
+        client.last_sync_at = Some(timestamp);
+        client.user_agent = user_agent;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.quota = quota;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.deleted_at = deleted_at;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.snapshot = Some(snapshot);
+        self.client = Some(client);
+        self.snapshot_data = Some(data);
+        Ok(())
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if Some(version_id) != client.snapshot.map(|snap| snap.version_id) {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "unexpected snapshot_version_id"
+            )));
+        }
+        if let Some(data) = &self.snapshot_data {
+            return Ok(Some(data.clone()));
+        }
+        if self.deleted {
+            return Ok(None);
+        }
+        let Some(rev) = self
+            .stored_doc()?
+            .and_then(|doc| doc["_rev"].as_str())
+            .map(|rev| rev.to_string())
+        else {
+            return Ok(None);
+        };
+        let path = format!("/{}/{SNAPSHOT_ATTACHMENT}", client_doc_id(self.client_id));
+        match self.storage.request("GET", &path).query("rev", &rev).call() {
+            Ok(response) => {
+                let mut data = vec![];
+                response.into_reader().read_to_end(&mut data)?;
+                Ok(Some(data.into()))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(StorageError::Backend(
+                anyhow::Error::new(err).context("Error reading snapshot from CouchDB"),
+            )),
+        }
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        if let Some(version) = self
+            .pending_versions()?
+            .into_iter()
+            .find(|v| v.parent_version_id == parent_version_id)
+        {
+            return Ok(Some(version));
+        }
+        if self.deleted {
+            return Ok(None);
+        }
+        let removed = self.removed_versions()?;
+        let response: Value = self
+            .storage
+            .request("POST", "/_find")
+            .send_json(json!({
+                "selector": {
+                    "type": "version",
+                    "client_id": self.client_id.to_string(),
+                    "parent_version_id": parent_version_id.to_string(),
+                },
+                "use_index": CHILDREN_INDEX,
+                "limit": 1,
+            }))
+            .context("Error querying CouchDB")?
+            .into_json()?;
+        let version = response["docs"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Invalid response from CouchDB"))?
+            .first()
+            .map(decode_version)
+            .transpose()?;
+        Ok(version.filter(|v| !removed.contains(&v.version_id)))
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        if self.removed_versions()?.contains(&version_id) {
+            return Ok(None);
+        }
+        if let Some(version) = self
+            .pending_versions()?
+            .into_iter()
+            .find(|v| v.version_id == version_id)
+        {
+            return Ok(Some(version));
+        }
+        if self.deleted {
+            return Ok(None);
+        }
+        Ok(self
+            .storage
+            .get_doc(&version_doc_id(self.client_id, version_id))?
+            .map(|doc| decode_version(&doc))
+            .transpose()?)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.check_new_version_id(version_id)?;
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if history_segment.len() > MAX_HISTORY_SEGMENT_LEN {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "History segment of {} bytes is too large for CouchDB",
+                history_segment.len()
+            )));
+        }
+        if self.get_version_by_parent(parent_version_id)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a child for {}",
+                self.client_id,
+                parent_version_id
+            )));
+        }
+        if self.get_version(version_id)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a version {}",
+                self.client_id,
+                version_id
+            )));
+        }
+
+        self.new_versions.push(Version {
+            version_id,
+            parent_version_id,
+            history_segment,
+            created_at: Some(Utc::now()),
+        });
+        client.latest_version_id = version_id;
+        if let Some(ref mut snap) = client.snapshot {
+            snap.versions_since = snap.versions_since.saturating_add(1);
+        }
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        if self.get_version(version_id)?.is_none() {
+            return Ok(());
+        }
+        self.removed.push(version_id);
+        // the client document is rewritten on commit, recording the deletion
+        if self.client.is_none() {
+            self.client = self.get_client()?;
+        }
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.client = None;
+        self.new_versions.clear();
+        self.removed.clear();
+        self.snapshot_data = None;
+        self.deleted = true;
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        // client document IDs sort in the same order as client IDs; the document for `after`, if
+        // it still exists, is the first returned and is skipped
+        let start = match after {
+            Some(after) => client_doc_id(after),
+            None => "client-".to_string(),
+        };
+        let response: Value = self
+            .storage
+            .request("GET", "/_all_docs")
+            .query("startkey", &json!(start).to_string())
+            .query("endkey", &json!("client-\u{fff0}").to_string())
+            .query("limit", &(limit + 1).to_string())
+            .call()
+            .context("Error listing CouchDB documents")?
+            .into_json()?;
+        let rows = response["rows"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Invalid response from CouchDB"))?;
+        let mut client_ids = vec![];
+        for row in rows {
+            let doc_id = get_str(row, "id")?;
+            let client_id = Uuid::parse_str(doc_id.trim_start_matches("client-"))
+                .context("Invalid client document ID")?;
+            if Some(client_id) != after {
+                client_ids.push(client_id);
+            }
+        }
+        client_ids.truncate(limit);
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        if std::mem::take(&mut self.deleted) {
+            self.delete_stored()?;
+        }
+        let Some(client) = self.client.take() else {
+            // every change modifies the client, so there is nothing to commit
+            return Ok(());
+        };
+        let doc = self.client_doc(&client)?;
+        // the write includes the revision read, if any, so it conflicts if the client document
+        // has been modified since then, serializing transactions
+        let Some(rev) = self.storage.put_doc(&client_doc_id(self.client_id), &doc)? else {
+            return Err(StorageError::TryAgainLater);
+        };
+        self.new_versions.clear();
+        self.snapshot_data = None;
+        self.removed.clear();
+        self.stored_doc = None;
+
+        // the transaction is committed, so failing to move the pending versions is not an error
+        if let Err(err) = self.storage.flush_pending(self.client_id, doc, rev) {
+            log::warn!(
+                "Error moving versions of client {} to their own documents: {err:#}",
+                self.client_id
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::NIL_VERSION_ID;
+
+    /// Connect to the server given in `TEST_COUCHDB_URL` and create a fresh database, or return
+    /// `None` if that variable is not set, in which case the test should be skipped.
+    fn storage() -> anyhow::Result<Option<CouchDbStorage>> {
+        let Ok(url) = std::env::var("TEST_COUCHDB_URL") else {
+            return Ok(None);
+        };
+        let mut storage = CouchDbStorage::new(&url, &format!("test-{}", Uuid::new_v4()));
+        if let Ok(username) = std::env::var("TEST_COUCHDB_USER") {
+            let password = std::env::var("TEST_COUCHDB_PASSWORD").unwrap_or_default();
+            storage = storage.with_credentials(&username, &password);
+        }
+        storage.create_database()?;
+        Ok(Some(storage))
+    }
+
+    #[test]
+    fn test_client_doc_round_trip() -> anyhow::Result<()> {
+        let storage = CouchDbStorage::new("http://localhost:5984/", "db");
+        assert_eq!(storage.db_url, "http://localhost:5984/db");
+        let client_id = Uuid::new_v4();
+        let version = Version {
+            version_id: Uuid::new_v4(),
+            parent_version_id: NIL_VERSION_ID,
+            history_segment: vec![1, 2, 3].into(),
+            created_at: Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+        };
+        let mut txn = Txn {
+            storage: &storage,
+            client_id,
+            stored_doc: Some(None),
+            client: None,
+            new_versions: vec![version.clone()],
+            snapshot_data: None,
+            removed: vec![],
+            deleted: false,
+        };
+        let client = Client {
+            latest_version_id: version.version_id,
+            snapshot: Some(
+                Snapshot::new(
+                    Uuid::new_v4(),
+                    Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+                )
+                .with_versions_since(4),
+            ),
+            created_at: Some(Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap()),
+            last_sync_at: None,
+            user_agent: Some("taskchampion/1.0".into()),
+            quota: Quota {
+                max_bytes: None,
+                max_versions: Some(1000),
+            },
+            deleted_at: None,
+        };
+        let doc = txn.client_doc(&client)?;
+        assert_eq!(doc["_id"], json!(client_doc_id(client_id)));
+        assert_eq!(decode_client(&doc)?, client);
+        assert_eq!(decode_pending(&doc)?, vec![version]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+
+        // the version has been moved to its own document
+        let doc = storage.get_doc(&client_doc_id(client_id))?.unwrap();
+        assert_eq!(decode_pending(&doc)?, vec![]);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        assert_eq!(txn.get_version(version_id)?, Some(version));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_versions() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let version = Version {
+            version_id,
+            parent_version_id: NIL_VERSION_ID,
+            history_segment: vec![4].into(),
+            created_at: None,
+        };
+
+        // simulate a commit interrupted before its versions were moved
+        let doc = json!({
+            "type": "client",
+            "client_id": client_id.to_string(),
+            "latest_version_id": version_id.to_string(),
+            "snapshot": null,
+            "pending": [encode_version(client_id, &version)],
+        });
+        storage.put_doc(&client_doc_id(client_id), &doc)?.unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(version_id)?, Some(version.clone()));
+        let child_id = Uuid::new_v4();
+        txn.add_version(child_id, version_id, vec![5].into())?;
+        txn.commit()?;
+
+        let doc = storage.get_doc(&client_doc_id(client_id))?.unwrap();
+        assert_eq!(decode_pending(&doc)?, vec![]);
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, Some(version));
+        assert_eq!(
+            txn.get_version_by_parent(version_id)?.unwrap().version_id,
+            child_id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+
+        // two transactions add a child of the same version
+        let mut txn1 = storage.txn(client_id)?;
+        let mut txn2 = storage.txn(client_id)?;
+        txn1.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+        txn2.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![2].into())?;
+        txn1.commit()?;
+        let err = txn2.commit().unwrap_err();
+        assert!(matches!(err, StorageError::TryAgainLater));
+
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_snapshot(
+            Snapshot::new(version_id, Utc::now()).with_versions_since(2),
+            vec![9].into(),
+        )?;
+        txn.commit()?;
+
+        // a later change to the client keeps the snapshot data
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![9].into()));
+        assert_eq!(storage.max_versions_since_snapshot()?, Some((client_id, 3)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let mut client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+
+        let mut txn = storage.txn(client_ids[0])?;
+        assert_eq!(txn.list_clients(None, 10)?, client_ids);
+        assert_eq!(
+            txn.list_clients(Some(client_ids[0]), 1)?,
+            vec![client_ids[1]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.set_snapshot(Snapshot::new(v1, Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        // v2 remains pending in the client document
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(v2, v1, vec![3].into())?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(v1)?, None);
+        assert_eq!(txn.get_version(v2)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+
+        // the client can be created again
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
+        Ok(())
+    }
+    #[test]
+    fn test_prune_versions() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        let mut parent_version_id = Uuid::nil();
+        for vid in &vids {
+            txn.add_version(*vid, parent_version_id, vec![1].into())?;
+            parent_version_id = *vid;
+        }
+        txn.set_snapshot(Snapshot::new(vids[1], Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.prune_versions(vids[1])?, 2);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(vids[0])?, None);
+        assert_eq!(txn.get_version(vids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+        assert_eq!(
+            txn.get_version_by_parent(vids[1])?.unwrap().version_id,
+            vids[2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_sync() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let timestamp = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.created_at.is_some());
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent, Some("tc/1.0".into()));
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(storage) = storage()? else {
+                return Ok(());
+            };
+            test(&storage)
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
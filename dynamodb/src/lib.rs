@@ -0,0 +1,978 @@
+//! This crate implements a DynamoDB storage backend for the TaskChampion sync server.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_dynamodb::DynamoDbStorage;
+//! let storage = DynamoDbStorage::new("taskchampion")?;
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, AttributeValue, BillingMode, Delete, KeySchemaElement, KeyType, Put,
+    ScalarAttributeType, TransactWriteItem,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use taskchampion_sync_server_core::runtime::run;
+use taskchampion_sync_server_core::{
+    Bytes, Client, Quota, Snapshot, Storage, StorageCapabilities, StorageError, StorageTxn, Version,
+};
+use uuid::Uuid;
+
+type Item = HashMap<String, AttributeValue>;
+
+/// The largest history segment stored, leaving room within DynamoDB's 400KiB item limit for the
+/// item's other attributes.
+const MAX_HISTORY_SEGMENT_LEN: usize = 390 * 1024;
+
+/// DynamoDB allows at most 100 items in a transaction.
+const MAX_TRANSACTION_ITEMS: usize = 100;
+
+/// The sort key of the item holding a client's metadata.
+const CLIENT_SK: &str = "client";
+/// The sort key of the item holding a client's snapshot data.
+const SNAPSHOT_SK: &str = "snapshot";
+
+/// The sort key of the item holding a version.
+fn version_sk(version_id: Uuid) -> String {
+    format!("version#{version_id}")
+}
+
+/// The sort key of the item holding the ID of the child of a version.
+fn child_sk(parent_version_id: Uuid) -> String {
+    format!("child#{parent_version_id}")
+}
+
+fn s(value: impl ToString) -> AttributeValue {
+    AttributeValue::S(value.to_string())
+}
+
+fn n(value: impl ToString) -> AttributeValue {
+    AttributeValue::N(value.to_string())
+}
+
+fn get_s<'i>(item: &'i Item, name: &str) -> anyhow::Result<&'i str> {
+    item.get(name)
+        .and_then(|v| v.as_s().ok())
+        .map(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Item is missing string attribute {name}"))
+}
+
+fn get_uuid(item: &Item, name: &str) -> anyhow::Result<Uuid> {
+    Ok(Uuid::parse_str(get_s(item, name)?)?)
+}
+
+fn get_n<T: std::str::FromStr>(item: &Item, name: &str) -> anyhow::Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let value = item
+        .get(name)
+        .and_then(|v| v.as_n().ok())
+        .ok_or_else(|| anyhow::anyhow!("Item is missing number attribute {name}"))?;
+    Ok(value.parse()?)
+}
+
+/// Get an optional number attribute.
+fn get_optional_n<T: std::str::FromStr>(item: &Item, name: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if !item.contains_key(name) {
+        return Ok(None);
+    }
+    get_n(item, name).map(Some)
+}
+
+fn get_b(item: &Item, name: &str) -> anyhow::Result<Bytes> {
+    item.get(name)
+        .and_then(|v| v.as_b().ok())
+        .map(|b| Bytes::copy_from_slice(b.as_ref()))
+        .ok_or_else(|| anyhow::anyhow!("Item is missing binary attribute {name}"))
+}
+
+/// Get an optional timestamp attribute, in seconds since the Unix epoch.
+fn get_timestamp(item: &Item, name: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+    if !item.contains_key(name) {
+        return Ok(None);
+    }
+    let timestamp = get_n(item, name)?;
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp attribute {name}"))
+}
+
+/// Decode a client item, returning the client and the item's revision.
+fn decode_client(item: &Item) -> anyhow::Result<(Client, u64)> {
+    let snapshot = match item.get("snapshot_version_id") {
+        Some(_) => Some(Snapshot {
+            version_id: get_uuid(item, "snapshot_version_id")?,
+            timestamp: get_timestamp(item, "snapshot_timestamp")?
+                .ok_or_else(|| anyhow::anyhow!("Item is missing snapshot_timestamp"))?,
+            versions_since: get_n(item, "versions_since_snapshot")?,
+        }),
+        None => None,
+    };
+    let user_agent = match item.get("user_agent") {
+        Some(_) => Some(get_s(item, "user_agent")?.to_string()),
+        None => None,
+    };
+    let client = Client {
+        latest_version_id: get_uuid(item, "latest_version_id")?,
+        snapshot,
+        created_at: get_timestamp(item, "created_at")?,
+        last_sync_at: get_timestamp(item, "last_sync_at")?,
+        user_agent,
+        quota: Quota {
+            max_bytes: get_optional_n(item, "quota_max_bytes")?,
+            max_versions: get_optional_n(item, "quota_max_versions")?,
+        },
+        deleted_at: get_timestamp(item, "deleted_at")?,
+    };
+    Ok((client, get_n(item, "rev")?))
+}
+
+/// A storage backend which uses a DynamoDB table.
+///
+/// Each transaction reads the client's metadata, including a revision number, with strongly
+/// consistent reads. On commit, all changes are written in a single DynamoDB transaction which
+/// is conditional on the revision being unchanged, and on new versions not already existing. If
+/// another transaction has modified the client in the meantime, nothing is written and the commit
+/// fails with [`StorageError::TryAgainLater`].
+///
+/// Deleting a client cannot be done in a single DynamoDB transaction, so the client item is
+/// deleted first, conditional on its revision, followed by the client's other items. If that is
+/// interrupted, the remaining items are removed when the client is next deleted.
+///
+/// Finding the client with the most versions since its snapshot requires scanning the table.
+pub struct DynamoDbStorage {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbStorage {
+    /// Create a new instance using the given table, with configuration and credentials loaded
+    /// from the environment in the usual way for AWS SDKs.
+    pub fn new(table: &str) -> anyhow::Result<Self> {
+        let config = run(aws_config::load_from_env())?;
+        Ok(Self::with_client(
+            aws_sdk_dynamodb::Client::new(&config),
+            table,
+        ))
+    }
+
+    /// Create a new instance using the given DynamoDB client and table.
+    pub fn with_client(client: aws_sdk_dynamodb::Client, table: &str) -> Self {
+        Self {
+            client,
+            table: table.to_string(),
+        }
+    }
+
+    /// Create the table, with on-demand billing.
+    pub fn create_table(&self) -> anyhow::Result<()> {
+        let request = self
+            .client
+            .create_table()
+            .table_name(&self.table)
+            .billing_mode(BillingMode::PayPerRequest)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("pk")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build()?,
+            )
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("sk")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build()?,
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("pk")
+                    .key_type(KeyType::Hash)
+                    .build()?,
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("sk")
+                    .key_type(KeyType::Range)
+                    .build()?,
+            );
+        run(request.send())?.context("Error creating DynamoDB table")?;
+        Ok(())
+    }
+
+    /// Get an item with a strongly consistent read.
+    fn get_item(&self, client_id: Uuid, sk: &str) -> anyhow::Result<Option<Item>> {
+        let request = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("pk", s(client_id))
+            .key("sk", s(sk))
+            .consistent_read(true);
+        let output = run(request.send())?.context("Error reading from DynamoDB")?;
+        Ok(output.item)
+    }
+}
+
+impl Storage for DynamoDbStorage {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        Ok(Box::new(Txn {
+            storage: self,
+            client_id,
+            stored_client: None,
+            client: None,
+            pending: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            deleted: false,
+        }))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_client_enumeration: true,
+            supports_rollback: true,
+            supports_compaction: true,
+            max_history_segment_len: MAX_HISTORY_SEGMENT_LEN,
+            supports_soft_delete: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let mut max: Option<(Uuid, u32)> = None;
+        let mut start_key = None;
+        loop {
+            let request = self
+                .client
+                .scan()
+                .table_name(&self.table)
+                .filter_expression("sk = :sk AND attribute_exists(snapshot_version_id)")
+                .expression_attribute_values(":sk", s(CLIENT_SK))
+                .set_exclusive_start_key(start_key)
+                .consistent_read(true);
+            let output = run(request.send())?.context("Error scanning DynamoDB table")?;
+            for item in output.items() {
+                let client_id = get_uuid(item, "pk")?;
+                let versions_since: u32 = get_n(item, "versions_since_snapshot")?;
+                let is_max = match max {
+                    Some((_, m)) => versions_since > m,
+                    None => true,
+                };
+                if is_max {
+                    max = Some((client_id, versions_since));
+                }
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(max);
+            }
+        }
+    }
+}
+
+struct Txn<'a> {
+    storage: &'a DynamoDbStorage,
+    client_id: Uuid,
+    /// The client as stored in the table, with its revision, once it has been read.
+    stored_client: Option<Option<(Client, u64)>>,
+    /// The client as modified in this transaction, if it has been modified.
+    client: Option<Client>,
+    /// Uncommitted items other than the client item, keyed by sort key, with a flag indicating
+    /// that the item must not already exist.
+    pending: BTreeMap<String, (Item, bool)>,
+    /// Sort keys of items to be deleted on commit.
+    removed: BTreeSet<String>,
+    /// Whether the client's stored items are deleted on commit, before `pending` is written.
+    deleted: bool,
+}
+
+impl Txn<'_> {
+    /// Get an item, including uncommitted changes.
+    fn get_item(&self, sk: &str) -> anyhow::Result<Option<Item>> {
+        if let Some((item, _)) = self.pending.get(sk) {
+            return Ok(Some(item.clone()));
+        }
+        if self.deleted || self.removed.contains(sk) {
+            return Ok(None);
+        }
+        self.storage.get_item(self.client_id, sk)
+    }
+
+    /// Add an item to be written on commit.
+    fn put_item(&mut self, sk: String, mut item: Item, must_not_exist: bool) {
+        item.insert("pk".into(), s(self.client_id));
+        item.insert("sk".into(), s(&sk));
+        self.removed.remove(&sk);
+        self.pending.insert(sk, (item, must_not_exist));
+    }
+
+    /// Add an item to be deleted on commit.
+    fn remove_item(&mut self, sk: String) {
+        self.pending.remove(&sk);
+        self.removed.insert(sk);
+    }
+
+    /// Get the stored client and its revision, reading it if necessary.
+    fn stored_client(&mut self) -> anyhow::Result<Option<(Client, u64)>> {
+        if self.stored_client.is_none() {
+            let stored = self
+                .storage
+                .get_item(self.client_id, CLIENT_SK)?
+                .map(|item| decode_client(&item))
+                .transpose()?;
+            self.stored_client = Some(stored);
+        }
+        Ok(self.stored_client.clone().flatten())
+    }
+
+    fn client_item(&self, client: &Client, rev: u64) -> Item {
+        let mut item = Item::new();
+        item.insert("pk".into(), s(self.client_id));
+        item.insert("sk".into(), s(CLIENT_SK));
+        item.insert("latest_version_id".into(), s(client.latest_version_id));
+        item.insert("rev".into(), n(rev));
+        if let Some(snap) = &client.snapshot {
+            item.insert("snapshot_version_id".into(), s(snap.version_id));
+            item.insert("snapshot_timestamp".into(), n(snap.timestamp.timestamp()));
+            item.insert("versions_since_snapshot".into(), n(snap.versions_since));
+        }
+        if let Some(created_at) = client.created_at {
+            item.insert("created_at".into(), n(created_at.timestamp()));
+        }
+        if let Some(last_sync_at) = client.last_sync_at {
+            item.insert("last_sync_at".into(), n(last_sync_at.timestamp()));
+        }
+        if let Some(user_agent) = &client.user_agent {
+            item.insert("user_agent".into(), s(user_agent));
+        }
+        if let Some(max_bytes) = client.quota.max_bytes {
+            item.insert("quota_max_bytes".into(), n(max_bytes));
+        }
+        if let Some(max_versions) = client.quota.max_versions {
+            item.insert("quota_max_versions".into(), n(max_versions));
+        }
+        if let Some(deleted_at) = client.deleted_at {
+            item.insert("deleted_at".into(), n(deleted_at.timestamp()));
+        }
+        item
+    }
+
+    /// Delete the stored client item, failing if it has been modified since it was read, and
+    /// then all of the client's other items.
+    fn delete_stored(&mut self) -> anyhow::Result<()> {
+        let storage = self.storage;
+        let table = &storage.table;
+        if let Some((_, rev)) = self.stored_client()? {
+            let request = storage
+                .client
+                .delete_item()
+                .table_name(table)
+                .key("pk", s(self.client_id))
+                .key("sk", s(CLIENT_SK))
+                .condition_expression("rev = :rev")
+                .expression_attribute_values(":rev", n(rev));
+            match run(request.send())? {
+                Ok(_) => {}
+                Err(err)
+                    if err
+                        .as_service_error()
+                        .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+                {
+                    return Err(StorageError::TryAgainLater.into());
+                }
+                Err(err) => return Err(err).context("Error deleting client from DynamoDB"),
+            }
+        }
+        self.stored_client = Some(None);
+
+        let mut start_key = None;
+        loop {
+            let request = storage
+                .client
+                .query()
+                .table_name(table)
+                .key_condition_expression("pk = :pk")
+                .expression_attribute_values(":pk", s(self.client_id))
+                .projection_expression("sk")
+                .set_exclusive_start_key(start_key)
+                .consistent_read(true);
+            let output = run(request.send())?.context("Error querying DynamoDB table")?;
+            for item in output.items() {
+                let sk = get_s(item, "sk")?;
+                // a client item here was created concurrently, after the deletion
+                if sk == CLIENT_SK {
+                    continue;
+                }
+                let request = storage
+                    .client
+                    .delete_item()
+                    .table_name(table)
+                    .key("pk", s(self.client_id))
+                    .key("sk", s(sk));
+                run(request.send())?.context("Error deleting from DynamoDB")?;
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl StorageTxn for Txn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        if let Some(client) = &self.client {
+            return Ok(Some(client.clone()));
+        }
+        if self.deleted {
+            return Ok(None);
+        }
+        Ok(self.stored_client()?.map(|(client, _)| client))
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        if self.get_client()?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already exists",
+                self.client_id
+            )));
+        }
+        self.client = Some(Client::new(latest_version_id, Utc::now()));
+        Ok(())
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        let Some(mut client) = self.get_client()? else {
+            return Ok(());
+        };
+        client.last_sync_at = Some(timestamp);
+        client.user_agent = user_agent;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.quota = quota;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.deleted_at = deleted_at;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.snapshot = Some(snapshot);
+        self.client = Some(client);
+        let item = Item::from([("data".to_string(), AttributeValue::B(Blob::new(data)))]);
+        self.put_item(SNAPSHOT_SK.to_string(), item, false);
+        Ok(())
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if Some(version_id) != client.snapshot.map(|snap| snap.version_id) {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "unexpected snapshot_version_id"
+            )));
+        }
+        Ok(self
+            .get_item(SNAPSHOT_SK)?
+            .map(|item| get_b(&item, "data"))
+            .transpose()?)
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        let Some(item) = self.get_item(&child_sk(parent_version_id))? else {
+            return Ok(None);
+        };
+        self.get_version(get_uuid(&item, "version_id")?)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        let Some(item) = self.get_item(&version_sk(version_id))? else {
+            return Ok(None);
+        };
+        Ok(Some(Version {
+            version_id,
+            parent_version_id: get_uuid(&item, "parent_version_id")?,
+            history_segment: get_b(&item, "history_segment")?,
+            created_at: get_timestamp(&item, "created_at")?,
+        }))
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.check_new_version_id(version_id)?;
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if history_segment.len() > MAX_HISTORY_SEGMENT_LEN {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "History segment of {} bytes is too large for DynamoDB",
+                history_segment.len()
+            )));
+        }
+
+        let child_sk = child_sk(parent_version_id);
+        if self.get_item(&child_sk)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a child for {}",
+                self.client_id,
+                parent_version_id
+            )));
+        }
+        let version_sk = version_sk(version_id);
+        if self.get_item(&version_sk)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a version {}",
+                self.client_id,
+                version_id
+            )));
+        }
+
+        let version_item = Item::from([
+            ("parent_version_id".to_string(), s(parent_version_id)),
+            (
+                "history_segment".to_string(),
+                AttributeValue::B(Blob::new(history_segment)),
+            ),
+            ("created_at".to_string(), n(Utc::now().timestamp())),
+        ]);
+        self.put_item(version_sk, version_item, true);
+        let child_item = Item::from([("version_id".to_string(), s(version_id))]);
+        self.put_item(child_sk, child_item, true);
+
+        client.latest_version_id = version_id;
+        if let Some(ref mut snap) = client.snapshot {
+            snap.versions_since = snap.versions_since.saturating_add(1);
+        }
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let Some(version) = self.get_version(version_id)? else {
+            return Ok(());
+        };
+        self.remove_item(version_sk(version_id));
+        let child_sk = child_sk(version.parent_version_id);
+        if let Some(child) = self.get_item(&child_sk)? {
+            if get_uuid(&child, "version_id")? == version_id {
+                self.remove_item(child_sk);
+            }
+        }
+        // the client is rewritten on commit, so that the deletion is serialized with other changes
+        if self.client.is_none() {
+            self.client = self.get_client()?;
+        }
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.client = None;
+        self.pending.clear();
+        self.removed.clear();
+        self.deleted = true;
+        Ok(())
+    }
+
+    /// DynamoDB scans are not ordered, so this scans all clients.
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let mut client_ids = vec![];
+        let mut start_key = None;
+        loop {
+            let request = self
+                .storage
+                .client
+                .scan()
+                .table_name(&self.storage.table)
+                .filter_expression("sk = :sk")
+                .expression_attribute_values(":sk", s(CLIENT_SK))
+                .projection_expression("pk")
+                .set_exclusive_start_key(start_key)
+                .consistent_read(true);
+            let output = run(request.send())?.context("Error scanning DynamoDB table")?;
+            for item in output.items() {
+                let client_id = get_uuid(item, "pk")?;
+                if after.is_none_or(|after| client_id > after) {
+                    client_ids.push(client_id);
+                }
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                break;
+            }
+        }
+        client_ids.sort_unstable();
+        client_ids.truncate(limit);
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        if std::mem::take(&mut self.deleted) {
+            self.delete_stored()?;
+        }
+        let Some(client) = self.client.take() else {
+            // every change modifies the client, so there is nothing to commit
+            return Ok(());
+        };
+        let stored_rev = self.stored_client()?.map(|(_, rev)| rev);
+        let table = &self.storage.table;
+
+        let mut items = vec![];
+        for (_, (item, must_not_exist)) in std::mem::take(&mut self.pending) {
+            let mut put = Put::builder().table_name(table).set_item(Some(item));
+            if must_not_exist {
+                put = put.condition_expression("attribute_not_exists(sk)");
+            }
+            items.push(
+                TransactWriteItem::builder()
+                    .put(put.build().context("Invalid DynamoDB put")?)
+                    .build(),
+            );
+        }
+        for sk in std::mem::take(&mut self.removed) {
+            let delete = Delete::builder()
+                .table_name(table)
+                .key("pk", s(self.client_id))
+                .key("sk", s(sk));
+            items.push(
+                TransactWriteItem::builder()
+                    .delete(delete.build().context("Invalid DynamoDB delete")?)
+                    .build(),
+            );
+        }
+
+        // the write of the client item is conditional on its revision, serializing transactions
+        let mut put = Put::builder()
+            .table_name(table)
+            .set_item(Some(self.client_item(&client, stored_rev.unwrap_or(0) + 1)));
+        put = match stored_rev {
+            Some(rev) => put
+                .condition_expression("rev = :rev")
+                .expression_attribute_values(":rev", n(rev)),
+            None => put.condition_expression("attribute_not_exists(pk)"),
+        };
+        items.push(
+            TransactWriteItem::builder()
+                .put(put.build().context("Invalid DynamoDB put")?)
+                .build(),
+        );
+
+        if items.len() > MAX_TRANSACTION_ITEMS {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Transaction has too many changes for DynamoDB"
+            )));
+        }
+        let request = self
+            .storage
+            .client
+            .transact_write_items()
+            .set_transact_items(Some(items));
+        match run(request.send())? {
+            Ok(_) => Ok(()),
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|e| e.is_transaction_canceled_exception()) =>
+            {
+                Err(StorageError::TryAgainLater)
+            }
+            Err(err) => Err(StorageError::Backend(
+                anyhow::Error::new(err).context("Error committing to DynamoDB"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::NIL_VERSION_ID;
+
+    /// Connect to the endpoint given in `TEST_DYNAMODB_ENDPOINT` and create a fresh table, or
+    /// return `None` if that variable is not set, in which case the test should be skipped.
+    fn storage() -> anyhow::Result<Option<DynamoDbStorage>> {
+        let Ok(endpoint) = std::env::var("TEST_DYNAMODB_ENDPOINT") else {
+            return Ok(None);
+        };
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .build();
+        let storage = DynamoDbStorage::with_client(
+            aws_sdk_dynamodb::Client::from_conf(config),
+            &format!("test-{}", Uuid::new_v4()),
+        );
+        storage.create_table()?;
+        Ok(Some(storage))
+    }
+
+    #[test]
+    fn test_client_item_round_trip() -> anyhow::Result<()> {
+        let storage = DynamoDbStorage::with_client(
+            aws_sdk_dynamodb::Client::from_conf(
+                aws_sdk_dynamodb::Config::builder()
+                    .behavior_version(BehaviorVersion::latest())
+                    .build(),
+            ),
+            "table",
+        );
+        let txn = Txn {
+            storage: &storage,
+            client_id: Uuid::new_v4(),
+            stored_client: None,
+            client: None,
+            pending: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            deleted: false,
+        };
+        let client = Client {
+            latest_version_id: Uuid::new_v4(),
+            snapshot: Some(
+                Snapshot::new(
+                    Uuid::new_v4(),
+                    Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+                )
+                .with_versions_since(4),
+            ),
+            created_at: Some(Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap()),
+            last_sync_at: Some(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()),
+            user_agent: Some("taskchampion/1.0".into()),
+            quota: Quota {
+                max_bytes: Some(1 << 30),
+                max_versions: None,
+            },
+            deleted_at: None,
+        };
+        assert_eq!(decode_client(&txn.client_item(&client, 7))?, (client, 7));
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+
+        // two transactions add a child of the same version
+        let mut txn1 = storage.txn(client_id)?;
+        let mut txn2 = storage.txn(client_id)?;
+        txn1.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![1].into())?;
+        txn2.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![2].into())?;
+        txn1.commit()?;
+        let err = txn2.commit().unwrap_err();
+        assert!(matches!(err, StorageError::TryAgainLater));
+
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_snapshot(
+            Snapshot::new(version_id, Utc::now()).with_versions_since(2),
+            vec![9].into(),
+        )?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![9].into()));
+        assert_eq!(storage.max_versions_since_snapshot()?, Some((client_id, 2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let mut client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.set_snapshot(Snapshot::new(Uuid::new_v4(), Utc::now()), vec![1].into())?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+
+        let mut txn = storage.txn(client_ids[0])?;
+        assert_eq!(txn.list_clients(None, 10)?, client_ids);
+        assert_eq!(
+            txn.list_clients(Some(client_ids[0]), 1)?,
+            vec![client_ids[1]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![2].into())?;
+        txn.commit()?;
+
+        // a deletion conflicting with another transaction's change is not committed
+        let mut txn1 = storage.txn(client_id)?;
+        txn1.get_client()?;
+        let mut txn2 = storage.txn(client_id)?;
+        txn2.add_version(Uuid::new_v4(), version_id, vec![3].into())?;
+        txn2.commit()?;
+        txn1.delete_client()?;
+        assert!(txn1.commit().is_err());
+        assert!(storage.txn(client_id)?.get_client()?.is_some());
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        assert_eq!(storage.get_item(client_id, SNAPSHOT_SK)?, None);
+        Ok(())
+    }
+    #[test]
+    fn test_prune_versions() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        let mut parent_version_id = Uuid::nil();
+        for vid in &vids {
+            txn.add_version(*vid, parent_version_id, vec![1].into())?;
+            parent_version_id = *vid;
+        }
+        txn.set_snapshot(Snapshot::new(vids[1], Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.prune_versions(vids[1])?, 2);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(vids[0])?, None);
+        assert_eq!(txn.get_version(vids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+        assert_eq!(
+            txn.get_version_by_parent(vids[1])?.unwrap().version_id,
+            vids[2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_sync() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let timestamp = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.created_at.is_some());
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent, Some("tc/1.0".into()));
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(storage) = storage()? else {
+                return Ok(());
+            };
+            test(&storage)
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
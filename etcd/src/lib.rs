@@ -0,0 +1,363 @@
+//! This crate implements an etcd storage backend for the TaskChampion sync server.
+//!
+//! The backend is a [`KvBackend`], storing clients, versions, child-version links, and snapshots
+//! as individual etcd keys. Use it with [`KvStorage`]:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_etcd::{EtcdKv, EtcdStorage};
+//! let storage: EtcdStorage = EtcdStorage::new(EtcdKv::new(&["http://localhost:2379"])?);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use etcd_client::{Client, Compare, CompareOp, GetOptions, KvClient, Txn, TxnOp};
+use std::collections::BTreeMap;
+use taskchampion_sync_server_core::runtime::run;
+use taskchampion_sync_server_core::{KvBackend, KvStorage, KvTxn, StorageError};
+
+/// Storage in etcd.
+pub type EtcdStorage = KvStorage<EtcdKv>;
+
+/// The default prefix for all keys written to etcd.
+pub const DEFAULT_NAMESPACE: &[u8] = b"taskchampion/";
+
+/// A [`KvBackend`] storing data in etcd.
+///
+/// Transactions are optimistic: the revision of every key read in a transaction is recorded, and
+/// on commit the transaction's writes are applied in a single etcd `Txn`, conditioned on those
+/// revisions being unchanged. If another transaction has modified any of those keys in the
+/// meantime, nothing is written and the commit fails with [`StorageError::TryAgainLater`].
+pub struct EtcdKv {
+    kv: KvClient,
+    namespace: Vec<u8>,
+}
+
+impl EtcdKv {
+    /// Create a new instance using the etcd cluster at the given endpoints, such as
+    /// `http://localhost:2379`, with keys prefixed by [`DEFAULT_NAMESPACE`].
+    pub fn new<S: AsRef<str>>(endpoints: &[S]) -> anyhow::Result<Self> {
+        Self::with_namespace(endpoints, DEFAULT_NAMESPACE)
+    }
+
+    /// Create a new instance with all keys prefixed by `namespace`, allowing several servers or
+    /// other applications to share an etcd cluster.
+    pub fn with_namespace<S: AsRef<str>>(
+        endpoints: &[S],
+        namespace: &[u8],
+    ) -> anyhow::Result<Self> {
+        let endpoints: Vec<String> = endpoints.iter().map(|e| e.as_ref().to_string()).collect();
+        let client = run(Client::connect(endpoints, None))?.context("Error connecting to etcd")?;
+        Ok(Self {
+            kv: client.kv_client(),
+            namespace: namespace.to_vec(),
+        })
+    }
+}
+
+impl KvBackend for EtcdKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(EtcdKvTxn {
+            kv: self.kv.clone(),
+            namespace: &self.namespace,
+            reads: BTreeMap::new(),
+            scans: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        }))
+    }
+}
+
+struct EtcdKvTxn<'a> {
+    kv: KvClient,
+    namespace: &'a [u8],
+    /// The modification revision of each key (including namespace) read in this transaction, or
+    /// 0 if the key did not exist.
+    reads: BTreeMap<Vec<u8>, i64>,
+    /// The cluster revision at which each prefix (including namespace) was scanned.
+    scans: BTreeMap<Vec<u8>, i64>,
+    /// Uncommitted changes, with `None` representing a deletion.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl EtcdKvTxn<'_> {
+    fn etcd_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut etcd_key = self.namespace.to_vec();
+        etcd_key.extend_from_slice(key);
+        etcd_key
+    }
+
+    /// Record the revision of a read key, keeping the first revision seen.
+    fn record_read(&mut self, etcd_key: Vec<u8>, mod_revision: i64) {
+        self.reads.entry(etcd_key).or_insert(mod_revision);
+    }
+}
+
+impl KvTxn for EtcdKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        let etcd_key = self.etcd_key(key);
+        let mut kv = self.kv.clone();
+        let resp = run({
+            let etcd_key = etcd_key.clone();
+            async move { kv.get(etcd_key, None).await }
+        })??;
+        match resp.kvs().first() {
+            Some(item) => {
+                self.record_read(etcd_key, item.mod_revision());
+                Ok(Some(item.value().to_vec()))
+            }
+            None => {
+                self.record_read(etcd_key, 0);
+                Ok(None)
+            }
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.pending.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let etcd_prefix = self.etcd_key(prefix);
+        let mut kv = self.kv.clone();
+        let resp = run({
+            let etcd_prefix = etcd_prefix.clone();
+            async move {
+                kv.get(etcd_prefix, Some(GetOptions::new().with_prefix()))
+                    .await
+            }
+        })??;
+        let revision = resp
+            .header()
+            .map(|h| h.revision())
+            .ok_or_else(|| anyhow::anyhow!("etcd response has no header"))?;
+        self.scans.entry(etcd_prefix).or_insert(revision);
+
+        let mut result = BTreeMap::new();
+        for item in resp.kvs() {
+            self.record_read(item.key().to_vec(), item.mod_revision());
+            result.insert(
+                item.key()[self.namespace.len()..].to_vec(),
+                item.value().to_vec(),
+            );
+        }
+        for (k, v) in self
+            .pending
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+        {
+            match v {
+                Some(v) => result.insert(k.clone(), v.clone()),
+                None => result.remove(k),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // keys read, whether present or absent, must not have been modified since
+        let mut compares: Vec<Compare> = std::mem::take(&mut self.reads)
+            .into_iter()
+            .map(|(k, rev)| Compare::mod_revision(k, CompareOp::Equal, rev))
+            .collect();
+        // no keys may have been added to or modified in a scanned prefix since the scan;
+        // deletions are caught by the reads above
+        compares.extend(
+            std::mem::take(&mut self.scans)
+                .into_iter()
+                .map(|(prefix, rev)| {
+                    Compare::mod_revision(prefix, CompareOp::Less, rev + 1).with_prefix()
+                }),
+        );
+        let ops: Vec<TxnOp> = pending
+            .into_iter()
+            .map(|(k, v)| {
+                let etcd_key = self.etcd_key(&k);
+                match v {
+                    Some(v) => TxnOp::put(etcd_key, v, None),
+                    None => TxnOp::delete(etcd_key, None),
+                }
+            })
+            .collect();
+        let txn = Txn::new().when(compares).and_then(ops);
+        let mut kv = self.kv.clone();
+        let resp = run(async move { kv.txn(txn).await })?.context("Error committing to etcd")?;
+        if !resp.succeeded() {
+            return Err(StorageError::TryAgainLater.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Connect to the test server given in `TEST_ETCD_ENDPOINT`, or return `None` if that
+    /// variable is not set, in which case the test should be skipped. Each call uses a fresh
+    /// namespace.
+    fn backend() -> anyhow::Result<Option<EtcdKv>> {
+        let Ok(endpoint) = std::env::var("TEST_ETCD_ENDPOINT") else {
+            return Ok(None);
+        };
+        let namespace = format!("test-{}/", Uuid::new_v4());
+        Ok(Some(EtcdKv::with_namespace(
+            &[endpoint],
+            namespace.as_bytes(),
+        )?))
+    }
+
+    fn assert_try_again_later(err: anyhow::Error) {
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::TryAgainLater)
+        ));
+    }
+
+    #[test]
+    fn test_get_put_delete() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn = kv.txn()?;
+        txn.put(b"a", b"1")?;
+        txn.put(b"b", b"2")?;
+        // reads see uncommitted writes
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        txn.delete(b"a")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, None);
+        assert_eq!(txn.get(b"b")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn = kv.txn()?;
+        txn.put(b"a/1", b"x")?;
+        txn.put(b"a/2", b"y")?;
+        txn.put(b"ab", b"z")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        txn.put(b"a/3", b"w")?;
+        txn.delete(b"a/1")?;
+        assert_eq!(
+            txn.scan_prefix(b"a/")?,
+            vec![
+                (b"a/2".to_vec(), b"y".to_vec()),
+                (b"a/3".to_vec(), b"w".to_vec())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"a", b"1")?;
+        }
+        assert_eq!(kv.txn()?.get(b"a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.get(b"a")?, None);
+
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"a", b"2")?;
+        txn2.commit()?;
+
+        txn1.put(b"a", b"1")?;
+        assert_try_again_later(txn1.commit().unwrap_err());
+        assert_eq!(kv.txn()?.get(b"a")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_conflict() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.scan_prefix(b"a/")?, vec![]);
+
+        // a key added within the scanned prefix conflicts
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"a/1", b"2")?;
+        txn2.commit()?;
+
+        txn1.put(b"b", b"1")?;
+        assert_try_again_later(txn1.commit().unwrap_err());
+        assert_eq!(kv.txn()?.get(b"b")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let storage = EtcdStorage::new(kv);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(kv) = backend()? else {
+                return Ok(());
+            };
+            test(&EtcdStorage::new(kv))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,358 @@
+//! This crate implements a FoundationDB storage backend for the TaskChampion sync server.
+//!
+//! The backend is a [`KvBackend`], so use it with [`KvStorage`]. The FoundationDB network must be
+//! started with [`boot`] before any instances are created, and the returned guard kept alive
+//! until the storage is no longer needed:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_foundationdb::{boot, FdbKv, FdbStorage};
+//! // SAFETY: the network is started once, and the guard is dropped before the process exits.
+//! let _network = unsafe { boot() };
+//! let storage: FdbStorage = FdbStorage::new(FdbKv::new(None)?);
+//! # Ok(())
+//! # }
+//! ```
+use foundationdb::future::FdbValue;
+use foundationdb::{Database, RangeOption, Transaction};
+use futures::executor::block_on;
+use futures::TryStreamExt;
+use taskchampion_sync_server_core::{KvBackend, KvStorage, KvTxn, StorageError};
+
+pub use foundationdb::boot;
+
+/// Storage in FoundationDB.
+pub type FdbStorage = KvStorage<FdbKv>;
+
+/// The default prefix for all keys written to FoundationDB.
+pub const DEFAULT_PREFIX: &[u8] = b"taskchampion/";
+
+/// The largest chunk of a value stored in a single FoundationDB value, which is limited to 100kB.
+const CHUNK_SIZE: usize = 90_000;
+
+/// The subspace containing each key's first chunk, preceded by the number of chunks.
+const MAIN: u8 = 0;
+/// The subspace containing each key's subsequent chunks, keyed by chunk index.
+const CHUNKS: u8 = 1;
+
+/// Get the first key after all keys beginning with `prefix`.
+fn strinc(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // every byte was 0xff; this cannot occur for the keys used here
+    vec![0xff]
+}
+
+/// A [`KvBackend`] storing data in a FoundationDB cluster.
+///
+/// Each transaction is a FoundationDB transaction, and so is serializable across all servers
+/// sharing the cluster. If a transaction conflicts with another, its commit fails with
+/// [`StorageError::TryAgainLater`].
+///
+/// FoundationDB limits values to 100kB, so larger values are split into chunks. Transactions are
+/// limited to 10MB, which limits the size of history segments and snapshots.
+pub struct FdbKv {
+    db: Database,
+    prefix: Vec<u8>,
+}
+
+impl FdbKv {
+    /// Create a new instance using the cluster described by the given cluster file, or the
+    /// default cluster file if `None`, with keys prefixed by [`DEFAULT_PREFIX`].
+    pub fn new(cluster_file: Option<&str>) -> anyhow::Result<Self> {
+        Self::with_prefix(cluster_file, DEFAULT_PREFIX)
+    }
+
+    /// Create a new instance with all keys prefixed by `prefix`, allowing several servers or
+    /// other applications to share a cluster.
+    pub fn with_prefix(cluster_file: Option<&str>, prefix: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            db: Database::new(cluster_file)?,
+            prefix: prefix.to_vec(),
+        })
+    }
+}
+
+impl KvBackend for FdbKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(FdbKvTxn {
+            prefix: &self.prefix,
+            trx: Some(self.db.create_trx()?),
+        }))
+    }
+}
+
+struct FdbKvTxn<'a> {
+    prefix: &'a [u8],
+    /// The FoundationDB transaction, until it is committed.
+    trx: Option<Transaction>,
+}
+
+impl FdbKvTxn<'_> {
+    fn trx(&self) -> anyhow::Result<&Transaction> {
+        self.trx
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Transaction already committed"))
+    }
+
+    fn main_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut main_key = self.prefix.to_vec();
+        main_key.push(MAIN);
+        main_key.extend_from_slice(key);
+        main_key
+    }
+
+    fn chunk_key(&self, key: &[u8], index: u16) -> Vec<u8> {
+        let mut chunk_key = self.prefix.to_vec();
+        chunk_key.push(CHUNKS);
+        chunk_key.extend_from_slice(key);
+        chunk_key.extend_from_slice(&index.to_be_bytes());
+        chunk_key
+    }
+
+    /// Get the number of chunks in the stored value for a key, or 0 if there is no value.
+    fn chunk_count(&self, key: &[u8]) -> anyhow::Result<u16> {
+        match block_on(self.trx()?.get(&self.main_key(key), false))? {
+            Some(main) => Ok(u16::from_be_bytes(
+                main.get(..2)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid value"))?
+                    .try_into()?,
+            )),
+            None => Ok(0),
+        }
+    }
+
+    /// Reassemble the value for a key, given the content of its main key.
+    fn read_value(&self, key: &[u8], main: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let count = u16::from_be_bytes(
+            main.get(..2)
+                .ok_or_else(|| anyhow::anyhow!("Invalid value"))?
+                .try_into()?,
+        );
+        let mut value = main[2..].to_vec();
+        for index in 1..count {
+            let chunk = block_on(self.trx()?.get(&self.chunk_key(key, index), false))?
+                .ok_or_else(|| anyhow::anyhow!("Missing chunk {index} of value"))?;
+            value.extend_from_slice(&chunk);
+        }
+        Ok(value)
+    }
+
+    /// Clear the chunks after the first for a key.
+    fn clear_chunks(&self, key: &[u8], count: u16) -> anyhow::Result<()> {
+        for index in 1..count {
+            self.trx()?.clear(&self.chunk_key(key, index));
+        }
+        Ok(())
+    }
+}
+
+impl KvTxn for FdbKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(main) = block_on(self.trx()?.get(&self.main_key(key), false))? else {
+            return Ok(None);
+        };
+        Ok(Some(self.read_value(key, &main)?))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.clear_chunks(key, self.chunk_count(key)?)?;
+        let chunks: Vec<&[u8]> = if value.is_empty() {
+            vec![value]
+        } else {
+            value.chunks(CHUNK_SIZE).collect()
+        };
+        let count = u16::try_from(chunks.len())
+            .map_err(|_| anyhow::anyhow!("Value of {} bytes is too large", value.len()))?;
+        let mut main = count.to_be_bytes().to_vec();
+        main.extend_from_slice(chunks[0]);
+        let trx = self.trx()?;
+        trx.set(&self.main_key(key), &main);
+        for (index, chunk) in (1..count).zip(&chunks[1..]) {
+            trx.set(&self.chunk_key(key, index), chunk);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.clear_chunks(key, self.chunk_count(key)?)?;
+        self.trx()?.clear(&self.main_key(key));
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let begin = self.main_key(prefix);
+        let end = strinc(&begin);
+        let values: Vec<FdbValue> = block_on(
+            self.trx()?
+                .get_ranges_keyvalues(RangeOption::from((begin, end)), false)
+                .try_collect(),
+        )?;
+        let main_prefix_len = self.prefix.len() + 1;
+        values
+            .iter()
+            .map(|kv| {
+                let key = &kv.key()[main_prefix_len..];
+                Ok((key.to_vec(), self.read_value(key, kv.value())?))
+            })
+            .collect()
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        let trx = self
+            .trx
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Transaction already committed"))?;
+        match block_on(trx.commit()) {
+            Ok(_) => Ok(()),
+            Err(err) if err.is_retryable() => Err(StorageError::TryAgainLater.into()),
+            Err(err) => Err(anyhow::anyhow!("Error committing to FoundationDB: {err}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::Once;
+    use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Connect to the cluster given by `TEST_FDB_CLUSTER_FILE`, or return `None` if that
+    /// variable is not set, in which case the test should be skipped. Each call uses a fresh
+    /// prefix.
+    fn backend() -> anyhow::Result<Option<FdbKv>> {
+        let Ok(cluster_file) = std::env::var("TEST_FDB_CLUSTER_FILE") else {
+            return Ok(None);
+        };
+        static BOOT: Once = Once::new();
+        // SAFETY: the network is started only once, and never stopped.
+        BOOT.call_once(|| std::mem::forget(unsafe { boot() }));
+        let prefix = format!("test-{}/", Uuid::new_v4());
+        Ok(Some(FdbKv::with_prefix(
+            Some(&cluster_file),
+            prefix.as_bytes(),
+        )?))
+    }
+
+    #[test]
+    fn test_strinc() {
+        assert_eq!(strinc(b"ab"), b"ac");
+        assert_eq!(strinc(b"a\xff"), b"b");
+        assert_eq!(strinc(b"\x00"), b"\x01");
+    }
+
+    #[test]
+    fn test_get_put_delete() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn = kv.txn()?;
+        txn.put(b"a", b"1")?;
+        txn.put(b"b", b"")?;
+        // reads see uncommitted writes
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(txn.get(b"b")?, Some(vec![]));
+        txn.delete(b"a")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_values() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let large: Vec<u8> = (0..CHUNK_SIZE * 2 + 17).map(|i| i as u8).collect();
+        let mut txn = kv.txn()?;
+        txn.put(b"a", &large)?;
+        txn.put(b"b", b"small")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, Some(large.clone()));
+        assert_eq!(
+            txn.scan_prefix(b"")?,
+            vec![(b"a".to_vec(), large), (b"b".to_vec(), b"small".to_vec())]
+        );
+        // replacing a large value with a small one removes its chunks
+        txn.put(b"a", b"x")?;
+        txn.commit()?;
+
+        let txn = FdbKvTxn {
+            prefix: &kv.prefix,
+            trx: Some(kv.db.create_trx()?),
+        };
+        assert_eq!(txn.chunk_count(b"a")?, 1);
+        assert!(block_on(txn.trx()?.get(&txn.chunk_key(b"a", 1), false))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.get(b"a")?, None);
+
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"a", b"2")?;
+        txn2.commit()?;
+
+        txn1.put(b"a", b"1")?;
+        let err = txn1.commit().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::TryAgainLater)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let storage = FdbStorage::new(kv);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(kv) = backend()? else {
+                return Ok(());
+            };
+            test(&FdbStorage::new(kv))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,1151 @@
+//! This crate implements a plain filesystem storage backend for the TaskChampion sync server.
+//!
+//! Each client is stored as a directory of files, described in the crate's README, so that the
+//! server's data can be inspected and backed up with ordinary tools.
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use taskchampion_sync_server_core::{
+    Bytes, Client, Quota, Snapshot, Storage, StorageCapabilities, StorageError, StorageTxn,
+    Version, VersionReader,
+};
+use uuid::Uuid;
+
+/// The file containing a client's metadata.
+const CLIENT_FILE: &str = "client.json";
+/// The file containing a client's snapshot data.
+const SNAPSHOT_FILE: &str = "snapshot";
+/// The directory in which a transaction's files are staged during commit.
+const STAGING_DIR: &str = ".txn";
+/// The file listing the staged files, whose presence marks a transaction as committed.
+const MANIFEST_FILE: &str = "manifest";
+/// The prefix of a line in the manifest naming a file to be removed, rather than a staged file.
+const MANIFEST_REMOVE_PREFIX: &str = "-";
+/// The prefix of the name to which a client's directory is renamed while it is being deleted.
+const DELETED_DIR_PREFIX: &str = ".deleted-";
+/// The directory in which streamed snapshot data is spooled until commit.
+const SPOOL_DIR: &str = ".spool";
+
+/// The contents of [`CLIENT_FILE`].
+#[derive(Serialize, Deserialize)]
+struct ClientFile {
+    latest_version_id: Uuid,
+    snapshot: Option<SnapshotFile>,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    created_at: Option<i64>,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    last_sync_at: Option<i64>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    quota_max_bytes: Option<u64>,
+    #[serde(default)]
+    quota_max_versions: Option<u64>,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    deleted_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    version_id: Uuid,
+    /// Seconds since the Unix epoch.
+    timestamp: i64,
+    versions_since: u32,
+}
+
+impl From<&Client> for ClientFile {
+    fn from(client: &Client) -> Self {
+        ClientFile {
+            latest_version_id: client.latest_version_id,
+            snapshot: client.snapshot.as_ref().map(|snap| SnapshotFile {
+                version_id: snap.version_id,
+                timestamp: snap.timestamp.timestamp(),
+                versions_since: snap.versions_since,
+            }),
+            created_at: client.created_at.map(|t| t.timestamp()),
+            last_sync_at: client.last_sync_at.map(|t| t.timestamp()),
+            user_agent: client.user_agent.clone(),
+            quota_max_bytes: client.quota.max_bytes,
+            quota_max_versions: client.quota.max_versions,
+            deleted_at: client.deleted_at.map(|t| t.timestamp()),
+        }
+    }
+}
+
+/// Convert a timestamp in seconds since the Unix epoch to a `DateTime`.
+fn parse_timestamp(timestamp: i64) -> anyhow::Result<DateTime<Utc>> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp {timestamp}"))
+}
+
+impl TryFrom<ClientFile> for Client {
+    type Error = anyhow::Error;
+
+    fn try_from(file: ClientFile) -> anyhow::Result<Self> {
+        let snapshot = file
+            .snapshot
+            .map(|snap| -> anyhow::Result<Snapshot> {
+                Ok(Snapshot {
+                    version_id: snap.version_id,
+                    timestamp: parse_timestamp(snap.timestamp)?,
+                    versions_since: snap.versions_since,
+                })
+            })
+            .transpose()?;
+        Ok(Client {
+            latest_version_id: file.latest_version_id,
+            snapshot,
+            created_at: file.created_at.map(parse_timestamp).transpose()?,
+            last_sync_at: file.last_sync_at.map(parse_timestamp).transpose()?,
+            user_agent: file.user_agent,
+            quota: Quota {
+                max_bytes: file.quota_max_bytes,
+                max_versions: file.quota_max_versions,
+            },
+            deleted_at: file.deleted_at.map(parse_timestamp).transpose()?,
+        })
+    }
+}
+
+/// Read a file, returning `None` if it does not exist.
+fn read_optional(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Error reading `{}`", path.display())),
+    }
+}
+
+/// Write a file and flush it to disk.
+fn write_synced(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut file =
+        fs::File::create(path).with_context(|| format!("Error creating `{}`", path.display()))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A file of uncommitted data in [`SPOOL_DIR`], removed when dropped unless it has been moved
+/// into place.
+struct SpoolFile {
+    path: PathBuf,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                log::warn!("Failed to remove `{}`: {err:?}", self.path.display())
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The uncommitted contents of a file.
+enum PendingData {
+    Bytes(Bytes),
+    Spooled(SpoolFile),
+}
+
+/// Flush a directory to disk, so that renames within it are durable.
+fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Move staged files into place, if the staged transaction was committed, and remove the staging
+/// directory. This completes a commit that was interrupted, or discards one that was not yet
+/// committed.
+fn finish_staged(client_dir: &Path) -> anyhow::Result<()> {
+    let staging_dir = client_dir.join(STAGING_DIR);
+    if let Some(manifest) = read_optional(&staging_dir.join(MANIFEST_FILE))? {
+        let manifest = String::from_utf8(manifest).context("Invalid manifest")?;
+        for (i, rel_path) in manifest.lines().enumerate() {
+            if let Some(rel_path) = rel_path.strip_prefix(MANIFEST_REMOVE_PREFIX) {
+                match fs::remove_file(client_dir.join(rel_path)) {
+                    // a file that is already gone was removed before the commit was interrupted
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                    _ => continue,
+                }
+            }
+            let staged = staging_dir.join(i.to_string());
+            // a file that is already gone was moved before the commit was interrupted
+            if !staged.exists() {
+                continue;
+            }
+            let target = client_dir.join(rel_path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&staged, &target)
+                .with_context(|| format!("Error moving `{}` into place", target.display()))?;
+        }
+        sync_dir(client_dir)?;
+    }
+    match fs::remove_dir_all(&staging_dir) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A storage backend which stores each client as a directory of plain files.
+///
+/// Transactions for the same client are serialized: a second call to `txn` for a client blocks
+/// until the first transaction for that client is dropped. Transactions for different clients
+/// proceed concurrently. The directory must not be shared by several server processes.
+///
+/// Changes are buffered in memory until commit, except that snapshot data and history segments
+/// given as readers are spooled to files in the `.spool` directory. On commit, the changed files are written to the
+/// client's staging directory, followed by a manifest listing their final paths, and are then
+/// renamed into place. Writing the manifest is the point at which the commit takes effect: if the
+/// commit is interrupted, the next transaction for the client either completes it, if the manifest
+/// was written, or discards it.
+pub struct FsStorage {
+    root: PathBuf,
+    /// Clients with an active transaction.
+    locked: Mutex<HashSet<Uuid>>,
+    /// Notified when a client is removed from `locked`.
+    unlocked: Condvar,
+}
+
+impl FsStorage {
+    /// Create a new instance storing data in the given directory, creating it if necessary.
+    pub fn new<P: AsRef<Path>>(directory: P) -> anyhow::Result<FsStorage> {
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("Failed to create `{}`.", directory.as_ref().display()))?;
+        // spooled data left behind by a crash was never committed
+        match fs::remove_dir_all(directory.as_ref().join(SPOOL_DIR)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        Ok(FsStorage {
+            root: directory.as_ref().to_path_buf(),
+            locked: Mutex::new(HashSet::new()),
+            unlocked: Condvar::new(),
+        })
+    }
+
+    /// Read the metadata for the client stored in the given directory, if any.
+    fn read_client(client_dir: &Path) -> Result<Option<Client>, StorageError> {
+        let Some(data) = read_optional(&client_dir.join(CLIENT_FILE))? else {
+            return Ok(None);
+        };
+        let file: ClientFile = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid client file in `{}`", client_dir.display()))?;
+        Ok(Some(file.try_into()?))
+    }
+}
+
+impl Storage for FsStorage {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
+        let mut locked = self.locked.lock().expect("poisoned lock");
+        while locked.contains(&client_id) {
+            locked = self.unlocked.wait(locked).expect("poisoned lock");
+        }
+        locked.insert(client_id);
+        drop(locked);
+
+        // the transaction releases the lock when dropped, including on error below
+        let txn = FsTxn {
+            storage: self,
+            client_id,
+            dir: self.root.join(client_id.to_string()),
+            pending: BTreeMap::new(),
+            deleted: false,
+        };
+        finish_staged(&txn.dir)?;
+        Ok(Box::new(txn))
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_client_enumeration: true,
+            supports_rollback: true,
+            supports_compaction: true,
+            supports_streaming: true,
+            supports_soft_delete: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let mut max: Option<(Uuid, u32)> = None;
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let Some(client_id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            let Some(client) = Self::read_client(&entry.path())? else {
+                continue;
+            };
+            if let Some(snap) = client.snapshot {
+                let is_max = match max {
+                    Some((_, versions_since)) => snap.versions_since > versions_since,
+                    None => true,
+                };
+                if is_max {
+                    max = Some((client_id, snap.versions_since));
+                }
+            }
+        }
+        Ok(max)
+    }
+}
+
+struct FsTxn<'a> {
+    storage: &'a FsStorage,
+    client_id: Uuid,
+    dir: PathBuf,
+    /// Uncommitted file contents, keyed by path relative to `dir`, or None for files to remove.
+    pending: BTreeMap<String, Option<PendingData>>,
+    /// Whether the client's existing files are deleted on commit, before `pending` is written.
+    deleted: bool,
+}
+
+impl FsTxn<'_> {
+    fn read(&self, rel_path: &str) -> Result<Option<Bytes>, StorageError> {
+        match self.pending.get(rel_path) {
+            Some(Some(PendingData::Bytes(data))) => Ok(Some(data.clone())),
+            Some(Some(PendingData::Spooled(spool))) => Ok(Some(fs::read(&spool.path)?.into())),
+            Some(None) => Ok(None),
+            None if self.deleted => Ok(None),
+            None => Ok(read_optional(&self.dir.join(rel_path))?.map(Bytes::from)),
+        }
+    }
+
+    /// Open a file for reading, returning `None` if it does not exist.
+    fn open(&self, rel_path: &str) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        let path = match self.pending.get(rel_path) {
+            Some(Some(PendingData::Bytes(data))) => {
+                return Ok(Some(Box::new(Cursor::new(data.clone()))))
+            }
+            Some(Some(PendingData::Spooled(spool))) => spool.path.clone(),
+            Some(None) => return Ok(None),
+            None if self.deleted => return Ok(None),
+            None => self.dir.join(rel_path),
+        };
+        match fs::File::open(&path) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StorageError::Backend(
+                anyhow::Error::new(err).context(format!("Error opening `{}`", path.display())),
+            )),
+        }
+    }
+
+    fn write(&mut self, rel_path: String, data: impl Into<Bytes>) {
+        self.pending
+            .insert(rel_path, Some(PendingData::Bytes(data.into())));
+    }
+
+    /// Copy data from a reader into a new file in [`SPOOL_DIR`], to be moved into place at
+    /// `rel_path` on commit.
+    fn write_spooled(&mut self, rel_path: String, data: &mut dyn Read) -> Result<(), StorageError> {
+        let spool_dir = self.storage.root.join(SPOOL_DIR);
+        fs::create_dir_all(&spool_dir)
+            .with_context(|| format!("Failed to create `{}`.", spool_dir.display()))?;
+        let spool = SpoolFile {
+            path: spool_dir.join(Uuid::new_v4().to_string()),
+        };
+        let mut file = fs::File::create(&spool.path)
+            .with_context(|| format!("Error creating `{}`", spool.path.display()))?;
+        std::io::copy(data, &mut file)?;
+        file.sync_all()?;
+        self.pending
+            .insert(rel_path, Some(PendingData::Spooled(spool)));
+        Ok(())
+    }
+
+    fn remove(&mut self, rel_path: String) {
+        self.pending.insert(rel_path, None);
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), StorageError> {
+        let data = serde_json::to_vec_pretty(&ClientFile::from(client))
+            .context("Error serializing client")?;
+        self.write(CLIENT_FILE.to_string(), data);
+        Ok(())
+    }
+
+    fn version_path(version_id: Uuid) -> String {
+        format!("versions/{version_id}")
+    }
+
+    fn child_path(parent_version_id: Uuid) -> String {
+        format!("children/{parent_version_id}")
+    }
+
+    fn time_path(version_id: Uuid) -> String {
+        format!("times/{version_id}")
+    }
+
+    /// Add a version, writing its file to the given path with `write`, after checking that it
+    /// does not conflict with an existing version.
+    fn put_version<F>(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        write: F,
+    ) -> Result<(), StorageError>
+    where
+        F: FnOnce(&mut Self, String) -> Result<(), StorageError>,
+    {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        self.check_new_version_id(version_id)?;
+
+        let child_path = Self::child_path(parent_version_id);
+        if self.read(&child_path)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a child for {}",
+                self.client_id,
+                parent_version_id
+            )));
+        }
+        let version_path = Self::version_path(version_id);
+        if self.read(&version_path)?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already has a version {}",
+                self.client_id,
+                version_id
+            )));
+        }
+
+        write(self, version_path)?;
+        self.write(child_path, format!("{version_id}\n").into_bytes());
+        self.write(
+            Self::time_path(version_id),
+            format!("{}\n", Utc::now().timestamp()).into_bytes(),
+        );
+
+        client.latest_version_id = version_id;
+        if let Some(ref mut snap) = client.snapshot {
+            snap.versions_since = snap.versions_since.saturating_add(1);
+        }
+        self.put_client(&client)
+    }
+
+    /// Remove the client's directory. The directory is first renamed, which is the point at which
+    /// the deletion takes effect, so that an interrupted removal does not leave a partial client.
+    fn remove_dir(&self) -> Result<(), StorageError> {
+        let removing = self
+            .storage
+            .root
+            .join(format!("{DELETED_DIR_PREFIX}{}", self.client_id));
+        // left behind by an earlier interrupted removal
+        if removing.exists() {
+            fs::remove_dir_all(&removing)?;
+        }
+        match fs::rename(&self.dir, &removing) {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            res => res.with_context(|| format!("Error removing `{}`", self.dir.display()))?,
+        }
+        sync_dir(&self.storage.root)?;
+        if let Err(err) = fs::remove_dir_all(&removing) {
+            log::warn!("Failed to remove `{}`: {err:?}", removing.display());
+        }
+        Ok(())
+    }
+}
+
+impl StorageTxn for FsTxn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
+        if self.pending.contains_key(CLIENT_FILE) || self.deleted {
+            let Some(data) = self.read(CLIENT_FILE)? else {
+                return Ok(None);
+            };
+            let file: ClientFile = serde_json::from_slice(&data).context("Invalid client file")?;
+            return Ok(Some(file.try_into()?));
+        }
+        FsStorage::read_client(&self.dir)
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
+        if self.get_client()?.is_some() {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Client {} already exists",
+                self.client_id
+            )));
+        }
+        self.put_client(&Client::new(latest_version_id, Utc::now()))
+    }
+
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        let Some(mut client) = self.get_client()? else {
+            return Ok(());
+        };
+        client.last_sync_at = Some(timestamp);
+        client.user_agent = user_agent;
+        self.put_client(&client)
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.quota = quota;
+        self.put_client(&client)
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.deleted_at = deleted_at;
+        self.put_client(&client)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.snapshot = Some(snapshot);
+        self.put_client(&client)?;
+        self.write(SNAPSHOT_FILE.to_string(), data);
+        Ok(())
+    }
+
+    /// The data is spooled to a file rather than held in memory.
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        data: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        let mut client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        client.snapshot = Some(snapshot);
+        self.put_client(&client)?;
+        self.write_spooled(SNAPSHOT_FILE.to_string(), data)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if Some(version_id) != client.snapshot.map(|snap| snap.version_id) {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "unexpected snapshot_version_id"
+            )));
+        }
+        self.read(SNAPSHOT_FILE)
+    }
+
+    fn get_snapshot_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<Box<dyn Read + Send>>, StorageError> {
+        let client = self.get_client()?.ok_or(StorageError::NotFound)?;
+        if Some(version_id) != client.snapshot.map(|snap| snap.version_id) {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "unexpected snapshot_version_id"
+            )));
+        }
+        self.open(SNAPSHOT_FILE)
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<Version>, StorageError> {
+        let Some(child) = self.read(&Self::child_path(parent_version_id))? else {
+            return Ok(None);
+        };
+        let child = std::str::from_utf8(&child).context("Invalid child file")?;
+        self.get_version(Uuid::parse_str(child.trim()).context("Invalid child file")?)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
+        let Some(data) = self.read(&Self::version_path(version_id))? else {
+            return Ok(None);
+        };
+        let newline = data
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| anyhow::anyhow!("Invalid version file for {version_id}"))?;
+        let parent = std::str::from_utf8(&data[..newline]).context("Invalid version file")?;
+        // versions added before times were recorded have no time file
+        let created_at = match self.read(&Self::time_path(version_id))? {
+            Some(time) => {
+                let time = std::str::from_utf8(&time).context("Invalid time file")?;
+                Some(parse_timestamp(
+                    time.trim().parse().context("Invalid time file")?,
+                )?)
+            }
+            None => None,
+        };
+        Ok(Some(Version {
+            version_id,
+            parent_version_id: Uuid::parse_str(parent).context("Invalid version file")?,
+            history_segment: data.slice(newline + 1..),
+            created_at,
+        }))
+    }
+
+    fn get_version_reader_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        let Some(child) = self.read(&Self::child_path(parent_version_id))? else {
+            return Ok(None);
+        };
+        let child = std::str::from_utf8(&child).context("Invalid child file")?;
+        self.get_version_reader(Uuid::parse_str(child.trim()).context("Invalid child file")?)
+    }
+
+    fn get_version_reader(
+        &mut self,
+        version_id: Uuid,
+    ) -> Result<Option<VersionReader>, StorageError> {
+        let Some(file) = self.open(&Self::version_path(version_id))? else {
+            return Ok(None);
+        };
+        // the parent version ID is on the first line, and the rest is the history segment
+        let mut file = BufReader::new(file);
+        let mut parent = String::new();
+        file.read_line(&mut parent)?;
+        let parent = parent
+            .strip_suffix('\n')
+            .ok_or_else(|| anyhow::anyhow!("Invalid version file for {version_id}"))?;
+        Ok(Some(VersionReader {
+            version_id,
+            parent_version_id: Uuid::parse_str(parent).context("Invalid version file")?,
+            history_segment: Box::new(file),
+        }))
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.put_version(version_id, parent_version_id, |txn, version_path| {
+            let mut data = format!("{parent_version_id}\n").into_bytes();
+            data.extend_from_slice(&history_segment);
+            txn.write(version_path, data);
+            Ok(())
+        })
+    }
+
+    /// The segment is spooled to a file rather than held in memory.
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &mut dyn Read,
+    ) -> Result<(), StorageError> {
+        self.put_version(version_id, parent_version_id, |txn, version_path| {
+            let header = format!("{parent_version_id}\n").into_bytes();
+            txn.write_spooled(version_path, &mut header.as_slice().chain(history_segment))
+        })
+    }
+
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        let Some(version) = self.get_version(version_id)? else {
+            return Ok(());
+        };
+        self.remove(Self::version_path(version_id));
+        self.remove(Self::time_path(version_id));
+        let child_path = Self::child_path(version.parent_version_id);
+        if let Some(child) = self.read(&child_path)? {
+            if std::str::from_utf8(&child).is_ok_and(|c| c.trim() == version_id.to_string()) {
+                self.remove(child_path);
+            }
+        }
+        Ok(())
+    }
+
+    /// The client's directory is removed on commit, by first renaming it out of place. If this
+    /// transaction also creates the client again, the new files are written after that rename, so
+    /// an interrupted commit may leave the client deleted but not re-created.
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.pending.clear();
+        self.deleted = true;
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let mut client_ids = vec![];
+        for entry in fs::read_dir(&self.storage.root)? {
+            let entry = entry?;
+            let Some(client_id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            if after.is_some_and(|after| client_id <= after) {
+                continue;
+            }
+            // a client directory may exist without a client, such as while it is being created
+            let own = client_id == self.client_id;
+            if (entry.path().join(CLIENT_FILE).exists() && !(own && self.deleted))
+                || (own && self.pending.get(CLIENT_FILE).is_some_and(Option::is_some))
+            {
+                client_ids.push(client_id);
+            }
+        }
+        client_ids.sort_unstable();
+        client_ids.truncate(limit);
+        Ok(client_ids)
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        if std::mem::take(&mut self.deleted) {
+            self.remove_dir()?;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let staging_dir = self.dir.join(STAGING_DIR);
+        fs::create_dir_all(&staging_dir)
+            .with_context(|| format!("Failed to create `{}`.", staging_dir.display()))?;
+
+        let mut manifest = String::new();
+        for (i, (rel_path, data)) in pending.iter().enumerate() {
+            let staged = staging_dir.join(i.to_string());
+            match data {
+                Some(PendingData::Bytes(data)) => write_synced(&staged, data)?,
+                // the spooled file was synced when it was written
+                Some(PendingData::Spooled(spool)) => fs::rename(&spool.path, &staged)
+                    .with_context(|| format!("Error staging `{}`", spool.path.display()))?,
+                None => manifest.push_str(MANIFEST_REMOVE_PREFIX),
+            }
+            manifest.push_str(rel_path);
+            manifest.push('\n');
+        }
+        let manifest_tmp = staging_dir.join(format!("{MANIFEST_FILE}.tmp"));
+        write_synced(&manifest_tmp, manifest.as_bytes())?;
+        fs::rename(&manifest_tmp, staging_dir.join(MANIFEST_FILE))?;
+        sync_dir(&staging_dir)?;
+
+        // the transaction is now committed; move the files into place
+        finish_staged(&self.dir)?;
+        Ok(())
+    }
+}
+
+impl Drop for FsTxn<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.storage.locked.lock().expect("poisoned lock");
+        locked.remove(&self.client_id);
+        self.storage.unlocked.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::NIL_VERSION_ID;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_add_version_files() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, b"segment".to_vec().into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let client_dir = tmp_dir.path().join(client_id.to_string());
+        assert_eq!(
+            fs::read(client_dir.join("versions").join(version_id.to_string()))?,
+            format!("{NIL_VERSION_ID}\nsegment").into_bytes()
+        );
+        assert_eq!(
+            fs::read_to_string(client_dir.join("children").join(NIL_VERSION_ID.to_string()))?,
+            format!("{version_id}\n")
+        );
+        assert!(!client_dir.join(STAGING_DIR).exists());
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, b"segment".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let snapshot = Snapshot::new(
+            version_id,
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        )
+        .with_versions_since(3);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_snapshot(snapshot.clone(), vec![1, 2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snapshot));
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(vec![1, 2].into()));
+        drop(txn);
+        assert_eq!(storage.max_versions_since_snapshot()?, Some((client_id, 3)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_sync() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let timestamp = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        // recording a sync for a nonexistent client does nothing
+        txn.record_sync(timestamp, None)?;
+        assert_eq!(txn.get_client()?, None);
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.created_at.is_some());
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent, Some("tc/1.0".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_quota() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let quota = Quota {
+            max_bytes: Some(1024),
+            max_versions: None,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.set_quota(quota).is_err());
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_quota(quota)?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(storage.txn(client_id)?.get_quota()?, quota);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_streaming() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let snapshot = Snapshot::new(version_id, Utc::now());
+        let spool_dir = tmp_dir.path().join(SPOOL_DIR);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_snapshot_from_reader(snapshot.clone(), &mut &b"snapshot data"[..])?;
+        assert_eq!(fs::read_dir(&spool_dir)?.count(), 1);
+        let mut data = vec![];
+        txn.get_snapshot_reader(version_id)?
+            .unwrap()
+            .read_to_end(&mut data)?;
+        assert_eq!(data, b"snapshot data".to_vec());
+        txn.commit()?;
+        drop(txn);
+
+        // the spooled file was moved into place
+        assert_eq!(fs::read_dir(&spool_dir)?.count(), 0);
+        let client_dir = tmp_dir.path().join(client_id.to_string());
+        assert_eq!(
+            fs::read(client_dir.join(SNAPSHOT_FILE))?,
+            b"snapshot data".to_vec()
+        );
+
+        // spooled data is removed if the transaction is not committed
+        let mut txn = storage.txn(client_id)?;
+        txn.set_snapshot_from_reader(snapshot, &mut &b"other data"[..])?;
+        drop(txn);
+        assert_eq!(fs::read_dir(&spool_dir)?.count(), 0);
+
+        let mut txn = storage.txn(client_id)?;
+        let mut data = vec![];
+        txn.get_snapshot_reader(version_id)?
+            .unwrap()
+            .read_to_end(&mut data)?;
+        assert_eq!(data, b"snapshot data".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_streaming() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version_from_reader(version_id, NIL_VERSION_ID, &mut &b"segment"[..])?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        txn.commit()?;
+        drop(txn);
+
+        // the version file has the same format as one written from memory
+        assert_eq!(fs::read_dir(tmp_dir.path().join(SPOOL_DIR))?.count(), 0);
+        let client_dir = tmp_dir.path().join(client_id.to_string());
+        assert_eq!(
+            fs::read(client_dir.join("versions").join(version_id.to_string()))?,
+            format!("{NIL_VERSION_ID}\nsegment").into_bytes()
+        );
+
+        let mut txn = storage.txn(client_id)?;
+        let mut version = txn.get_version_reader_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.parent_version_id, NIL_VERSION_ID);
+        let mut data = vec![];
+        version.history_segment.read_to_end(&mut data)?;
+        assert_eq!(data, b"segment".to_vec());
+        assert!(txn.get_version_reader(Uuid::new_v4())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+        }
+        assert_eq!(storage.txn(client_id)?.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_commit_completed() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        // simulate a commit interrupted after the manifest was written, and after the first
+        // file was moved into place
+        let client_dir = tmp_dir.path().join(client_id.to_string());
+        let staging_dir = client_dir.join(STAGING_DIR);
+        fs::create_dir_all(client_dir.join("versions"))?;
+        fs::create_dir_all(&staging_dir)?;
+        fs::write(
+            client_dir.join("versions").join(version_id.to_string()),
+            format!("{NIL_VERSION_ID}\nabc"),
+        )?;
+        fs::write(
+            staging_dir.join("1"),
+            serde_json::to_vec(&ClientFile {
+                latest_version_id: version_id,
+                snapshot: None,
+                created_at: None,
+                last_sync_at: None,
+                user_agent: None,
+                quota_max_bytes: None,
+                quota_max_versions: None,
+                deleted_at: None,
+            })?,
+        )?;
+        fs::write(
+            staging_dir.join(MANIFEST_FILE),
+            format!("versions/{version_id}\n{CLIENT_FILE}\n"),
+        )?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        assert!(!staging_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_commit_discarded() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+
+        // simulate a commit interrupted before the manifest was written
+        let staging_dir = tmp_dir.path().join(client_id.to_string()).join(STAGING_DIR);
+        fs::create_dir_all(&staging_dir)?;
+        fs::write(staging_dir.join("0"), b"{}")?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert!(!staging_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_txns_serialized() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        // each thread adds a version to the latest version, which only succeeds if the
+        // transactions do not overlap
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| -> Result<(), StorageError> {
+                    let mut txn = storage.txn(client_id)?;
+                    let latest = txn.get_client()?.unwrap().latest_version_id;
+                    txn.add_version(Uuid::new_v4(), latest, Bytes::new())?;
+                    txn.commit()
+                });
+            }
+        });
+
+        let mut txn = storage.txn(client_id)?;
+        let mut parent = NIL_VERSION_ID;
+        let mut count = 0;
+        while let Some(version) = txn.get_version_by_parent(parent)? {
+            parent = version.version_id;
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let mut client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(Uuid::new_v4())?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+        // a directory without a client is not listed
+        fs::create_dir(tmp_dir.path().join(Uuid::new_v4().to_string()))?;
+
+        let mut txn = storage.txn(client_ids[0])?;
+        assert_eq!(txn.list_clients(None, 10)?, client_ids);
+        assert_eq!(txn.list_clients(None, 1)?, vec![client_ids[0]]);
+        assert_eq!(
+            txn.list_clients(Some(client_ids[0]), 10)?,
+            client_ids[1..].to_vec()
+        );
+        assert_eq!(
+            txn.list_clients(Some(client_ids[2]), 10)?,
+            Vec::<Uuid>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1].into())?;
+        txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![2].into())?;
+        txn.commit()?;
+
+        // the deletion is visible in the transaction, but not on disk until commit
+        txn.delete_client()?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id)?, None);
+        assert_eq!(txn.list_clients(None, 10)?, Vec::<Uuid>::new());
+        assert!(tmp_dir.path().join(client_id.to_string()).exists());
+
+        // deleting and re-creating in one transaction leaves only the new client
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+        assert_eq!(fs::read_dir(tmp_dir.path())?.count(), 0);
+        assert_eq!(storage.txn(client_id)?.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_versions() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = FsStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let client_dir = tmp_dir.path().join(client_id.to_string());
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(v1, NIL_VERSION_ID, vec![1].into())?;
+        txn.add_version(v2, v1, vec![2].into())?;
+        txn.set_snapshot(Snapshot::new(v1, Utc::now()), vec![3].into())?;
+        txn.commit()?;
+
+        assert_eq!(txn.prune_versions(v1)?, 1);
+        // the files are not removed until commit
+        assert!(client_dir.join(format!("versions/{v1}")).exists());
+        txn.commit()?;
+        drop(txn);
+
+        assert!(!client_dir.join(format!("versions/{v1}")).exists());
+        assert!(!client_dir
+            .join(format!("children/{NIL_VERSION_ID}"))
+            .exists());
+        assert!(client_dir.join(format!("versions/{v2}")).exists());
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(v1)?, None);
+        assert_eq!(txn.get_version_by_parent(v1)?.unwrap().version_id, v2);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let tmp_dir = TempDir::new()?;
+            test(&FsStorage::new(tmp_dir.path())?)
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
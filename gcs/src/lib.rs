@@ -0,0 +1,342 @@
+//! This crate implements a [`BlobStore`] using Google Cloud Storage.
+//!
+//! Combined with [`BlobStorage`], history segments and snapshots are stored as objects keyed by
+//! client and version ID, while client and version metadata remain in a small local index such as
+//! a SQLite database:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_core::InMemoryStorage;
+//! use taskchampion_sync_server_storage_gcs::{GcsBlobStore, GcsStorage};
+//! let blobs = GcsBlobStore::new("my-bucket")?;
+//! let storage: GcsStorage<_> = GcsStorage::new(InMemoryStorage::new(), blobs);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+use google_cloud_storage::http::objects::download::Range;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use google_cloud_storage::http::Error;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use taskchampion_sync_server_core::runtime::run;
+use taskchampion_sync_server_core::{BlobStorage, BlobStore, Clock, RetryPolicy, SystemClock};
+
+/// Storage with history segments and snapshots in GCS, and metadata in `S`.
+pub type GcsStorage<S> = BlobStorage<S, GcsBlobStore>;
+
+/// Get the HTTP status of an error response from GCS, if any.
+fn status(err: &Error) -> Option<u16> {
+    match err {
+        Error::Response(response) => Some(response.code),
+        _ => None,
+    }
+}
+
+/// Determine whether a failed request may succeed if retried: rate-limiting, server errors, and
+/// failures to reach GCS at all.
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::Response(response) => response.code == 429 || response.code >= 500,
+        Error::HttpClient(_) => true,
+        _ => false,
+    }
+}
+
+/// Call `f` until it succeeds, fails with an error that is not retryable, or the policy's
+/// attempts are exhausted, waiting between attempts with exponential backoff.
+fn retry<T, E, F>(
+    policy: &RetryPolicy,
+    clock: &dyn Clock,
+    is_retryable: impl Fn(&E) -> bool,
+    mut f: F,
+) -> anyhow::Result<Result<T, E>>
+where
+    E: Display,
+    F: FnMut() -> anyhow::Result<Result<T, E>>,
+{
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match f()? {
+            Err(err) if attempt < policy.max_attempts && is_retryable(&err) => {
+                log::debug!("retrying GCS request after attempt {attempt} failed: {err}");
+                clock.sleep(backoff);
+                backoff = std::cmp::min(backoff * 2, policy.max_backoff);
+                attempt += 1;
+            }
+            res => return Ok(res),
+        }
+    }
+}
+
+/// A [`BlobStore`] storing each blob as an object in a GCS bucket.
+///
+/// Every blob operation is idempotent, so each request, including writes, is retried according
+/// to the store's [`RetryPolicy`] when it fails with a transient error.
+pub struct GcsBlobStore {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    policy: RetryPolicy,
+    clock: Arc<dyn Clock>,
+}
+
+impl GcsBlobStore {
+    /// Create a new instance using the given bucket, authenticating with Application Default
+    /// Credentials.
+    pub fn new(bucket: &str) -> anyhow::Result<Self> {
+        let config = run(ClientConfig::default().with_auth())?
+            .context("Could not find Application Default Credentials")?;
+        Ok(Self::with_client(Client::new(config), bucket))
+    }
+
+    fn with_client(client: Client, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            policy: RetryPolicy::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Prefix all object names with the given string, allowing several servers to share a
+    /// bucket. The prefix should typically end with `/`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Retry failed requests according to `policy`, in place of [`RetryPolicy::default`].
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Use the given clock to wait between retries.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn object(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Run the request built by `f`, with retries.
+    fn request<T, F, Fut>(&self, f: F) -> anyhow::Result<Result<T, Error>>
+    where
+        T: Send + 'static,
+        F: Fn(Client, String) -> Fut,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        retry(&self.policy, self.clock.as_ref(), is_retryable, || {
+            run(f(self.client.clone(), self.bucket.clone()))
+        })
+    }
+}
+
+impl BlobStore for GcsBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let object = self.object(key);
+        self.request(|client, bucket| {
+            let object = object.clone();
+            let data = data.to_vec();
+            async move {
+                let request = UploadObjectRequest {
+                    bucket,
+                    ..Default::default()
+                };
+                client
+                    .upload_object(&request, data, &UploadType::Simple(Media::new(object)))
+                    .await
+            }
+        })?
+        .with_context(|| format!("Error writing GCS object {object}"))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let object = self.object(key);
+        let result = self.request(|client, bucket| {
+            let request = GetObjectRequest {
+                bucket,
+                object: object.clone(),
+                ..Default::default()
+            };
+            async move { client.download_object(&request, &Range::default()).await }
+        })?;
+        match result {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if status(&err) == Some(404) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Error reading GCS object {object}")),
+        }
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let object = self.object(key);
+        let result = self.request(|client, bucket| {
+            let request = DeleteObjectRequest {
+                bucket,
+                object: object.clone(),
+                ..Default::default()
+            };
+            async move { client.delete_object(&request).await }
+        })?;
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) if status(&err) == Some(404) => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Error deleting GCS object {object}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use taskchampion_sync_server_core::{InMemoryStorage, ManualClock, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a store for the bucket given in `TEST_GCS_BUCKET`, or return `None` if that
+    /// variable is not set, in which case the test should be skipped. Each call uses a fresh
+    /// prefix.
+    fn blob_store() -> anyhow::Result<Option<GcsBlobStore>> {
+        let Ok(bucket) = std::env::var("TEST_GCS_BUCKET") else {
+            return Ok(None);
+        };
+        let store = GcsBlobStore::new(&bucket)?.with_prefix(format!("test-{}/", Uuid::new_v4()));
+        Ok(Some(store))
+    }
+
+    fn clock() -> ManualClock {
+        ManualClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn test_prefix() {
+        let store =
+            GcsBlobStore::with_client(Client::new(ClientConfig::default().anonymous()), "bucket");
+        assert_eq!(store.object("a/b"), "a/b");
+        let store = store.with_prefix("server1/");
+        assert_eq!(store.object("a/b"), "server1/a/b");
+    }
+
+    #[test]
+    fn test_retry_succeeds() -> anyhow::Result<()> {
+        let clock = clock();
+        let start = clock.now();
+        let mut attempts = 0;
+        let result = retry(
+            &RetryPolicy::default(),
+            &clock,
+            |status: &u16| *status >= 500,
+            || {
+                attempts += 1;
+                Ok(if attempts < 3 { Err(503) } else { Ok("done") })
+            },
+        )?;
+        assert_eq!(result, Ok("done"));
+        assert_eq!(attempts, 3);
+        // backoff of 10ms, then 20ms
+        assert_eq!(clock.now() - start, chrono::Duration::milliseconds(30));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_gives_up() -> anyhow::Result<()> {
+        let clock = clock();
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(15),
+        };
+        let mut attempts = 0;
+        let result: Result<(), u16> = retry(
+            &policy,
+            &clock,
+            |s: &u16| *s >= 500,
+            || {
+                attempts += 1;
+                Ok(Err(500))
+            },
+        )?;
+        assert_eq!(result, Err(500));
+        assert_eq!(attempts, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_not_retryable() -> anyhow::Result<()> {
+        let clock = clock();
+        let mut attempts = 0;
+        let result: Result<(), u16> = retry(
+            &RetryPolicy::default(),
+            &clock,
+            |s: &u16| *s >= 500,
+            || {
+                attempts += 1;
+                Ok(Err(403))
+            },
+        )?;
+        assert_eq!(result, Err(403));
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_get_delete() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        assert_eq!(store.get("a")?, None);
+        store.put("a", b"data")?;
+        assert_eq!(store.get("a")?, Some(b"data".to_vec()));
+        store.delete("a")?;
+        assert_eq!(store.get("a")?, None);
+        // deleting a missing object is not an error
+        store.delete("a")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        let storage = GcsStorage::new(InMemoryStorage::new(), store);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(store) = blob_store()? else {
+                return Ok(());
+            };
+            test(&GcsStorage::new(InMemoryStorage::new(), store))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,302 @@
+//! This crate implements an LMDB storage backend for the TaskChampion sync server.
+//!
+//! The backend is a [`KvBackend`], storing clients, snapshots, versions (keyed by client ID and
+//! version ID), and child-version links (keyed by client ID and parent version ID) in separate
+//! databases within one LMDB environment. Use it with [`KvStorage`]:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_lmdb::{LmdbKv, LmdbStorage};
+//! let storage: LmdbStorage = LmdbStorage::new(LmdbKv::new("/var/lib/taskchampion")?);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use heed::types::Bytes;
+use heed::{Database, Env, EnvOpenOptions, RwTxn};
+use std::path::Path;
+use taskchampion_sync_server_core::{kv_keys, KvBackend, KvStorage, KvTxn};
+
+/// Storage in LMDB.
+pub type LmdbStorage = KvStorage<LmdbKv>;
+
+/// The default maximum size of the environment, in bytes.
+pub const DEFAULT_MAP_SIZE: usize = 1 << 30;
+
+/// The database for each key tag.
+const DATABASES: [(u8, &str); 7] = [
+    (kv_keys::CLIENT, "clients"),
+    (kv_keys::CLIENT_METADATA, "client_metadata"),
+    (kv_keys::SNAPSHOT, "snapshots"),
+    (kv_keys::RETAINED_SNAPSHOT, "retained_snapshots"),
+    (kv_keys::VERSION, "versions"),
+    (kv_keys::VERSION_TIMESTAMP, "version_timestamps"),
+    (kv_keys::CHILD, "children"),
+];
+
+type Db = Database<Bytes, Bytes>;
+
+/// A [`KvBackend`] storing data in an LMDB environment.
+///
+/// Each key is stored in the database for its tag, without the tag itself. Each transaction is an
+/// LMDB write transaction, which is aborted if dropped without committing. LMDB allows only one
+/// write transaction at a time, so a second call to `txn` blocks until the first transaction is
+/// dropped. Readers are never blocked.
+pub struct LmdbKv {
+    env: Env,
+    dbs: [Db; 7],
+}
+
+impl LmdbKv {
+    /// Create a new instance using an environment in the given directory, creating it if
+    /// necessary, with a maximum size of [`DEFAULT_MAP_SIZE`].
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::with_map_size(path, DEFAULT_MAP_SIZE)
+    }
+
+    /// Create a new instance with the given maximum environment size, in bytes. This is the size
+    /// of a virtual memory mapping, and need not be available on disk.
+    pub fn with_map_size<P: AsRef<Path>>(path: P, map_size: usize) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create `{}`.", path.display()))?;
+        // SAFETY: the environment is not opened more than once in this process, and the files
+        // are not modified other than through LMDB.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(map_size)
+                .max_dbs(DATABASES.len() as u32)
+                .open(path)
+        }
+        .with_context(|| format!("Error opening LMDB environment at `{}`", path.display()))?;
+
+        let mut wtxn = env.write_txn()?;
+        let [clients, client_metadata, snapshots, retained_snapshots, versions, version_timestamps, children] =
+            DATABASES.map(|(_, name)| env.create_database::<Bytes, Bytes>(&mut wtxn, Some(name)));
+        let dbs = [
+            clients?,
+            client_metadata?,
+            snapshots?,
+            retained_snapshots?,
+            versions?,
+            version_timestamps?,
+            children?,
+        ];
+        wtxn.commit()?;
+        Ok(Self { env, dbs })
+    }
+
+    /// Get the database for the given key tag.
+    fn db(&self, tag: u8) -> anyhow::Result<Db> {
+        DATABASES
+            .iter()
+            .position(|(t, _)| *t == tag)
+            .map(|i| self.dbs[i])
+            .ok_or_else(|| anyhow::anyhow!("Unknown key tag {tag}"))
+    }
+
+    /// Split a key into the database for its tag and the remainder of the key.
+    fn split<'k>(&self, key: &'k [u8]) -> anyhow::Result<(Db, &'k [u8])> {
+        let (tag, rest) = key
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Empty key"))?;
+        Ok((self.db(*tag)?, rest))
+    }
+}
+
+impl KvBackend for LmdbKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(LmdbKvTxn {
+            kv: self,
+            txn: Some(self.env.write_txn()?),
+        }))
+    }
+}
+
+struct LmdbKvTxn<'a> {
+    kv: &'a LmdbKv,
+    /// The write transaction, until it is committed.
+    txn: Option<RwTxn<'a>>,
+}
+
+impl<'a> LmdbKvTxn<'a> {
+    fn txn(&mut self) -> anyhow::Result<&mut RwTxn<'a>> {
+        self.txn
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Transaction already committed"))
+    }
+}
+
+impl KvTxn for LmdbKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let (db, key) = self.kv.split(key)?;
+        Ok(db.get(self.txn()?, key)?.map(|v| v.to_vec()))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        let (db, key) = self.kv.split(key)?;
+        db.put(self.txn()?, key, value)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        let (db, key) = self.kv.split(key)?;
+        db.delete(self.txn()?, key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let scans: Vec<(u8, &[u8])> = match prefix.split_first() {
+            Some((tag, rest)) => vec![(*tag, rest)],
+            None => DATABASES.iter().map(|(tag, _)| (*tag, &[][..])).collect(),
+        };
+        let mut result = vec![];
+        for (tag, prefix) in scans {
+            let db = self.kv.db(tag)?;
+            let txn = self.txn()?;
+            // LMDB does not accept an empty key, so an empty prefix scans the whole database
+            let items = if prefix.is_empty() {
+                db.iter(txn)?.collect::<heed::Result<Vec<_>>>()?
+            } else {
+                db.prefix_iter(txn, prefix)?
+                    .collect::<heed::Result<Vec<_>>>()?
+            };
+            for (k, v) in items {
+                let mut key = vec![tag];
+                key.extend_from_slice(k);
+                result.push((key, v.to_vec()));
+            }
+        }
+        // databases are scanned in an arbitrary order
+        result.sort();
+        Ok(result)
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        let txn = self
+            .txn
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Transaction already committed"))?;
+        txn.commit().context("Error committing to LMDB")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_get_put_delete() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = LmdbKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        txn.put(b"ca", b"1")?;
+        txn.put(b"vb", b"2")?;
+        // reads see uncommitted writes
+        assert_eq!(txn.get(b"ca")?, Some(b"1".to_vec()));
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"ca")?, Some(b"1".to_vec()));
+        // the tag selects the database
+        assert_eq!(txn.get(b"va")?, None);
+        txn.delete(b"ca")?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"ca")?, None);
+        assert_eq!(txn.get(b"vb")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_tag() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = LmdbKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        assert!(txn.put(b"xa", b"1").is_err());
+        assert!(txn.get(b"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = LmdbKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        txn.put(b"va1", b"x")?;
+        txn.put(b"va2", b"y")?;
+        txn.put(b"vb", b"z")?;
+        txn.put(b"ca", b"w")?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = kv.txn()?;
+        txn.put(b"va3", b"u")?;
+        txn.delete(b"va1")?;
+        assert_eq!(
+            txn.scan_prefix(b"va")?,
+            vec![
+                (b"va2".to_vec(), b"y".to_vec()),
+                (b"va3".to_vec(), b"u".to_vec())
+            ]
+        );
+        assert_eq!(
+            txn.scan_prefix(b"c")?,
+            vec![(b"ca".to_vec(), b"w".to_vec())]
+        );
+        assert_eq!(txn.scan_prefix(b"")?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = LmdbKv::new(tmp_dir.path())?;
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"ca", b"1")?;
+        }
+        assert_eq!(kv.txn()?.get(b"ca")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = LmdbStorage::new(LmdbKv::new(tmp_dir.path())?);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let tmp_dir = TempDir::new()?;
+            test(&LmdbStorage::new(LmdbKv::new(tmp_dir.path())?))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,248 @@
+//! Versioned schema migrations for the PostgreSQL database.
+//!
+//! The schema version is stored in the `meta` table, and is equal to the number of migrations
+//! that have been applied. Migrations must therefore never be removed or reordered; new
+//! migrations are added to the end of [`MIGRATIONS`].
+use crate::Connection;
+use anyhow::Context;
+
+/// A single migration step, upgrading the schema by one version.
+type Migration = fn(&mut Connection) -> anyhow::Result<()>;
+
+/// All migrations, in order.
+const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    client_metadata,
+    client_quota,
+    version_timestamps,
+    client_deletion,
+];
+
+/// The keys of the advisory lock serializing migration runners. This uses the two-key form of the
+/// lock, so it cannot conflict with the per-client locks.
+const MIGRATION_LOCK: (i32, i32) = (0x7463_7373, 1);
+
+/// Version 1: the original schema.
+fn initial_schema(con: &mut Connection) -> anyhow::Result<()> {
+    con.batch_execute(
+        "CREATE TABLE clients (
+            client_id UUID PRIMARY KEY,
+            latest_version_id UUID NOT NULL,
+            snapshot_version_id UUID,
+            versions_since_snapshot BIGINT,
+            snapshot_timestamp BIGINT,
+            snapshot BYTEA);
+        CREATE TABLE versions (
+            client_id UUID NOT NULL,
+            version_id UUID NOT NULL,
+            parent_version_id UUID NOT NULL,
+            history_segment BYTEA NOT NULL,
+            PRIMARY KEY (client_id, version_id));
+        CREATE UNIQUE INDEX versions_by_parent ON versions (client_id, parent_version_id);",
+    )?;
+    Ok(())
+}
+
+/// Version 2: client creation and sync metadata.
+fn client_metadata(con: &mut Connection) -> anyhow::Result<()> {
+    con.batch_execute(
+        "ALTER TABLE clients
+            ADD COLUMN created_at BIGINT,
+            ADD COLUMN last_sync_at BIGINT,
+            ADD COLUMN user_agent TEXT",
+    )?;
+    Ok(())
+}
+
+/// Version 3: per-client quotas.
+fn client_quota(con: &mut Connection) -> anyhow::Result<()> {
+    con.batch_execute(
+        "ALTER TABLE clients
+            ADD COLUMN quota_max_bytes BIGINT,
+            ADD COLUMN quota_max_versions BIGINT",
+    )?;
+    Ok(())
+}
+
+/// Version 4: version creation timestamps.
+fn version_timestamps(con: &mut Connection) -> anyhow::Result<()> {
+    con.batch_execute("ALTER TABLE versions ADD COLUMN created_at BIGINT")?;
+    Ok(())
+}
+
+/// Version 5: the time at which a client was marked deleted.
+fn client_deletion(con: &mut Connection) -> anyhow::Result<()> {
+    con.batch_execute("ALTER TABLE clients ADD COLUMN deleted_at BIGINT")?;
+    Ok(())
+}
+
+/// Get the schema version of the database, creating the `meta` table if necessary.
+fn get_schema_version(con: &mut Connection) -> anyhow::Result<u32> {
+    con.batch_execute(
+        "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value BIGINT NOT NULL)",
+    )?;
+    let version: Option<i64> = con
+        .query_opt("SELECT value FROM meta WHERE key = 'schema_version'", &[])?
+        .map(|r| r.try_get(0))
+        .transpose()?;
+    Ok(version.unwrap_or(0).try_into()?)
+}
+
+/// Bring the database schema up to date, applying any pending migrations in a single
+/// transaction.
+pub(crate) fn run(con: &mut Connection) -> anyhow::Result<()> {
+    run_migrations(con, MIGRATIONS)
+}
+
+fn run_migrations(con: &mut Connection, migrations: &[Migration]) -> anyhow::Result<()> {
+    con.begin()?;
+    // The advisory lock ensures that concurrent runners apply each migration only once.
+    con.execute(
+        "SELECT pg_advisory_xact_lock($1, $2)",
+        &[&MIGRATION_LOCK.0, &MIGRATION_LOCK.1],
+    )
+    .context("Error locking schema")?;
+    let current = get_schema_version(con).context("Error reading schema version")?;
+    let latest = migrations.len() as u32;
+    if current > latest {
+        anyhow::bail!(
+            "Database schema version {current} is newer than the latest supported version {latest}"
+        );
+    }
+    if current == latest {
+        con.commit()?;
+        return Ok(());
+    }
+
+    for (i, migration) in migrations.iter().enumerate().skip(current as usize) {
+        let version = i as u32 + 1;
+        log::info!("Migrating database schema to version {version}");
+        migration(con).with_context(|| format!("Error migrating to schema version {version}"))?;
+    }
+    con.execute(
+        "INSERT INTO meta (key, value) VALUES ('schema_version', $1)
+         ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+        &[&i64::from(latest)],
+    )?;
+    con.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::new_pool;
+    use deadpool_postgres::Pool;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    /// A new, empty schema in the test database given in `TEST_DB_URL`, so that migrations run in
+    /// isolation from other tests. The schema is dropped when this value is.
+    struct TestSchema {
+        pool: Pool,
+        admin: Pool,
+        schema: String,
+    }
+
+    impl TestSchema {
+        /// Create a new schema, or return `None` if `TEST_DB_URL` is not set, in which case the
+        /// test should be skipped.
+        fn new() -> anyhow::Result<Option<TestSchema>> {
+            let Ok(url) = std::env::var("TEST_DB_URL") else {
+                return Ok(None);
+            };
+            let mut config = url.parse::<tokio_postgres::Config>()?;
+            let admin = new_pool(config.clone())?;
+            let schema = format!("migrations_{}", Uuid::new_v4().simple());
+            Connection::get(&admin)?.batch_execute(&format!("CREATE SCHEMA {schema}"))?;
+            config.options(format!("-c search_path={schema}"));
+            let pool = new_pool(config)?;
+            Ok(Some(TestSchema {
+                pool,
+                admin,
+                schema,
+            }))
+        }
+
+        /// Get a connection using this schema.
+        fn con(&self) -> anyhow::Result<Connection> {
+            Connection::get(&self.pool)
+        }
+    }
+
+    impl Drop for TestSchema {
+        fn drop(&mut self) {
+            let _ = Connection::get(&self.admin).and_then(|mut con| {
+                Ok(con.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema))?)
+            });
+        }
+    }
+
+    fn add_row(con: &mut Connection) -> anyhow::Result<()> {
+        con.batch_execute(
+            "CREATE TABLE IF NOT EXISTS counter (x INTEGER);
+             INSERT INTO counter (x) VALUES (1);",
+        )?;
+        Ok(())
+    }
+
+    fn rows(con: &mut Connection) -> anyhow::Result<i64> {
+        Ok(con
+            .query_opt("SELECT COUNT(*) FROM counter", &[])?
+            .context("no row")?
+            .try_get(0)?)
+    }
+
+    #[test]
+    fn fresh_database() -> anyhow::Result<()> {
+        let Some(test) = TestSchema::new()? else {
+            return Ok(());
+        };
+        run(&mut test.con()?)?;
+        assert_eq!(
+            get_schema_version(&mut test.con()?)?,
+            MIGRATIONS.len() as u32
+        );
+        // the tables exist
+        test.con()?.execute("SELECT snapshot FROM clients", &[])?;
+        test.con()?
+            .execute("SELECT history_segment FROM versions", &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn rerun_is_noop() -> anyhow::Result<()> {
+        let Some(test) = TestSchema::new()? else {
+            return Ok(());
+        };
+        let migrations: &[Migration] = &[initial_schema, add_row];
+        run_migrations(&mut test.con()?, migrations)?;
+        run_migrations(&mut test.con()?, migrations)?;
+        assert_eq!(get_schema_version(&mut test.con()?)?, 2);
+        assert_eq!(rows(&mut test.con()?)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn applies_only_pending() -> anyhow::Result<()> {
+        let Some(test) = TestSchema::new()? else {
+            return Ok(());
+        };
+        run_migrations(&mut test.con()?, &[initial_schema, add_row])?;
+        run_migrations(&mut test.con()?, &[initial_schema, add_row, add_row])?;
+        assert_eq!(get_schema_version(&mut test.con()?)?, 3);
+        assert_eq!(rows(&mut test.con()?)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_schema_refused() -> anyhow::Result<()> {
+        let Some(test) = TestSchema::new()? else {
+            return Ok(());
+        };
+        run_migrations(&mut test.con()?, &[initial_schema, add_row])?;
+        let err = run_migrations(&mut test.con()?, &[initial_schema]).unwrap_err();
+        assert!(err.to_string().contains("newer than the latest supported"));
+        Ok(())
+    }
+}
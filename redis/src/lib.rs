@@ -0,0 +1,461 @@
+//! This crate implements a Redis storage backend for the TaskChampion sync server.
+//!
+//! The backend is a [`KvBackend`], storing clients, versions, child-version links, and snapshots
+//! as individual Redis keys. Use it with [`KvStorage`]:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_redis::{RedisKv, RedisStorage};
+//! let storage: RedisStorage = RedisStorage::new(RedisKv::new("redis://localhost/")?);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use redis::{Commands, Connection, ErrorKind, RedisError};
+use std::collections::{BTreeMap, HashSet};
+use taskchampion_sync_server_core::{KvBackend, KvStorage, KvTxn, StorageError};
+
+/// Storage in Redis.
+pub type RedisStorage = KvStorage<RedisKv>;
+
+/// The default prefix for all keys written to Redis.
+pub const DEFAULT_NAMESPACE: &[u8] = b"taskchampion:";
+
+/// A [`KvBackend`] storing data in Redis.
+///
+/// Each transaction uses its own connection, and transactions are optimistic: every key read in a
+/// transaction is watched, and the transaction's writes are applied with `MULTI`/`EXEC` on commit.
+/// If any watched key was modified by another transaction in the meantime, nothing is written and
+/// the commit fails with [`StorageError::TryAgainLater`].
+///
+/// A prefix scan watches every key it returns, and also a sentinel key for the prefix, so that the
+/// commit also fails if another transaction added a key matching the prefix. The lengths of the
+/// scanned prefixes are recorded in Redis, and each write increments the sentinel for the prefix
+/// of the written key at each of those lengths. Sentinel keys begin with a NUL byte, so they do
+/// not collide with the namespaced data keys.
+pub struct RedisKv {
+    client: redis::Client,
+    namespace: Vec<u8>,
+}
+
+impl RedisKv {
+    /// Create a new instance using the Redis server at the given URL, such as
+    /// `redis://localhost:6379/0`, with keys prefixed by [`DEFAULT_NAMESPACE`].
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        Self::with_namespace(url, DEFAULT_NAMESPACE)
+    }
+
+    /// Create a new instance with all keys prefixed by `namespace`, allowing several servers to
+    /// share a Redis database. The namespace must not be empty or begin with a NUL byte.
+    pub fn with_namespace(url: &str, namespace: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            namespace.first().is_some_and(|b| *b != 0),
+            "The Redis namespace must not be empty or begin with a NUL byte"
+        );
+        let client = redis::Client::open(url).context("Invalid Redis URL")?;
+        // check that the server is reachable
+        client
+            .get_connection()
+            .context("Error connecting to Redis")?;
+        Ok(Self {
+            client,
+            namespace: namespace.to_vec(),
+        })
+    }
+}
+
+/// Convert a Redis error, treating a lost connection, a timeout, or a server which is loading or
+/// asks the client to try again as a transient [`StorageError::Retryable`] error.
+fn classify(err: RedisError) -> StorageError {
+    let transient = err.is_io_error()
+        || err.is_timeout()
+        || err.is_connection_dropped()
+        || matches!(
+            err.kind(),
+            ErrorKind::TryAgain | ErrorKind::BusyLoadingError
+        );
+    if transient {
+        StorageError::Retryable(err.into())
+    } else {
+        StorageError::Backend(err.into())
+    }
+}
+
+impl KvBackend for RedisKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(RedisKvTxn {
+            con: self
+                .client
+                .get_connection()
+                .map_err(classify)
+                .context("Error connecting to Redis")?,
+            namespace: &self.namespace,
+            watched: HashSet::new(),
+            pending: BTreeMap::new(),
+        }))
+    }
+}
+
+/// The name of the set of scanned prefix lengths.
+const SCANNED_LENGTHS: &[u8] = b"scanned-lengths";
+
+/// The name prefixing each per-prefix sentinel key.
+const SENTINEL: &[u8] = b"sentinel:";
+
+/// Escape the glob metacharacters recognized by Redis's `SCAN ... MATCH`.
+fn escape_glob(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        if matches!(b, b'*' | b'?' | b'[' | b']' | b'\\') {
+            escaped.push(b'\\');
+        }
+        escaped.push(*b);
+    }
+    escaped
+}
+
+struct RedisKvTxn<'a> {
+    con: Connection,
+    namespace: &'a [u8],
+    /// Keys (including namespace) which have been watched in this transaction.
+    watched: HashSet<Vec<u8>>,
+    /// Uncommitted changes, with `None` representing a deletion.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl RedisKvTxn<'_> {
+    fn redis_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut redis_key = self.namespace.to_vec();
+        redis_key.extend_from_slice(key);
+        redis_key
+    }
+
+    /// The Redis key for the given bookkeeping name and suffix, outside of the namespace's data
+    /// keys.
+    fn meta_key(&self, name: &[u8], suffix: &[u8]) -> Vec<u8> {
+        let mut meta_key = vec![0];
+        meta_key.extend_from_slice(self.namespace);
+        meta_key.extend_from_slice(name);
+        meta_key.extend_from_slice(suffix);
+        meta_key
+    }
+
+    /// Watch a key, so that the commit fails if it is modified by another transaction.
+    fn watch(&mut self, redis_key: &[u8]) -> anyhow::Result<()> {
+        self.watch_all(&[redis_key.to_vec()])
+    }
+
+    /// Watch several keys with a single command.
+    fn watch_all(&mut self, redis_keys: &[Vec<u8>]) -> anyhow::Result<()> {
+        let unwatched: Vec<&Vec<u8>> = redis_keys
+            .iter()
+            .filter(|k| !self.watched.contains(*k))
+            .collect();
+        if unwatched.is_empty() {
+            return Ok(());
+        }
+        redis::cmd("WATCH")
+            .arg(&unwatched)
+            .query::<()>(&mut self.con)
+            .map_err(classify)?;
+        self.watched.extend(unwatched.into_iter().cloned());
+        Ok(())
+    }
+}
+
+impl KvTxn for RedisKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        let redis_key = self.redis_key(key);
+        self.watch(&redis_key)?;
+        Ok(self.con.get(redis_key).map_err(classify)?)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.pending.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // Record the prefix length before watching the sentinel, so that any write committed
+        // after the sentinel is watched increments it.
+        let lengths = self.meta_key(SCANNED_LENGTHS, b"");
+        self.con
+            .sadd::<_, _, ()>(lengths, prefix.len())
+            .map_err(classify)?;
+        let sentinel = self.meta_key(SENTINEL, prefix);
+        self.watch(&sentinel)?;
+
+        let mut pattern = escape_glob(&self.redis_key(prefix));
+        pattern.push(b'*');
+        let redis_keys: Vec<Vec<u8>> = self.con.scan_match(pattern).map_err(classify)?.collect();
+        self.watch_all(&redis_keys)?;
+
+        let mut result = BTreeMap::new();
+        for redis_key in redis_keys {
+            // keys deleted since the scan are skipped
+            if let Some(value) = self
+                .con
+                .get::<_, Option<Vec<u8>>>(&redis_key)
+                .map_err(classify)?
+            {
+                result.insert(redis_key[self.namespace.len()..].to_vec(), value);
+            }
+        }
+        for (k, v) in self
+            .pending
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+        {
+            match v {
+                Some(v) => result.insert(k.clone(), v.clone()),
+                None => result.remove(k),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            redis::cmd("UNWATCH")
+                .query::<()>(&mut self.con)
+                .map_err(classify)?;
+            return Ok(());
+        }
+        // Watch the scanned prefix lengths, so that the commit fails if a scan of a new length
+        // begins before the sentinels are incremented.
+        let lengths_key = self.meta_key(SCANNED_LENGTHS, b"");
+        self.watch(&lengths_key)?;
+        let lengths: Vec<usize> = self.con.smembers(lengths_key).map_err(classify)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (k, v) in pending {
+            let redis_key = self.redis_key(&k);
+            match v {
+                Some(v) => {
+                    pipe.set(redis_key, v).ignore();
+                    // a deleted key was returned by, and watched in, any scan it matched
+                    for len in lengths.iter().filter(|len| **len <= k.len()) {
+                        pipe.incr(self.meta_key(SENTINEL, &k[..*len]), 1).ignore();
+                    }
+                }
+                None => {
+                    pipe.del(redis_key).ignore();
+                }
+            };
+        }
+        // EXEC returns nil if any watched key was modified
+        let result: Option<()> = pipe.query(&mut self.con).map_err(classify)?;
+        if result.is_none() {
+            return Err(StorageError::TryAgainLater.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Connect to the test server given in `TEST_REDIS_URL`, or return `None` if that variable is
+    /// not set, in which case the test should be skipped. Each call uses a fresh namespace.
+    fn backend() -> anyhow::Result<Option<RedisKv>> {
+        let Ok(url) = std::env::var("TEST_REDIS_URL") else {
+            return Ok(None);
+        };
+        let namespace = format!("test-{}:", Uuid::new_v4());
+        Ok(Some(RedisKv::with_namespace(&url, namespace.as_bytes())?))
+    }
+
+    #[test]
+    fn test_escape_glob() {
+        assert_eq!(escape_glob(b"a*b?c[d]e\\f"), b"a\\*b\\?c\\[d\\]e\\\\f");
+        assert_eq!(escape_glob(b"plain"), b"plain");
+    }
+
+    #[test]
+    fn test_classify() {
+        for kind in [ErrorKind::TryAgain, ErrorKind::BusyLoadingError] {
+            let err = RedisError::from((kind, "transient"));
+            assert!(StorageError::is_retryable(&classify(err)));
+        }
+        let err = RedisError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert!(StorageError::is_retryable(&classify(err)));
+        // a response of the wrong type is permanent
+        let err = RedisError::from((ErrorKind::TypeError, "permanent"));
+        assert!(!StorageError::is_retryable(&classify(err)));
+    }
+
+    #[test]
+    fn test_get_put_delete() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn = kv.txn()?;
+        txn.put(b"a", b"1")?;
+        txn.put(b"b", b"2")?;
+        // reads see uncommitted writes
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        txn.delete(b"a")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"a")?, None);
+        assert_eq!(txn.get(b"b")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn = kv.txn()?;
+        txn.put(b"a*1", b"x")?;
+        txn.put(b"a*2", b"y")?;
+        txn.put(b"ab", b"z")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        txn.put(b"a*3", b"w")?;
+        txn.delete(b"a*1")?;
+        assert_eq!(
+            txn.scan_prefix(b"a*")?,
+            vec![
+                (b"a*2".to_vec(), b"y".to_vec()),
+                (b"a*3".to_vec(), b"w".to_vec())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"a", b"1")?;
+        }
+        assert_eq!(kv.txn()?.get(b"a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.get(b"a")?, None);
+
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"a", b"2")?;
+        txn2.commit()?;
+
+        txn1.put(b"a", b"1")?;
+        let err = txn1.commit().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::TryAgainLater)
+        ));
+        assert_eq!(kv.txn()?.get(b"a")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_conflict() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let mut txn = kv.txn()?;
+        txn.put(b"a1", b"x")?;
+        txn.commit()?;
+
+        // a key added with the scanned prefix causes a conflict
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.scan_prefix(b"a")?.len(), 1);
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"a2", b"y")?;
+        txn2.commit()?;
+        txn1.put(b"b", b"z")?;
+        let err = txn1.commit().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::TryAgainLater)
+        ));
+
+        // as does a scanned key being modified
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.scan_prefix(b"a")?.len(), 2);
+        let mut txn2 = kv.txn()?;
+        txn2.delete(b"a1")?;
+        txn2.commit()?;
+        txn1.put(b"b", b"z")?;
+        assert!(txn1.commit().is_err());
+
+        // but a key with another prefix does not
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.scan_prefix(b"a")?.len(), 1);
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"c", b"w")?;
+        txn2.commit()?;
+        txn1.put(b"b", b"z")?;
+        txn1.commit()?;
+
+        // sentinel keys are not visible to scans
+        assert_eq!(kv.txn()?.scan_prefix(b"")?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let Some(kv) = backend()? else {
+            return Ok(());
+        };
+        let storage = RedisStorage::new(kv);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(kv) = backend()? else {
+                return Ok(());
+            };
+            test(&RedisStorage::new(kv))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
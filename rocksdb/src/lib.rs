@@ -0,0 +1,304 @@
+//! This crate implements a RocksDB storage backend for the TaskChampion sync server.
+//!
+//! The backend is a [`KvBackend`], storing clients, snapshots, versions, and child-version links
+//! in separate column families. Use it with [`KvStorage`]:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_rocksdb::{RocksDbKv, RocksDbStorage};
+//! let storage: RocksDbStorage = RocksDbStorage::new(RocksDbKv::new("/var/lib/taskchampion")?);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use taskchampion_sync_server_core::{kv_keys, KvBackend, KvStorage, KvTxn};
+
+/// Storage in RocksDB.
+pub type RocksDbStorage = KvStorage<RocksDbKv>;
+
+/// The column family for each key tag.
+const COLUMN_FAMILIES: [(u8, &str); 7] = [
+    (kv_keys::CLIENT, "clients"),
+    (kv_keys::CLIENT_METADATA, "client_metadata"),
+    (kv_keys::SNAPSHOT, "snapshots"),
+    (kv_keys::RETAINED_SNAPSHOT, "retained_snapshots"),
+    (kv_keys::VERSION, "versions"),
+    (kv_keys::VERSION_TIMESTAMP, "version_timestamps"),
+    (kv_keys::CHILD, "children"),
+];
+
+/// A [`KvBackend`] storing data in a RocksDB database.
+///
+/// Each key is stored in the column family for its tag, without the tag itself. Transactions
+/// buffer their writes and apply them in a single `WriteBatch` on commit, so a commit is atomic
+/// and durable. Transactions are serialized: a second call to `txn` blocks until the first
+/// transaction is dropped.
+pub struct RocksDbKv {
+    db: DB,
+    lock: Mutex<()>,
+}
+
+impl RocksDbKv {
+    /// Create a new instance using a database in the given directory, creating it if necessary.
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let names = COLUMN_FAMILIES.iter().map(|(_, name)| *name);
+        let db = DB::open_cf(&opts, path.as_ref(), names).with_context(|| {
+            format!(
+                "Error opening RocksDB database at `{}`",
+                path.as_ref().display()
+            )
+        })?;
+        Ok(Self {
+            db,
+            lock: Mutex::new(()),
+        })
+    }
+}
+
+impl KvBackend for RocksDbKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(RocksDbKvTxn {
+            _guard: self.lock.lock().expect("poisoned lock"),
+            db: &self.db,
+            pending: BTreeMap::new(),
+        }))
+    }
+}
+
+struct RocksDbKvTxn<'a> {
+    _guard: MutexGuard<'a, ()>,
+    db: &'a DB,
+    /// Uncommitted changes, with `None` representing a deletion.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl RocksDbKvTxn<'_> {
+    /// Split a key into the column family for its tag and the remainder of the key.
+    fn split<'k>(&self, key: &'k [u8]) -> anyhow::Result<(&ColumnFamily, &'k [u8])> {
+        let (tag, rest) = key
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Empty key"))?;
+        Ok((self.cf(*tag)?, rest))
+    }
+
+    fn cf(&self, tag: u8) -> anyhow::Result<&ColumnFamily> {
+        let (_, name) = COLUMN_FAMILIES
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .ok_or_else(|| anyhow::anyhow!("Unknown key tag {tag}"))?;
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow::anyhow!("Missing column family {name}"))
+    }
+
+    /// Scan the column family for `tag` for keys beginning with `prefix`, adding them to `result`
+    /// with the tag restored.
+    fn scan_cf(
+        &self,
+        tag: u8,
+        prefix: &[u8],
+        result: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let cf = self.cf(tag)?;
+        for item in self
+            .db
+            .iterator_cf(cf, IteratorMode::From(prefix, Direction::Forward))
+        {
+            let (k, v) = item?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            let mut key = vec![tag];
+            key.extend_from_slice(&k);
+            result.insert(key, v.into_vec());
+        }
+        Ok(())
+    }
+}
+
+impl KvTxn for RocksDbKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        let (cf, key) = self.split(key)?;
+        Ok(self.db.get_cf(cf, key)?)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.split(key)?;
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.split(key)?;
+        self.pending.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut result = BTreeMap::new();
+        match prefix.split_first() {
+            Some((tag, rest)) => self.scan_cf(*tag, rest, &mut result)?,
+            None => {
+                for (tag, _) in COLUMN_FAMILIES {
+                    self.scan_cf(tag, &[], &mut result)?;
+                }
+            }
+        }
+        for (k, v) in self
+            .pending
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+        {
+            match v {
+                Some(v) => result.insert(k.clone(), v.clone()),
+                None => result.remove(k),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        let mut batch = WriteBatch::default();
+        for (k, v) in std::mem::take(&mut self.pending) {
+            let (cf, key) = self.split(&k)?;
+            match v {
+                Some(v) => batch.put_cf(cf, key, v),
+                None => batch.delete_cf(cf, key),
+            }
+        }
+        self.db
+            .write(batch)
+            .context("Error committing to RocksDB")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_get_put_delete() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = RocksDbKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        txn.put(b"ca", b"1")?;
+        txn.put(b"vb", b"2")?;
+        // reads see uncommitted writes
+        assert_eq!(txn.get(b"ca")?, Some(b"1".to_vec()));
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"ca")?, Some(b"1".to_vec()));
+        // the tag selects the column family
+        assert_eq!(txn.get(b"va")?, None);
+        txn.delete(b"ca")?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"ca")?, None);
+        assert_eq!(txn.get(b"vb")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_tag() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = RocksDbKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        assert!(txn.put(b"xa", b"1").is_err());
+        assert!(txn.get(b"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = RocksDbKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        txn.put(b"va1", b"x")?;
+        txn.put(b"va2", b"y")?;
+        txn.put(b"vb", b"z")?;
+        txn.put(b"ca", b"w")?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = kv.txn()?;
+        txn.put(b"va3", b"u")?;
+        txn.delete(b"va1")?;
+        assert_eq!(
+            txn.scan_prefix(b"va")?,
+            vec![
+                (b"va2".to_vec(), b"y".to_vec()),
+                (b"va3".to_vec(), b"u".to_vec())
+            ]
+        );
+        assert_eq!(
+            txn.scan_prefix(b"c")?,
+            vec![(b"ca".to_vec(), b"w".to_vec())]
+        );
+        assert_eq!(txn.scan_prefix(b"")?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = RocksDbKv::new(tmp_dir.path())?;
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"ca", b"1")?;
+        }
+        assert_eq!(kv.txn()?.get(b"ca")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_persists() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let storage = RocksDbStorage::new(RocksDbKv::new(tmp_dir.path())?);
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+            txn.commit()?;
+        }
+
+        let storage = RocksDbStorage::new(RocksDbKv::new(tmp_dir.path())?);
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let tmp_dir = TempDir::new()?;
+            test(&RocksDbStorage::new(RocksDbKv::new(tmp_dir.path())?))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,195 @@
+//! This crate implements a [`BlobStore`] using S3 or an S3-compatible object store, such as MinIO.
+//!
+//! Combined with [`BlobStorage`], history segments and snapshots are stored as objects keyed by
+//! client and version ID, while client and version metadata remain in a small local index such as
+//! a SQLite database:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_core::InMemoryStorage;
+//! use taskchampion_sync_server_storage_s3::{S3BlobStore, S3Storage};
+//! let blobs = S3BlobStore::new("my-bucket", "us-east-1", None)?;
+//! let storage: S3Storage<_> = S3Storage::new(InMemoryStorage::new(), blobs);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use taskchampion_sync_server_core::{BlobStorage, BlobStore};
+
+/// Storage with history segments and snapshots in S3, and metadata in `S`.
+pub type S3Storage<S> = BlobStorage<S, S3BlobStore>;
+
+/// A [`BlobStore`] storing each blob as an object in an S3 bucket.
+pub struct S3BlobStore {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3BlobStore {
+    /// Create a new instance using the given bucket and region. If `endpoint` is given, it is
+    /// used in place of the AWS endpoint for the region, with path-style addressing, as is usual
+    /// for S3-compatible stores.
+    ///
+    /// Credentials are taken from the environment, the AWS profile, or the instance metadata, in
+    /// that order.
+    pub fn new(bucket: &str, region: &str, endpoint: Option<&str>) -> anyhow::Result<Self> {
+        let credentials = Credentials::default().context("Could not load S3 credentials")?;
+        let bucket = match endpoint {
+            Some(endpoint) => {
+                let region = Region::Custom {
+                    region: region.to_string(),
+                    endpoint: endpoint.to_string(),
+                };
+                Bucket::new(bucket, region, credentials)?.with_path_style()
+            }
+            None => {
+                let region: Region = region.parse().context("Invalid S3 region")?;
+                Bucket::new(bucket, region, credentials)?
+            }
+        };
+        Ok(Self {
+            bucket,
+            prefix: String::new(),
+        })
+    }
+
+    /// Prefix all object keys with the given string, allowing several servers to share a bucket.
+    /// The prefix should typically end with `/`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl BlobStore for S3BlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key);
+        let response = self
+            .bucket
+            .put_object(&path, data)
+            .with_context(|| format!("Error writing S3 object {path}"))?;
+        if !(200..300).contains(&response.status_code()) {
+            anyhow::bail!(
+                "Error writing S3 object {path}: status {}",
+                response.status_code()
+            );
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.path(key);
+        let response = self
+            .bucket
+            .get_object(&path)
+            .with_context(|| format!("Error reading S3 object {path}"))?;
+        match response.status_code() {
+            404 => Ok(None),
+            200..=299 => Ok(Some(response.bytes().to_vec())),
+            status => anyhow::bail!("Error reading S3 object {path}: status {status}"),
+        }
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let path = self.path(key);
+        let response = self
+            .bucket
+            .delete_object(&path)
+            .with_context(|| format!("Error deleting S3 object {path}"))?;
+        // S3 returns 204 whether or not the object existed, but compatible stores may return 404
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => anyhow::bail!("Error deleting S3 object {path}: status {status}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a store for the bucket given in `TEST_S3_BUCKET`, or return `None` if that variable
+    /// is not set, in which case the test should be skipped. Each call uses a fresh prefix.
+    fn blob_store() -> anyhow::Result<Option<S3BlobStore>> {
+        let Ok(bucket) = std::env::var("TEST_S3_BUCKET") else {
+            return Ok(None);
+        };
+        let region = std::env::var("TEST_S3_REGION").unwrap_or_else(|_| "us-east-1".into());
+        let endpoint = std::env::var("TEST_S3_ENDPOINT").ok();
+        let store = S3BlobStore::new(&bucket, &region, endpoint.as_deref())?
+            .with_prefix(format!("test-{}/", Uuid::new_v4()));
+        Ok(Some(store))
+    }
+
+    #[test]
+    fn test_prefix() -> anyhow::Result<()> {
+        let credentials = Credentials::new(Some("key"), Some("secret"), None, None, None)?;
+        let bucket = Bucket::new("bucket", Region::UsEast1, credentials)?;
+        let store = S3BlobStore {
+            bucket,
+            prefix: String::new(),
+        };
+        assert_eq!(store.path("a/b"), "a/b");
+        let store = store.with_prefix("server1/");
+        assert_eq!(store.path("a/b"), "server1/a/b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_get_delete() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        assert_eq!(store.get("a")?, None);
+        store.put("a", b"data")?;
+        assert_eq!(store.get("a")?, Some(b"data".to_vec()));
+        store.delete("a")?;
+        assert_eq!(store.get("a")?, None);
+        // deleting a missing object is not an error
+        store.delete("a")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> anyhow::Result<()> {
+        let Some(store) = blob_store()? else {
+            return Ok(());
+        };
+        let storage = S3Storage::new(InMemoryStorage::new(), store);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let Some(store) = blob_store()? else {
+                return Ok(());
+            };
+            test(&S3Storage::new(InMemoryStorage::new(), store))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -0,0 +1,12 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        // use a vendored protoc, so that building does not require one to be installed
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        // generate `Bytes` fields, so that history segments and snapshots are not copied
+        tonic_build::configure()
+            .bytes(["."])
+            .compile_protos(&["proto/sync.proto"], &["proto"])?;
+    }
+    Ok(())
+}
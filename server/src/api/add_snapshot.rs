@@ -1,11 +1,8 @@
-use crate::api::{server_error_to_actix, ServerState, SNAPSHOT_CONTENT_TYPE};
+use crate::api::{server_error_to_actix, spool_payload, ServerState, SNAPSHOT_CONTENT_TYPE};
 use actix_web::{error, post, web, HttpMessage, HttpRequest, HttpResponse, Result};
-use futures::StreamExt;
+use std::io::Read;
 use std::sync::Arc;
-use taskchampion_sync_server_core::VersionId;
-
-/// Max snapshot size: 100MB
-const MAX_SIZE: usize = 100 * 1024 * 1024;
+use taskchampion_sync_server_core::{ChangeEvent, ClientId, VersionId};
 
 /// Add a new snapshot, after checking prerequisites.  The snapshot should be transmitted in the
 /// request entity body and must have content-type `application/vnd.taskchampion.snapshot`.  The
@@ -20,7 +17,7 @@ pub(crate) async fn service(
     req: HttpRequest,
     server_state: web::Data<Arc<ServerState>>,
     path: web::Path<VersionId>,
-    mut payload: web::Payload,
+    payload: web::Payload,
 ) -> Result<HttpResponse> {
     let version_id = path.into_inner();
 
@@ -31,26 +28,40 @@ pub(crate) async fn service(
 
     let client_id = server_state.client_id_header(&req)?;
 
-    // read the body in its entirety
-    let mut body = web::BytesMut::new();
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk?;
-        // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("Snapshot over maximum allowed size"));
-        }
-        body.extend_from_slice(&chunk);
-    }
-
-    if body.is_empty() {
+    let (mut body, size) =
+        spool_payload(payload, server_state.body_limits.max_snapshot_len).await?;
+    if size == 0 {
         return Err(error::ErrorBadRequest("No snapshot supplied"));
     }
 
-    server_state
+    let _lock = server_state.client_locks.lock(client_id).await;
+
+    store_snapshot(&server_state, client_id, version_id, &mut body, size)?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Add a snapshot of `size` bytes, read from `data`, notifying subscribers if it is accepted.
+/// Returns whether the snapshot was accepted. The caller must hold the client's lock.
+pub(crate) fn store_snapshot(
+    server_state: &ServerState,
+    client_id: ClientId,
+    version_id: VersionId,
+    data: &mut dyn Read,
+    size: usize,
+) -> Result<bool> {
+    let accepted = server_state
         .server
-        .add_snapshot(client_id, version_id, body.to_vec())
+        .add_snapshot_from_reader(client_id, version_id, data)
         .map_err(server_error_to_actix)?;
-    Ok(HttpResponse::Ok().body(""))
+    if accepted {
+        server_state.notifier.publish(ChangeEvent::SnapshotSet {
+            client_id,
+            version_id,
+            size,
+            timestamp: server_state.clock.now(),
+        });
+    }
+    Ok(accepted)
 }
 
 #[cfg(test)]
@@ -59,7 +70,7 @@ mod test {
     use crate::WebServer;
     use actix_web::{http::StatusCode, test, App};
     use pretty_assertions::assert_eq;
-    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use taskchampion_sync_server_core::{Bytes, InMemoryStorage, Storage, NIL_VERSION_ID};
     use uuid::Uuid;
 
     #[actix_rt::test]
@@ -72,7 +83,7 @@ mod test {
         {
             let mut txn = storage.txn(client_id).unwrap();
             txn.new_client(version_id).unwrap();
-            txn.add_version(version_id, NIL_VERSION_ID, vec![])?;
+            txn.add_version(version_id, NIL_VERSION_ID, Bytes::new())?;
             txn.commit()?;
         }
 
@@ -99,8 +110,7 @@ mod test {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
-        use actix_web::body::MessageBody;
-        let bytes = resp.into_body().try_into_bytes().unwrap();
+        let bytes = test::read_body(resp).await;
         assert_eq!(bytes.as_ref(), b"abcd");
 
         Ok(())
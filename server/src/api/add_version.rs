@@ -1,17 +1,15 @@
 use crate::api::{
-    failure_to_ise, server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE,
-    PARENT_VERSION_ID_HEADER, SNAPSHOT_REQUEST_HEADER, VERSION_ID_HEADER,
+    payload_too_large, server_error_to_actix, spool_payload, storage_error_to_actix, ServerState,
+    HISTORY_SEGMENT_CONTENT_TYPE, PARENT_VERSION_ID_HEADER, SNAPSHOT_REQUEST_HEADER,
+    VERSION_ID_HEADER,
 };
-use actix_web::{error, post, web, HttpMessage, HttpRequest, HttpResponse, Result};
-use futures::StreamExt;
+use actix_web::{error, http::header, post, web, HttpMessage, HttpRequest, HttpResponse, Result};
 use std::sync::Arc;
 use taskchampion_sync_server_core::{
-    AddVersionResult, ServerError, SnapshotUrgency, VersionId, NIL_VERSION_ID,
+    AddVersionCheck, AddVersionResult, ChangeEvent, ServerError, SnapshotUrgency, VersionId,
+    NIL_VERSION_ID,
 };
 
-/// Max history segment size: 100MB
-const MAX_SIZE: usize = 100 * 1024 * 1024;
-
 /// Add a new version, after checking prerequisites.  The history segment should be transmitted in
 /// the request entity body and must have content-type
 /// `application/vnd.taskchampion.history-segment`.  The content can be encoded in any of the
@@ -30,7 +28,7 @@ pub(crate) async fn service(
     req: HttpRequest,
     server_state: web::Data<Arc<ServerState>>,
     path: web::Path<VersionId>,
-    mut payload: web::Payload,
+    payload: web::Payload,
 ) -> Result<HttpResponse> {
     let parent_version_id = path.into_inner();
 
@@ -41,27 +39,54 @@ pub(crate) async fn service(
 
     let client_id = server_state.client_id_header(&req)?;
 
-    // read the body in its entirety
-    let mut body = web::BytesMut::new();
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk?;
-        // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+    // check whether the version would be accepted before reading the body
+    let segment_len = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let max_size = server_state.max_history_segment_len();
+    if segment_len > max_size {
+        return Err(payload_too_large(max_size));
+    }
+    match server_state
+        .server
+        .check_add_version(client_id, parent_version_id, segment_len)
+        .map_err(server_error_to_actix)?
+    {
+        AddVersionCheck::Accept => {}
+        AddVersionCheck::StaleParent(parent_version_id) => {
+            let mut rb = HttpResponse::Conflict();
+            rb.append_header((PARENT_VERSION_ID_HEADER, parent_version_id.to_string()));
+            return Ok(rb.finish());
         }
-        body.extend_from_slice(&chunk);
+        AddVersionCheck::TooLarge => return Err(payload_too_large(max_size)),
     }
 
-    if body.is_empty() {
+    let (mut body, size) = spool_payload(payload, max_size).await?;
+    if size == 0 {
         return Err(error::ErrorBadRequest("Empty body"));
     }
 
+    // the body is spooled before locking, so that a slow upload does not delay other requests
+    let _lock = server_state.client_locks.lock(client_id).await;
+
     loop {
-        return match server_state
-            .server
-            .add_version(client_id, parent_version_id, body.to_vec())
-        {
+        return match server_state.server.add_version_from_reader(
+            client_id,
+            parent_version_id,
+            &mut body,
+        ) {
             Ok((AddVersionResult::Ok(version_id), snap_urgency)) => {
+                server_state.record_sync(&req, client_id);
+                server_state.notifier.publish(ChangeEvent::VersionAdded {
+                    client_id,
+                    version_id,
+                    parent_version_id,
+                    size,
+                    timestamp: server_state.clock.now(),
+                });
                 let mut rb = HttpResponse::Ok();
                 rb.append_header((VERSION_ID_HEADER, version_id.to_string()));
                 match snap_urgency {
@@ -86,8 +111,13 @@ pub(crate) async fn service(
                     .server
                     .txn(client_id)
                     .map_err(server_error_to_actix)?;
-                txn.new_client(NIL_VERSION_ID).map_err(failure_to_ise)?;
-                txn.commit().map_err(failure_to_ise)?;
+                txn.new_client(NIL_VERSION_ID)
+                    .map_err(storage_error_to_actix)?;
+                txn.commit().map_err(storage_error_to_actix)?;
+                server_state.notifier.publish(ChangeEvent::ClientCreated {
+                    client_id,
+                    timestamp: server_state.clock.now(),
+                });
                 continue;
             }
             Err(e) => Err(server_error_to_actix(e)),
@@ -98,10 +128,13 @@ pub(crate) async fn service(
 #[cfg(test)]
 mod test {
     use crate::api::CLIENT_ID_HEADER;
-    use crate::WebServer;
+    use crate::{BodyLimits, WebServer};
     use actix_web::{http::StatusCode, test, App};
+    use chrono::{TimeZone, Utc};
+    use futures::StreamExt;
     use pretty_assertions::assert_eq;
-    use taskchampion_sync_server_core::{InMemoryStorage, Storage};
+    use std::sync::Arc;
+    use taskchampion_sync_server_core::{ChangeEvent, InMemoryStorage, ManualClock, Storage};
     use uuid::Uuid;
 
     #[actix_rt::test]
@@ -147,6 +180,40 @@ mod test {
         assert_eq!(resp.headers().get("X-Parent-Version-Id"), None);
     }
 
+    #[actix_rt::test]
+    async fn test_event_timestamp() {
+        let client_id = Uuid::new_v4();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(now));
+        let server = WebServer::with_clock(Default::default(), None, InMemoryStorage::new(), clock);
+        let mut events = server.server_state.notifier.subscribe(client_id);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/client/add-version/{}", Uuid::nil());
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the client is created, and then the version added, both at the server's time
+        assert!(matches!(
+            events.next().await,
+            Some(ChangeEvent::ClientCreated { timestamp, .. }) if timestamp == now
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(ChangeEvent::VersionAdded { timestamp, .. }) if timestamp == now
+        ));
+    }
+
     #[actix_rt::test]
     async fn test_auto_add_client() {
         let client_id = Uuid::new_v4();
@@ -164,6 +231,7 @@ mod test {
                 "application/vnd.taskchampion.history-segment",
             ))
             .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .append_header(("User-Agent", "taskchampion/1.0"))
             .set_payload(b"abcd".to_vec())
             .to_request();
         let resp = test::call_service(&app, req).await;
@@ -187,6 +255,9 @@ mod test {
             let client = txn.get_client().unwrap().unwrap();
             assert_eq!(client.latest_version_id, new_version_id);
             assert_eq!(client.snapshot, None);
+            assert!(client.created_at.is_some());
+            assert!(client.last_sync_at.is_some());
+            assert_eq!(client.user_agent, Some("taskchampion/1.0".into()));
         }
     }
 
@@ -268,4 +339,58 @@ mod test {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
+
+    #[actix_rt::test]
+    async fn test_too_large() {
+        let client_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // the request is rejected based on the Content-Length header alone
+        let uri = format!("/v1/client/add-version/{}", parent_version_id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .insert_header(("Content-Length", (200 * 1024 * 1024).to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    async fn test_body_limit() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new())
+            .with_body_limits(BodyLimits {
+                max_history_segment_len: 3,
+                ..BodyLimits::default()
+            });
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let uri = format!("/v1/client/add-version/{}", Uuid::nil());
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            body.as_ref(),
+            b"Request body exceeds the maximum of 3 bytes"
+        );
+    }
 }
@@ -0,0 +1,357 @@
+use crate::api::{bearer_token_matches, server_error_to_actix, ServerState, VersionInfo};
+use actix_web::{delete, error, get, post, web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use taskchampion_sync_server_core::{Client, ClientId, ClientStats, ServerError};
+
+/// The default number of items in a page of results.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The maximum number of items in a page of results.
+const MAX_LIMIT: usize = 1000;
+
+/// Query parameters for paged results.
+#[derive(Deserialize)]
+struct PageQuery {
+    /// Begin after this client ID.
+    after: Option<ClientId>,
+    /// The maximum number of items to return.
+    limit: Option<usize>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+}
+
+/// A client, as returned by the admin API.
+#[derive(Serialize)]
+struct ClientInfo {
+    client_id: ClientId,
+    #[serde(flatten)]
+    client: Client,
+    stats: Option<ClientStats>,
+}
+
+/// A page of clients. `next` is the value of `after` with which to request the next page, if
+/// there may be more clients.
+#[derive(Serialize)]
+struct ClientPage {
+    clients: Vec<ClientInfo>,
+    next: Option<ClientId>,
+}
+
+/// Query parameters for expiring versions.
+#[derive(Deserialize)]
+struct ExpireQuery {
+    /// Expire versions added before this time.
+    before: DateTime<Utc>,
+}
+
+/// Check that the request carries the admin token. The admin API is only served if a token is
+/// configured.
+fn authorize(req: &HttpRequest, server_state: &ServerState) -> Result<()> {
+    match &server_state.admin_token {
+        Some(token) if bearer_token_matches(req, token) => Ok(()),
+        _ => Err(error::ErrorUnauthorized("invalid admin token")),
+    }
+}
+
+/// Get a client and its statistics.
+fn client_info(server_state: &ServerState, client_id: ClientId) -> Result<ClientInfo, ServerError> {
+    let server = &server_state.server;
+    let client = server.get_client(client_id)?;
+    // statistics are informational, and not supported by every backend
+    let stats = server.get_client_stats(client_id).ok();
+    Ok(ClientInfo {
+        client_id,
+        client,
+        stats,
+    })
+}
+
+/// List clients, with their statistics, in pages.
+#[get("/clients")]
+async fn list_clients(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    query: web::Query<PageQuery>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    let limit = query.limit();
+    let client_ids = server_state
+        .server
+        .list_clients(query.after, limit)
+        .map_err(server_error_to_actix)?;
+    let next = if client_ids.len() == limit {
+        client_ids.last().copied()
+    } else {
+        None
+    };
+    let mut clients = Vec::with_capacity(client_ids.len());
+    for client_id in client_ids {
+        match client_info(&server_state, client_id) {
+            Ok(info) => clients.push(info),
+            // a client purged since it was listed is skipped
+            Err(ServerError::NoSuchClient) => {}
+            Err(err) => return Err(server_error_to_actix(err)),
+        }
+    }
+    Ok(HttpResponse::Ok().json(ClientPage { clients, next }))
+}
+
+/// Get a client, with its statistics.
+#[get("/clients/{client_id}")]
+async fn get_client(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    let info = client_info(&server_state, path.into_inner()).map_err(server_error_to_actix)?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+/// Get a client's version chain, from its latest version back toward its first.
+#[get("/clients/{client_id}/versions")]
+async fn get_versions(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+    query: web::Query<PageQuery>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    let versions = server_state
+        .server
+        .get_version_chain(path.into_inner(), query.limit())
+        .map_err(server_error_to_actix)?;
+    let versions: Vec<VersionInfo> = versions.into_iter().map(VersionInfo::from).collect();
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+/// Mark a client as deleted, to be purged after the grace period.
+#[delete("/clients/{client_id}")]
+async fn delete_client(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    server_state
+        .server
+        .delete_client(path.into_inner())
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Restore a deleted client which has not yet been purged.
+#[post("/clients/{client_id}/undelete")]
+async fn undelete_client(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    server_state
+        .server
+        .undelete_client(path.into_inner())
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Delete a client's versions added before the given time.
+#[post("/clients/{client_id}/expire-versions")]
+async fn expire_versions(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+    query: web::Query<ExpireQuery>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    let expired = server_state
+        .server
+        .expire_versions_older_than(path.into_inner(), query.before)
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "expired": expired })))
+}
+
+/// Purge deleted clients whose grace period has passed, rather than waiting for the periodic
+/// purge.
+#[post("/purge-deleted-clients")]
+async fn purge_deleted_clients(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    let purged = server_state
+        .server
+        .purge_deleted_clients()
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "purged": purged })))
+}
+
+/// Get statistics about the space used by all clients.
+#[get("/stats")]
+async fn storage_stats(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    authorize(&req, &server_state)?;
+    let stats = server_state
+        .server
+        .get_storage_stats()
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// The admin API, under `/admin/v1`. Every request requires the admin token as a bearer token.
+pub(crate) fn admin_scope() -> actix_web::Scope {
+    web::scope("/admin/v1")
+        .service(list_clients)
+        .service(get_client)
+        .service(get_versions)
+        .service(delete_client)
+        .service(undelete_client)
+        .service(expire_versions)
+        .service(purge_deleted_clients)
+        .service(storage_stats)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    const AUTH: (&str, &str) = ("Authorization", "Bearer secret");
+
+    fn storage_with_client(client_id: Uuid, version_id: Uuid) -> anyhow::Result<InMemoryStorage> {
+        let storage = InMemoryStorage::new();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+        txn.commit()?;
+        drop(txn);
+        Ok(storage)
+    }
+
+    #[actix_rt::test]
+    async fn test_not_served_without_token() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let req = test::TestRequest::get()
+            .uri("/admin/v1/clients")
+            .insert_header(AUTH)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_requires_token() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new())
+            .with_admin_token("secret".into());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let req = test::TestRequest::get()
+            .uri("/admin/v1/clients")
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_inspect_clients() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let server = WebServer::new(
+            Default::default(),
+            None,
+            storage_with_client(client_id, version_id)?,
+        )
+        .with_admin_token("secret".into());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/admin/v1/clients")
+            .insert_header(AUTH)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["clients"][0]["client_id"], client_id.to_string());
+        assert_eq!(
+            body["clients"][0]["latest_version_id"],
+            version_id.to_string()
+        );
+        assert_eq!(body["clients"][0]["stats"]["version_count"], 1);
+        assert_eq!(body["next"], Value::Null);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/v1/clients/{client_id}/versions"))
+            .insert_header(AUTH)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body[0]["version_id"], version_id.to_string());
+        assert_eq!(body[0]["size"], 3);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/v1/clients/{}", Uuid::new_v4()))
+            .insert_header(AUTH)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/v1/stats")
+            .insert_header(AUTH)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["client_count"], 1);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_client() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(
+            Default::default(),
+            None,
+            storage_with_client(client_id, Uuid::new_v4())?,
+        )
+        .with_admin_token("secret".into());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/admin/v1/clients/{client_id}"))
+            .insert_header(AUTH)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/v1/clients/{client_id}"))
+            .insert_header(AUTH)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body["deleted_at"].is_string());
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/v1/clients/{client_id}/undelete"))
+            .insert_header(AUTH)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::post()
+            .uri("/admin/v1/purge-deleted-clients")
+            .insert_header(AUTH)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["purged"], 0);
+        Ok(())
+    }
+}
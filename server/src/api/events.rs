@@ -0,0 +1,116 @@
+use crate::api::ServerState;
+use actix_web::{get, http::header, rt, web, HttpRequest, HttpResponse, Result};
+use futures::{stream, StreamExt};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use taskchampion_sync_server_core::ChangeEvent;
+
+/// Interval at which a comment is sent, so that proxies do not close an idle stream.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Format an event as a server-sent event.
+fn sse_event(event: &ChangeEvent) -> web::Bytes {
+    let data = serde_json::to_string(event).expect("events are serializable");
+    web::Bytes::from(format!("data: {data}\n\n"))
+}
+
+/// Stream the sync activity for a client as server-sent events (`text/event-stream`), as a
+/// simpler alternative to `/v1/client/updates` for scripts and dashboards.
+///
+/// Each event's data is a JSON object, in the same format as the WebSocket messages: a `type` of
+/// `client_created`, `version_added`, or `snapshot_set`, with the details of the change. A comment
+/// is sent every 15 seconds to keep the stream open.
+///
+/// Events are only sent for changes made through this server process, and are not persisted.
+#[get("/v1/client/events")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let events = server_state
+        .notifier
+        .subscribe(client_id)
+        .map(|event| sse_event(&event));
+    let keep_alive = stream::unfold(
+        rt::time::interval(KEEP_ALIVE_INTERVAL),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((web::Bytes::from_static(b": keep-alive\n\n"), interval))
+        },
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        // disable buffering in nginx and compression, either of which would delay events
+        .append_header(("X-Accel-Buffering", "no"))
+        .append_header((header::CONTENT_ENCODING, "identity"))
+        .streaming(stream::select(events, keep_alive).map(Ok::<_, Infallible>)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::body::MessageBody;
+    use actix_web::{http::StatusCode, test, App};
+    use chrono::{TimeZone, Utc};
+    use futures::future::poll_fn;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::InMemoryStorage;
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn sse_format() {
+        let client_id = Uuid::new_v4();
+        let event = ChangeEvent::ClientDeleted {
+            client_id,
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        };
+        assert_eq!(
+            sse_event(&event),
+            format!(
+                "data: {{\"type\":\"client_deleted\",\"client_id\":\"{client_id}\",\
+                 \"timestamp\":\"2024-01-01T00:00:00Z\"}}\n\n"
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_stream() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/events")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "text/event-stream"
+        );
+
+        let event = ChangeEvent::ClientDeleted {
+            client_id,
+            timestamp: Utc::now(),
+        };
+        server.server_state.notifier.publish(event.clone());
+
+        // skip the initial keep-alive comment
+        let mut body = Box::pin(resp.into_body());
+        loop {
+            let chunk = poll_fn(|cx| body.as_mut().poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            if !chunk.starts_with(b":") {
+                assert_eq!(chunk, sse_event(&event));
+                break;
+            }
+        }
+    }
+}
@@ -1,10 +1,38 @@
 use crate::api::{
-    server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE, PARENT_VERSION_ID_HEADER,
-    VERSION_ID_HEADER,
+    read_chunks, server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE,
+    PARENT_VERSION_ID_HEADER, VERSION_ID_HEADER,
 };
-use actix_web::{error, get, web, HttpRequest, HttpResponse, Result};
+use crate::notify::Subscription;
+use actix_web::{error, get, rt, web, HttpRequest, HttpResponse, Result};
+use futures::StreamExt;
+use serde::Deserialize;
 use std::sync::Arc;
-use taskchampion_sync_server_core::{GetVersionResult, ServerError, VersionId};
+use std::time::{Duration, Instant};
+use taskchampion_sync_server_core::{ChangeEvent, GetVersionResult, ServerError, VersionId};
+
+/// The longest a request may wait for a new version.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    /// How long to wait for a new version, such as `30s`.
+    wait: Option<String>,
+}
+
+/// Parse a wait duration, in seconds with an optional `s` suffix, such as `30s`.
+fn parse_wait(wait: &str) -> Option<Duration> {
+    let secs = wait.strip_suffix('s').unwrap_or(wait).parse().ok()?;
+    Some(Duration::from_secs(secs).min(MAX_WAIT))
+}
+
+/// Wait until a version is added.
+async fn version_added(events: &mut Subscription) {
+    while let Some(event) = events.next().await {
+        if let ChangeEvent::VersionAdded { .. } = event {
+            return;
+        }
+    }
+}
 
 /// Get a child version.
 ///
@@ -12,42 +40,83 @@ use taskchampion_sync_server_core::{GetVersionResult, ServerError, VersionId};
 /// with content-type `application/vnd.taskchampion.history-segment`.  The `X-Version-Id` and
 /// `X-Parent-Version-Id` headers contain the corresponding values.
 ///
-/// If no such child exists, returns a 404 with no content.
+/// If no such child exists, returns a 404 with no content. With a `wait` query parameter such as
+/// `?wait=30s`, the request is instead held open until a child is added or the wait, of at most
+/// 60 seconds, elapses, so a replica can poll much less frequently.
+///
 /// Returns other 4xx or 5xx responses on other errors.
 #[get("/v1/client/get-child-version/{parent_version_id}")]
 pub(crate) async fn service(
     req: HttpRequest,
     server_state: web::Data<Arc<ServerState>>,
     path: web::Path<VersionId>,
+    query: web::Query<WaitQuery>,
 ) -> Result<HttpResponse> {
     let parent_version_id = path.into_inner();
     let client_id = server_state.client_id_header(&req)?;
+    let deadline = match &query.wait {
+        Some(wait) => Some(
+            Instant::now() + parse_wait(wait).ok_or_else(|| error::ErrorBadRequest("bad wait"))?,
+        ),
+        None => None,
+    };
+
+    loop {
+        // subscribe before looking for the version, so that a version added in between is not
+        // missed
+        let mut events = deadline.map(|_| server_state.notifier.subscribe(client_id));
+        let result = {
+            let _lock = server_state.client_locks.lock(client_id).await;
+            server_state
+                .server
+                .get_child_version_reader(client_id, parent_version_id)
+        };
+
+        if let (Ok(GetVersionResult::NotFound), Some(deadline), Some(events)) =
+            (&result, deadline, &mut events)
+        {
+            // the lock is not held while waiting, so that the version can be added
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !remaining.is_zero()
+                && rt::time::timeout(remaining, version_added(events))
+                    .await
+                    .is_ok()
+            {
+                continue;
+            }
+        }
 
-    match server_state
-        .server
-        .get_child_version(client_id, parent_version_id)
-    {
-        Ok(GetVersionResult::Success {
-            version_id,
-            parent_version_id,
-            history_segment,
-        }) => Ok(HttpResponse::Ok()
-            .content_type(HISTORY_SEGMENT_CONTENT_TYPE)
-            .append_header((VERSION_ID_HEADER, version_id.to_string()))
-            .append_header((PARENT_VERSION_ID_HEADER, parent_version_id.to_string()))
-            .body(history_segment)),
-        Ok(GetVersionResult::NotFound) => Err(error::ErrorNotFound("no such version")),
-        Ok(GetVersionResult::Gone) => Err(error::ErrorGone("version has been deleted")),
-        // Note that the HTTP client cannot differentiate `NotFound` and `NoSuchClient`, as both
-        // are a 404 NOT FOUND response. In either case, the HTTP client will typically attempt
-        // to add a new version, which may create the new client at the same time.
-        Err(ServerError::NoSuchClient) => Err(error::ErrorNotFound("no such client")),
-        Err(e) => Err(server_error_to_actix(e)),
+        return match result {
+            Ok(GetVersionResult::Success {
+                version_id,
+                parent_version_id,
+                history_segment,
+            }) => {
+                server_state.record_sync(&req, client_id);
+                Ok(HttpResponse::Ok()
+                    .content_type(HISTORY_SEGMENT_CONTENT_TYPE)
+                    .append_header((VERSION_ID_HEADER, version_id.to_string()))
+                    .append_header((PARENT_VERSION_ID_HEADER, parent_version_id.to_string()))
+                    .streaming(read_chunks(history_segment)))
+            }
+            Ok(GetVersionResult::NotFound) => {
+                // the client is up to date, which completes a sync
+                server_state.record_sync(&req, client_id);
+                Err(error::ErrorNotFound("no such version"))
+            }
+            Ok(GetVersionResult::Gone) => Err(error::ErrorGone("version has been deleted")),
+            // Note that the HTTP client cannot differentiate `NotFound` and `NoSuchClient`, as
+            // both are a 404 NOT FOUND response. In either case, the HTTP client will typically
+            // attempt to add a new version, which may create the new client at the same time.
+            Err(ServerError::NoSuchClient) => Err(error::ErrorNotFound("no such client")),
+            Err(e) => Err(server_error_to_actix(e)),
+        };
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use crate::api::CLIENT_ID_HEADER;
     use crate::WebServer;
     use actix_web::{http::StatusCode, test, App};
@@ -66,7 +135,7 @@ mod test {
         {
             let mut txn = storage.txn(client_id).unwrap();
             txn.new_client(Uuid::new_v4()).unwrap();
-            txn.add_version(version_id, parent_version_id, b"abcd".to_vec())
+            txn.add_version(version_id, parent_version_id, b"abcd".to_vec().into())
                 .unwrap();
             txn.commit().unwrap();
         }
@@ -95,8 +164,7 @@ mod test {
             &"application/vnd.taskchampion.history-segment".to_string()
         );
 
-        use actix_web::body::MessageBody;
-        let bytes = resp.into_body().try_into_bytes().unwrap();
+        let bytes = test::read_body(resp).await;
         assert_eq!(bytes.as_ref(), b"abcd");
     }
 
@@ -130,7 +198,7 @@ mod test {
         {
             let mut txn = storage.txn(client_id).unwrap();
             txn.new_client(Uuid::new_v4()).unwrap();
-            txn.add_version(test_version_id, NIL_VERSION_ID, b"vers".to_vec())
+            txn.add_version(test_version_id, NIL_VERSION_ID, b"vers".to_vec().into())
                 .unwrap();
             txn.commit().unwrap();
         }
@@ -178,4 +246,81 @@ mod test {
         assert_eq!(resp.headers().get("X-Version-Id"), None);
         assert_eq!(resp.headers().get("X-Parent-Version-Id"), None);
     }
+
+    #[actix_rt::test]
+    async fn parse_wait_durations() {
+        assert_eq!(parse_wait("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_wait("5"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_wait("3600s"), Some(MAX_WAIT));
+        assert_eq!(parse_wait("soon"), None);
+        assert_eq!(parse_wait("-1s"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_wait() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"vers".to_vec().into())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let get = test::TestRequest::get()
+            .uri(&format!(
+                "/v1/client/get-child-version/{version_id}?wait=30s"
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let add = async {
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+            let req = test::TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{version_id}"))
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"abcd".to_vec())
+                .to_request();
+            test::call_service(&app, req).await
+        };
+        let (get_resp, add_resp) = futures::join!(test::call_service(&app, get), add);
+        assert_eq!(add_resp.status(), StatusCode::OK);
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(
+            get_resp.headers().get("X-Version-Id"),
+            add_resp.headers().get("X-Version-Id")
+        );
+        assert_eq!(
+            get_resp.headers().get("X-Parent-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+
+        // without a new version, the wait times out
+        let latest_version_id = add_resp.headers().get("X-Version-Id").unwrap();
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/v1/client/get-child-version/{}?wait=1s",
+                latest_version_id.to_str().unwrap()
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/v1/client/get-child-version/{version_id}?wait=later"
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
 }
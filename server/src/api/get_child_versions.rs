@@ -0,0 +1,248 @@
+use crate::api::{
+    server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE, PARENT_VERSION_ID_HEADER,
+    VERSION_ID_HEADER,
+};
+use actix_web::{error, get, web, HttpRequest, HttpResponse, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{ClientId, GetVersionResult, ServerError, Version, VersionId};
+use uuid::Uuid;
+
+/// The default number of versions in a batch.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The maximum number of versions in a batch.
+const MAX_LIMIT: usize = 1000;
+
+/// The size of history segments, in bytes, after which no more versions are added to a batch:
+/// 16MiB. A batch always contains at least one version.
+const MAX_BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub(super) struct BatchQuery {
+    /// The maximum number of versions to return.
+    pub(super) limit: Option<usize>,
+}
+
+/// Encode versions as the parts of a `multipart/mixed` body with the given boundary.
+fn multipart_body(versions: &[Version], boundary: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for version in versions {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Type: {HISTORY_SEGMENT_CONTENT_TYPE}\r\n\
+                 {VERSION_ID_HEADER}: {}\r\n{PARENT_VERSION_ID_HEADER}: {}\r\n\
+                 Content-Length: {}\r\n\r\n",
+                version.version_id,
+                version.parent_version_id,
+                version.history_segment.len(),
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&version.history_segment);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    body
+}
+
+/// Get a batch of child versions: the child of the given parent, its child, and so on, up to
+/// `limit` versions. This allows a replica which has been offline for a long time to catch up in
+/// a few requests rather than one request per version.
+///
+/// On success, the response is `multipart/mixed`, with one part per version in order. Each part
+/// has content-type `application/vnd.taskchampion.history-segment`, and `X-Version-Id` and
+/// `X-Parent-Version-Id` headers, exactly as in a GetChildVersion response. A batch may contain
+/// fewer than `limit` versions, to bound the size of the response, so the replica should request
+/// another batch beginning with the last version until it receives a 404.
+///
+/// If the parent has no child, the response is a 404 if the client is up to date, or a 410 if
+/// the parent version has been deleted, as for GetChildVersion.
+#[get("/v1/client/get-child-versions/{parent_version_id}")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+    query: web::Query<BatchQuery>,
+) -> Result<HttpResponse> {
+    let parent_version_id = path.into_inner();
+    let client_id = server_state.client_id_header(&req)?;
+    let _lock = server_state.client_locks.lock(client_id).await;
+    let versions = child_versions(
+        &req,
+        &server_state,
+        client_id,
+        parent_version_id,
+        query.limit,
+    )?;
+
+    let boundary = Uuid::new_v4().simple().to_string();
+    Ok(HttpResponse::Ok()
+        .content_type(format!("multipart/mixed; boundary={boundary}"))
+        .body(multipart_body(&versions, &boundary)))
+}
+
+/// Get a batch of child versions, as described for [`service`], recording the sync. The caller
+/// must hold the client's lock.
+pub(super) fn child_versions(
+    req: &HttpRequest,
+    server_state: &ServerState,
+    client_id: ClientId,
+    parent_version_id: VersionId,
+    limit: Option<usize>,
+) -> Result<Vec<Version>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut versions = server_state
+        .server
+        .get_versions_since(client_id, parent_version_id, limit)
+        .map_err(server_error_to_actix)?;
+    if versions.is_empty() {
+        // distinguish an up-to-date client from a deleted parent version
+        return match server_state
+            .server
+            .get_child_version(client_id, parent_version_id)
+        {
+            Ok(GetVersionResult::Gone) => Err(error::ErrorGone("version has been deleted")),
+            Ok(_) => {
+                server_state.record_sync(req, client_id);
+                Err(error::ErrorNotFound("no such version"))
+            }
+            Err(ServerError::NoSuchClient) => Err(error::ErrorNotFound("no such client")),
+            Err(e) => Err(server_error_to_actix(e)),
+        };
+    }
+
+    let mut total = 0;
+    let count = versions
+        .iter()
+        .enumerate()
+        .take_while(|(i, v)| {
+            total += v.history_segment.len();
+            *i == 0 || total <= MAX_BATCH_BYTES
+        })
+        .count();
+    versions.truncate(count);
+
+    server_state.record_sync(req, client_id);
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+
+    #[actix_rt::test]
+    async fn multipart_encoding() {
+        let (v1, v2) = (Uuid::new_v4(), Uuid::new_v4());
+        let versions = vec![
+            Version {
+                version_id: v1,
+                parent_version_id: NIL_VERSION_ID,
+                history_segment: b"ab".to_vec().into(),
+                created_at: None,
+            },
+            Version {
+                version_id: v2,
+                parent_version_id: v1,
+                history_segment: b"c".to_vec().into(),
+                created_at: None,
+            },
+        ];
+        let body = String::from_utf8(multipart_body(&versions, "XYZ")).unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "--XYZ\r\n\
+                 Content-Type: application/vnd.taskchampion.history-segment\r\n\
+                 X-Version-Id: {v1}\r\n\
+                 X-Parent-Version-Id: {NIL_VERSION_ID}\r\n\
+                 Content-Length: 2\r\n\r\n\
+                 ab\r\n\
+                 --XYZ\r\n\
+                 Content-Type: application/vnd.taskchampion.history-segment\r\n\
+                 X-Version-Id: {v2}\r\n\
+                 X-Parent-Version-Id: {v1}\r\n\
+                 Content-Length: 1\r\n\r\n\
+                 c\r\n\
+                 --XYZ--\r\n"
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let (v1, v2, v3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(v1, NIL_VERSION_ID, b"a".to_vec().into())
+                .unwrap();
+            txn.add_version(v2, v1, b"b".to_vec().into()).unwrap();
+            txn.add_version(v3, v2, b"c".to_vec().into()).unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-versions/{v1}?limit=10"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let content_type = resp
+            .headers()
+            .get("Content-Type")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap()
+            .to_string();
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body.matches(&format!("--{boundary}\r\n")).count(), 2);
+        assert!(body.contains(&format!("X-Version-Id: {v2}")));
+        assert!(body.contains(&format!("X-Version-Id: {v3}")));
+        assert!(body.ends_with(&format!("--{boundary}--\r\n")));
+    }
+
+    #[actix_rt::test]
+    async fn test_up_to_date_and_gone() {
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(v1, NIL_VERSION_ID, b"a".to_vec().into())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-versions/{v1}"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-versions/{}", Uuid::new_v4()))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+    }
+}
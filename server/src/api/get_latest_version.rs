@@ -0,0 +1,105 @@
+use crate::api::{server_error_to_actix, ServerState, VERSION_ID_HEADER};
+use actix_web::{route, web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{Snapshot, VersionId};
+
+#[derive(Serialize)]
+struct LatestVersion {
+    latest_version_id: VersionId,
+    snapshot: Option<Snapshot>,
+}
+
+/// Get the client's latest version ID and snapshot metadata, without reading any version, so that
+/// a replica or a monitor can check whether it is up to date.
+///
+/// The response is a JSON object with `latest_version_id`, which is the nil UUID if the client
+/// has no versions, and `snapshot`, which is null or an object with `version_id`, `timestamp`,
+/// and `versions_since`. The latest version ID is also given in the `X-Version-Id` header, so a
+/// `HEAD` request suffices to check it.
+///
+/// This is not part of the sync protocol, and is not used by replicas.
+#[route("/v1/client/latest-version", method = "GET", method = "HEAD")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let (latest_version_id, snapshot) = server_state
+        .server
+        .get_latest_version(client_id)
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::Ok()
+        .append_header((VERSION_ID_HEADER, latest_version_id.to_string()))
+        .json(LatestVersion {
+            latest_version_id,
+            snapshot,
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec().into())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/latest-version")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["latest_version_id"], version_id.to_string());
+        assert_eq!(body["snapshot"], Value::Null);
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/v1/client/latest-version")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_client_not_found() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/latest-version")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,12 +1,82 @@
-use crate::api::{server_error_to_actix, ServerState, SNAPSHOT_CONTENT_TYPE, VERSION_ID_HEADER};
-use actix_web::{error, get, web, HttpRequest, HttpResponse, Result};
+use crate::api::{
+    read_chunks, server_error_to_actix, ServerState, SNAPSHOT_CONTENT_TYPE, SPOOL_THRESHOLD,
+    VERSION_ID_HEADER,
+};
+use actix_web::http::header::{self, ETag, EntityTag, IfNoneMatch};
+use actix_web::{error, get, web, HttpMessage, HttpRequest, HttpResponse, Result};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::sync::Arc;
+use taskchampion_sync_server_core::VersionId;
+use tempfile::SpooledTempFile;
+
+/// The entity tag for a snapshot. A snapshot's data never changes, so its version ID suffices.
+fn snapshot_etag(version_id: VersionId) -> EntityTag {
+    EntityTag::new_strong(version_id.to_string())
+}
+
+/// Determine whether an `If-None-Match` header matches the given entity tag.
+fn none_match(if_none_match: &IfNoneMatch, etag: &EntityTag) -> bool {
+    match if_none_match {
+        IfNoneMatch::Any => true,
+        IfNoneMatch::Items(items) => items.iter().any(|item| item.weak_eq(etag)),
+    }
+}
+
+/// A single byte range requested with a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The bytes from the first offset through the second, inclusive, or to the end.
+    From(u64, Option<u64>),
+    /// The last bytes, of the given number.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parse a `Range` header. Returns None for anything other than a single byte range, in
+    /// which case the header is ignored.
+    fn parse(value: &str) -> Option<ByteRange> {
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (first, last) = spec.trim().split_once('-')?;
+        match (first.parse().ok(), last) {
+            (None, last) if first.is_empty() => Some(ByteRange::Suffix(last.parse().ok()?)),
+            (Some(first), "") => Some(ByteRange::From(first, None)),
+            (Some(first), last) => {
+                let last = last.parse().ok()?;
+                (first <= last).then_some(ByteRange::From(first, Some(last)))
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// Resolve the range against data of the given length, giving the first and last offsets,
+    /// inclusive, or None if the range is not satisfiable.
+    fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::From(first, last) if first < len => {
+                Some((first, last.map_or(len - 1, |last| last.min(len - 1))))
+            }
+            ByteRange::Suffix(n) if n > 0 && len > 0 => Some((len - n.min(len), len - 1)),
+            _ => None,
+        }
+    }
+}
 
 /// Get a snapshot.
 ///
 /// If a snapshot for this client exists, it is returned with content-type
 /// `application/vnd.taskchampion.snapshot`.  The `X-Version-Id` header contains the version of the
-/// snapshot.
+/// snapshot, and the `ETag` header identifies it.
+///
+/// If the request's `If-None-Match` header matches the current snapshot, returns a 304 with no
+/// content, so that a replica which already has the snapshot need not download it again.
+///
+/// A single byte range may be requested with a `Range` header, so that an interrupted download
+/// can be resumed, and the response is then a 206 with a `Content-Range` header. An `If-Range`
+/// header with the snapshot's `ETag` ensures that the rest of the same snapshot is returned; if
+/// the snapshot has changed, the whole snapshot is returned with a 200.
 ///
 /// If no snapshot exists, returns a 404 with no content.  Returns other 4xx or 5xx responses on
 /// other errors.
@@ -16,23 +86,80 @@ pub(crate) async fn service(
     server_state: web::Data<Arc<ServerState>>,
 ) -> Result<HttpResponse> {
     let client_id = server_state.client_id_header(&req)?;
+    let _lock = server_state.client_locks.lock(client_id).await;
 
-    if let Some((version_id, data)) = server_state
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        let (_, snapshot) = server_state
+            .server
+            .get_latest_version(client_id)
+            .map_err(server_error_to_actix)?;
+        if let Some(snapshot) = snapshot {
+            let etag = snapshot_etag(snapshot.version_id);
+            if none_match(&if_none_match, &etag) {
+                return Ok(HttpResponse::NotModified()
+                    .append_header((VERSION_ID_HEADER, snapshot.version_id.to_string()))
+                    .insert_header(ETag(etag))
+                    .finish());
+            }
+        }
+    }
+
+    let Some((version_id, reader)) = server_state
         .server
-        .get_snapshot(client_id)
+        .get_snapshot_reader(client_id)
         .map_err(server_error_to_actix)?
-    {
-        Ok(HttpResponse::Ok()
-            .content_type(SNAPSHOT_CONTENT_TYPE)
-            .append_header((VERSION_ID_HEADER, version_id.to_string()))
-            .body(data))
-    } else {
-        Err(error::ErrorNotFound("no snapshot"))
-    }
+    else {
+        return Err(error::ErrorNotFound("no snapshot"));
+    };
+    let etag = snapshot_etag(version_id);
+
+    // a range of a different snapshot is useless, so in that case return the whole snapshot
+    let if_range_matches = match req.headers().get(header::IF_RANGE) {
+        Some(if_range) => if_range.to_str().ok() == Some(etag.to_string().as_str()),
+        None => true,
+    };
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ByteRange::parse)
+        .filter(|_| if_range_matches);
+
+    let mut rb = HttpResponse::Ok();
+    rb.content_type(SNAPSHOT_CONTENT_TYPE)
+        .append_header((VERSION_ID_HEADER, version_id.to_string()))
+        .append_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(ETag(etag));
+    let Some(range) = range else {
+        return Ok(rb.streaming(read_chunks(reader)));
+    };
+
+    // the snapshot's length is not known without reading it, so spool it to find its length
+    // and seek within it
+    let (mut file, len) = web::block(move || -> io::Result<_> {
+        let mut reader = reader;
+        let mut file = SpooledTempFile::new(SPOOL_THRESHOLD);
+        let len = io::copy(&mut reader, &mut file)?;
+        Ok((file, len))
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    let Some((first, last)) = range.resolve(len) else {
+        return Ok(HttpResponse::RangeNotSatisfiable()
+            .append_header((header::CONTENT_RANGE, format!("bytes */{len}")))
+            .finish());
+    };
+    file.seek(SeekFrom::Start(first))
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(rb
+        .status(actix_web::http::StatusCode::PARTIAL_CONTENT)
+        .append_header((header::CONTENT_RANGE, format!("bytes {first}-{last}/{len}")))
+        .streaming(read_chunks(Box::new(file.take(last - first + 1)))))
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use crate::api::CLIENT_ID_HEADER;
     use crate::WebServer;
     use actix_web::{http::StatusCode, test, App};
@@ -80,10 +207,10 @@ mod test {
             txn.set_snapshot(
                 Snapshot {
                     version_id,
-                    versions_since: 3,
+                    versions_since: 0,
                     timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
                 },
-                snapshot_data.clone(),
+                snapshot_data.clone().into(),
             )
             .unwrap();
             txn.commit().unwrap();
@@ -101,8 +228,147 @@ mod test {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
-        use actix_web::body::MessageBody;
-        let bytes = resp.into_body().try_into_bytes().unwrap();
+        assert_eq!(
+            resp.headers().get("ETag").unwrap(),
+            &format!("\"{version_id}\"")
+        );
+
+        let bytes = test::read_body(resp).await;
         assert_eq!(bytes.as_ref(), snapshot_data);
     }
+
+    #[actix_rt::test]
+    async fn test_if_none_match() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(Uuid::new_v4()).unwrap();
+            txn.set_snapshot(
+                Snapshot {
+                    version_id,
+                    versions_since: 0,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                vec![1, 2, 3, 4].into(),
+            )
+            .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        // the current snapshot is not modified
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .append_header(("If-None-Match", format!("\"{version_id}\"")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+
+        // an older snapshot is replaced
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .append_header(("If-None-Match", format!("\"{}\"", Uuid::new_v4())))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn parse_range() {
+        assert_eq!(
+            ByteRange::parse("bytes=2-4"),
+            Some(ByteRange::From(2, Some(4)))
+        );
+        assert_eq!(ByteRange::parse("bytes=5-"), Some(ByteRange::From(5, None)));
+        assert_eq!(ByteRange::parse("bytes=-3"), Some(ByteRange::Suffix(3)));
+        assert_eq!(ByteRange::parse("bytes=4-2"), None);
+        assert_eq!(ByteRange::parse("bytes=0-1,4-5"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+        assert_eq!(ByteRange::parse("bytes=-"), None);
+    }
+
+    #[actix_rt::test]
+    async fn resolve_range() {
+        assert_eq!(ByteRange::From(2, Some(4)).resolve(8), Some((2, 4)));
+        assert_eq!(ByteRange::From(2, Some(100)).resolve(8), Some((2, 7)));
+        assert_eq!(ByteRange::From(5, None).resolve(8), Some((5, 7)));
+        assert_eq!(ByteRange::From(8, None).resolve(8), None);
+        assert_eq!(ByteRange::Suffix(3).resolve(8), Some((5, 7)));
+        assert_eq!(ByteRange::Suffix(30).resolve(8), Some((0, 7)));
+        assert_eq!(ByteRange::Suffix(0).resolve(8), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_range() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(Uuid::new_v4()).unwrap();
+            txn.set_snapshot(
+                Snapshot {
+                    version_id,
+                    versions_since: 0,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                b"abcdefgh".to_vec().into(),
+            )
+            .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let get = |headers: &[(&'static str, String)]| {
+            let mut req = test::TestRequest::get()
+                .uri("/v1/client/snapshot")
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()));
+            for header in headers {
+                req = req.append_header(header.clone());
+            }
+            req.to_request()
+        };
+
+        let resp = test::call_service(&app, get(&[("Range", "bytes=2-4".into())])).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 2-4/8");
+        assert_eq!(test::read_body(resp).await.as_ref(), b"cde");
+
+        let etag = format!("\"{version_id}\"");
+        let resp = test::call_service(
+            &app,
+            get(&[("Range", "bytes=5-".into()), ("If-Range", etag)]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(test::read_body(resp).await.as_ref(), b"fgh");
+
+        let resp = test::call_service(&app, get(&[("Range", "bytes=10-".into())])).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes */8");
+
+        // a range of a different snapshot returns the whole snapshot
+        let resp = test::call_service(
+            &app,
+            get(&[
+                ("Range", "bytes=5-".into()),
+                ("If-Range", format!("\"{}\"", Uuid::new_v4())),
+            ]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Accept-Ranges").unwrap(), "bytes");
+        assert_eq!(test::read_body(resp).await.as_ref(), b"abcdefgh");
+    }
 }
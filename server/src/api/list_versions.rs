@@ -0,0 +1,112 @@
+use crate::api::{server_error_to_actix, ServerState, VersionInfo};
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{VersionId, NIL_VERSION_ID};
+
+/// The default number of versions returned.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The maximum number of versions returned.
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct VersionsQuery {
+    /// List the versions following this one; by default, from the beginning of the history.
+    since: Option<VersionId>,
+    /// The maximum number of versions to return.
+    limit: Option<usize>,
+}
+
+/// List the versions following the `since` version, for debugging a replica which does not
+/// catch up.
+///
+/// The response is a JSON array of objects with `version_id`, `parent_version_id`, `created_at`,
+/// and `size` (the size of the history segment in bytes), in order. History segments are not
+/// included. An empty array means that the `since` version has no child; if that version is not
+/// the latest, it may have been deleted, and the replica must use a snapshot.
+///
+/// This is not part of the sync protocol, and is not used by replicas.
+#[get("/v1/client/versions")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    query: web::Query<VersionsQuery>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let since = query.since.unwrap_or(NIL_VERSION_ID);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let versions = server_state
+        .server
+        .get_versions_since(client_id, since, limit)
+        .map_err(server_error_to_actix)?;
+    let versions: Vec<VersionInfo> = versions.into_iter().map(VersionInfo::from).collect();
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let (v1, v2, v3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(v1, NIL_VERSION_ID, b"a".to_vec().into())
+                .unwrap();
+            txn.add_version(v2, v1, b"bb".to_vec().into()).unwrap();
+            txn.add_version(v3, v2, b"ccc".to_vec().into()).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/versions")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["version_id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, vec![v1.to_string(), v2.to_string(), v3.to_string()]);
+        assert_eq!(body[1]["parent_version_id"], v1.to_string());
+        assert_eq!(body[1]["size"], 2);
+        assert!(body[1].get("history_segment").is_none());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/versions?since={v1}&limit=1"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["version_id"], v2.to_string());
+    }
+
+    #[actix_rt::test]
+    async fn test_client_not_found() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/versions")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,13 +1,45 @@
 use std::collections::HashSet;
+use std::io::{self, Read, Seek, Write};
+use std::sync::Arc;
 
-use actix_web::{error, web, HttpRequest, Result, Scope};
-use taskchampion_sync_server_core::{ClientId, Server, ServerError};
+use crate::client_cert::ClientCertificates;
+use crate::client_lock::ClientLocks;
+use crate::cors::CorsConfig;
+use crate::metrics::ServerMetrics;
+use crate::notify::Notifier;
+use crate::rate_limit::RateLimiter;
+use actix_web::{error, http::header, web, HttpRequest, Result, Scope};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use snapshot_upload::SnapshotUploads;
+use taskchampion_sync_server_core::{
+    ClientId, Clock, Server, ServerError, StorageError, Version, VersionId, MAX_HISTORY_SEGMENT_LEN,
+};
+use tempfile::SpooledTempFile;
 use uuid::Uuid;
 
 mod add_snapshot;
 mod add_version;
+mod admin;
+mod events;
 mod get_child_version;
+mod get_child_versions;
+mod get_latest_version;
 mod get_snapshot;
+mod list_versions;
+mod server_info;
+mod snapshot_upload;
+mod updates;
+mod v2;
+
+/// Request bodies larger than this are spooled to a temporary file: 1MiB
+const SPOOL_THRESHOLD: usize = 1024 * 1024;
+
+/// Size of the chunks in which response bodies are streamed: 64KiB
+const CHUNK_SIZE: usize = 64 * 1024;
 
 /// The content-type for history segments (opaque blobs of bytes)
 pub(crate) const HISTORY_SEGMENT_CONTENT_TYPE: &str =
@@ -28,14 +60,74 @@ pub(crate) const PARENT_VERSION_ID_HEADER: &str = "X-Parent-Version-Id";
 /// The header name for parent version ID
 pub(crate) const SNAPSHOT_REQUEST_HEADER: &str = "X-Snapshot-Request";
 
+/// The largest request bodies accepted. Larger bodies are rejected with 413 Payload Too Large.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyLimits {
+    /// The largest history segment accepted, in bytes. This cannot exceed the storage
+    /// backend's limit.
+    pub max_history_segment_len: usize,
+    /// The largest snapshot accepted, in bytes.
+    pub max_snapshot_len: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        BodyLimits {
+            max_history_segment_len: MAX_HISTORY_SEGMENT_LEN,
+            max_snapshot_len: 100 * 1024 * 1024,
+        }
+    }
+}
+
+/// A version in a client's history, without its history segment, for debugging and
+/// administration.
+#[derive(Serialize)]
+pub(crate) struct VersionInfo {
+    version_id: VersionId,
+    parent_version_id: VersionId,
+    created_at: Option<DateTime<Utc>>,
+    /// The size of the history segment, in bytes.
+    size: usize,
+}
+
+impl From<Version> for VersionInfo {
+    fn from(version: Version) -> Self {
+        Self {
+            version_id: version.version_id,
+            parent_version_id: version.parent_version_id,
+            created_at: version.created_at,
+            size: version.history_segment.len(),
+        }
+    }
+}
+
 /// The type containing a reference to the persistent state for the server
 pub(crate) struct ServerState {
     pub(crate) server: Server,
+    /// The server's clock, used to timestamp change events.
+    pub(crate) clock: Arc<dyn Clock>,
     pub(crate) client_id_allowlist: Option<HashSet<Uuid>>,
+    pub(crate) metrics: ServerMetrics,
+    /// Whether to write a structured access log record for each request.
+    pub(crate) access_log: bool,
+    /// Whether to compress responses, as negotiated with `Accept-Encoding`.
+    pub(crate) compress: bool,
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) client_locks: ClientLocks,
+    /// The bearer token required by the admin API, which is only served if this is set.
+    pub(crate) admin_token: Option<String>,
+    /// Notifies subscribers, such as WebSocket connections, of changes to clients' data.
+    pub(crate) notifier: Notifier,
+    pub(crate) snapshot_uploads: SnapshotUploads,
+    pub(crate) body_limits: BodyLimits,
+    /// The client IDs which each client certificate may use, if that is restricted.
+    pub(crate) client_certificates: Option<ClientCertificates>,
+    /// The cross-origin requests to allow, if any.
+    pub(crate) cors: Option<CorsConfig>,
 }
 
 impl ServerState {
-    /// Get the client id
+    /// Get the client id, checking that the client is allowed and within its rate limits.
     fn client_id_header(&self, req: &HttpRequest) -> Result<ClientId> {
         fn badrequest() -> error::Error {
             error::ErrorBadRequest("bad x-client-id")
@@ -48,19 +140,65 @@ impl ServerState {
                     return Err(error::ErrorForbidden("unknown x-client-id"));
                 }
             }
+            if let Some(client_certificates) = &self.client_certificates {
+                if !client_certificates.allows(req.conn_data(), client_id) {
+                    return Err(error::ErrorForbidden(
+                        "x-client-id not allowed for this client certificate",
+                    ));
+                }
+            }
+            self.rate_limiter.check(req, client_id)?;
             Ok(client_id)
         } else {
             Err(badrequest())
         }
     }
+
+    /// The largest history segment accepted, in bytes, as configured and as supported by the
+    /// storage backend.
+    pub(crate) fn max_history_segment_len(&self) -> usize {
+        self.body_limits
+            .max_history_segment_len
+            .min(self.server.capabilities().max_history_segment_len)
+            .min(MAX_HISTORY_SEGMENT_LEN)
+    }
+
+    /// Record that the client has synced, along with its user agent. This metadata is
+    /// informational, so a failure to record it is logged rather than failing the request.
+    fn record_sync(&self, req: &HttpRequest, client_id: ClientId) {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        if let Err(err) = self.server.record_sync(client_id, user_agent) {
+            log::warn!("Could not record sync for client {client_id}: {err}");
+        }
+    }
 }
 
+pub(crate) use admin::admin_scope;
+
 pub(crate) fn api_scope() -> Scope {
     web::scope("")
         .service(get_child_version::service)
+        .service(get_child_versions::service)
         .service(add_version::service)
         .service(get_snapshot::service)
         .service(add_snapshot::service)
+        .service(snapshot_upload::start)
+        .service(snapshot_upload::status)
+        .service(snapshot_upload::put_chunk)
+        .service(snapshot_upload::finalize)
+        .service(list_versions::service)
+        .service(get_latest_version::service)
+        .service(server_info::service)
+        .service(updates::service)
+        .service(events::service)
+        .service(v2::add_versions)
+        .service(v2::get_child_versions)
+        .service(v2::add_snapshot)
+        .service(v2::get_snapshot)
 }
 
 /// Convert a `anyhow::Error` to an Actix ISE
@@ -72,21 +210,113 @@ fn failure_to_ise(err: anyhow::Error) -> actix_web::Error {
 fn server_error_to_actix(err: ServerError) -> actix_web::Error {
     match err {
         ServerError::NoSuchClient => error::ErrorNotFound(err),
+        ServerError::ClientDeleted => error::ErrorNotFound(err),
+        ServerError::TryAgainLater => error::ErrorServiceUnavailable(err),
+        ServerError::QuotaExceeded => error::ErrorForbidden(err),
+        ServerError::InvalidVersionId(_) => error::ErrorConflict(err),
         ServerError::Other(err) => error::ErrorInternalServerError(err),
     }
 }
 
+/// Convert a StorageError to an Actix error, as for the corresponding ServerError
+fn storage_error_to_actix(err: StorageError) -> actix_web::Error {
+    server_error_to_actix(err.into())
+}
+
+/// Check whether the request's `Authorization` header carries the bearer token `token`.
+///
+/// The token is compared by verifying an HMAC of the presented token, which takes the same time
+/// wherever the two tokens differ.
+pub(crate) fn bearer_token_matches(req: &HttpRequest, token: &str) -> bool {
+    let Some(presented) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    let mac = |value: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(value.as_bytes());
+        mac
+    };
+    mac(presented)
+        .verify_slice(&mac(token).finalize().into_bytes())
+        .is_ok()
+}
+
+/// An error for a request body larger than `max_size` bytes.
+fn payload_too_large(max_size: usize) -> error::Error {
+    error::ErrorPayloadTooLarge(format!(
+        "Request body exceeds the maximum of {max_size} bytes"
+    ))
+}
+
+/// Read a request body into a temporary file, which is only written to disk if the body is
+/// larger than [`SPOOL_THRESHOLD`]. Returns the file, rewound to its beginning, and the size of
+/// the body. A body larger than `max_size` is rejected with 413 Payload Too Large.
+async fn spool_payload(
+    mut payload: web::Payload,
+    max_size: usize,
+) -> Result<(SpooledTempFile, usize)> {
+    let mut file = SpooledTempFile::new(SPOOL_THRESHOLD);
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        if size > max_size {
+            return Err(payload_too_large(max_size));
+        }
+        file.write_all(&chunk)
+            .map_err(error::ErrorInternalServerError)?;
+    }
+    file.rewind().map_err(error::ErrorInternalServerError)?;
+    Ok((file, size))
+}
+
+/// Stream the data from a reader in chunks, reading on the blocking thread pool.
+fn read_chunks(reader: Box<dyn Read + Send>) -> impl Stream<Item = Result<web::Bytes, io::Error>> {
+    futures::stream::try_unfold(reader, |mut reader| async move {
+        let (reader, chunk) = web::block(move || -> io::Result<_> {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let len = reader.read(&mut chunk)?;
+            chunk.truncate(len);
+            Ok((reader, chunk))
+        })
+        .await
+        .map_err(io::Error::other)??;
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((web::Bytes::from(chunk), reader)))
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use taskchampion_sync_server_core::InMemoryStorage;
+    use taskchampion_sync_server_core::{InMemoryStorage, SystemClock};
 
     #[test]
     fn client_id_header_allow_all() {
         let client_id = Uuid::new_v4();
         let state = ServerState {
             server: Server::new(Default::default(), InMemoryStorage::new()),
+            clock: Arc::new(SystemClock),
             client_id_allowlist: None,
+            metrics: Default::default(),
+            access_log: false,
+            compress: false,
+            rate_limiter: Default::default(),
+            client_locks: Default::default(),
+            admin_token: None,
+            notifier: Default::default(),
+            snapshot_uploads: Default::default(),
+            body_limits: Default::default(),
+            client_certificates: None,
+            cors: None,
         };
         let req = actix_web::test::TestRequest::default()
             .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
@@ -100,7 +330,19 @@ mod test {
         let client_id_disallowed = Uuid::new_v4();
         let state = ServerState {
             server: Server::new(Default::default(), InMemoryStorage::new()),
+            clock: Arc::new(SystemClock),
             client_id_allowlist: Some([client_id_ok].into()),
+            metrics: Default::default(),
+            access_log: false,
+            compress: false,
+            rate_limiter: Default::default(),
+            client_locks: Default::default(),
+            admin_token: None,
+            notifier: Default::default(),
+            snapshot_uploads: Default::default(),
+            body_limits: Default::default(),
+            client_certificates: None,
+            cors: None,
         };
         let req = actix_web::test::TestRequest::default()
             .insert_header((CLIENT_ID_HEADER, client_id_ok.to_string()))
@@ -118,4 +360,59 @@ mod test {
             403
         );
     }
+
+    #[test]
+    fn client_id_header_no_certificate() {
+        let client_id = Uuid::new_v4();
+        let mut client_certificates = ClientCertificates::default();
+        client_certificates
+            .allow(&"0".repeat(64), client_id)
+            .unwrap();
+        let state = ServerState {
+            server: Server::new(Default::default(), InMemoryStorage::new()),
+            clock: Arc::new(SystemClock),
+            client_id_allowlist: None,
+            metrics: Default::default(),
+            access_log: false,
+            compress: false,
+            rate_limiter: Default::default(),
+            client_locks: Default::default(),
+            admin_token: None,
+            notifier: Default::default(),
+            snapshot_uploads: Default::default(),
+            body_limits: Default::default(),
+            client_certificates: Some(client_certificates),
+            cors: None,
+        };
+        // a request over a connection without a client certificate is not allowed any client ID
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_http_request();
+        assert_eq!(
+            state
+                .client_id_header(&req)
+                .unwrap_err()
+                .as_response_error()
+                .status_code(),
+            403
+        );
+    }
+
+    #[test]
+    fn server_error_try_again_later() {
+        let err = server_error_to_actix(ServerError::TryAgainLater);
+        assert_eq!(err.as_response_error().status_code(), 503);
+    }
+
+    #[test]
+    fn server_error_quota_exceeded() {
+        let err = server_error_to_actix(ServerError::QuotaExceeded);
+        assert_eq!(err.as_response_error().status_code(), 403);
+    }
+
+    #[test]
+    fn server_error_invalid_version_id() {
+        let err = server_error_to_actix(ServerError::InvalidVersionId(Uuid::nil()));
+        assert_eq!(err.as_response_error().status_code(), 409);
+    }
 }
@@ -0,0 +1,110 @@
+use crate::api::ServerState;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// The versions of the HTTP API served.
+const API_VERSIONS: &[&str] = &["v1"];
+
+/// The optional features with which the server was built.
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "azure")]
+    "azure",
+    #[cfg(feature = "mysql")]
+    "mysql",
+    #[cfg(feature = "otel")]
+    "otel",
+    #[cfg(feature = "s3")]
+    "s3",
+    #[cfg(feature = "sqlcipher")]
+    "sqlcipher",
+    #[cfg(feature = "sqlx")]
+    "sqlx",
+];
+
+#[derive(Serialize)]
+struct ServerInfo {
+    version: &'static str,
+    api_versions: &'static [&'static str],
+    limits: Limits,
+    snapshot_policy: SnapshotPolicy,
+    features: &'static [&'static str],
+    storage: StorageInfo,
+}
+
+#[derive(Serialize)]
+struct Limits {
+    /// The largest history segment accepted, in bytes.
+    max_history_segment_len: usize,
+    /// The largest snapshot accepted, in bytes.
+    max_snapshot_len: usize,
+}
+
+/// The thresholds at which the server requests a snapshot.
+#[derive(Serialize)]
+struct SnapshotPolicy {
+    snapshot_versions: u32,
+    snapshot_days: i64,
+}
+
+/// Optional capabilities of the storage backend.
+#[derive(Serialize)]
+struct StorageInfo {
+    supports_streaming: bool,
+    supports_compaction: bool,
+    supports_client_enumeration: bool,
+}
+
+/// Describe the server: its version, the API versions it serves, its limits and snapshot
+/// policy, and the features it was built with, as a JSON object. No client ID is required.
+#[get("/v1/server-info")]
+pub(crate) async fn service(server_state: web::Data<Arc<ServerState>>) -> HttpResponse {
+    let server = &server_state.server;
+    let config = server.config();
+    let capabilities = server.capabilities();
+    HttpResponse::Ok().json(ServerInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        api_versions: API_VERSIONS,
+        limits: Limits {
+            max_history_segment_len: server_state.max_history_segment_len(),
+            max_snapshot_len: server_state.body_limits.max_snapshot_len,
+        },
+        snapshot_policy: SnapshotPolicy {
+            snapshot_versions: config.snapshot_versions,
+            snapshot_days: config.snapshot_days,
+        },
+        features: FEATURES,
+        storage: StorageInfo {
+            supports_streaming: capabilities.supports_streaming,
+            supports_compaction: capabilities.supports_compaction,
+            supports_client_enumeration: capabilities.supports_client_enumeration,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::WebServer;
+    use actix_web::{test, App};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use taskchampion_sync_server_core::{InMemoryStorage, ServerConfig};
+
+    #[actix_rt::test]
+    async fn test_server_info() {
+        let config = ServerConfig {
+            snapshot_versions: 50,
+            ..ServerConfig::default()
+        };
+        let server = WebServer::new(config, None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get().uri("/v1/server-info").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["api_versions"], serde_json::json!(["v1"]));
+        assert_eq!(body["snapshot_policy"]["snapshot_versions"], 50);
+        assert!(body["limits"]["max_history_segment_len"].as_u64().unwrap() > 0);
+        assert!(body["features"].is_array());
+    }
+}
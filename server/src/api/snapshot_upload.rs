@@ -0,0 +1,416 @@
+use crate::api::{add_snapshot, failure_to_ise, server_error_to_actix, spool_payload, ServerState};
+use actix_web::{error, get, http::header, post, put, web, HttpRequest, HttpResponse, Result};
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use taskchampion_sync_server_core::{ClientId, VersionId};
+use uuid::Uuid;
+
+/// The header name for the ID of a snapshot upload.
+const UPLOAD_ID_HEADER: &str = "X-Upload-Id";
+
+/// The header name for the number of bytes of a snapshot upload received so far.
+const UPLOAD_OFFSET_HEADER: &str = "X-Upload-Offset";
+
+/// The maximum number of snapshot uploads in progress. Each client may have only one. Beyond
+/// this, the least-recently-updated upload is discarded.
+const MAX_UPLOADS: usize = 16;
+
+/// Uploads which have not received a chunk for this long are discarded.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A snapshot upload in progress, assembled in a temporary file.
+struct Upload {
+    version_id: VersionId,
+    file: File,
+    size: usize,
+    updated: Instant,
+}
+
+impl Upload {
+    /// Write `data` at `offset`, which must not be beyond the end of the data received so far,
+    /// replacing any data after it. This allows a chunk to be resent if its response was lost.
+    fn write_at(&mut self, offset: usize, data: &mut dyn Read) -> anyhow::Result<()> {
+        self.file.set_len(offset as u64)?;
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        let len = io::copy(data, &mut self.file)?;
+        self.size = offset + len as usize;
+        self.updated = Instant::now();
+        Ok(())
+    }
+}
+
+/// An upload in progress, shared between the requests adding to it.
+type SharedUpload = Arc<Mutex<Upload>>;
+
+/// The snapshot uploads in progress, by ID, with the client to which each belongs.
+#[derive(Default)]
+pub(crate) struct SnapshotUploads {
+    uploads: Mutex<HashMap<Uuid, (ClientId, SharedUpload)>>,
+}
+
+impl SnapshotUploads {
+    /// Begin an upload of a snapshot for the given version, discarding any upload already in
+    /// progress for the client, any idle uploads, and if necessary the least-recently-updated
+    /// upload.
+    fn start(&self, client_id: ClientId, version_id: VersionId) -> Result<Uuid> {
+        let file = tempfile::tempfile()
+            .context("Creating snapshot upload file")
+            .map_err(failure_to_ise)?;
+        let now = Instant::now();
+        // an upload which is locked is receiving a chunk, so is treated as just updated
+        let updated = |upload: &Mutex<Upload>| match upload.try_lock() {
+            Ok(upload) => upload.updated,
+            Err(_) => now,
+        };
+        let mut uploads = self.uploads.lock().expect("poisoned lock");
+        uploads.retain(|_, (upload_client_id, upload)| {
+            *upload_client_id != client_id
+                && now.saturating_duration_since(updated(upload)) < UPLOAD_TIMEOUT
+        });
+        if uploads.len() >= MAX_UPLOADS {
+            let oldest = uploads
+                .iter()
+                .min_by_key(|(_, (_, upload))| updated(upload))
+                .map(|(upload_id, _)| *upload_id);
+            if let Some(oldest) = oldest {
+                uploads.remove(&oldest);
+            }
+        }
+        let upload_id = Uuid::new_v4();
+        let upload = Upload {
+            version_id,
+            file,
+            size: 0,
+            updated: now,
+        };
+        uploads.insert(upload_id, (client_id, Arc::new(Mutex::new(upload))));
+        Ok(upload_id)
+    }
+
+    /// Get the client's upload with the given ID.
+    fn get(&self, client_id: ClientId, upload_id: Uuid) -> Result<SharedUpload> {
+        let uploads = self.uploads.lock().expect("poisoned lock");
+        match uploads.get(&upload_id) {
+            Some((upload_client_id, upload)) if *upload_client_id == client_id => {
+                Ok(upload.clone())
+            }
+            _ => Err(error::ErrorNotFound("no such upload")),
+        }
+    }
+
+    /// Remove the client's upload with the given ID, returning it.
+    fn take(&self, client_id: ClientId, upload_id: Uuid) -> Result<SharedUpload> {
+        let upload = self.get(client_id, upload_id)?;
+        self.uploads
+            .lock()
+            .expect("poisoned lock")
+            .remove(&upload_id);
+        Ok(upload)
+    }
+}
+
+/// A response giving the number of bytes received so far.
+fn offset_response(size: usize) -> HttpResponse {
+    HttpResponse::Ok()
+        .append_header((UPLOAD_OFFSET_HEADER, size.to_string()))
+        .finish()
+}
+
+/// Begin a resumable upload of a snapshot for the given version, as an alternative to
+/// AddSnapshot for large snapshots over unreliable connections.
+///
+/// The response is a 201 with the upload ID in the `X-Upload-Id` header and the upload's URL in
+/// the `Location` header. The snapshot is then sent in chunks with `PUT
+/// /v1/client/snapshot-uploads/{upload_id}?offset={offset}`, and committed with `POST
+/// /v1/client/snapshot-uploads/{upload_id}/finalize`. A client may have only one upload in
+/// progress, so beginning another discards the first, and uploads are discarded after five
+/// minutes without a chunk, or when the server has too many uploads in progress.
+///
+/// The client must already exist.
+#[post("/v1/client/add-snapshot/{version_id}/uploads")]
+pub(crate) async fn start(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    server_state
+        .server
+        .get_client(client_id)
+        .map_err(server_error_to_actix)?;
+    let upload_id = server_state
+        .snapshot_uploads
+        .start(client_id, path.into_inner())?;
+    Ok(HttpResponse::Created()
+        .append_header((UPLOAD_ID_HEADER, upload_id.to_string()))
+        .append_header((
+            header::LOCATION,
+            format!("/v1/client/snapshot-uploads/{upload_id}"),
+        ))
+        .finish())
+}
+
+/// Get the number of bytes of the snapshot received so far, in the `X-Upload-Offset` header, so
+/// that an interrupted upload can be resumed from that offset.
+#[get("/v1/client/snapshot-uploads/{upload_id}")]
+pub(crate) async fn status(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let upload = server_state
+        .snapshot_uploads
+        .get(client_id, path.into_inner())?;
+    let size = upload.lock().expect("poisoned lock").size;
+    Ok(offset_response(size))
+}
+
+#[derive(Deserialize)]
+struct ChunkQuery {
+    /// The offset in the snapshot at which the chunk begins.
+    offset: usize,
+}
+
+/// Add a chunk of the snapshot, beginning at the `offset` query parameter.
+///
+/// The offset may be at most the number of bytes received so far. Any data already received after
+/// the offset is replaced, so a chunk can be sent again if its response was lost. On success,
+/// the response is a 200 with the number of bytes received in the `X-Upload-Offset` header. If the
+/// offset is beyond that, the response is a 409 with the same header.
+#[put("/v1/client/snapshot-uploads/{upload_id}")]
+pub(crate) async fn put_chunk(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<Uuid>,
+    query: web::Query<ChunkQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let upload = server_state
+        .snapshot_uploads
+        .get(client_id, path.into_inner())?;
+    let offset = query.offset;
+
+    let conflict = |size: usize| {
+        HttpResponse::Conflict()
+            .append_header((UPLOAD_OFFSET_HEADER, size.to_string()))
+            .finish()
+    };
+
+    // check the offset before reading the body
+    let size = upload.lock().expect("poisoned lock").size;
+    if offset > size {
+        return Ok(conflict(size));
+    }
+
+    let max_size = server_state
+        .body_limits
+        .max_snapshot_len
+        .saturating_sub(offset);
+    let (mut body, _) = spool_payload(payload, max_size).await?;
+
+    let mut upload = upload.lock().expect("poisoned lock");
+    if offset > upload.size {
+        return Ok(conflict(upload.size));
+    }
+    upload.write_at(offset, &mut body).map_err(failure_to_ise)?;
+    Ok(offset_response(upload.size))
+}
+
+/// Add the uploaded snapshot, as for AddSnapshot, and discard the upload.
+#[post("/v1/client/snapshot-uploads/{upload_id}/finalize")]
+pub(crate) async fn finalize(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let upload = server_state
+        .snapshot_uploads
+        .take(client_id, path.into_inner())?;
+
+    let _lock = server_state.client_locks.lock(client_id).await;
+    let mut upload = upload.lock().expect("poisoned lock");
+    if upload.size == 0 {
+        return Err(error::ErrorBadRequest("No snapshot supplied"));
+    }
+    let (version_id, size) = (upload.version_id, upload.size);
+    upload
+        .file
+        .rewind()
+        .map_err(error::ErrorInternalServerError)?;
+    add_snapshot::store_snapshot(&server_state, client_id, version_id, &mut upload.file, size)?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Bytes, InMemoryStorage, Storage, NIL_VERSION_ID};
+
+    #[actix_rt::test]
+    async fn test_upload() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, Bytes::new())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-snapshot/{version_id}/uploads"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let location = location.to_string();
+
+        let put = |offset: usize, data: &'static [u8]| {
+            test::TestRequest::put()
+                .uri(&format!("{location}?offset={offset}"))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(data)
+                .to_request()
+        };
+        fn offset<B>(resp: &actix_web::dev::ServiceResponse<B>) -> String {
+            resp.headers()
+                .get("X-Upload-Offset")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+
+        let resp = test::call_service(&app, put(0, b"abc")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(offset(&resp), "3");
+
+        // a chunk beyond the data received is rejected
+        let resp = test::call_service(&app, put(5, b"fgh")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(offset(&resp), "3");
+
+        // a chunk can be resent
+        let resp = test::call_service(&app, put(3, b"dxx")).await;
+        assert_eq!(offset(&resp), "6");
+        let resp = test::call_service(&app, put(4, b"ef")).await;
+        assert_eq!(offset(&resp), "6");
+
+        let req = test::TestRequest::get()
+            .uri(&location)
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(offset(&resp), "6");
+
+        // another client cannot see the upload
+        let req = test::TestRequest::get()
+            .uri(&location)
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("{location}/finalize"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the upload is gone, and the snapshot is stored
+        let req = test::TestRequest::get()
+            .uri(&location)
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await.as_ref(), b"abcdef");
+    }
+
+    #[actix_rt::test]
+    async fn one_upload_per_client() {
+        let uploads = SnapshotUploads::default();
+        let client_id = Uuid::new_v4();
+        let first = uploads.start(client_id, Uuid::new_v4()).unwrap();
+        let second = uploads.start(client_id, Uuid::new_v4()).unwrap();
+        assert!(uploads.get(client_id, first).is_err());
+        assert!(uploads.get(client_id, second).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn oldest_upload_evicted() {
+        let uploads = SnapshotUploads::default();
+        let client_ids: Vec<Uuid> = (0..MAX_UPLOADS).map(|_| Uuid::new_v4()).collect();
+        let upload_ids: Vec<Uuid> = client_ids
+            .iter()
+            .map(|client_id| uploads.start(*client_id, Uuid::new_v4()).unwrap())
+            .collect();
+        // the first upload is the oldest
+        uploads
+            .get(client_ids[0], upload_ids[0])
+            .unwrap()
+            .lock()
+            .unwrap()
+            .updated -= Duration::from_secs(1);
+        uploads.start(Uuid::new_v4(), Uuid::new_v4()).unwrap();
+        assert!(uploads.get(client_ids[0], upload_ids[0]).is_err());
+        for (client_id, upload_id) in client_ids.iter().zip(&upload_ids).skip(1) {
+            assert!(uploads.get(*client_id, *upload_id).is_ok());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn idle_upload_discarded() {
+        let uploads = SnapshotUploads::default();
+        let client_id = Uuid::new_v4();
+        let idle = uploads.start(client_id, Uuid::new_v4()).unwrap();
+        uploads
+            .get(client_id, idle)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .updated -= UPLOAD_TIMEOUT;
+        uploads.start(Uuid::new_v4(), Uuid::new_v4()).unwrap();
+        assert!(uploads.get(client_id, idle).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_upload_unknown_client() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "/v1/client/add-snapshot/{}/uploads",
+                Uuid::new_v4()
+            ))
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::api::ServerState;
+use crate::notify::Subscription;
+use actix_web::{get, rt, web, HttpRequest, HttpResponse, Result};
+use actix_ws::{Message, MessageStream, Session};
+use futures::future::{select, Either};
+use futures::StreamExt;
+use std::sync::Arc;
+
+/// Notify a replica of changes to its client's data over a WebSocket, so that an always-on
+/// replica can sync as soon as another replica adds a version, rather than polling.
+///
+/// Each change is sent as a text message containing a JSON object, with a `type` of
+/// `version_added` and the `version_id`, `parent_version_id`, `size`, and `timestamp` of the new
+/// version, or a `type` of `snapshot_set` and the `version_id`, `size`, and `timestamp` of a new
+/// snapshot, or a `type` of `client_created` and the `timestamp` at which the client was created
+/// by its first version. Messages from the replica are ignored, except that pings are answered.
+///
+/// Notifications are only sent for changes made through this server process, and are not
+/// persisted, so a replica should sync when it connects.
+#[get("/v1/client/updates")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    body: web::Payload,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    rt::spawn(forward(
+        server_state.notifier.subscribe(client_id),
+        session,
+        messages,
+    ));
+    Ok(response)
+}
+
+/// Send events to the WebSocket until it is closed.
+async fn forward(mut events: Subscription, mut session: Session, mut messages: MessageStream) {
+    let reason = loop {
+        match select(events.next(), messages.next()).await {
+            Either::Left((Some(event), _)) => {
+                let text = serde_json::to_string(&event).expect("events are serializable");
+                if session.text(text).await.is_err() {
+                    return;
+                }
+            }
+            Either::Left((None, _)) => break None,
+            Either::Right((Some(Ok(Message::Ping(data))), _)) => {
+                if session.pong(&data).await.is_err() {
+                    return;
+                }
+            }
+            Either::Right((Some(Ok(Message::Close(reason))), _)) => break reason,
+            Either::Right((Some(Ok(_)), _)) => {}
+            Either::Right((Some(Err(_)) | None, _)) => return,
+        }
+    };
+    let _ = session.close(reason).await;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use futures::StreamExt;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{ChangeEvent, InMemoryStorage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_upgrade() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/updates")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .append_header(("Connection", "Upgrade"))
+            .append_header(("Upgrade", "websocket"))
+            .append_header(("Sec-WebSocket-Version", "13"))
+            .append_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        // a plain GET is not a WebSocket handshake
+        let req = test::TestRequest::get()
+            .uri("/v1/client/updates")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_version_added() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let mut events = server.server_state.notifier.subscribe(client_id);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version_id: Uuid = resp
+            .headers()
+            .get("X-Version-Id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        // the client did not exist, so it was created first
+        let Some(ChangeEvent::ClientCreated {
+            client_id: event_client_id,
+            ..
+        }) = events.next().await
+        else {
+            panic!("expected a ClientCreated event");
+        };
+        assert_eq!(event_client_id, client_id);
+
+        let Some(ChangeEvent::VersionAdded {
+            client_id: event_client_id,
+            version_id: event_version_id,
+            parent_version_id,
+            size,
+            ..
+        }) = events.next().await
+        else {
+            panic!("expected a VersionAdded event");
+        };
+        assert_eq!(event_client_id, client_id);
+        assert_eq!(event_version_id, version_id);
+        assert_eq!(parent_version_id, NIL_VERSION_ID);
+        assert_eq!(size, 4);
+    }
+}
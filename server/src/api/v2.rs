@@ -0,0 +1,755 @@
+//! Version 2 of the sync protocol, in which requests and responses are structured messages
+//! rather than raw bodies with metadata in headers. This allows metadata such as timestamps and
+//! checksums, and batches of versions, which the headers of the v1 protocol cannot express.
+//!
+//! Each message is encoded as CBOR (`application/cbor`) or protobuf (`application/x-protobuf`),
+//! as given by the request's `Content-Type`. The response is encoded as requested with `Accept`,
+//! or otherwise in the same encoding as the request, defaulting to CBOR. The messages are
+//! described for protobuf clients in `proto/v2.proto`; in CBOR, each message is a map keyed by
+//! field name. As in v1, the client ID is given in the `X-Client-Id` header, and errors are
+//! returned as plain text.
+
+use crate::api::{
+    add_snapshot::store_snapshot,
+    get_child_versions::{child_versions, BatchQuery},
+    payload_too_large, server_error_to_actix, spool_payload, storage_error_to_actix, ServerState,
+};
+use actix_web::http::header::{self, Accept};
+use actix_web::{
+    error, get, post, web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Result,
+};
+use prost::Message;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{
+    AddVersionResult, Bytes, ChangeEvent, HistorySegment, ServerError, SnapshotUrgency as Urgency,
+    Version, VersionId, NIL_VERSION_ID,
+};
+
+/// The content-type for CBOR-encoded messages.
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// The content-type for protobuf-encoded messages.
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// The size of history segments, in bytes, accepted in a single AddVersions request: 16MiB.
+/// A single version as large as the history segment limit is always accepted.
+const MAX_BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// Allowance for the fields of a request other than its history segments or snapshot.
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+/// An encoding of messages, negotiated with `Content-Type` and `Accept`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Cbor,
+    Protobuf,
+}
+
+impl Encoding {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            CBOR_CONTENT_TYPE => Some(Encoding::Cbor),
+            PROTOBUF_CONTENT_TYPE | "application/protobuf" => Some(Encoding::Protobuf),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Cbor => CBOR_CONTENT_TYPE,
+            Encoding::Protobuf => PROTOBUF_CONTENT_TYPE,
+        }
+    }
+
+    /// Get the encoding of the request body, failing with 415 Unsupported Media Type if it is
+    /// not supported.
+    fn of_request(req: &HttpRequest) -> Result<Self> {
+        Self::from_content_type(req.content_type()).ok_or_else(|| {
+            error::ErrorUnsupportedMediaType(format!(
+                "Content-Type must be {CBOR_CONTENT_TYPE} or {PROTOBUF_CONTENT_TYPE}"
+            ))
+        })
+    }
+
+    /// Get the encoding for the response, as requested with `Accept`, or otherwise the same as
+    /// the request's, failing with 406 Not Acceptable if no supported encoding is acceptable.
+    fn for_response(req: &HttpRequest, request_encoding: Option<Encoding>) -> Result<Self> {
+        let default = request_encoding.unwrap_or(Encoding::Cbor);
+        let Some(accept) = req.get_header::<Accept>() else {
+            return Ok(default);
+        };
+        for mime in accept.ranked() {
+            match mime.essence_str() {
+                "*/*" | "application/*" => return Ok(default),
+                essence => {
+                    if let Some(encoding) = Self::from_content_type(essence) {
+                        return Ok(encoding);
+                    }
+                }
+            }
+        }
+        Err(error::ErrorNotAcceptable(format!(
+            "Accept must allow {CBOR_CONTENT_TYPE} or {PROTOBUF_CONTENT_TYPE}"
+        )))
+    }
+
+    fn decode<M: Message + Default + DeserializeOwned>(self, body: Bytes) -> Result<M> {
+        match self {
+            Encoding::Cbor => ciborium::from_reader(body.as_ref())
+                .map_err(|err| error::ErrorBadRequest(format!("Invalid CBOR message: {err}"))),
+            Encoding::Protobuf => M::decode(body)
+                .map_err(|err| error::ErrorBadRequest(format!("Invalid protobuf message: {err}"))),
+        }
+    }
+
+    fn encode<M: Message + Serialize>(self, message: &M) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(message, &mut body)
+                    .map_err(error::ErrorInternalServerError)?;
+                Ok(body)
+            }
+            Encoding::Protobuf => Ok(message.encode_to_vec()),
+        }
+    }
+
+    /// Complete a response with the given message as its body.
+    fn respond<M: Message + Serialize>(
+        self,
+        mut builder: HttpResponseBuilder,
+        message: &M,
+    ) -> Result<HttpResponse> {
+        Ok(builder
+            .content_type(self.content_type())
+            .insert_header((header::VARY, "Accept"))
+            .body(self.encode(message)?))
+    }
+}
+
+/// How urgently the server would like the client to send a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub(crate) enum SnapshotUrgency {
+    None = 0,
+    Low = 1,
+    High = 2,
+}
+
+impl From<Urgency> for SnapshotUrgency {
+    fn from(urgency: Urgency) -> Self {
+        match urgency {
+            Urgency::None => SnapshotUrgency::None,
+            Urgency::Low => SnapshotUrgency::Low,
+            Urgency::High => SnapshotUrgency::High,
+        }
+    }
+}
+
+/// A version in a client's history.
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub(crate) struct VersionMessage {
+    #[prost(string, tag = "1")]
+    pub(crate) version_id: String,
+    #[prost(string, tag = "2")]
+    pub(crate) parent_version_id: String,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub(crate) history_segment: Bytes,
+    /// The SHA-256 digest of the history segment. This is optional in requests, but if given
+    /// the request is rejected unless it matches.
+    #[prost(bytes = "vec", tag = "4")]
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) checksum: Vec<u8>,
+    /// When the version was added, in milliseconds since the Unix epoch, if known. This is
+    /// ignored in requests.
+    #[prost(int64, optional, tag = "5")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<i64>,
+}
+
+impl From<Version> for VersionMessage {
+    fn from(version: Version) -> Self {
+        Self {
+            version_id: version.version_id.to_string(),
+            parent_version_id: version.parent_version_id.to_string(),
+            checksum: checksum(&version.history_segment),
+            history_segment: version.history_segment,
+            created_at: version.created_at.map(|t| t.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub(crate) struct AddVersionsRequest {
+    /// The versions to add, each a child of the one before it.
+    #[prost(message, repeated, tag = "1")]
+    pub(crate) versions: Vec<VersionMessage>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub(crate) struct AddVersionsResponse {
+    /// The ID of the last version added.
+    #[prost(string, optional, tag = "1")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) version_id: Option<String>,
+    /// If the versions were not added, as the first one's parent was not the latest version,
+    /// the ID of the latest version.
+    #[prost(string, optional, tag = "2")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expected_parent_version_id: Option<String>,
+    #[prost(enumeration = "SnapshotUrgency", tag = "3")]
+    pub(crate) snapshot_urgency: i32,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub(crate) struct GetChildVersionsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub(crate) versions: Vec<VersionMessage>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub(crate) struct SnapshotMessage {
+    #[prost(string, tag = "1")]
+    pub(crate) version_id: String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub(crate) snapshot: Bytes,
+    /// The SHA-256 digest of the snapshot. This is optional in requests, but if given the
+    /// request is rejected unless it matches.
+    #[prost(bytes = "vec", tag = "3")]
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) checksum: Vec<u8>,
+    /// When the snapshot was added, in milliseconds since the Unix epoch. This is ignored in
+    /// requests.
+    #[prost(int64, optional, tag = "4")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+pub(crate) struct AddSnapshotResponse {
+    /// Whether the snapshot was kept. The server may ignore a snapshot, such as one older than
+    /// the latest snapshot.
+    #[prost(bool, tag = "1")]
+    pub(crate) accepted: bool,
+}
+
+/// Calculate the SHA-256 digest of some data.
+fn checksum(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+/// Check the checksum given for some data, if any.
+fn verify_checksum(data: &[u8], expected: &[u8]) -> Result<()> {
+    if !expected.is_empty() && checksum(data) != expected {
+        return Err(error::ErrorBadRequest("checksum mismatch"));
+    }
+    Ok(())
+}
+
+/// Parse a version ID from a request message.
+fn parse_version_id(value: &str) -> Result<VersionId> {
+    VersionId::parse_str(value)
+        .map_err(|_| error::ErrorBadRequest(format!("bad version ID {value:?}")))
+}
+
+/// Read and decode a request message of at most `max_size` bytes.
+async fn read_message<M: Message + Default + DeserializeOwned>(
+    encoding: Encoding,
+    payload: web::Payload,
+    max_size: usize,
+) -> Result<M> {
+    let (mut file, size) = spool_payload(payload, max_size).await?;
+    let mut body = Vec::with_capacity(size);
+    file.read_to_end(&mut body)
+        .map_err(error::ErrorInternalServerError)?;
+    encoding.decode(body.into())
+}
+
+/// Add a batch of versions, each a child of the one before it, as an `AddVersionsRequest`. The
+/// client chooses the ID of each version. A client which does not exist is created.
+///
+/// On success, the response is a 200 OK with an `AddVersionsResponse` containing the ID of the
+/// last version added. If storage cannot add every version, such as when the client's quota
+/// would be exceeded, this may be an earlier version, and the client should retry the rest. If
+/// the first version's parent is not the latest version, nothing is added and the response is a
+/// 409 CONFLICT with an `AddVersionsResponse` containing the expected parent version ID.
+///
+/// Returns other 4xx or 5xx responses on other errors.
+#[post("/v2/client/add-versions")]
+pub(crate) async fn add_versions(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    payload: web::Payload,
+) -> Result<HttpResponse> {
+    let encoding = Encoding::of_request(&req)?;
+    let response_encoding = Encoding::for_response(&req, Some(encoding))?;
+    let client_id = server_state.client_id_header(&req)?;
+
+    let max_size = server_state.max_history_segment_len();
+    let request: AddVersionsRequest = read_message(
+        encoding,
+        payload,
+        max_size.max(MAX_BATCH_BYTES) + MESSAGE_OVERHEAD,
+    )
+    .await?;
+    if request.versions.is_empty() {
+        return Err(error::ErrorBadRequest("No versions supplied"));
+    }
+    let mut versions: Vec<(VersionId, VersionId, HistorySegment)> =
+        Vec::with_capacity(request.versions.len());
+    for version in request.versions {
+        let version_id = parse_version_id(&version.version_id)?;
+        let parent_version_id = parse_version_id(&version.parent_version_id)?;
+        if let Some(&(previous_id, _, _)) = versions.last() {
+            if parent_version_id != previous_id {
+                return Err(error::ErrorBadRequest(format!(
+                    "Version {version_id} is not a child of {previous_id}"
+                )));
+            }
+        }
+        if version.history_segment.is_empty() {
+            return Err(error::ErrorBadRequest("Empty history segment"));
+        }
+        if version.history_segment.len() > max_size {
+            return Err(payload_too_large(max_size));
+        }
+        verify_checksum(&version.history_segment, &version.checksum)?;
+        versions.push((version_id, parent_version_id, version.history_segment));
+    }
+    // (version ID, parent version ID, size) of each version, for notifications
+    let added: Vec<_> = versions
+        .iter()
+        .map(|(id, parent_id, segment)| (*id, *parent_id, segment.len()))
+        .collect();
+
+    let _lock = server_state.client_locks.lock(client_id).await;
+
+    let (result, snapshot_urgency) = loop {
+        match server_state
+            .server
+            .add_versions(client_id, versions.clone())
+        {
+            Err(ServerError::NoSuchClient) => {
+                // Create a new client and repeat the `add_versions` call.
+                let mut txn = server_state
+                    .server
+                    .txn(client_id)
+                    .map_err(server_error_to_actix)?;
+                txn.new_client(NIL_VERSION_ID)
+                    .map_err(storage_error_to_actix)?;
+                txn.commit().map_err(storage_error_to_actix)?;
+                server_state.notifier.publish(ChangeEvent::ClientCreated {
+                    client_id,
+                    timestamp: server_state.clock.now(),
+                });
+            }
+            result => break result.map_err(server_error_to_actix)?,
+        }
+    };
+    match result {
+        AddVersionResult::Ok(last_version_id) => {
+            server_state.record_sync(&req, client_id);
+            let timestamp = server_state.clock.now();
+            for &(version_id, parent_version_id, size) in &added {
+                server_state.notifier.publish(ChangeEvent::VersionAdded {
+                    client_id,
+                    version_id,
+                    parent_version_id,
+                    size,
+                    timestamp,
+                });
+                if version_id == last_version_id {
+                    break;
+                }
+            }
+            response_encoding.respond(
+                HttpResponse::Ok(),
+                &AddVersionsResponse {
+                    version_id: Some(last_version_id.to_string()),
+                    expected_parent_version_id: None,
+                    snapshot_urgency: SnapshotUrgency::from(snapshot_urgency).into(),
+                },
+            )
+        }
+        AddVersionResult::ExpectedParentVersion(expected) => response_encoding.respond(
+            HttpResponse::Conflict(),
+            &AddVersionsResponse {
+                version_id: None,
+                expected_parent_version_id: Some(expected.to_string()),
+                snapshot_urgency: SnapshotUrgency::None.into(),
+            },
+        ),
+    }
+}
+
+/// Get a batch of child versions, as in the v1 GetChildVersions, as a
+/// `GetChildVersionsResponse`. Each version includes its checksum and, if known, when it was
+/// added.
+///
+/// If the parent has no child, the response is a 404 if the client is up to date, or a 410 if
+/// the parent version has been deleted.
+#[get("/v2/client/get-child-versions/{parent_version_id}")]
+pub(crate) async fn get_child_versions(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+    query: web::Query<BatchQuery>,
+) -> Result<HttpResponse> {
+    let parent_version_id = path.into_inner();
+    let response_encoding = Encoding::for_response(&req, None)?;
+    let client_id = server_state.client_id_header(&req)?;
+    let _lock = server_state.client_locks.lock(client_id).await;
+    let versions = child_versions(
+        &req,
+        &server_state,
+        client_id,
+        parent_version_id,
+        query.limit,
+    )?;
+
+    response_encoding.respond(
+        HttpResponse::Ok(),
+        &GetChildVersionsResponse {
+            versions: versions.into_iter().map(VersionMessage::from).collect(),
+        },
+    )
+}
+
+/// Add a snapshot, as a `SnapshotMessage`.
+///
+/// On success, the response is a 200 OK with an `AddSnapshotResponse` indicating whether the
+/// snapshot was kept.
+///
+/// Returns other 4xx or 5xx responses on other errors.
+#[post("/v2/client/add-snapshot")]
+pub(crate) async fn add_snapshot(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    payload: web::Payload,
+) -> Result<HttpResponse> {
+    let encoding = Encoding::of_request(&req)?;
+    let response_encoding = Encoding::for_response(&req, Some(encoding))?;
+    let client_id = server_state.client_id_header(&req)?;
+
+    let max_size = server_state.body_limits.max_snapshot_len;
+    let request: SnapshotMessage =
+        read_message(encoding, payload, max_size.saturating_add(MESSAGE_OVERHEAD)).await?;
+    let version_id = parse_version_id(&request.version_id)?;
+    if request.snapshot.is_empty() {
+        return Err(error::ErrorBadRequest("No snapshot supplied"));
+    }
+    if request.snapshot.len() > max_size {
+        return Err(payload_too_large(max_size));
+    }
+    verify_checksum(&request.snapshot, &request.checksum)?;
+
+    let _lock = server_state.client_locks.lock(client_id).await;
+
+    let size = request.snapshot.len();
+    let accepted = store_snapshot(
+        &server_state,
+        client_id,
+        version_id,
+        &mut request.snapshot.as_ref(),
+        size,
+    )?;
+    response_encoding.respond(HttpResponse::Ok(), &AddSnapshotResponse { accepted })
+}
+
+/// Get the latest snapshot, as a `SnapshotMessage` including its checksum and when it was added.
+///
+/// If no snapshot exists, returns a 404.  Returns other 4xx or 5xx responses on other errors.
+#[get("/v2/client/snapshot")]
+pub(crate) async fn get_snapshot(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let response_encoding = Encoding::for_response(&req, None)?;
+    let client_id = server_state.client_id_header(&req)?;
+    let _lock = server_state.client_locks.lock(client_id).await;
+
+    let (_, snapshot) = server_state
+        .server
+        .get_latest_version(client_id)
+        .map_err(server_error_to_actix)?;
+    let Some((version_id, data)) = server_state
+        .server
+        .get_snapshot(client_id)
+        .map_err(server_error_to_actix)?
+    else {
+        return Err(error::ErrorNotFound("no snapshot"));
+    };
+    let created_at = snapshot
+        .filter(|snapshot| snapshot.version_id == version_id)
+        .map(|snapshot| snapshot.timestamp);
+
+    response_encoding.respond(
+        HttpResponse::Ok(),
+        &SnapshotMessage {
+            version_id: version_id.to_string(),
+            checksum: checksum(&data),
+            snapshot: data,
+            created_at: created_at.map(|t| t.timestamp_millis()),
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage};
+    use uuid::Uuid;
+
+    fn version(version_id: VersionId, parent_version_id: VersionId, data: &[u8]) -> VersionMessage {
+        VersionMessage {
+            version_id: version_id.to_string(),
+            parent_version_id: parent_version_id.to_string(),
+            history_segment: Bytes::copy_from_slice(data),
+            checksum: vec![],
+            created_at: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn response_encoding() {
+        let req = test::TestRequest::default().to_http_request();
+        assert_eq!(Encoding::for_response(&req, None).unwrap(), Encoding::Cbor);
+        assert_eq!(
+            Encoding::for_response(&req, Some(Encoding::Protobuf)).unwrap(),
+            Encoding::Protobuf
+        );
+
+        let req = test::TestRequest::default()
+            .insert_header(("Accept", "application/cbor;q=0.5, application/x-protobuf"))
+            .to_http_request();
+        assert_eq!(
+            Encoding::for_response(&req, Some(Encoding::Cbor)).unwrap(),
+            Encoding::Protobuf
+        );
+
+        let req = test::TestRequest::default()
+            .insert_header(("Accept", "*/*"))
+            .to_http_request();
+        assert_eq!(
+            Encoding::for_response(&req, Some(Encoding::Protobuf)).unwrap(),
+            Encoding::Protobuf
+        );
+
+        let req = test::TestRequest::default()
+            .insert_header(("Accept", "application/json"))
+            .to_http_request();
+        assert!(Encoding::for_response(&req, None).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn encoding_round_trip() {
+        let message = AddVersionsRequest {
+            versions: vec![version(Uuid::new_v4(), NIL_VERSION_ID, b"abc")],
+        };
+        for encoding in [Encoding::Cbor, Encoding::Protobuf] {
+            let body = encoding.encode(&message).unwrap();
+            assert_eq!(
+                encoding.decode::<AddVersionsRequest>(body.into()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_add_and_get_versions() {
+        let client_id = Uuid::new_v4();
+        let (v1, v2) = (Uuid::new_v4(), Uuid::new_v4());
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        // a new client's first versions, in CBOR
+        let mut second = version(v2, v1, b"second");
+        second.checksum = checksum(b"second");
+        let request = AddVersionsRequest {
+            versions: vec![version(v1, NIL_VERSION_ID, b"first"), second],
+        };
+        let req = test::TestRequest::post()
+            .uri("/v2/client/add-versions")
+            .insert_header(("Content-Type", CBOR_CONTENT_TYPE))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(Encoding::Cbor.encode(&request).unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            CBOR_CONTENT_TYPE
+        );
+        let body = test::read_body(resp).await;
+        let response: AddVersionsResponse = Encoding::Cbor.decode(body).unwrap();
+        assert_eq!(response.version_id, Some(v2.to_string()));
+        assert_eq!(response.expected_parent_version_id, None);
+        assert_eq!(response.snapshot_urgency(), SnapshotUrgency::High);
+
+        // the versions, in protobuf
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/client/get-child-versions/{NIL_VERSION_ID}"))
+            .insert_header(("Accept", PROTOBUF_CONTENT_TYPE))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let response: GetChildVersionsResponse = Encoding::Protobuf.decode(body).unwrap();
+        assert_eq!(response.versions.len(), 2);
+        assert_eq!(response.versions[0].version_id, v1.to_string());
+        assert_eq!(response.versions[0].history_segment, &b"first"[..]);
+        assert_eq!(response.versions[0].checksum, checksum(b"first"));
+        assert_eq!(response.versions[1].version_id, v2.to_string());
+        assert_eq!(response.versions[1].parent_version_id, v1.to_string());
+
+        // the client is up to date
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/client/get-child-versions/{v2}"))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_add_versions_conflict() {
+        let client_id = Uuid::new_v4();
+        let latest = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(latest).unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let request = AddVersionsRequest {
+            versions: vec![version(Uuid::new_v4(), Uuid::new_v4(), b"abc")],
+        };
+        let req = test::TestRequest::post()
+            .uri("/v2/client/add-versions")
+            .insert_header(("Content-Type", PROTOBUF_CONTENT_TYPE))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(request.encode_to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body = test::read_body(resp).await;
+        let response = AddVersionsResponse::decode(body).unwrap();
+        assert_eq!(response.version_id, None);
+        assert_eq!(
+            response.expected_parent_version_id,
+            Some(latest.to_string())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_add_versions_bad_requests() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let v1 = Uuid::new_v4();
+
+        let mut bad_checksum = version(v1, NIL_VERSION_ID, b"abc");
+        bad_checksum.checksum = checksum(b"def");
+        let not_a_chain = vec![
+            version(v1, NIL_VERSION_ID, b"abc"),
+            version(Uuid::new_v4(), Uuid::new_v4(), b"def"),
+        ];
+        for versions in [vec![], vec![bad_checksum], not_a_chain] {
+            let req = test::TestRequest::post()
+                .uri("/v2/client/add-versions")
+                .insert_header(("Content-Type", CBOR_CONTENT_TYPE))
+                .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(
+                    Encoding::Cbor
+                        .encode(&AddVersionsRequest { versions })
+                        .unwrap(),
+                )
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/v2/client/add-versions")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload("{}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let req = test::TestRequest::post()
+            .uri("/v2/client/add-versions")
+            .insert_header(("Content-Type", CBOR_CONTENT_TYPE))
+            .insert_header(("Accept", "application/json"))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(vec![0xa0])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abc".to_vec().into())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v2/client/snapshot")
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let request = SnapshotMessage {
+            version_id: version_id.to_string(),
+            snapshot: b"snapshot".to_vec().into(),
+            checksum: checksum(b"snapshot"),
+            created_at: None,
+        };
+        let req = test::TestRequest::post()
+            .uri("/v2/client/add-snapshot")
+            .insert_header(("Content-Type", PROTOBUF_CONTENT_TYPE))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(request.encode_to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert!(AddSnapshotResponse::decode(body).unwrap().accepted);
+
+        let req = test::TestRequest::get()
+            .uri("/v2/client/snapshot")
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let response: SnapshotMessage = Encoding::Cbor.decode(body).unwrap();
+        assert_eq!(response.version_id, version_id.to_string());
+        assert_eq!(response.snapshot, &b"snapshot"[..]);
+        assert_eq!(response.checksum, checksum(b"snapshot"));
+        assert!(response.created_at.is_some());
+    }
+}
@@ -1,33 +1,80 @@
 #![deny(clippy::all)]
 
+#[cfg(feature = "grpc")]
+use actix_web::rt;
 use actix_web::{
     dev::ServiceResponse,
-    http::StatusCode,
-    middleware::{ErrorHandlerResponse, ErrorHandlers, Logger},
-    App, HttpServer,
+    http::{Method, StatusCode},
+    middleware::{Condition, ErrorHandlerResponse, ErrorHandlers, Logger},
+    App, HttpMessage, HttpServer,
 };
-use clap::{arg, builder::ValueParser, value_parser, ArgAction, Command};
-use std::{collections::HashSet, ffi::OsString};
-use taskchampion_sync_server::WebServer;
-use taskchampion_sync_server_core::ServerConfig;
+use clap::{arg, builder::ValueParser, value_parser, ArgAction, ArgMatches, Command};
+#[cfg(feature = "grpc")]
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+    FutureExt,
+};
+#[cfg(unix)]
+use listenfd::ListenFd;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::{collections::HashSet, ffi::OsString, sync::Arc, time::Duration};
+#[cfg(feature = "grpc")]
+use std::{future::Future, net::ToSocketAddrs, pin::pin};
+#[cfg(feature = "tls")]
+use taskchampion_sync_server::ClientCertificates;
+#[cfg(feature = "grpc")]
+use taskchampion_sync_server::GrpcSyncServer;
+use taskchampion_sync_server::{
+    init_logging, validate_origin, BodyLimits, CorsConfig, LogFormat, RateLimit, RateLimitConfig,
+    RequestId, WebServer, WebhookConfig,
+};
+use taskchampion_sync_server_core::{
+    BlobStorage, BlobStore, Clock, QuotaStorage, Server, ServerConfig, Storage, SystemClock,
+};
+#[cfg(feature = "azure")]
+use taskchampion_sync_server_storage_azure::AzureBlobStore;
+#[cfg(feature = "mysql")]
+use taskchampion_sync_server_storage_mysql::MySqlStorage;
+#[cfg(feature = "postgres")]
+use taskchampion_sync_server_storage_postgres::PostgresStorage;
+#[cfg(feature = "s3")]
+use taskchampion_sync_server_storage_s3::S3BlobStore;
 use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+#[cfg(feature = "sqlx")]
+use taskchampion_sync_server_storage_sqlx::SqlxStorage;
 use uuid::Uuid;
 
+/// The format of the text access log: Actix's default format followed by the request ID.
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{X-Request-Id}o"#;
+
+/// Interval at which deleted clients are checked for purging.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 fn command() -> Command {
     let defaults = ServerConfig::default();
     let default_snapshot_versions = defaults.snapshot_versions.to_string();
     let default_snapshot_days = defaults.snapshot_days.to_string();
-    Command::new("taskchampion-sync-server")
+    let default_transient_retries = defaults.transient_retries.to_string();
+    let default_deletion_grace_days = defaults.deletion_grace_days.to_string();
+    let command = Command::new("taskchampion-sync-server")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Server for TaskChampion")
         .arg(
             arg!(-l --listen <ADDRESS>)
-                .help("Address and Port on which to listen on. Can be an IP Address or a DNS name followed by a colon and a port e.g. localhost:8080")
+                .help("Address and Port on which to listen on. Can be an IP Address or a DNS name followed by a colon and a port e.g. localhost:8080, or unix: followed by the path of a Unix socket e.g. unix:/run/taskchampion.sock")
                 .value_delimiter(',')
                 .value_parser(ValueParser::string())
                 .env("LISTEN")
                 .action(ArgAction::Append)
-                .required(true),
+                .required_unless_present_any([
+                    "check",
+                    "delete-client",
+                    "undelete-client",
+                    "socket-activation",
+                ]),
         )
         .arg(
             arg!(-d --"data-dir" <DIR> "Directory in which to store data")
@@ -55,23 +102,566 @@ fn command() -> Command {
                 .env("SNAPSHOT_DAYS")
                 .default_value(default_snapshot_days),
         )
+        .arg(
+            arg!(--"transient-retries" <NUM> "Number of times to retry an operation after a transient storage error")
+                .value_parser(value_parser!(u32))
+                .env("TRANSIENT_RETRIES")
+                .default_value(default_transient_retries),
+        )
+        .arg(
+            arg!(--"deletion-grace-days" <NUM> "Number of days after a client is deleted before its data is purged")
+                .value_parser(value_parser!(i64))
+                .env("DELETION_GRACE_DAYS")
+                .default_value(default_deletion_grace_days),
+        )
+        .arg(
+            arg!(--"delete-client" <CLIENT_ID> "Mark a client as deleted, to be purged after the grace period, and exit")
+                .value_parser(value_parser!(Uuid))
+                .conflicts_with("undelete-client"),
+        )
+        .arg(
+            arg!(--"undelete-client" <CLIENT_ID> "Restore a deleted client which has not yet been purged, and exit")
+                .value_parser(value_parser!(Uuid)),
+        )
+        .arg(
+            arg!(--"metrics-listen" <ADDRESS> "Address and port on which to serve Prometheus metrics at /metrics, separately from the sync protocol")
+                .value_parser(ValueParser::string())
+                .env("METRICS_LISTEN")
+                .required(false),
+        )
+        .arg(
+            arg!(--"metrics-token" <TOKEN> "Bearer token required to read /metrics; with a token, metrics are also served on the main listener")
+                .value_parser(ValueParser::string())
+                .env("METRICS_TOKEN")
+                .required(false),
+        )
+        .arg(
+            arg!(--"admin-token" <TOKEN> "Bearer token required by the admin API under /admin/v1, which is only served if this is given")
+                .value_parser(ValueParser::string())
+                .env("ADMIN_TOKEN")
+                .required(false),
+        )
+        .arg(
+            arg!(--"client-rate-limit" <PER_MINUTE> "Maximum sustained number of sync requests per minute from each client ID; further requests are rejected with 429 Too Many Requests")
+                .value_parser(value_parser!(u32))
+                .env("CLIENT_RATE_LIMIT")
+                .required(false),
+        )
+        .arg(
+            arg!(--"ip-rate-limit" <PER_MINUTE> "Maximum sustained number of sync requests per minute from each source IP address")
+                .value_parser(value_parser!(u32))
+                .env("IP_RATE_LIMIT")
+                .required(false),
+        )
+        .arg(
+            arg!(--"rate-limit-burst" <NUM> "Number of requests allowed in a burst above the rate limits")
+                .value_parser(value_parser!(u32))
+                .env("RATE_LIMIT_BURST")
+                .default_value("20"),
+        )
+        .arg(
+            arg!(--"log-format" <FORMAT> "Format of log output: `text`, or `json` for one JSON object per line, including an access log record with structured fields for each request")
+                .value_parser(value_parser!(LogFormat))
+                .env("LOG_FORMAT")
+                .default_value("text"),
+        )
+        .arg(
+            arg!(--"max-history-segment-size" <BYTES> "Largest history segment accepted, in bytes; larger requests are rejected with 413 Payload Too Large")
+                .value_parser(value_parser!(usize))
+                .env("MAX_HISTORY_SEGMENT_SIZE")
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-snapshot-size" <BYTES> "Largest snapshot accepted, in bytes")
+                .value_parser(value_parser!(usize))
+                .env("MAX_SNAPSHOT_SIZE")
+                .required(false),
+        )
+        .arg(
+            arg!(--compress "Compress responses with gzip, zstd, or brotli when the client accepts it")
+                .env("COMPRESS")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"cors-origin" <ORIGIN> "Origin, such as https://tasks.example.com, from which browsers may make cross-origin requests, or * for any origin (can be repeated)")
+                .value_delimiter(',')
+                .value_parser(parse_origin)
+                .env("CORS_ORIGINS")
+                .action(ArgAction::Append)
+                .required(false),
+        )
+        .arg(
+            arg!(--"cors-method" <METHOD> "HTTP method allowed in cross-origin requests (can be repeated)")
+                .value_delimiter(',')
+                .value_parser(parse_method)
+                .env("CORS_METHODS")
+                .action(ArgAction::Append)
+                .default_values(["GET", "POST", "PUT"]),
+        )
+        .arg(
+            arg!(--"webhook-url" <URL> "URL to which an event is POSTed as JSON when a client is created or a version or snapshot is added (can be repeated)")
+                .value_delimiter(',')
+                .value_parser(parse_webhook_url)
+                .env("WEBHOOK_URLS")
+                .action(ArgAction::Append)
+                .required(false),
+        )
+        .arg(
+            arg!(--"webhook-secret" <SECRET> "Secret with which to sign webhook requests with HMAC-SHA256, in the X-Webhook-Signature header")
+                .env("WEBHOOK_SECRET")
+                .requires("webhook-url")
+                .required(false),
+        )
+        .arg(
+            arg!(--"webhook-retries" <N> "Number of times to retry a failed webhook request before dropping the event")
+                .value_parser(value_parser!(u32))
+                .env("WEBHOOK_RETRIES")
+                .default_value("5"),
+        )
+        .arg(
+            arg!(--"socket-activation" "Also serve on the listening sockets passed by systemd socket activation")
+                .env("SOCKET_ACTIVATION")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--h2c "Also accept HTTP/2 without TLS from clients which know the server supports it, such as a reverse proxy using HTTP/2 to reach the server")
+                .env("H2C")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"shutdown-timeout" <SECONDS> "Number of seconds to wait for in-progress requests to finish when shutting down on SIGTERM or SIGINT")
+                .value_parser(value_parser!(u64))
+                .env("SHUTDOWN_TIMEOUT")
+                .default_value("30"),
+        )
+        .arg(
+            arg!(--check "Check the integrity of the stored data, print any problems, and exit")
+                .action(ArgAction::SetTrue),
+        );
+    #[cfg(feature = "postgres")]
+    let command = command.arg(
+        arg!(--"postgres-url" <URL> "URL of a PostgreSQL database in which to store data, instead of the data directory")
+            .value_parser(ValueParser::string())
+            .env("POSTGRES_URL")
+            .required(false),
+    );
+    #[cfg(feature = "mysql")]
+    let command = command.arg(
+        arg!(--"mysql-url" <URL> "URL of a MySQL or MariaDB database in which to store data, instead of the data directory")
+            .value_parser(ValueParser::string())
+            .env("MYSQL_URL")
+            .required(false),
+    );
+    #[cfg(feature = "otel")]
+    let command = command
+        .arg(
+            arg!(--"otlp-endpoint" <URL> "Endpoint of an OpenTelemetry collector to which to export traces of requests and storage operations with OTLP over gRPC, e.g. http://localhost:4317")
+                .value_parser(ValueParser::string())
+                .env("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .required(false),
+        )
+        .arg(
+            arg!(--"otel-service-name" <NAME> "Service name with which to label exported traces")
+                .value_parser(ValueParser::string())
+                .env("OTEL_SERVICE_NAME")
+                .default_value("taskchampion-sync-server"),
+        );
+    #[cfg(feature = "grpc")]
+    let command = command.arg(
+        arg!(--"grpc-listen" <ADDRESS> "Address and port on which to serve the sync protocol with gRPC, in addition to HTTP")
+            .value_parser(ValueParser::string())
+            .env("GRPC_LISTEN")
+            .required(false),
+    );
+    #[cfg(feature = "tls")]
+    let command = command
+        .arg(
+            arg!(--"tls-cert" <FILE> "PEM file containing the TLS certificate chain with which to serve HTTPS, instead of HTTP")
+                .value_parser(value_parser!(PathBuf))
+                .env("TLS_CERT")
+                .requires("tls-key")
+                .conflicts_with("h2c")
+                .required(false),
+        )
+        .arg(
+            arg!(--"tls-key" <FILE> "PEM file containing the private key for the TLS certificate")
+                .value_parser(value_parser!(PathBuf))
+                .env("TLS_KEY")
+                .requires("tls-cert")
+                .required(false),
+        )
+        .arg(
+            arg!(--"tls-client-ca" <FILE> "PEM file containing the CA certificates which must have issued the certificates presented by clients")
+                .value_parser(value_parser!(PathBuf))
+                .env("TLS_CLIENT_CA")
+                .requires("tls-cert")
+                .required(false),
+        )
+        .arg(
+            arg!(--"client-cert" <FINGERPRINT_CLIENT_ID> "SHA-256 fingerprint of a client certificate and a client ID it may use, as FINGERPRINT=CLIENT_ID (can be repeated; if not specified, any client certificate may use any client ID)")
+                .value_delimiter(',')
+                .value_parser(parse_client_cert)
+                .env("CLIENT_CERT")
+                .action(ArgAction::Append)
+                .requires("tls-client-ca")
+                .required(false),
+        );
+    #[cfg(feature = "sqlcipher")]
+    let command = command.arg(
+        arg!(--"sqlite-passphrase" <PASSPHRASE> "Passphrase with which to encrypt the SQLite database in the data directory")
+            .value_parser(ValueParser::string())
+            .env("SQLITE_PASSPHRASE")
+            .required(false),
+    );
+    #[cfg(feature = "sqlx")]
+    let command = command.arg(
+        arg!(--"db-url" <URL> "URL of a database in which to store data, instead of the data directory; the scheme selects the driver (postgres, mysql, or sqlite)")
+            .value_parser(ValueParser::string())
+            .env("DB_URL")
+            .required(false),
+    );
+    #[cfg(feature = "azure")]
+    let command = command
+        .arg(
+            arg!(--"azure-account" <ACCOUNT> "Azure storage account in which to store history segments and snapshots, with metadata in the data directory")
+                .value_parser(ValueParser::string())
+                .env("AZURE_STORAGE_ACCOUNT")
+                .required(false),
+        )
+        .arg(
+            arg!(--"azure-container" <CONTAINER> "Azure Blob Storage container, created if it does not exist")
+                .value_parser(ValueParser::string())
+                .env("AZURE_STORAGE_CONTAINER")
+                .default_value("taskchampion"),
+        )
+        .arg(
+            arg!(--"azure-sas-token" <TOKEN> "SAS token for the Azure storage account")
+                .value_parser(ValueParser::string())
+                .env("AZURE_STORAGE_SAS_TOKEN")
+                .requires("azure-account")
+                .required(false),
+        );
+    #[cfg(feature = "s3")]
+    let command = command
+        .arg(
+            arg!(--"s3-bucket" <BUCKET> "S3 bucket in which to store history segments and snapshots, with metadata in the selected database")
+                .value_parser(ValueParser::string())
+                .env("S3_BUCKET")
+                .required(false),
+        )
+        .arg(
+            arg!(--"s3-region" <REGION> "Region of the S3 bucket")
+                .value_parser(ValueParser::string())
+                .env("S3_REGION")
+                .default_value("us-east-1"),
+        )
+        .arg(
+            arg!(--"s3-endpoint" <URL> "Endpoint of an S3-compatible object store, instead of AWS")
+                .value_parser(ValueParser::string())
+                .env("S3_ENDPOINT")
+                .requires("s3-bucket")
+                .required(false),
+        )
+        .arg(
+            arg!(--"s3-prefix" <PREFIX> "Prefix for all object keys in the S3 bucket")
+                .value_parser(ValueParser::string())
+                .env("S3_PREFIX")
+                .requires("s3-bucket")
+                .required(false),
+        );
+    command
+}
+
+/// Create the storage for metadata selected by the command-line arguments, timestamping new
+/// clients and versions with the given clock.
+fn metadata_storage(
+    matches: &ArgMatches,
+    clock: Arc<dyn Clock>,
+) -> anyhow::Result<Box<dyn Storage>> {
+    #[cfg(feature = "postgres")]
+    if let Some(url) = matches.get_one::<String>("postgres-url") {
+        return Ok(Box::new(PostgresStorage::new(url)?.with_clock(clock)));
+    }
+    #[cfg(feature = "mysql")]
+    if let Some(url) = matches.get_one::<String>("mysql-url") {
+        return Ok(Box::new(MySqlStorage::new(url)?.with_clock(clock)));
+    }
+    #[cfg(feature = "sqlx")]
+    if let Some(url) = matches.get_one::<String>("db-url") {
+        return Ok(Box::new(SqlxStorage::new(url)?.with_clock(clock)));
+    }
+    let data_dir: &OsString = matches.get_one("data-dir").unwrap();
+    #[cfg(feature = "sqlcipher")]
+    if let Some(passphrase) = matches.get_one::<String>("sqlite-passphrase") {
+        return Ok(Box::new(
+            SqliteStorage::with_passphrase(data_dir, passphrase)?.with_clock(clock),
+        ));
+    }
+    Ok(Box::new(SqliteStorage::new(data_dir)?.with_clock(clock)))
+}
+
+/// Create the object store for history segments and snapshots selected by the command-line
+/// arguments, if any.
+#[allow(unused_variables)]
+fn blob_store(matches: &ArgMatches) -> anyhow::Result<Option<Box<dyn BlobStore>>> {
+    #[cfg(feature = "azure")]
+    if let Some(account) = matches.get_one::<String>("azure-account") {
+        let container: &String = matches.get_one("azure-container").unwrap();
+        let Some(sas_token) = matches.get_one::<String>("azure-sas-token") else {
+            anyhow::bail!("--azure-sas-token is required with --azure-account");
+        };
+        return Ok(Some(Box::new(AzureBlobStore::new(
+            account, container, sas_token,
+        )?)));
+    }
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = matches.get_one::<String>("s3-bucket") {
+        let region: &String = matches.get_one("s3-region").unwrap();
+        let endpoint = matches.get_one::<String>("s3-endpoint").map(String::as_str);
+        let mut blobs = S3BlobStore::new(bucket, region, endpoint)?;
+        if let Some(prefix) = matches.get_one::<String>("s3-prefix") {
+            blobs = blobs.with_prefix(prefix);
+        }
+        return Ok(Some(Box::new(blobs)));
+    }
+    Ok(None)
+}
+
+/// Create the storage selected by the command-line arguments.
+///
+/// Client and version metadata are stored in the selected database. If an object store is
+/// selected, history segments and snapshots are stored there, keeping the database small.
+fn storage(matches: &ArgMatches, clock: Arc<dyn Clock>) -> anyhow::Result<Box<dyn Storage>> {
+    let storage = metadata_storage(matches, clock)?;
+    Ok(match blob_store(matches)? {
+        Some(blobs) => Box::new(BlobStorage::new(storage, blobs)),
+        None => storage,
+    })
+}
+
+/// Create a web server using the storage selected by the command-line arguments, enforcing any
+/// per-client quotas.
+fn web_server(
+    matches: &ArgMatches,
+    config: ServerConfig,
+    client_id_allowlist: Option<HashSet<Uuid>>,
+) -> anyhow::Result<WebServer> {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    Ok(WebServer::with_clock(
+        config,
+        client_id_allowlist,
+        QuotaStorage::new(storage(matches, clock.clone())?),
+        clock,
+    ))
+}
+
+/// Get the rate limits selected by the command-line arguments.
+fn rate_limits(matches: &ArgMatches) -> RateLimitConfig {
+    let burst: u32 = *matches.get_one("rate-limit-burst").unwrap();
+    let limit = |name| {
+        matches.get_one::<u32>(name).map(|per_minute| RateLimit {
+            per_minute: *per_minute,
+            burst,
+        })
+    };
+    RateLimitConfig {
+        per_client: limit("client-rate-limit"),
+        per_ip: limit("ip-rate-limit"),
+    }
+}
+
+/// Parse an origin for `--cors-origin`.
+fn parse_origin(value: &str) -> Result<String, String> {
+    validate_origin(value).map_err(|e| e.to_string())?;
+    Ok(value.to_string())
+}
+
+/// Parse an HTTP method for `--cors-method`, which is case-insensitive.
+fn parse_method(value: &str) -> Result<String, String> {
+    let method = value.to_ascii_uppercase();
+    Method::from_bytes(method.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(method)
+}
+
+/// Get the CORS configuration selected by the command-line arguments, if cross-origin requests
+/// are allowed.
+fn cors(matches: &ArgMatches) -> Option<CorsConfig> {
+    let allowed_origins: Vec<String> = matches.get_many("cors-origin")?.cloned().collect();
+    Some(CorsConfig {
+        allowed_origins,
+        allowed_methods: matches.get_many("cors-method").unwrap().cloned().collect(),
+    })
+}
+
+/// Parse a URL for `--webhook-url`.
+fn parse_webhook_url(value: &str) -> Result<String, String> {
+    if !value.starts_with("http://") && !value.starts_with("https://") {
+        return Err(format!(
+            "Webhook URL {value:?} must be an http or https URL"
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Get the webhook configuration selected by the command-line arguments, if any webhooks are
+/// configured.
+fn webhooks(matches: &ArgMatches) -> Option<WebhookConfig> {
+    let urls: Vec<String> = matches.get_many("webhook-url")?.cloned().collect();
+    Some(WebhookConfig {
+        urls,
+        secret: matches.get_one::<String>("webhook-secret").cloned(),
+        max_retries: *matches.get_one("webhook-retries").unwrap(),
+    })
+}
+
+/// Remove a Unix socket left by a previous run, so that its path can be bound again. Files which
+/// are not sockets are left in place, so binding fails rather than deleting them.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> anyhow::Result<&str> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(path)
+}
+
+/// Get the request body limits selected by the command-line arguments.
+fn body_limits(matches: &ArgMatches) -> BodyLimits {
+    let defaults = BodyLimits::default();
+    BodyLimits {
+        max_history_segment_len: matches
+            .get_one("max-history-segment-size")
+            .copied()
+            .unwrap_or(defaults.max_history_segment_len),
+        max_snapshot_len: matches
+            .get_one("max-snapshot-size")
+            .copied()
+            .unwrap_or(defaults.max_snapshot_len),
+    }
+}
+
+/// Parse a `FINGERPRINT=CLIENT_ID` pair for `--client-cert`.
+#[cfg(feature = "tls")]
+fn parse_client_cert(value: &str) -> Result<(String, Uuid), String> {
+    let (fingerprint, client_id) = value
+        .rsplit_once('=')
+        .ok_or_else(|| "expected FINGERPRINT=CLIENT_ID".to_string())?;
+    let fingerprint =
+        taskchampion_sync_server::parse_fingerprint(fingerprint).map_err(|e| e.to_string())?;
+    let client_id = Uuid::parse_str(client_id).map_err(|e| e.to_string())?;
+    Ok((fingerprint, client_id))
+}
+
+/// Get the client IDs allowed for each client certificate by the command-line arguments, if
+/// that is restricted.
+#[cfg(feature = "tls")]
+fn client_certificates(matches: &ArgMatches) -> anyhow::Result<Option<ClientCertificates>> {
+    let Some(pairs) = matches.get_many::<(String, Uuid)>("client-cert") else {
+        return Ok(None);
+    };
+    let mut client_certificates = ClientCertificates::default();
+    for (fingerprint, client_id) in pairs {
+        client_certificates.allow(fingerprint, *client_id)?;
+    }
+    Ok(Some(client_certificates))
+}
+
+/// Check the integrity of the storage selected by the command-line arguments, printing any
+/// problems. It is an error if there are any.
+fn check(matches: &ArgMatches) -> anyhow::Result<()> {
+    let problems = storage(matches, Arc::new(SystemClock))?.check()?;
+    for problem in &problems {
+        println!("{}: {}", problem.client_id, problem.problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("Found {} problems", problems.len());
+    }
+    Ok(())
+}
+
+/// Mark the client given by `--delete-client` as deleted, or restore the client given by
+/// `--undelete-client`.
+fn set_client_deleted(matches: &ArgMatches, config: ServerConfig) -> anyhow::Result<()> {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let server = Server::with_clock(config, storage(matches, clock.clone())?, clock);
+    if let Some(client_id) = matches.get_one::<Uuid>("delete-client") {
+        server.delete_client(*client_id)?;
+        println!("Deleted client {client_id}");
+    }
+    if let Some(client_id) = matches.get_one::<Uuid>("undelete-client") {
+        server.undelete_client(*client_id)?;
+        println!("Restored client {client_id}");
+    }
+    Ok(())
 }
 
 fn print_error<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
     if let Some(err) = res.response().error() {
-        log::error!("Internal Server Error caused by:\n{:?}", err);
+        // This runs after the request has been handled, so identify the request explicitly.
+        let request_id = res.request().extensions().get::<RequestId>().cloned();
+        log::error!(
+            "Internal Server Error in request {} caused by:\n{:?}",
+            request_id.as_ref().map_or("-", RequestId::as_str),
+            err
+        );
     }
     Ok(ErrorHandlerResponse::Response(res.map_into_left_body()))
 }
 
+/// Serve gRPC on the given address, if any, until the HTTP servers stop.
+#[cfg(feature = "grpc")]
+async fn serve_grpc(
+    grpc_service: GrpcSyncServer,
+    address: Option<&String>,
+    shutdown_timeout: Duration,
+    servers: impl Future<Output = std::io::Result<()>>,
+) -> anyhow::Result<()> {
+    let Some(address) = address else {
+        return Ok(servers.await?);
+    };
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{address} does not resolve to an address"))?;
+    log::info!("Serving gRPC on {}", address);
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let grpc = tonic::transport::Server::builder()
+        .add_service(grpc_service)
+        .serve_with_shutdown(addr, stop_rx.map(|_| ()));
+    let mut grpc = pin!(grpc);
+    match select(pin!(servers), grpc.as_mut()).await {
+        Either::Left((res, _)) => {
+            res?;
+            // the HTTP servers have shut down, such as on SIGTERM, so shut down gRPC as well
+            drop(stop_tx);
+            match rt::time::timeout(shutdown_timeout, grpc).await {
+                Ok(res) => res?,
+                Err(_) => log::warn!("Timed out waiting for gRPC requests to finish"),
+            }
+            Ok(())
+        }
+        Either::Right((res, _)) => {
+            res?;
+            anyhow::bail!("gRPC server stopped unexpectedly")
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
     let matches = command().get_matches();
+    let log_format: LogFormat = *matches.get_one("log-format").unwrap();
+    init_logging(log_format);
+    if matches.get_flag("check") {
+        return check(&matches);
+    }
 
-    let data_dir: &OsString = matches.get_one("data-dir").unwrap();
     let snapshot_versions: u32 = *matches.get_one("snapshot-versions").unwrap();
     let snapshot_days: i64 = *matches.get_one("snapshot-days").unwrap();
+    let transient_retries: u32 = *matches.get_one("transient-retries").unwrap();
+    let deletion_grace_days: i64 = *matches.get_one("deletion-grace-days").unwrap();
     let client_id_allowlist: Option<HashSet<Uuid>> = matches
         .get_many("allow-client-id")
         .map(|ids| ids.copied().collect());
@@ -79,20 +669,196 @@ async fn main() -> anyhow::Result<()> {
     let config = ServerConfig {
         snapshot_days,
         snapshot_versions,
+        transient_retries,
+        deletion_grace_days,
+    };
+    if matches.contains_id("delete-client") || matches.contains_id("undelete-client") {
+        return set_client_deleted(&matches, config);
+    }
+    let mut server = web_server(&matches, config, client_id_allowlist)?
+        .with_rate_limits(rate_limits(&matches))
+        .with_body_limits(body_limits(&matches));
+    if matches.get_flag("compress") {
+        server = server.with_compression();
+    }
+    if let Some(cors) = cors(&matches) {
+        server = server.with_cors(cors);
+    }
+    if let Some(webhooks) = webhooks(&matches) {
+        server = server.with_webhooks(webhooks);
+    }
+    if let Some(token) = matches.get_one::<String>("admin-token") {
+        server = server.with_admin_token(token.clone());
+    }
+    if let Some(token) = matches.get_one::<String>("metrics-token") {
+        server = server.with_metrics_token(token.clone());
+    }
+    #[cfg(feature = "otel")]
+    let tracer_provider = match matches.get_one::<String>("otlp-endpoint") {
+        Some(endpoint) => {
+            let service_name: &String = matches.get_one("otel-service-name").unwrap();
+            log::info!("Exporting traces to {}", endpoint);
+            Some(taskchampion_sync_server::init_tracing(
+                endpoint,
+                service_name,
+            )?)
+        }
+        None => None,
+    };
+    if log_format == LogFormat::Json {
+        // the server's own access log has structured fields, so it replaces actix's
+        server = server.with_access_log();
+    }
+    #[cfg(feature = "tls")]
+    let tls_config = match (
+        matches.get_one::<PathBuf>("tls-cert"),
+        matches.get_one::<PathBuf>("tls-key"),
+    ) {
+        (Some(cert), Some(key)) => Some(taskchampion_sync_server::load_tls_config(
+            cert,
+            key,
+            matches
+                .get_one::<PathBuf>("tls-client-ca")
+                .map(PathBuf::as_path),
+        )?),
+        _ => None,
     };
-    let server = WebServer::new(config, client_id_allowlist, SqliteStorage::new(data_dir)?);
+    #[cfg(feature = "tls")]
+    if let Some(client_certificates) = client_certificates(&matches)? {
+        server = server.with_client_certificates(client_certificates);
+    }
+    server.spawn_purge_thread(PURGE_INTERVAL);
+    let metrics_server = server.clone();
+    let shutdown_server = server.clone();
+    let shutdown_timeout: u64 = *matches.get_one("shutdown-timeout").unwrap();
+    #[cfg(feature = "grpc")]
+    let grpc_service = server.grpc_service();
 
     let mut http_server = HttpServer::new(move || {
         App::new()
             .wrap(ErrorHandlers::new().handler(StatusCode::INTERNAL_SERVER_ERROR, print_error))
-            .wrap(Logger::default())
+            .wrap(Condition::new(
+                log_format == LogFormat::Text,
+                Logger::new(ACCESS_LOG_FORMAT),
+            ))
             .configure(|cfg| server.config(cfg))
     });
-    for listen_address in matches.get_many::<String>("listen").unwrap() {
+    #[cfg(feature = "tls")]
+    {
+        http_server = http_server.on_connect(taskchampion_sync_server::client_certificate);
+    }
+    if matches.get_flag("socket-activation") {
+        #[cfg(unix)]
+        {
+            let mut listenfd = ListenFd::from_env();
+            if listenfd.len() == 0 {
+                anyhow::bail!("No sockets were passed by systemd socket activation");
+            }
+            for index in 0..listenfd.len() {
+                match listenfd.take_tcp_listener(index) {
+                    Ok(Some(listener)) => {
+                        log::info!("Serving on activated socket {}", listener.local_addr()?);
+                        #[cfg(feature = "tls")]
+                        if let Some(tls_config) = &tls_config {
+                            http_server =
+                                http_server.listen_rustls_0_23(listener, tls_config.clone())?;
+                            continue;
+                        }
+                        http_server = if matches.get_flag("h2c") {
+                            http_server.listen_auto_h2c(listener)?
+                        } else {
+                            http_server.listen(listener)?
+                        };
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        // not a TCP socket, so it should be a Unix socket
+                        let Ok(Some(listener)) = listenfd.take_unix_listener(index) else {
+                            return Err(err.into());
+                        };
+                        #[cfg(feature = "tls")]
+                        if tls_config.is_some() {
+                            anyhow::bail!("TLS is not supported on activated Unix sockets");
+                        }
+                        log::info!("Serving on activated Unix socket");
+                        http_server = http_server.listen_uds(listener)?;
+                    }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("Socket activation is not supported on this platform");
+    }
+    for listen_address in matches.get_many::<String>("listen").into_iter().flatten() {
+        if let Some(path) = listen_address.strip_prefix("unix:") {
+            #[cfg(feature = "tls")]
+            if tls_config.is_some() {
+                anyhow::bail!("TLS is not supported on Unix socket {path}");
+            }
+            #[cfg(unix)]
+            {
+                log::info!("Serving on Unix socket {}", path);
+                http_server = http_server.bind_uds(remove_stale_socket(path)?)?;
+                continue;
+            }
+            #[cfg(not(unix))]
+            anyhow::bail!("Unix sockets are not supported on this platform");
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = &tls_config {
+            log::info!("Serving HTTPS on {}", listen_address);
+            http_server = http_server.bind_rustls_0_23(listen_address, tls_config.clone())?;
+            continue;
+        }
         log::info!("Serving on {}", listen_address);
-        http_server = http_server.bind(listen_address)?
+        http_server = if matches.get_flag("h2c") {
+            http_server.bind_auto_h2c(listen_address)?
+        } else {
+            http_server.bind(listen_address)?
+        };
+    }
+    http_server = http_server.shutdown_timeout(shutdown_timeout);
+    let metrics_http_server = match matches.get_one::<String>("metrics-listen") {
+        Some(metrics_address) => {
+            log::info!("Serving metrics on {}", metrics_address);
+            Some(
+                HttpServer::new(move || {
+                    App::new().configure(|cfg| metrics_server.metrics_config(cfg))
+                })
+                .bind(metrics_address)?
+                .workers(1)
+                .shutdown_timeout(shutdown_timeout),
+            )
+        }
+        None => None,
+    };
+    let servers = async move {
+        match metrics_http_server {
+            Some(metrics_http_server) => {
+                futures::try_join!(http_server.run(), metrics_http_server.run()).map(|_| ())
+            }
+            None => http_server.run().await,
+        }
+    };
+    #[cfg(feature = "grpc")]
+    serve_grpc(
+        grpc_service,
+        matches.get_one::<String>("grpc-listen"),
+        Duration::from_secs(shutdown_timeout),
+        servers,
+    )
+    .await?;
+    #[cfg(not(feature = "grpc"))]
+    servers.await?;
+    // the servers have stopped, and in-progress requests have finished or timed out
+    log::info!("Flushing storage");
+    shutdown_server.flush()?;
+    #[cfg(feature = "otel")]
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(err) = tracer_provider.shutdown() {
+            log::warn!("Could not flush traces: {err}");
+        }
     }
-    http_server.run().await?;
     Ok(())
 }
 
@@ -100,7 +866,6 @@ async fn main() -> anyhow::Result<()> {
 mod test {
     use super::*;
     use actix_web::{self, App};
-    use clap::ArgMatches;
     use taskchampion_sync_server_core::InMemoryStorage;
     use temp_env::{with_var, with_var_unset, with_vars, with_vars_unset};
 
@@ -233,6 +998,29 @@ mod test {
         );
     }
 
+    #[test]
+    fn command_check() {
+        with_var_unset("LISTEN", || {
+            let matches = command().get_matches_from(["tss", "--check"]);
+            assert!(matches.get_flag("check"));
+        });
+    }
+
+    #[test]
+    fn command_compress() {
+        with_var_unset("COMPRESS", || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert!(!matches.get_flag("compress"));
+            let matches =
+                command().get_matches_from(["tss", "--listen", "localhost:8080", "--compress"]);
+            assert!(matches.get_flag("compress"));
+        });
+        with_var("COMPRESS", Some("true"), || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert!(matches.get_flag("compress"));
+        });
+    }
+
     #[test]
     fn command_data_dir() {
         with_var_unset("DATA_DIR", || {
@@ -255,6 +1043,150 @@ mod test {
         });
     }
 
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn command_postgres_url() {
+        with_var_unset("POSTGRES_URL", || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--postgres-url",
+                "postgresql://localhost/tss",
+            ]);
+            assert_eq!(
+                matches.get_one::<String>("postgres-url").unwrap(),
+                "postgresql://localhost/tss"
+            );
+        });
+    }
+
+    #[cfg(feature = "mysql")]
+    #[test]
+    fn command_mysql_url() {
+        with_var_unset("MYSQL_URL", || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--mysql-url",
+                "mysql://localhost/tss",
+            ]);
+            assert_eq!(
+                matches.get_one::<String>("mysql-url").unwrap(),
+                "mysql://localhost/tss"
+            );
+        });
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn command_sqlite_passphrase() {
+        with_var_unset("SQLITE_PASSPHRASE", || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--sqlite-passphrase",
+                "secret",
+            ]);
+            assert_eq!(
+                matches.get_one::<String>("sqlite-passphrase").unwrap(),
+                "secret"
+            );
+        });
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn command_s3() {
+        with_vars_unset(
+            ["S3_BUCKET", "S3_REGION", "S3_ENDPOINT", "S3_PREFIX"],
+            || {
+                let matches = command().get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8080",
+                    "--s3-bucket",
+                    "mybucket",
+                    "--s3-endpoint",
+                    "http://localhost:9000",
+                ]);
+                assert_eq!(matches.get_one::<String>("s3-bucket").unwrap(), "mybucket");
+                assert_eq!(matches.get_one::<String>("s3-region").unwrap(), "us-east-1");
+                assert_eq!(
+                    matches.get_one::<String>("s3-endpoint").unwrap(),
+                    "http://localhost:9000"
+                );
+                assert_eq!(matches.get_one::<String>("s3-prefix"), None);
+            },
+        );
+    }
+
+    #[test]
+    fn web_server_without_blob_store() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        with_vars_unset(["S3_BUCKET", "AZURE_STORAGE_ACCOUNT"], || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--data-dir",
+                tmp_dir.path().to_str().unwrap(),
+            ]);
+            assert!(blob_store(&matches)?.is_none());
+            web_server(&matches, ServerConfig::default(), None)?;
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "azure")]
+    #[test]
+    fn command_azure() {
+        with_vars_unset(
+            [
+                "AZURE_STORAGE_ACCOUNT",
+                "AZURE_STORAGE_CONTAINER",
+                "AZURE_STORAGE_SAS_TOKEN",
+            ],
+            || {
+                let matches = command().get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8080",
+                    "--azure-account",
+                    "myaccount",
+                    "--azure-sas-token",
+                    "sv=1&sig=2",
+                ]);
+                assert_eq!(
+                    matches.get_one::<String>("azure-account").unwrap(),
+                    "myaccount"
+                );
+                assert_eq!(
+                    matches.get_one::<String>("azure-container").unwrap(),
+                    "taskchampion"
+                );
+                assert_eq!(
+                    matches.get_one::<String>("azure-sas-token").unwrap(),
+                    "sv=1&sig=2"
+                );
+            },
+        );
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[test]
+    fn command_db_url_env() {
+        with_var("DB_URL", Some("postgres://localhost/tss"), || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(
+                matches.get_one::<String>("db-url").unwrap(),
+                "postgres://localhost/tss"
+            );
+        });
+    }
+
     #[test]
     fn command_snapshot() {
         with_vars_unset(["SNAPSHOT_DAYS", "SNAPSHOT_VERSIONS"], || {
@@ -287,6 +1219,486 @@ mod test {
         );
     }
 
+    #[test]
+    fn command_log_format() {
+        with_var_unset("LOG_FORMAT", || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(
+                *matches.get_one::<LogFormat>("log-format").unwrap(),
+                LogFormat::Text
+            );
+        });
+        with_var("LOG_FORMAT", Some("json"), || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(
+                *matches.get_one::<LogFormat>("log-format").unwrap(),
+                LogFormat::Json
+            );
+        });
+        with_var_unset("LOG_FORMAT", || {
+            let res = command().try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--log-format",
+                "xml",
+            ]);
+            assert!(res.is_err());
+        });
+    }
+
+    #[test]
+    fn command_body_limits() {
+        with_vars_unset(["MAX_HISTORY_SEGMENT_SIZE", "MAX_SNAPSHOT_SIZE"], || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(body_limits(&matches), BodyLimits::default());
+        });
+        with_var("MAX_SNAPSHOT_SIZE", Some("1000"), || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--max-history-segment-size",
+                "100",
+            ]);
+            assert_eq!(
+                body_limits(&matches),
+                BodyLimits {
+                    max_history_segment_len: 100,
+                    max_snapshot_len: 1000,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn command_webhooks() {
+        with_vars_unset(
+            ["WEBHOOK_URLS", "WEBHOOK_SECRET", "WEBHOOK_RETRIES"],
+            || {
+                let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+                assert_eq!(webhooks(&matches), None);
+
+                let matches = command().get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8080",
+                    "--webhook-url",
+                    "https://hooks.example.com/sync",
+                    "--webhook-url",
+                    "http://localhost:9000/",
+                    "--webhook-secret",
+                    "s3cret",
+                    "--webhook-retries",
+                    "2",
+                ]);
+                assert_eq!(
+                    webhooks(&matches),
+                    Some(WebhookConfig {
+                        urls: vec![
+                            "https://hooks.example.com/sync".into(),
+                            "http://localhost:9000/".into()
+                        ],
+                        secret: Some("s3cret".into()),
+                        max_retries: 2,
+                    })
+                );
+
+                let res = command().try_get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8080",
+                    "--webhook-url",
+                    "hooks.example.com",
+                ]);
+                assert!(res.is_err());
+
+                // a secret without a URL is an error
+                let res = command().try_get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8080",
+                    "--webhook-secret",
+                    "s3cret",
+                ]);
+                assert!(res.is_err());
+            },
+        );
+        with_var(
+            "WEBHOOK_URLS",
+            Some("https://hooks.example.com/sync"),
+            || {
+                let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+                assert_eq!(
+                    webhooks(&matches),
+                    Some(WebhookConfig {
+                        urls: vec!["https://hooks.example.com/sync".into()],
+                        ..WebhookConfig::default()
+                    })
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn command_cors() {
+        with_vars_unset(["CORS_ORIGINS", "CORS_METHODS"], || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(cors(&matches), None);
+
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--cors-origin",
+                "https://tasks.example.com",
+                "--cors-method",
+                "get",
+            ]);
+            assert_eq!(
+                cors(&matches),
+                Some(CorsConfig {
+                    allowed_origins: vec!["https://tasks.example.com".into()],
+                    allowed_methods: vec!["GET".into()],
+                })
+            );
+
+            let res = command().try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--cors-origin",
+                "tasks.example.com",
+            ]);
+            assert!(res.is_err());
+        });
+        with_var(
+            "CORS_ORIGINS",
+            Some("https://a.example.com,https://b.example.com"),
+            || {
+                let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+                assert_eq!(
+                    cors(&matches),
+                    Some(CorsConfig {
+                        allowed_origins: vec![
+                            "https://a.example.com".into(),
+                            "https://b.example.com".into()
+                        ],
+                        ..CorsConfig::default()
+                    })
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn command_shutdown_timeout() {
+        with_var_unset("SHUTDOWN_TIMEOUT", || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(*matches.get_one::<u64>("shutdown-timeout").unwrap(), 30);
+        });
+        with_var("SHUTDOWN_TIMEOUT", Some("300"), || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(*matches.get_one::<u64>("shutdown-timeout").unwrap(), 300);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remove_stale_socket_only() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+
+        let socket = tmp_dir.path().join("tss.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket)?);
+        assert!(socket.exists());
+        remove_stale_socket(socket.to_str().unwrap())?;
+        assert!(!socket.exists());
+
+        let file = tmp_dir.path().join("data");
+        std::fs::write(&file, b"data")?;
+        remove_stale_socket(file.to_str().unwrap())?;
+        assert!(file.exists());
+
+        // a path which does not exist is not an error
+        remove_stale_socket(socket.to_str().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn command_socket_activation() {
+        with_vars_unset(["LISTEN", "SOCKET_ACTIVATION"], || {
+            // --listen is not required with socket activation
+            let matches = command().get_matches_from(["tss", "--socket-activation"]);
+            assert!(matches.get_flag("socket-activation"));
+            assert!(matches.get_many::<String>("listen").is_none());
+
+            assert!(command().try_get_matches_from(["tss"]).is_err());
+        });
+    }
+
+    #[test]
+    fn command_h2c() {
+        with_var_unset("H2C", || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert!(!matches.get_flag("h2c"));
+            let matches =
+                command().get_matches_from(["tss", "--listen", "localhost:8080", "--h2c"]);
+            assert!(matches.get_flag("h2c"));
+        });
+        with_var("H2C", Some("true"), || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert!(matches.get_flag("h2c"));
+        });
+    }
+
+    #[test]
+    fn command_rate_limits() {
+        with_vars_unset(
+            ["CLIENT_RATE_LIMIT", "IP_RATE_LIMIT", "RATE_LIMIT_BURST"],
+            || {
+                let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+                assert_eq!(rate_limits(&matches), RateLimitConfig::default());
+
+                let matches = command().get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8080",
+                    "--client-rate-limit",
+                    "30",
+                    "--rate-limit-burst",
+                    "5",
+                ]);
+                assert_eq!(
+                    rate_limits(&matches),
+                    RateLimitConfig {
+                        per_client: Some(RateLimit {
+                            per_minute: 30,
+                            burst: 5
+                        }),
+                        per_ip: None,
+                    }
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn command_metrics() {
+        with_vars_unset(["METRICS_LISTEN", "METRICS_TOKEN"], || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--metrics-listen",
+                "127.0.0.1:9090",
+            ]);
+            assert_eq!(
+                matches.get_one::<String>("metrics-listen").unwrap(),
+                "127.0.0.1:9090"
+            );
+            assert_eq!(matches.get_one::<String>("metrics-token"), None);
+        });
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn command_grpc() {
+        with_var("GRPC_LISTEN", Some("127.0.0.1:50051"), || {
+            let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+            assert_eq!(
+                matches.get_one::<String>("grpc-listen").unwrap(),
+                "127.0.0.1:50051"
+            );
+        });
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn command_otel() {
+        with_vars(
+            [
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", Some("http://collector:4317")),
+                ("OTEL_SERVICE_NAME", None),
+            ],
+            || {
+                let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+                assert_eq!(
+                    matches.get_one::<String>("otlp-endpoint").unwrap(),
+                    "http://collector:4317"
+                );
+                assert_eq!(
+                    matches.get_one::<String>("otel-service-name").unwrap(),
+                    "taskchampion-sync-server"
+                );
+            },
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn command_tls() {
+        with_vars_unset(["TLS_CERT", "TLS_KEY"], || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8443",
+                "--tls-cert",
+                "/etc/tss/cert.pem",
+                "--tls-key",
+                "/etc/tss/key.pem",
+            ]);
+            assert_eq!(
+                matches.get_one::<PathBuf>("tls-cert").unwrap(),
+                &PathBuf::from("/etc/tss/cert.pem")
+            );
+            assert_eq!(
+                matches.get_one::<PathBuf>("tls-key").unwrap(),
+                &PathBuf::from("/etc/tss/key.pem")
+            );
+
+            // a certificate is useless without its key
+            let res = command().try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8443",
+                "--tls-cert",
+                "/etc/tss/cert.pem",
+            ]);
+            assert!(res.is_err());
+        });
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn command_client_certs() {
+        let fingerprint = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let client_id = Uuid::new_v4();
+        with_vars_unset(
+            ["TLS_CERT", "TLS_KEY", "TLS_CLIENT_CA", "CLIENT_CERT"],
+            || {
+                let matches = command().get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8443",
+                    "--tls-cert",
+                    "/etc/tss/cert.pem",
+                    "--tls-key",
+                    "/etc/tss/key.pem",
+                    "--tls-client-ca",
+                    "/etc/tss/ca.pem",
+                ]);
+                assert_eq!(client_certificates(&matches).unwrap(), None);
+
+                let matches = command().get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8443",
+                    "--tls-cert",
+                    "/etc/tss/cert.pem",
+                    "--tls-key",
+                    "/etc/tss/key.pem",
+                    "--tls-client-ca",
+                    "/etc/tss/ca.pem",
+                    "--client-cert",
+                    &format!("{}={client_id}", fingerprint.to_uppercase()),
+                ]);
+                let mut expected = ClientCertificates::default();
+                expected.allow(fingerprint, client_id).unwrap();
+                assert_eq!(client_certificates(&matches).unwrap(), Some(expected));
+
+                // a fingerprint is meaningless without client certificates
+                let res = command().try_get_matches_from([
+                    "tss",
+                    "--listen",
+                    "localhost:8443",
+                    "--client-cert",
+                    &format!("{fingerprint}={client_id}"),
+                ]);
+                assert!(res.is_err());
+            },
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn parse_client_cert_pairs() {
+        let client_id = Uuid::new_v4();
+        let fingerprint = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(
+            parse_client_cert(&format!("{fingerprint}={client_id}")),
+            Ok((fingerprint.to_string(), client_id))
+        );
+        assert!(parse_client_cert(fingerprint).is_err());
+        assert!(parse_client_cert(&format!("abcd={client_id}")).is_err());
+        assert!(parse_client_cert(&format!("{fingerprint}=not-a-uuid")).is_err());
+    }
+
+    #[test]
+    fn command_delete_client() {
+        with_vars_unset(["LISTEN", "DELETION_GRACE_DAYS"], || {
+            let matches = command().get_matches_from([
+                "tss",
+                "--delete-client",
+                "711d5cf3-0cf0-4eb8-9eca-6f7f220638c0",
+                "--deletion-grace-days",
+                "7",
+            ]);
+            assert_eq!(
+                matches.get_one::<Uuid>("delete-client"),
+                Some(&Uuid::parse_str("711d5cf3-0cf0-4eb8-9eca-6f7f220638c0").unwrap())
+            );
+            assert_eq!(*matches.get_one::<i64>("deletion-grace-days").unwrap(), 7);
+        });
+    }
+
+    #[test]
+    fn set_client_deleted_round_trip() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let client_id = Uuid::new_v4();
+        let data_dir = tmp_dir.path().to_str().unwrap();
+        with_vars_unset(["LISTEN", "S3_BUCKET", "AZURE_STORAGE_ACCOUNT"], || {
+            let matches = command().get_matches_from(["tss", "--data-dir", data_dir, "--check"]);
+            {
+                let storage = storage(&matches, Arc::new(SystemClock))?;
+                let mut txn = storage.txn(client_id)?;
+                txn.new_client(Uuid::nil())?;
+                txn.commit()?;
+            }
+
+            let client_id = client_id.to_string();
+            let matches = command().get_matches_from([
+                "tss",
+                "--data-dir",
+                data_dir,
+                "--delete-client",
+                &client_id,
+            ]);
+            set_client_deleted(&matches, ServerConfig::default())?;
+            let deleted_at = storage(&matches, Arc::new(SystemClock))?
+                .txn(Uuid::parse_str(&client_id)?)?
+                .get_client()?
+                .unwrap()
+                .deleted_at;
+            assert!(deleted_at.is_some());
+
+            let matches = command().get_matches_from([
+                "tss",
+                "--data-dir",
+                data_dir,
+                "--undelete-client",
+                &client_id,
+            ]);
+            set_client_deleted(&matches, ServerConfig::default())?;
+            let deleted_at = storage(&matches, Arc::new(SystemClock))?
+                .txn(Uuid::parse_str(&client_id)?)?
+                .get_client()?
+                .unwrap()
+                .deleted_at;
+            assert_eq!(deleted_at, None);
+            Ok(())
+        })
+    }
+
     #[actix_rt::test]
     async fn test_index_get() {
         let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
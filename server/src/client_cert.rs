@@ -0,0 +1,114 @@
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use taskchampion_sync_server_core::ClientId;
+
+/// The certificate a client presented when connecting over mutual TLS. This is stored in the
+/// connection data, where it is available to every request on the connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    fingerprint: String,
+}
+
+impl ClientCertificate {
+    /// Identify a certificate, given in DER form, by its SHA-256 fingerprint.
+    pub fn from_der(der: &[u8]) -> Self {
+        let fingerprint = Sha256::digest(der)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Self { fingerprint }
+    }
+
+    /// The certificate's SHA-256 fingerprint, in lower-case hex without separators.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// Normalize a SHA-256 fingerprint, accepting upper- or lower-case hex, optionally separated by
+/// colons as in the output of `openssl x509 -fingerprint -sha256`.
+pub fn parse_fingerprint(fingerprint: &str) -> anyhow::Result<String> {
+    let normalized: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid SHA-256 fingerprint {fingerprint:?}"));
+    }
+    Ok(normalized)
+}
+
+/// The client IDs which each client certificate may use. A certificate may be allowed more
+/// than one client ID, and a client ID may be used by more than one certificate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCertificates {
+    client_ids: HashMap<String, HashSet<ClientId>>,
+}
+
+impl ClientCertificates {
+    /// Allow the certificate with the given fingerprint, in any format accepted by
+    /// [`parse_fingerprint`], to use the given client ID.
+    pub fn allow(&mut self, fingerprint: &str, client_id: ClientId) -> anyhow::Result<()> {
+        self.client_ids
+            .entry(parse_fingerprint(fingerprint)?)
+            .or_default()
+            .insert(client_id);
+        Ok(())
+    }
+
+    /// Determine whether a request over a connection with the given certificate may use the
+    /// given client ID. Requests without a certificate are never allowed.
+    pub(crate) fn allows(&self, cert: Option<&ClientCertificate>, client_id: ClientId) -> bool {
+        cert.and_then(|cert| self.client_ids.get(cert.fingerprint()))
+            .is_some_and(|ids| ids.contains(&client_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    #[test]
+    fn fingerprint() {
+        assert_eq!(
+            ClientCertificate::from_der(b"abc").fingerprint(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn parse_fingerprint_formats() {
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(parse_fingerprint(expected).unwrap(), expected);
+        assert_eq!(
+            parse_fingerprint(
+                "BA:78:16:BF:8F:01:CF:EA:41:41:40:DE:5D:AE:22:23:\
+                 B0:03:61:A3:96:17:7A:9C:B4:10:FF:61:F2:00:15:AD"
+            )
+            .unwrap(),
+            expected
+        );
+        assert!(parse_fingerprint("ba7816bf").is_err());
+        assert!(parse_fingerprint(&"g".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn allows() {
+        let client_id = Uuid::new_v4();
+        let cert = ClientCertificate::from_der(b"abc");
+        let other_cert = ClientCertificate::from_der(b"def");
+        let mut certs = ClientCertificates::default();
+        certs
+            .allow(&cert.fingerprint().to_uppercase(), client_id)
+            .unwrap();
+
+        assert!(certs.allows(Some(&cert), client_id));
+        assert!(!certs.allows(Some(&cert), Uuid::new_v4()));
+        assert!(!certs.allows(Some(&other_cert), client_id));
+        assert!(!certs.allows(None, client_id));
+    }
+}
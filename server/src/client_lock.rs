@@ -0,0 +1,139 @@
+use futures::lock::{Mutex as AsyncMutex, OwnedMutexGuard};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A lock for each client, so that requests for the same client are processed one at a time
+/// while requests for different clients proceed in parallel.
+///
+/// This avoids wasted work, such as spooling and checking a version which will then conflict,
+/// when two replicas of the same client sync simultaneously. It only serializes requests within
+/// this process; correctness across processes relies on the storage's transactions.
+#[derive(Default)]
+pub(crate) struct ClientLocks {
+    /// The lock for each client which has a request in progress or waiting.
+    locks: Mutex<HashMap<Uuid, ClientLock>>,
+}
+
+/// The lock for a client, with the number of requests holding or waiting for it.
+#[derive(Default)]
+struct ClientLock {
+    lock: Arc<AsyncMutex<()>>,
+    users: usize,
+}
+
+impl ClientLocks {
+    /// Wait until no other request for the client is in progress, and lock it until the returned
+    /// guard is dropped.
+    pub(crate) async fn lock(&self, client_id: Uuid) -> ClientLockGuard<'_> {
+        let (user, lock) = self.add_user(client_id);
+        // if this future is dropped while waiting, `user` is dropped with it, so the client's
+        // lock is still forgotten when no longer used
+        let guard = lock.lock_owned().await;
+        ClientLockGuard {
+            guard: Some(guard),
+            _user: user,
+        }
+    }
+
+    /// Count a request using the client's lock, until the returned value is dropped, and get the
+    /// lock.
+    fn add_user(&self, client_id: Uuid) -> (ClientLockUser<'_>, Arc<AsyncMutex<()>>) {
+        let mut locks = self.locks.lock().expect("poisoned lock");
+        let entry = locks.entry(client_id).or_default();
+        entry.users += 1;
+        let user = ClientLockUser {
+            locks: self,
+            client_id,
+        };
+        (user, entry.lock.clone())
+    }
+
+    /// Get the number of clients with a request in progress or waiting.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().expect("poisoned lock").len()
+    }
+}
+
+/// A request using a client's lock, whether holding it or waiting for it.
+struct ClientLockUser<'a> {
+    locks: &'a ClientLocks,
+    client_id: Uuid,
+}
+
+impl Drop for ClientLockUser<'_> {
+    fn drop(&mut self) {
+        // forget the client's lock if no other request holds or awaits it
+        let mut locks = self.locks.locks.lock().expect("poisoned lock");
+        if let Some(entry) = locks.get_mut(&self.client_id) {
+            entry.users -= 1;
+            if entry.users == 0 {
+                locks.remove(&self.client_id);
+            }
+        }
+    }
+}
+
+/// A held per-client lock, released when dropped.
+pub(crate) struct ClientLockGuard<'a> {
+    guard: Option<OwnedMutexGuard<()>>,
+    _user: ClientLockUser<'a>,
+}
+
+impl Drop for ClientLockGuard<'_> {
+    fn drop(&mut self) {
+        // release the lock before `_user` is dropped, possibly forgetting it
+        drop(self.guard.take());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+    use pretty_assertions::assert_eq;
+
+    #[actix_rt::test]
+    async fn serializes_same_client() {
+        let locks = ClientLocks::default();
+        let client_id = Uuid::new_v4();
+
+        let guard = locks.lock(client_id).await;
+        let mut waiting = Box::pin(locks.lock(client_id));
+        assert!((&mut waiting).now_or_never().is_none());
+
+        // other clients are not blocked
+        assert!(locks.lock(Uuid::new_v4()).now_or_never().is_some());
+
+        drop(guard);
+        let guard = waiting.await;
+        assert_eq!(locks.len(), 1);
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn cancelled_waiter_forgotten() {
+        let locks = ClientLocks::default();
+        let client_id = Uuid::new_v4();
+
+        let guard = locks.lock(client_id).await;
+        let mut waiting = Box::pin(locks.lock(client_id));
+        assert!((&mut waiting).now_or_never().is_none());
+
+        // the waiter is cancelled, such as when its client disconnects
+        drop(waiting);
+        assert_eq!(locks.len(), 1);
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+
+        // the waiter is cancelled after the holder releases the lock
+        let guard = locks.lock(client_id).await;
+        let mut waiting = Box::pin(locks.lock(client_id));
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(guard);
+        drop(waiting);
+        assert_eq!(locks.len(), 0);
+    }
+}
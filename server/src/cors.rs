@@ -0,0 +1,99 @@
+use crate::api::{
+    CLIENT_ID_HEADER, PARENT_VERSION_ID_HEADER, SNAPSHOT_REQUEST_HEADER, VERSION_ID_HEADER,
+};
+use crate::logging::REQUEST_ID_HEADER;
+use actix_cors::Cors;
+use actix_web::http::header;
+
+/// How long a browser may cache the response to a preflight request, in seconds.
+const PREFLIGHT_MAX_AGE: usize = 60 * 60;
+
+/// Which cross-origin requests browsers should allow, so that browser-based replicas can sync
+/// with the server directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorsConfig {
+    /// Origins, such as `https://tasks.example.com`, allowed to make requests; `*` allows any
+    /// origin.
+    pub allowed_origins: Vec<String>,
+    /// HTTP methods allowed in cross-origin requests.
+    pub allowed_methods: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: ["GET", "POST", "PUT"].map(String::from).to_vec(),
+        }
+    }
+}
+
+/// Check that an origin is `*` or a valid origin, such as `https://tasks.example.com:8443`,
+/// without a path.
+pub fn validate_origin(origin: &str) -> anyhow::Result<()> {
+    if origin == "*" {
+        return Ok(());
+    }
+    let Some((scheme, host)) = origin.split_once("://") else {
+        anyhow::bail!("Origin {origin:?} has no scheme");
+    };
+    if scheme.is_empty() || host.is_empty() || host.contains('/') {
+        anyhow::bail!("Origin {origin:?} must be a scheme and host, without a path");
+    }
+    if header::HeaderValue::from_str(origin).is_err() {
+        anyhow::bail!("Origin {origin:?} is not a valid header value");
+    }
+    Ok(())
+}
+
+/// Build the CORS middleware for the given configuration. The origins must have been checked
+/// with [`validate_origin`].
+pub(crate) fn middleware(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allowed_headers([
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            header::RANGE,
+            header::IF_RANGE,
+        ])
+        .allowed_header(CLIENT_ID_HEADER)
+        .allowed_header(REQUEST_ID_HEADER)
+        .expose_headers([
+            VERSION_ID_HEADER,
+            PARENT_VERSION_ID_HEADER,
+            SNAPSHOT_REQUEST_HEADER,
+            REQUEST_ID_HEADER,
+            // snapshot downloads and uploads
+            "ETag",
+            "Content-Range",
+            "Location",
+            "X-Upload-Id",
+            "X-Upload-Offset",
+            "Retry-After",
+        ])
+        .max_age(PREFLIGHT_MAX_AGE);
+    for origin in &config.allowed_origins {
+        cors = if origin == "*" {
+            cors.allow_any_origin()
+        } else {
+            cors.allowed_origin(origin)
+        };
+    }
+    cors
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_origins() {
+        assert!(validate_origin("*").is_ok());
+        assert!(validate_origin("https://tasks.example.com").is_ok());
+        assert!(validate_origin("http://localhost:8080").is_ok());
+        assert!(validate_origin("tasks.example.com").is_err());
+        assert!(validate_origin("https://tasks.example.com/app").is_err());
+        assert!(validate_origin("https://").is_err());
+    }
+}
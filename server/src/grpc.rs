@@ -0,0 +1,479 @@
+use crate::api::ServerState;
+use actix_web::rt::task;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{
+    AddVersionResult, ChangeEvent, ClientId, GetVersionResult, ServerError, SnapshotUrgency,
+    VersionId, NIL_VERSION_ID,
+};
+use tonic::{Request, Response, Status};
+
+/// Types generated from `proto/sync.proto`.
+pub(crate) mod proto {
+    tonic::include_proto!("taskchampion.sync.v1");
+}
+
+use proto::{
+    add_version_response, sync_server::SyncServer, AddSnapshotRequest, AddSnapshotResponse,
+    AddVersionRequest, AddVersionResponse, GetChildVersionRequest, GetChildVersionResponse,
+    GetSnapshotRequest, GetSnapshotResponse,
+};
+
+/// The metadata key containing the client ID.
+const CLIENT_ID_METADATA: &str = "x-client-id";
+
+/// Allowance for the fields of a message other than its history segment or snapshot.
+const MESSAGE_OVERHEAD: usize = 1024;
+
+/// The gRPC server for [`GrpcService`], as returned by [`crate::WebServer::grpc_service`].
+pub type GrpcSyncServer = SyncServer<GrpcService>;
+
+/// The sync protocol as a gRPC service, sharing its state with the HTTP API.
+pub struct GrpcService {
+    server_state: Arc<ServerState>,
+}
+
+impl GrpcService {
+    /// Create a service accepting messages large enough for the configured body limits.
+    pub(crate) fn server(server_state: Arc<ServerState>) -> GrpcSyncServer {
+        let limits = &server_state.body_limits;
+        let max_message_size = limits
+            .max_history_segment_len
+            .max(limits.max_snapshot_len)
+            .saturating_add(MESSAGE_OVERHEAD);
+        SyncServer::new(Self { server_state }).max_decoding_message_size(max_message_size)
+    }
+
+    /// Get the client id, checking that the client is allowed and within its rate limits.
+    fn client_id<T>(&self, req: &Request<T>) -> Result<ClientId, Box<Status>> {
+        let client_id = req
+            .metadata()
+            .get(CLIENT_ID_METADATA)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| ClientId::parse_str(v).ok())
+            .ok_or_else(|| Status::invalid_argument("bad x-client-id"))?;
+        if let Some(allow_list) = &self.server_state.client_id_allowlist {
+            if !allow_list.contains(&client_id) {
+                return Err(Status::permission_denied("unknown x-client-id").into());
+            }
+        }
+        // gRPC is not served over TLS, so no request has a client certificate
+        if self.server_state.client_certificates.is_some() {
+            return Err(Status::permission_denied(
+                "x-client-id not allowed without a client certificate",
+            )
+            .into());
+        }
+        self.server_state
+            .rate_limiter
+            .check_addr(req.remote_addr().map(|a| a.ip()), client_id)
+            .map_err(|retry_after| {
+                Status::resource_exhausted(format!(
+                    "rate limit exceeded; retry after {}s",
+                    retry_after.as_secs().max(1)
+                ))
+            })?;
+        Ok(client_id)
+    }
+
+    /// Run a function which accesses storage on a thread where blocking is acceptable.
+    async fn blocking<R, F>(&self, f: F) -> Result<R, Status>
+    where
+        R: Send + 'static,
+        F: FnOnce(&ServerState) -> Result<R, Box<Status>> + Send + 'static,
+    {
+        let server_state = self.server_state.clone();
+        task::spawn_blocking(move || f(&server_state))
+            .await
+            .map_err(|_| Status::internal("request handler failed"))?
+            .map_err(|status| *status)
+    }
+}
+
+/// Get the user agent from the request metadata.
+fn user_agent<T>(req: &Request<T>) -> Option<String> {
+    req.metadata()
+        .get("user-agent")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Record that the client has synced, logging any failure as this metadata is informational.
+fn record_sync(server_state: &ServerState, client_id: ClientId, user_agent: Option<String>) {
+    if let Err(err) = server_state.server.record_sync(client_id, user_agent) {
+        log::warn!("Could not record sync for client {client_id}: {err}");
+    }
+}
+
+/// Parse a version ID from a request message.
+fn parse_version_id(value: &str) -> Result<VersionId, Box<Status>> {
+    VersionId::parse_str(value)
+        .map_err(|_| Status::invalid_argument(format!("bad version ID {value:?}")).into())
+}
+
+/// Convert a ServerError to the corresponding gRPC status.
+fn server_error_to_status(err: ServerError) -> Box<Status> {
+    let status = match err {
+        ServerError::NoSuchClient | ServerError::ClientDeleted => {
+            Status::not_found(err.to_string())
+        }
+        ServerError::TryAgainLater => Status::unavailable(err.to_string()),
+        ServerError::QuotaExceeded => Status::resource_exhausted(err.to_string()),
+        ServerError::InvalidVersionId(_) => Status::aborted(err.to_string()),
+        ServerError::Other(err) => {
+            log::error!("Internal error in gRPC request:\n{err:?}");
+            Status::internal("internal error")
+        }
+    };
+    Box::new(status)
+}
+
+#[tonic::async_trait]
+impl proto::sync_server::Sync for GrpcService {
+    async fn add_version(
+        &self,
+        request: Request<AddVersionRequest>,
+    ) -> Result<Response<AddVersionResponse>, Status> {
+        let client_id = self.client_id(&request).map_err(|status| *status)?;
+        let user_agent = user_agent(&request);
+        let AddVersionRequest {
+            parent_version_id,
+            history_segment,
+        } = request.into_inner();
+        let parent_version_id = parse_version_id(&parent_version_id).map_err(|status| *status)?;
+        let max_size = self.server_state.max_history_segment_len();
+        if history_segment.len() > max_size {
+            return Err(Status::resource_exhausted(format!(
+                "history segment exceeds the maximum of {max_size} bytes"
+            )));
+        }
+        if history_segment.is_empty() {
+            return Err(Status::invalid_argument("empty history segment"));
+        }
+
+        let _lock = self.server_state.client_locks.lock(client_id).await;
+        self.blocking(move |server_state| {
+            let (result, snapshot_urgency) = loop {
+                match server_state.server.add_version_from_reader(
+                    client_id,
+                    parent_version_id,
+                    &mut history_segment.as_ref(),
+                ) {
+                    Err(ServerError::NoSuchClient) => {
+                        // Create a new client and repeat the `add_version` call.
+                        let mut txn = server_state
+                            .server
+                            .txn(client_id)
+                            .map_err(server_error_to_status)?;
+                        txn.new_client(NIL_VERSION_ID)
+                            .and_then(|_| txn.commit())
+                            .map_err(|err| server_error_to_status(err.into()))?;
+                        server_state.notifier.publish(ChangeEvent::ClientCreated {
+                            client_id,
+                            timestamp: server_state.clock.now(),
+                        });
+                    }
+                    result => break result.map_err(server_error_to_status)?,
+                }
+            };
+            let result = match result {
+                AddVersionResult::Ok(version_id) => {
+                    record_sync(server_state, client_id, user_agent);
+                    server_state.notifier.publish(ChangeEvent::VersionAdded {
+                        client_id,
+                        version_id,
+                        parent_version_id,
+                        size: history_segment.len(),
+                        timestamp: server_state.clock.now(),
+                    });
+                    add_version_response::Result::VersionId(version_id.to_string())
+                }
+                AddVersionResult::ExpectedParentVersion(expected) => {
+                    add_version_response::Result::ExpectedParentVersionId(expected.to_string())
+                }
+            };
+            let snapshot_urgency = match snapshot_urgency {
+                SnapshotUrgency::None => proto::SnapshotUrgency::None,
+                SnapshotUrgency::Low => proto::SnapshotUrgency::Low,
+                SnapshotUrgency::High => proto::SnapshotUrgency::High,
+            };
+            Ok(Response::new(AddVersionResponse {
+                result: Some(result),
+                snapshot_urgency: snapshot_urgency.into(),
+            }))
+        })
+        .await
+    }
+
+    async fn get_child_version(
+        &self,
+        request: Request<GetChildVersionRequest>,
+    ) -> Result<Response<GetChildVersionResponse>, Status> {
+        let client_id = self.client_id(&request).map_err(|status| *status)?;
+        let user_agent = user_agent(&request);
+        let parent_version_id =
+            parse_version_id(&request.get_ref().parent_version_id).map_err(|status| *status)?;
+
+        let _lock = self.server_state.client_locks.lock(client_id).await;
+        self.blocking(move |server_state| {
+            match server_state
+                .server
+                .get_child_version(client_id, parent_version_id)
+            {
+                Ok(GetVersionResult::Success {
+                    version_id,
+                    parent_version_id,
+                    history_segment,
+                }) => {
+                    record_sync(server_state, client_id, user_agent);
+                    Ok(Response::new(GetChildVersionResponse {
+                        version_id: version_id.to_string(),
+                        parent_version_id: parent_version_id.to_string(),
+                        history_segment,
+                    }))
+                }
+                Ok(GetVersionResult::NotFound) => {
+                    // the client is up to date, which completes a sync
+                    record_sync(server_state, client_id, user_agent);
+                    Err(Status::not_found("no such version").into())
+                }
+                Ok(GetVersionResult::Gone) => {
+                    Err(Status::failed_precondition("version has been deleted").into())
+                }
+                Err(e) => Err(server_error_to_status(e)),
+            }
+        })
+        .await
+    }
+
+    async fn add_snapshot(
+        &self,
+        request: Request<AddSnapshotRequest>,
+    ) -> Result<Response<AddSnapshotResponse>, Status> {
+        let client_id = self.client_id(&request).map_err(|status| *status)?;
+        let AddSnapshotRequest {
+            version_id,
+            snapshot,
+        } = request.into_inner();
+        let version_id = parse_version_id(&version_id).map_err(|status| *status)?;
+        let max_size = self.server_state.body_limits.max_snapshot_len;
+        if snapshot.len() > max_size {
+            return Err(Status::resource_exhausted(format!(
+                "snapshot exceeds the maximum of {max_size} bytes"
+            )));
+        }
+        if snapshot.is_empty() {
+            return Err(Status::invalid_argument("no snapshot supplied"));
+        }
+
+        let _lock = self.server_state.client_locks.lock(client_id).await;
+        self.blocking(move |server_state| {
+            let size = snapshot.len();
+            let accepted = server_state
+                .server
+                .add_snapshot(client_id, version_id, snapshot)
+                .map_err(server_error_to_status)?;
+            if accepted {
+                server_state.notifier.publish(ChangeEvent::SnapshotSet {
+                    client_id,
+                    version_id,
+                    size,
+                    timestamp: server_state.clock.now(),
+                });
+            }
+            Ok(Response::new(AddSnapshotResponse {}))
+        })
+        .await
+    }
+
+    async fn get_snapshot(
+        &self,
+        request: Request<GetSnapshotRequest>,
+    ) -> Result<Response<GetSnapshotResponse>, Status> {
+        let client_id = self.client_id(&request).map_err(|status| *status)?;
+
+        let _lock = self.server_state.client_locks.lock(client_id).await;
+        self.blocking(move |server_state| {
+            match server_state
+                .server
+                .get_snapshot(client_id)
+                .map_err(server_error_to_status)?
+            {
+                Some((version_id, snapshot)) => Ok(Response::new(GetSnapshotResponse {
+                    version_id: version_id.to_string(),
+                    snapshot,
+                })),
+                None => Err(Status::not_found("no snapshot").into()),
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::WebServer;
+    use pretty_assertions::assert_eq;
+    use proto::sync_server::Sync;
+    use taskchampion_sync_server_core::{Bytes, InMemoryStorage};
+    use tonic::Code;
+    use uuid::Uuid;
+
+    fn request<T>(client_id: Uuid, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(CLIENT_ID_METADATA, client_id.to_string().parse().unwrap());
+        request
+    }
+
+    fn service() -> GrpcService {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        GrpcService {
+            server_state: server.server_state,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn sync_round_trip() {
+        let client_id = Uuid::new_v4();
+        let service = service();
+
+        let response = service
+            .add_version(request(
+                client_id,
+                AddVersionRequest {
+                    parent_version_id: NIL_VERSION_ID.to_string(),
+                    history_segment: b"abcd".to_vec().into(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(add_version_response::Result::VersionId(version_id)) = response.result else {
+            panic!("expected a new version ID");
+        };
+
+        // adding another child of the nil version conflicts
+        let response = service
+            .add_version(request(
+                client_id,
+                AddVersionRequest {
+                    parent_version_id: NIL_VERSION_ID.to_string(),
+                    history_segment: b"efgh".to_vec().into(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            response.result,
+            Some(add_version_response::Result::ExpectedParentVersionId(
+                version_id.clone()
+            ))
+        );
+
+        let response = service
+            .get_child_version(request(
+                client_id,
+                GetChildVersionRequest {
+                    parent_version_id: NIL_VERSION_ID.to_string(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.version_id, version_id);
+        assert_eq!(response.history_segment, &b"abcd"[..]);
+
+        let status = service
+            .get_child_version(request(
+                client_id,
+                GetChildVersionRequest {
+                    parent_version_id: version_id.clone(),
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[actix_rt::test]
+    async fn snapshot_round_trip() {
+        let client_id = Uuid::new_v4();
+        let service = service();
+
+        let status = service
+            .get_snapshot(request(client_id, GetSnapshotRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let response = service
+            .add_version(request(
+                client_id,
+                AddVersionRequest {
+                    parent_version_id: NIL_VERSION_ID.to_string(),
+                    history_segment: b"abcd".to_vec().into(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(add_version_response::Result::VersionId(version_id)) = response.result else {
+            panic!("expected a new version ID");
+        };
+
+        service
+            .add_snapshot(request(
+                client_id,
+                AddSnapshotRequest {
+                    version_id: version_id.clone(),
+                    snapshot: b"snap".to_vec().into(),
+                },
+            ))
+            .await
+            .unwrap();
+        let response = service
+            .get_snapshot(request(client_id, GetSnapshotRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.version_id, version_id);
+        assert_eq!(response.snapshot, &b"snap"[..]);
+    }
+
+    #[actix_rt::test]
+    async fn bad_requests() {
+        let service = service();
+
+        let status = service
+            .get_snapshot(Request::new(GetSnapshotRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .get_child_version(request(
+                Uuid::new_v4(),
+                GetChildVersionRequest {
+                    parent_version_id: "not-a-uuid".into(),
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .add_version(request(
+                Uuid::new_v4(),
+                AddVersionRequest {
+                    parent_version_id: NIL_VERSION_ID.to_string(),
+                    history_segment: Bytes::new(),
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -1,18 +1,79 @@
 #![deny(clippy::all)]
 
 mod api;
+mod client_cert;
+mod client_lock;
+mod cors;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod logging;
+mod metrics;
+mod notify;
+mod rate_limit;
+#[cfg(feature = "otel")]
+mod telemetry;
+#[cfg(feature = "tls")]
+mod tls;
+mod webhook;
 
-use actix_web::{get, middleware, web, Responder};
-use api::{api_scope, ServerState};
-use std::{collections::HashSet, sync::Arc};
-use taskchampion_sync_server_core::{Server, ServerConfig, Storage};
+use actix_web::{
+    dev::Service, get, http::header::TryIntoHeaderPair, middleware, web, HttpMessage, HttpResponse,
+    Responder,
+};
+use api::{admin_scope, api_scope, ServerState};
+#[cfg(feature = "grpc")]
+use grpc::GrpcService;
+use metrics::ServerMetrics;
+use rate_limit::RateLimiter;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use taskchampion_sync_server_core::{
+    Clock, InstrumentedStorage, MetricsRegistry, Server, ServerConfig, Storage, SystemClock,
+};
 use uuid::Uuid;
 
+pub use api::BodyLimits;
+pub use client_cert::{parse_fingerprint, ClientCertificate, ClientCertificates};
+pub use cors::{validate_origin, CorsConfig};
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcSyncServer;
+pub use logging::{init_logging, LogFormat, RequestId, ACCESS_LOG_TARGET};
+pub use rate_limit::{RateLimit, RateLimitConfig};
+#[cfg(feature = "otel")]
+pub use telemetry::init_tracing;
+#[cfg(feature = "tls")]
+pub use tls::{client_certificate, load_tls_config};
+pub use webhook::WebhookConfig;
+
 #[get("/")]
 async fn index() -> impl Responder {
     format!("TaskChampion sync server v{}", env!("CARGO_PKG_VERSION"))
 }
 
+/// Report that the process is alive, without touching storage, for load balancers and
+/// orchestrators probing it.
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    "ok"
+}
+
+/// Report whether the server can serve requests, by probing the storage. This returns 503 if
+/// the storage is unreachable, so that the instance can be taken out of rotation.
+#[get("/readyz")]
+async fn readyz(server_state: web::Data<Arc<ServerState>>) -> HttpResponse {
+    match server_state.server.check_ready() {
+        Ok(()) => HttpResponse::Ok().body("ok"),
+        Err(err) => {
+            log::warn!("Readiness check failed: {err}");
+            HttpResponse::ServiceUnavailable().body("storage unavailable")
+        }
+    }
+}
+
 /// A Server represents a sync server.
 #[derive(Clone)]
 pub struct WebServer {
@@ -21,39 +82,505 @@ pub struct WebServer {
 
 impl WebServer {
     /// Create a new sync server with the given storage implementation.
+    ///
+    /// Metrics about requests and storage operations are recorded, and can be read from
+    /// `/metrics` in the Prometheus format; see [`WebServer::with_metrics_token`] and
+    /// [`WebServer::metrics_config`].
     pub fn new<ST: Storage + 'static>(
         config: ServerConfig,
         client_id_allowlist: Option<HashSet<Uuid>>,
         storage: ST,
     ) -> Self {
+        Self::with_clock(config, client_id_allowlist, storage, Arc::new(SystemClock))
+    }
+
+    /// Create a new sync server, as for [`WebServer::new`], using the given clock for the server's
+    /// timestamps, such as those of snapshots and change events.
+    pub fn with_clock<ST: Storage + 'static>(
+        config: ServerConfig,
+        client_id_allowlist: Option<HashSet<Uuid>>,
+        storage: ST,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let registry = Arc::new(MetricsRegistry::new());
+        let storage = InstrumentedStorage::new(storage, registry.clone());
+        #[cfg(feature = "otel")]
+        let storage = taskchampion_sync_server_core::TracedStorage::new(storage);
         Self {
             server_state: Arc::new(ServerState {
-                server: Server::new(config, storage),
+                server: Server::with_clock(config, storage, clock.clone()),
+                clock,
                 client_id_allowlist,
+                metrics: ServerMetrics::new(registry),
+                access_log: false,
+                compress: false,
+                rate_limiter: Default::default(),
+                client_locks: Default::default(),
+                admin_token: None,
+                notifier: Default::default(),
+                snapshot_uploads: Default::default(),
+                body_limits: Default::default(),
+                client_certificates: None,
+                cors: None,
             }),
         }
     }
 
+    /// Require the given bearer token to read `/metrics`, and serve it alongside the sync
+    /// protocol. Without a token, metrics are only served by [`WebServer::metrics_config`].
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_metrics_token(mut self, token: String) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_metrics_token called after the server was cloned")
+            .metrics
+            .token = Some(token);
+        self
+    }
+
+    /// Write a record for each request to the access log, under [`ACCESS_LOG_TARGET`], with the
+    /// request's details as structured fields for [`LogFormat::Json`].
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_access_log(mut self) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_access_log called after the server was cloned")
+            .access_log = true;
+        self
+    }
+
+    /// Compress responses, such as history segments and snapshots, with gzip, zstd, or brotli,
+    /// as negotiated with the client's `Accept-Encoding` header. This reduces the transfer size
+    /// at the cost of CPU time, which benefits clients on slow links.
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_compression(mut self) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_compression called after the server was cloned")
+            .compress = true;
+        self
+    }
+
+    /// Limit the size of history segments and snapshots accepted from clients.
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_body_limits(mut self, limits: BodyLimits) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_body_limits called after the server was cloned")
+            .body_limits = limits;
+        self
+    }
+
+    /// Serve the admin API under `/admin/v1`, requiring the given bearer token. Without a
+    /// token, the admin API is not served.
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_admin_token(mut self, token: String) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_admin_token called after the server was cloned")
+            .admin_token = Some(token);
+        self
+    }
+
+    /// Only allow each client certificate to use the given client IDs, rejecting other requests
+    /// with 403 Forbidden. The certificate is read from the connection data, where it is stored
+    /// by `client_certificate` when serving with mutual TLS.
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_client_certificates(mut self, client_certificates: ClientCertificates) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_client_certificates called after the server was cloned")
+            .client_certificates = Some(client_certificates);
+        self
+    }
+
+    /// Allow cross-origin requests from browsers, so that browser-based replicas can sync
+    /// directly with the server. The origins must have been checked with [`validate_origin`].
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_cors(mut self, config: CorsConfig) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_cors called after the server was cloned")
+            .cors = Some(config);
+        self
+    }
+
+    /// POST an event to each of the configured webhook URLs when a client is created or a
+    /// version or snapshot is added. Delivery happens in the background, with retries.
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_webhooks(mut self, config: WebhookConfig) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_webhooks called after the server was cloned")
+            .notifier
+            .set_webhooks(webhook::Webhooks::start(&config));
+        self
+    }
+
+    /// Limit the rate of requests to the sync protocol endpoints, per client and per source
+    /// address. Requests exceeding a limit are rejected with 429 Too Many Requests.
+    ///
+    /// This must be called before the server is cloned.
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        Arc::get_mut(&mut self.server_state)
+            .expect("with_rate_limits called after the server was cloned")
+            .rate_limiter = RateLimiter::new(config);
+        self
+    }
+
+    /// Start a thread which purges deleted clients whose grace period has passed, checking every
+    /// `interval`. Failures are logged and retried at the next check.
+    pub fn spawn_purge_thread(&self, interval: Duration) -> thread::JoinHandle<()> {
+        let server_state = self.server_state.clone();
+        thread::spawn(move || loop {
+            match server_state.server.purge_deleted_clients() {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {purged} deleted clients"),
+                Err(err) => log::warn!("Could not purge deleted clients: {err}"),
+            }
+            thread::sleep(interval);
+        })
+    }
+
+    /// Write any changes buffered in memory to the storage, such as before the process exits.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        Ok(self.server_state.server.flush()?)
+    }
+
     /// Get an Actix-web service for this server.
     pub fn config(&self, cfg: &mut web::ServiceConfig) {
+        let mut scope = web::scope("")
+            .app_data(web::Data::new(self.server_state.clone()))
+            .service(index)
+            .service(healthz)
+            .service(readyz);
+        if self.server_state.metrics.token.is_some() {
+            scope = scope.route("/metrics", web::get().to(metrics::service));
+        }
+        if self.server_state.admin_token.is_some() {
+            scope = scope.service(admin_scope());
+        }
+        let server_state = self.server_state.clone();
         cfg.service(
-            web::scope("")
-                .app_data(web::Data::new(self.server_state.clone()))
+            scope
+                .service(api_scope())
                 .wrap(
                     middleware::DefaultHeaders::new().add(("Cache-Control", "no-store, max-age=0")),
                 )
-                .service(index)
-                .service(api_scope()),
+                .wrap(middleware::Condition::new(
+                    self.server_state.compress,
+                    middleware::Compress::default(),
+                ))
+                .wrap(middleware::Condition::new(
+                    self.server_state.cors.is_some(),
+                    cors::middleware(&self.server_state.cors.clone().unwrap_or_default()),
+                ))
+                .wrap_fn(move |req, srv| {
+                    let server_state = server_state.clone();
+                    let start = Instant::now();
+                    let request_id = RequestId::for_request(&req);
+                    req.extensions_mut().insert(request_id.clone());
+                    #[cfg(feature = "otel")]
+                    let span = telemetry::request_span(&req);
+                    let fut = logging::with_request_id(request_id.clone(), srv.call(req));
+                    #[cfg(feature = "otel")]
+                    let fut = tracing::Instrument::instrument(fut, span.clone());
+                    async move {
+                        let mut res = fut.await?;
+                        if let Ok((name, value)) =
+                            (logging::REQUEST_ID_HEADER, request_id.as_str()).try_into_pair()
+                        {
+                            res.headers_mut().insert(name, value);
+                        }
+                        let elapsed = start.elapsed();
+                        server_state.metrics.record(&res, elapsed);
+                        if server_state.access_log {
+                            logging::log_request(&res, elapsed);
+                        }
+                        #[cfg(feature = "otel")]
+                        telemetry::record_response(&span, &res);
+                        Ok(res)
+                    }
+                }),
         );
     }
+
+    /// Get an Actix-web service serving only `/metrics`, for a separate listener which is not
+    /// publicly reachable. The metrics token is required if one is set.
+    pub fn metrics_config(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::new(self.server_state.clone()))
+            .route("/metrics", web::get().to(metrics::service));
+    }
+
+    /// Get a gRPC service implementing the sync protocol, as defined in `proto/sync.proto`,
+    /// sharing its state with the HTTP API. Serve it with `tonic::transport::Server`.
+    #[cfg(feature = "grpc")]
+    pub fn grpc_service(&self) -> GrpcSyncServer {
+        GrpcService::server(self.server_state.clone())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use actix_web::{test, App};
+    use actix_web::{http::StatusCode, test, App};
     use pretty_assertions::assert_eq;
-    use taskchampion_sync_server_core::InMemoryStorage;
+    use taskchampion_sync_server_core::{FaultConfig, FlakyStorage, InMemoryStorage};
+
+    #[actix_rt::test]
+    async fn test_healthz() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "ok");
+    }
+
+    #[actix_rt::test]
+    async fn test_readyz() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_readyz_storage_unavailable() {
+        let storage = FlakyStorage::new(
+            InMemoryStorage::new(),
+            FaultConfig {
+                failure_rate: 1.0,
+                ..FaultConfig::default()
+            },
+        );
+        let server = WebServer::new(Default::default(), None, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_not_public() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_with_token() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new())
+            .with_metrics_token("secret".into());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/metrics")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains(
+            "http_requests_total{endpoint=\"/healthz\",method=\"GET\",status=\"200\"} 1"
+        ));
+        assert!(body.contains("active_clients 0"));
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_config() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let metrics_app =
+            test::init_service(App::new().configure(|sc| server.metrics_config(sc))).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-version/{}", Uuid::nil()))
+            .insert_header(("X-Client-Id", client_id.to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&metrics_app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains(
+            "http_requests_total{endpoint=\"/v1/client/get-child-version/{parent_version_id}\""
+        ));
+        assert!(body.contains("storage_operations_total{operation=\"get_client\""));
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limit() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new())
+            .with_rate_limits(RateLimitConfig {
+                per_client: Some(RateLimit {
+                    per_minute: 1,
+                    burst: 1,
+                }),
+                per_ip: None,
+            });
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let uri = format!("/v1/client/get-child-version/{}", Uuid::nil());
+        let get_child = |client_id: Uuid| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(("X-Client-Id", client_id.to_string()))
+                .to_request()
+        };
+
+        let client_id = Uuid::new_v4();
+        let resp = test::call_service(&app, get_child(client_id)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, get_child(client_id)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "60");
+
+        // other clients, and other endpoints, are not limited
+        let resp = test::call_service(&app, get_child(Uuid::new_v4())).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_compression() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(Uuid::nil()).unwrap();
+            txn.add_version(Uuid::new_v4(), Uuid::nil(), vec![b'a'; 4096].into())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let server = WebServer::new(Default::default(), None, storage).with_compression();
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let uri = format!("/v1/client/get-child-version/{}", Uuid::nil());
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("X-Client-Id", client_id.to_string()))
+            .insert_header(("Accept-Encoding", "gzip"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Content-Encoding").unwrap(), "gzip");
+        let body = test::read_body(resp).await;
+        assert!(body.len() < 4096);
+
+        // without Accept-Encoding, the response is not compressed
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("X-Client-Id", client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("Content-Encoding"), None);
+        assert_eq!(test::read_body(resp).await.len(), 4096);
+    }
+
+    #[actix_rt::test]
+    async fn test_cors() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new()).with_cors(
+            CorsConfig {
+                allowed_origins: vec!["https://tasks.example.com".into()],
+                ..CorsConfig::default()
+            },
+        );
+        let app = test::init_service(App::new().configure(|sc| server.config(sc))).await;
+        let uri = format!("/v1/client/get-child-version/{}", Uuid::nil());
+
+        // a preflight request from the allowed origin
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri(&uri)
+            .insert_header(("Origin", "https://tasks.example.com"))
+            .insert_header(("Access-Control-Request-Method", "GET"))
+            .insert_header(("Access-Control-Request-Headers", "x-client-id"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Access-Control-Allow-Origin").unwrap(),
+            "https://tasks.example.com"
+        );
+
+        // the response to the actual request exposes the protocol's headers
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Origin", "https://tasks.example.com"))
+            .insert_header(("X-Client-Id", Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let exposed = resp
+            .headers()
+            .get("Access-Control-Expose-Headers")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_lowercase();
+        assert!(exposed.contains("x-version-id"));
+        assert!(exposed.contains("x-request-id"));
+
+        // other origins are not allowed
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Origin", "https://evil.example.com"))
+            .insert_header(("X-Client-Id", Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("Access-Control-Allow-Origin"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id() {
+        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // an ID is generated for each request
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        let request_id = resp
+            .headers()
+            .get("X-Request-Id")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(Uuid::parse_str(request_id).is_ok());
+
+        // an incoming ID is returned, including with errors
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-version/{}", Uuid::nil()))
+            .insert_header(("X-Request-Id", "from-proxy-1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("X-Request-Id").unwrap(), "from-proxy-1");
+    }
 
     #[actix_rt::test]
     async fn test_cache_control() {
@@ -0,0 +1,338 @@
+use crate::api::CLIENT_ID_HEADER;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::HttpMessage;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::kv::{self, Key, VisitSource};
+use log::Record;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The log target of the access log, with one record for each request.
+pub const ACCESS_LOG_TARGET: &str = "taskchampion_sync_server::access";
+
+/// The header carrying an identifier for the request, such as one assigned by a reverse proxy.
+/// The identifier is returned in the same header of the response.
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The longest request ID accepted from a client; longer IDs are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+thread_local! {
+    /// The ID of the request being handled on this thread, if any.
+    static CURRENT_REQUEST_ID: RefCell<Option<RequestId>> = const { RefCell::new(None) };
+}
+
+/// An identifier for a request, included in every log line written while handling it and
+/// returned to the client, so that a user's error report can be matched to the server's logs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    /// Use the request's `X-Request-Id` header, such as one assigned by a reverse proxy, or
+    /// generate a new ID if it is missing or unsuitable for logging.
+    pub(crate) fn for_request(req: &ServiceRequest) -> Self {
+        req.headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.chars().all(|c| c.is_ascii_graphic())
+            })
+            .map(|id| Self(id.into()))
+            .unwrap_or_else(|| Self(Uuid::new_v4().to_string().into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the ID of the request being handled on this thread, if any.
+    fn current() -> Option<Self> {
+        CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Make `request_id` the current request ID, as logged, whenever `fut` is polled.
+pub(crate) fn with_request_id<F: Future>(
+    request_id: RequestId,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    let mut fut = Box::pin(fut);
+    futures::future::poll_fn(move |cx| {
+        let previous = CURRENT_REQUEST_ID.replace(Some(request_id.clone()));
+        let poll = fut.as_mut().poll(cx);
+        CURRENT_REQUEST_ID.set(previous);
+        poll
+    })
+}
+
+/// The format in which log records are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LogFormat {
+    /// Human-readable lines, in env_logger's default format.
+    #[default]
+    Text,
+
+    /// One JSON object per line, for ingestion by log aggregators such as Loki or Elasticsearch.
+    /// Each object has `timestamp`, `level`, `target`, and `message` properties, as well as any
+    /// structured fields of the record, such as those of the access log.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("unknown log format {s:?}; expected `text` or `json`"),
+        }
+    }
+}
+
+/// Initialize logging to stderr in the given format, filtered by `RUST_LOG` as usual. Records
+/// written while handling a request include its ID.
+pub fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let timestamp = buf.timestamp();
+            writeln!(
+                buf,
+                "{}",
+                text_record(record, timestamp, RequestId::current().as_ref())
+            )
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                json_record(record, Utc::now(), RequestId::current().as_ref())
+            )
+        }),
+    };
+    builder.init();
+}
+
+/// Represent a log record as a line of text, in the format of env_logger's default format with
+/// the request ID, if any, added after the target.
+fn text_record(
+    record: &Record,
+    timestamp: impl std::fmt::Display,
+    request_id: Option<&RequestId>,
+) -> String {
+    match request_id {
+        Some(request_id) => format!(
+            "[{timestamp} {:<5} {} {request_id}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ),
+        None => format!(
+            "[{timestamp} {:<5} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ),
+    }
+}
+
+/// Represent a log record as a JSON object.
+fn json_record(record: &Record, timestamp: DateTime<Utc>, request_id: Option<&RequestId>) -> Value {
+    let mut fields = Map::new();
+    fields.insert(
+        "timestamp".into(),
+        timestamp
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+    if let Some(request_id) = request_id {
+        fields.insert("request_id".into(), request_id.as_str().into());
+    }
+    // visiting fields into a map cannot fail
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+    Value::Object(fields)
+}
+
+/// Collects the structured fields of a log record into a JSON object.
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(v) = value.to_bool() {
+            v.into()
+        } else if let Some(v) = value.to_u64() {
+            v.into()
+        } else if let Some(v) = value.to_i64() {
+            v.into()
+        } else if let Some(v) = value.to_f64() {
+            v.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Write an access log record for a completed request, with its details as structured fields.
+pub(crate) fn log_request<B>(res: &ServiceResponse<B>, elapsed: Duration) {
+    let req = res.request();
+    // missing headers are logged as `-`, as in common access log formats
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+    };
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    let endpoint = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().as_str();
+    let status = res.status().as_u16();
+    let latency_ms = elapsed.as_secs_f64() * 1000.0;
+    log::info!(
+        target: ACCESS_LOG_TARGET,
+        request_id = request_id.as_ref().map_or("-", RequestId::as_str),
+        client_id = header(CLIENT_ID_HEADER),
+        method = method,
+        endpoint = endpoint.as_str(),
+        status = status,
+        latency_ms = latency_ms;
+        "{method} {} {status} {latency_ms:.3}ms",
+        req.path()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use log::Level;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_log_format() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn json_record_fields() {
+        let fields: &[(&str, kv::Value)] = &[
+            ("client_id", kv::Value::from("abc")),
+            ("status", kv::Value::from(200u16)),
+            ("latency_ms", kv::Value::from(1.5f64)),
+            ("request_id", kv::Value::from("-")),
+        ];
+        let record = Record::builder()
+            .args(format_args!("GET / 200"))
+            .level(Level::Info)
+            .target(ACCESS_LOG_TARGET)
+            .key_values(&fields)
+            .build();
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            json_record(&record, timestamp, None),
+            serde_json::json!({
+                "timestamp": "2024-01-02T03:04:05.000Z",
+                "level": "INFO",
+                "target": ACCESS_LOG_TARGET,
+                "message": "GET / 200",
+                "client_id": "abc",
+                "status": 200,
+                "latency_ms": 1.5,
+                "request_id": "-",
+            })
+        );
+    }
+
+    #[test]
+    fn json_record_request_id() {
+        let record = Record::builder()
+            .args(format_args!("Could not record sync"))
+            .level(Level::Warn)
+            .target("taskchampion_sync_server::api")
+            .build();
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let request_id = RequestId("abc123".into());
+        assert_eq!(
+            json_record(&record, timestamp, Some(&request_id)),
+            serde_json::json!({
+                "timestamp": "2024-01-02T03:04:05.000Z",
+                "level": "WARN",
+                "target": "taskchampion_sync_server::api",
+                "message": "Could not record sync",
+                "request_id": "abc123",
+            })
+        );
+    }
+
+    #[test]
+    fn text_record_request_id() {
+        let record = Record::builder()
+            .args(format_args!("Could not record sync"))
+            .level(Level::Warn)
+            .target("taskchampion_sync_server::api")
+            .build();
+        let timestamp = "2024-01-02T03:04:05Z";
+        assert_eq!(
+            text_record(&record, timestamp, None),
+            "[2024-01-02T03:04:05Z WARN  taskchampion_sync_server::api] Could not record sync"
+        );
+        assert_eq!(
+            text_record(&record, timestamp, Some(&RequestId("abc123".into()))),
+            "[2024-01-02T03:04:05Z WARN  taskchampion_sync_server::api abc123] \
+             Could not record sync"
+        );
+    }
+
+    #[test]
+    fn request_id_for_request() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((REQUEST_ID_HEADER, "from-proxy-1"))
+            .to_srv_request();
+        assert_eq!(RequestId::for_request(&req).as_str(), "from-proxy-1");
+
+        // an ID which cannot be logged safely is replaced
+        for id in ["", "has spaces", &"x".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header((REQUEST_ID_HEADER, id))
+                .to_srv_request();
+            assert!(Uuid::parse_str(RequestId::for_request(&req).as_str()).is_ok());
+        }
+
+        let req = actix_web::test::TestRequest::default().to_srv_request();
+        assert!(Uuid::parse_str(RequestId::for_request(&req).as_str()).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn with_request_id_current() {
+        let request_id = RequestId("abc123".into());
+        let current = with_request_id(request_id.clone(), async {
+            actix_rt::task::yield_now().await;
+            RequestId::current()
+        })
+        .await;
+        assert_eq!(current, Some(request_id));
+        assert_eq!(RequestId::current(), None);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::api::{bearer_token_matches, ServerState, CLIENT_ID_HEADER};
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::ServiceResponse,
+    error,
+    http::header,
+    web, HttpRequest, HttpResponse, Result,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use taskchampion_sync_server_core::{MetricsRegistry, SIZE_BUCKETS};
+use uuid::Uuid;
+
+/// Name of the counter of HTTP requests, labeled with `endpoint`, `method`, and `status`.
+pub(crate) const HTTP_REQUESTS: &str = "http_requests_total";
+
+/// Name of the histogram of HTTP request latency in seconds, labeled with `endpoint`.
+pub(crate) const HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
+
+/// Name of the histogram of HTTP request body sizes in bytes, labeled with `endpoint`.
+pub(crate) const HTTP_REQUEST_SIZE: &str = "http_request_size_bytes";
+
+/// Name of the histogram of HTTP response body sizes in bytes, labeled with `endpoint`.
+pub(crate) const HTTP_RESPONSE_SIZE: &str = "http_response_size_bytes";
+
+/// Name of the gauge of clients which have made a successful request within
+/// [`ACTIVE_CLIENT_WINDOW`].
+pub(crate) const ACTIVE_CLIENTS: &str = "active_clients";
+
+/// The time since a client's last request during which it is counted as active.
+const ACTIVE_CLIENT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The content-type of the Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Metrics about the requests handled by the server.
+#[derive(Default)]
+pub(crate) struct ServerMetrics {
+    /// The registry holding HTTP and storage metrics.
+    pub(crate) registry: Arc<MetricsRegistry>,
+
+    /// The bearer token required to read metrics, if any.
+    pub(crate) token: Option<String>,
+
+    /// The time of each client's latest successful request.
+    active_clients: Mutex<HashMap<Uuid, Instant>>,
+}
+
+impl ServerMetrics {
+    /// Create metrics recorded in the given registry.
+    pub(crate) fn new(registry: Arc<MetricsRegistry>) -> Self {
+        Self {
+            registry,
+            ..Self::default()
+        }
+    }
+
+    /// Record a completed request.
+    pub(crate) fn record<B: MessageBody>(&self, res: &ServiceResponse<B>, elapsed: Duration) {
+        let req = res.request();
+        // only the route pattern is used, so that the number of series is bounded
+        let endpoint = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let status = res.status().as_u16().to_string();
+        let registry = &self.registry;
+        registry.inc_counter(
+            HTTP_REQUESTS,
+            &[
+                ("endpoint", &endpoint),
+                ("method", req.method().as_str()),
+                ("status", &status),
+            ],
+        );
+        let labels = [("endpoint", endpoint.as_str())];
+        registry.observe(HTTP_REQUEST_DURATION, &labels, elapsed.as_secs_f64());
+        let request_size = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if let Some(size) = request_size {
+            registry.observe_with_buckets(HTTP_REQUEST_SIZE, &labels, SIZE_BUCKETS, size as f64);
+        }
+        if let BodySize::Sized(size) = res.response().body().size() {
+            registry.observe_with_buckets(HTTP_RESPONSE_SIZE, &labels, SIZE_BUCKETS, size as f64);
+        }
+
+        if res.status().is_success() {
+            let client_id = req
+                .headers()
+                .get(CLIENT_ID_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| Uuid::parse_str(v).ok());
+            if let Some(client_id) = client_id {
+                let mut active = self.active_clients.lock().expect("poisoned lock");
+                active.insert(client_id, Instant::now());
+            }
+        }
+    }
+
+    /// Count the clients which have made a successful request recently, forgetting the others.
+    fn active_clients(&self) -> usize {
+        let mut active = self.active_clients.lock().expect("poisoned lock");
+        active.retain(|_, last| last.elapsed() < ACTIVE_CLIENT_WINDOW);
+        active.len()
+    }
+
+    /// Check that the request carries the metrics token, if one is required.
+    fn authorize(&self, req: &HttpRequest) -> Result<()> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        if !bearer_token_matches(req, token) {
+            return Err(error::ErrorUnauthorized("invalid metrics token"));
+        }
+        Ok(())
+    }
+}
+
+/// Get the server's metrics in the Prometheus text exposition format.
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let metrics = &server_state.metrics;
+    metrics.authorize(&req)?;
+    metrics
+        .registry
+        .set_gauge(ACTIVE_CLIENTS, &[], metrics.active_clients() as f64);
+    Ok(HttpResponse::Ok()
+        .content_type(PROMETHEUS_CONTENT_TYPE)
+        .body(metrics.registry.render_prometheus()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn authorize() {
+        let metrics = ServerMetrics::default();
+        assert!(metrics
+            .authorize(&TestRequest::default().to_http_request())
+            .is_ok());
+
+        let metrics = ServerMetrics {
+            token: Some("secret".into()),
+            ..ServerMetrics::default()
+        };
+        assert!(metrics
+            .authorize(&TestRequest::default().to_http_request())
+            .is_err());
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_http_request();
+        assert!(metrics.authorize(&req).is_err());
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert!(metrics.authorize(&req).is_ok());
+    }
+
+    #[test]
+    fn active_clients() {
+        let metrics = ServerMetrics::default();
+        let client_id = Uuid::new_v4();
+        {
+            let mut active = metrics.active_clients.lock().unwrap();
+            active.insert(client_id, Instant::now());
+            active.insert(Uuid::new_v4(), Instant::now());
+            if let Some(old) = Instant::now().checked_sub(ACTIVE_CLIENT_WINDOW) {
+                active.insert(Uuid::new_v4(), old);
+            }
+        }
+        assert_eq!(metrics.active_clients(), 2);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::webhook::Webhooks;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::Stream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use taskchampion_sync_server_core::{ChangeEvent, ClientId};
+
+type Subscribers = Arc<Mutex<HashMap<ClientId, Vec<UnboundedSender<ChangeEvent>>>>>;
+
+/// Notifies subscribers of changes to a client's data, such as replicas connected to
+/// `/v1/client/updates`, and delivers every change to the webhooks, if any. Notifications are
+/// only delivered for changes made through this process.
+#[derive(Default)]
+pub(crate) struct Notifier {
+    subscribers: Subscribers,
+    webhooks: Option<Webhooks>,
+}
+
+impl Notifier {
+    /// Also deliver every event to the given webhooks.
+    pub(crate) fn set_webhooks(&mut self, webhooks: Webhooks) {
+        self.webhooks = Some(webhooks);
+    }
+
+    /// Subscribe to changes to the given client's data.
+    pub(crate) fn subscribe(&self, client_id: ClientId) -> Subscription {
+        let (sender, receiver) = unbounded();
+        self.subscribers
+            .lock()
+            .expect("poisoned lock")
+            .entry(client_id)
+            .or_default()
+            .push(sender);
+        Subscription {
+            client_id,
+            receiver,
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    /// Deliver an event to the subscribers for its client, and queue it for the webhooks.
+    pub(crate) fn publish(&self, event: ChangeEvent) {
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.publish(&event);
+        }
+        let subscribers = self.subscribers.lock().expect("poisoned lock");
+        if let Some(senders) = subscribers.get(&event.client_id()) {
+            for sender in senders {
+                // a send fails only if the subscription is being dropped
+                let _ = sender.unbounded_send(event.clone());
+            }
+        }
+    }
+
+    /// The number of open subscriptions, for all clients.
+    #[cfg(test)]
+    pub(crate) fn subscription_count(&self) -> usize {
+        let subscribers = self.subscribers.lock().expect("poisoned lock");
+        subscribers.values().map(Vec::len).sum()
+    }
+}
+
+/// A stream of the events for a client, which unsubscribes when dropped.
+pub(crate) struct Subscription {
+    client_id: ClientId,
+    receiver: UnboundedReceiver<ChangeEvent>,
+    subscribers: Subscribers,
+}
+
+impl Stream for Subscription {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChangeEvent>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.receiver.close();
+        let mut subscribers = self.subscribers.lock().expect("poisoned lock");
+        if let Some(senders) = subscribers.get_mut(&self.client_id) {
+            senders.retain(|sender| !sender.is_closed());
+            if senders.is_empty() {
+                subscribers.remove(&self.client_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use futures::StreamExt;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    fn deleted(client_id: ClientId) -> ChangeEvent {
+        ChangeEvent::ClientDeleted {
+            client_id,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn publish_to_client_subscribers() {
+        let notifier = Notifier::default();
+        let (client1, client2) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut sub1a = notifier.subscribe(client1);
+        let mut sub1b = notifier.subscribe(client1);
+        let sub2 = notifier.subscribe(client2);
+        assert_eq!(notifier.subscription_count(), 3);
+
+        let event = deleted(client1);
+        notifier.publish(event.clone());
+        assert_eq!(sub1a.next().await, Some(event.clone()));
+        assert_eq!(sub1b.next().await, Some(event));
+
+        drop(sub1a);
+        assert_eq!(notifier.subscription_count(), 2);
+        drop(sub2);
+        assert_eq!(notifier.subscription_count(), 1);
+
+        // publishing without subscribers does nothing
+        notifier.publish(deleted(client2));
+    }
+}
@@ -0,0 +1,330 @@
+use actix_web::{error, http::header, HttpRequest, HttpResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The number of keys tracked by a limiter. Beyond this, the least-recently-used key is forgotten,
+/// bounding memory use. A forgotten key's bucket is full when it is next seen.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// A limit on the rate of requests, as a token bucket.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RateLimit {
+    /// The sustained number of requests allowed per minute.
+    pub per_minute: u32,
+
+    /// The number of requests which may be made in a burst, after a period of inactivity.
+    pub burst: u32,
+}
+
+/// Rate limits applied to the sync protocol endpoints. Requests exceeding a limit are rejected
+/// with 429 Too Many Requests and a `Retry-After` header.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RateLimitConfig {
+    /// The limit for each client ID, if any.
+    pub per_client: Option<RateLimit>,
+
+    /// The limit for each source IP address, if any. This is the address of the peer, so behind
+    /// a reverse proxy it applies to all requests together.
+    pub per_ip: Option<RateLimit>,
+}
+
+/// The state of the token bucket for one key.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// The tick at which the bucket was last used.
+    used: u64,
+}
+
+/// The buckets for each key, in a least-recently-used map.
+struct BucketMap<K> {
+    buckets: HashMap<K, Bucket>,
+    /// Keys by the tick at which their bucket was last used, oldest first.
+    by_use: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+/// Token buckets for each key, such as a client ID.
+struct Buckets<K> {
+    /// Tokens added per minute.
+    per_minute: f64,
+    /// The capacity of each bucket.
+    burst: f64,
+    map: Mutex<BucketMap<K>>,
+}
+
+impl<K: Eq + Hash + Clone> Buckets<K> {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            per_minute: f64::from(limit.per_minute),
+            // a bucket which can never hold a whole token would reject every request
+            burst: f64::from(limit.burst.max(1)),
+            map: Mutex::new(BucketMap {
+                buckets: HashMap::new(),
+                by_use: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BucketMap<K>> {
+        self.map.lock().expect("poisoned lock")
+    }
+
+    /// The number of tokens in `bucket` at `now`.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_minute / 60.0).min(self.burst)
+    }
+
+    /// Check that the bucket for `key` holds a token, without taking it, or return the time until
+    /// one is available. This does not track a new key.
+    fn check(&self, map: &BucketMap<K>, key: &K, now: Instant) -> Result<(), Duration> {
+        let tokens = match map.buckets.get(key) {
+            Some(bucket) => self.refilled(bucket, now),
+            None => self.burst,
+        };
+        if tokens >= 1.0 {
+            return Ok(());
+        }
+        if self.per_minute <= 0.0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - tokens) * 60.0 / self.per_minute,
+        ))
+    }
+
+    /// Take a token from the bucket for `key`, which must have been checked first.
+    fn take(&self, map: &mut BucketMap<K>, key: K, now: Instant) {
+        map.tick += 1;
+        let tick = map.tick;
+        let tokens = match map.buckets.get(&key) {
+            Some(bucket) => {
+                map.by_use.remove(&bucket.used);
+                self.refilled(bucket, now)
+            }
+            None => {
+                if map.buckets.len() >= MAX_TRACKED_KEYS {
+                    if let Some((_, oldest)) = map.by_use.pop_first() {
+                        map.buckets.remove(&oldest);
+                    }
+                }
+                self.burst
+            }
+        };
+        map.by_use.insert(tick, key.clone());
+        map.buckets.insert(
+            key,
+            Bucket {
+                tokens: tokens - 1.0,
+                updated: now,
+                used: tick,
+            },
+        );
+    }
+
+    /// Take a token from the bucket for `key`, or return the time until one is available.
+    #[cfg(test)]
+    fn acquire(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut map = self.lock();
+        self.check(&map, &key, now)?;
+        self.take(&mut map, key, now);
+        Ok(())
+    }
+}
+
+/// Limits the rate of requests from each client and source address.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    per_client: Option<Buckets<Uuid>>,
+    per_ip: Option<Buckets<IpAddr>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            per_client: config.per_client.map(Buckets::new),
+            per_ip: config.per_ip.map(Buckets::new),
+        }
+    }
+
+    /// Check that a request from the given client is within the rate limits, failing with 429
+    /// Too Many Requests if not.
+    pub(crate) fn check(&self, req: &HttpRequest, client_id: Uuid) -> actix_web::Result<()> {
+        self.check_addr(req.peer_addr().map(|a| a.ip()), client_id)
+            .map_err(too_many_requests)
+    }
+
+    /// Check that a request from the given client and source address is within the rate limits,
+    /// returning how long to wait before retrying if not.
+    pub(crate) fn check_addr(&self, ip: Option<IpAddr>, client_id: Uuid) -> Result<(), Duration> {
+        self.check_at(ip, client_id, Instant::now())
+    }
+
+    fn check_at(&self, ip: Option<IpAddr>, client_id: Uuid, now: Instant) -> Result<(), Duration> {
+        // Both limits are checked before a token is taken from either, so a request rejected by
+        // one limit does not count against the other. The per-IP limit is checked first, so a
+        // single address cannot create client buckets faster than it is allowed to make requests.
+        let per_ip = match (&self.per_ip, ip) {
+            (Some(per_ip), Some(ip)) => {
+                let map = per_ip.lock();
+                per_ip.check(&map, &ip, now)?;
+                Some((per_ip, map, ip))
+            }
+            _ => None,
+        };
+        let per_client = match &self.per_client {
+            Some(per_client) => {
+                let map = per_client.lock();
+                per_client.check(&map, &client_id, now)?;
+                Some((per_client, map))
+            }
+            None => None,
+        };
+        if let Some((per_ip, mut map, ip)) = per_ip {
+            per_ip.take(&mut map, ip, now);
+        }
+        if let Some((per_client, mut map)) = per_client {
+            per_client.take(&mut map, client_id, now);
+        }
+        Ok(())
+    }
+}
+
+/// Build a 429 Too Many Requests error, asking the client to retry after `retry_after`.
+fn too_many_requests(retry_after: Duration) -> actix_web::Error {
+    // Retry-After is in whole seconds, so round up
+    let secs = retry_after
+        .as_secs()
+        .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+    error::InternalError::from_response(
+        "rate limit exceeded",
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, secs.max(1)))
+            .body("rate limit exceeded"),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn buckets_refill() {
+        let buckets = Buckets::new(RateLimit {
+            per_minute: 60,
+            burst: 2,
+        });
+        let start = Instant::now();
+        assert_eq!(buckets.acquire(1, start), Ok(()));
+        assert_eq!(buckets.acquire(1, start), Ok(()));
+        assert_eq!(buckets.acquire(1, start), Err(Duration::from_secs(1)));
+        // other keys are independent
+        assert_eq!(buckets.acquire(2, start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(buckets.acquire(1, later), Err(Duration::from_millis(500)));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(buckets.acquire(1, later), Ok(()));
+
+        // the bucket holds at most `burst` tokens
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(buckets.acquire(1, much_later), Ok(()));
+        assert_eq!(buckets.acquire(1, much_later), Ok(()));
+        assert!(buckets.acquire(1, much_later).is_err());
+    }
+
+    #[test]
+    fn buckets_bounded() {
+        let buckets = Buckets::new(RateLimit {
+            per_minute: 1,
+            burst: 1,
+        });
+        let now = Instant::now();
+        for key in 0..MAX_TRACKED_KEYS + 10 {
+            assert_eq!(buckets.acquire(key, now), Ok(()));
+        }
+        assert_eq!(buckets.lock().buckets.len(), MAX_TRACKED_KEYS);
+        assert_eq!(buckets.lock().by_use.len(), MAX_TRACKED_KEYS);
+        // the least-recently-used keys were forgotten, so their buckets are full again
+        assert_eq!(buckets.acquire(0, now), Ok(()));
+        assert!(buckets.acquire(MAX_TRACKED_KEYS + 9, now).is_err());
+    }
+
+    #[test]
+    fn limiter_unlimited() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at(None, Uuid::new_v4(), now).is_ok());
+        }
+    }
+
+    #[test]
+    fn limiter_per_client_and_ip() {
+        let limit = RateLimit {
+            per_minute: 1,
+            burst: 1,
+        };
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_client: Some(limit),
+            per_ip: Some(RateLimit {
+                per_minute: 1,
+                burst: 2,
+            }),
+        });
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let (client1, client2, client3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert!(limiter.check_at(ip, client1, now).is_ok());
+
+        // client1 is limited, regardless of address
+        let retry_after = limiter.check_at(None, client1, now).unwrap_err();
+        let res = too_many_requests(retry_after).error_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "60");
+
+        // the address allows one more request, from any client
+        assert!(limiter.check_at(ip, client2, now).is_ok());
+        assert!(limiter.check_at(ip, client3, now).is_err());
+    }
+
+    #[test]
+    fn limiter_rejection_consumes_nothing() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_client: Some(RateLimit {
+                per_minute: 1,
+                burst: 1,
+            }),
+            per_ip: Some(RateLimit {
+                per_minute: 1,
+                burst: 2,
+            }),
+        });
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let client_id = Uuid::new_v4();
+        let now = Instant::now();
+        assert!(limiter.check_at(None, client_id, now).is_ok());
+
+        // rejected by the per-client limit, without taking a token from the address
+        for _ in 0..5 {
+            assert!(limiter.check_at(ip, client_id, now).is_err());
+        }
+        assert!(limiter.check_at(ip, Uuid::new_v4(), now).is_ok());
+        assert!(limiter.check_at(ip, Uuid::new_v4(), now).is_ok());
+
+        // rejected by the per-address limit, without tracking the client
+        let other = Uuid::new_v4();
+        assert!(limiter.check_at(ip, other, now).is_err());
+        let per_client = limiter.per_client.as_ref().unwrap();
+        assert!(!per_client.lock().buckets.contains_key(&other));
+    }
+}
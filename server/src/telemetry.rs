@@ -0,0 +1,106 @@
+use crate::api::CLIENT_ID_HEADER;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
+};
+use anyhow::Context;
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tracing::{field, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Reads W3C trace context from request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Create the span covering the handling of a request, continuing the trace given in the
+/// request's `traceparent` header, if any.
+pub(crate) fn request_span(req: &ServiceRequest) -> Span {
+    let span = tracing::info_span!(
+        "http_request",
+        otel.name = field::Empty,
+        otel.kind = "server",
+        http.request.method = %req.method(),
+        http.route = field::Empty,
+        http.response.status_code = field::Empty,
+        client_id = field::Empty,
+    );
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+    span.set_parent(parent);
+    if let Some(client_id) = req.headers().get(CLIENT_ID_HEADER) {
+        if let Ok(client_id) = client_id.to_str() {
+            span.record("client_id", client_id);
+        }
+    }
+    span
+}
+
+/// Record the route and status of a completed request on its span. The route is only known
+/// once the request has been routed.
+pub(crate) fn record_response<B>(span: &Span, res: &ServiceResponse<B>) {
+    let req = res.request();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    span.record("otel.name", format!("{} {route}", req.method()));
+    span.record("http.route", route.as_str());
+    span.record("http.response.status_code", res.status().as_u16());
+}
+
+/// Export spans for requests and storage operations with OTLP over gRPC to the collector at
+/// `endpoint`, such as `http://localhost:4317`, naming this service `service_name`.
+///
+/// This must be called from within a Tokio runtime. The returned provider should be shut down
+/// before exiting, to flush any spans not yet exported.
+pub fn init_tracing(endpoint: &str, service_name: &str) -> anyhow::Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .context("Could not create OTLP exporter")?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )]))
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn header_extractor() {
+        let req = TestRequest::default()
+            .insert_header(("traceparent", "00-abc-def-01"))
+            .to_http_request();
+        let extractor = HeaderExtractor(req.headers());
+        assert_eq!(extractor.get("traceparent"), Some("00-abc-def-01"));
+        assert_eq!(extractor.get("tracestate"), None);
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+    }
+}
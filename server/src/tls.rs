@@ -0,0 +1,127 @@
+use crate::client_cert::ClientCertificate;
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use anyhow::{anyhow, Context};
+use rustls::{
+    crypto::ring, pki_types::CertificateDer, server::WebPkiClientVerifier, RootCertStore,
+    ServerConfig,
+};
+use std::{any::Any, fs::File, io::BufReader, path::Path, sync::Arc};
+
+/// Read all of the certificates in a PEM file, failing if there are none.
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Opening {}", path.display()))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Reading certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Load a TLS configuration from a PEM file containing the server's certificate chain, leaf
+/// first, and a PEM file containing its private key, as issued by e.g. Let's Encrypt.
+///
+/// If `client_ca_path` is given, clients must present a certificate issued by one of the CA
+/// certificates in that PEM file.
+pub fn load_tls_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> anyhow::Result<ServerConfig> {
+    let certs = read_certs(cert_path)?;
+
+    let mut key_reader = BufReader::new(
+        File::open(key_path).with_context(|| format!("Opening {}", key_path.display()))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .with_context(|| format!("Reading private key from {}", key_path.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+
+    // Select the provider explicitly, as more than one may be compiled in.
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca_cert in read_certs(client_ca_path)? {
+                roots.add(ca_cert).with_context(|| {
+                    format!("Adding CA certificate from {}", client_ca_path.display())
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("Building client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(certs, key)
+        .context("Certificate does not match private key")
+}
+
+/// Record the certificate presented by the client, if any, in the connection data. This is
+/// suitable for [`actix_web::HttpServer::on_connect`].
+pub fn client_certificate(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = stream.get_ref();
+        if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+            data.insert(ClientCertificate::from_der(cert));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn load() {
+        load_tls_config(&testdata("cert.pem"), &testdata("key.pem"), None).unwrap();
+    }
+
+    #[test]
+    fn load_client_ca() {
+        let ca = testdata("cert.pem");
+        load_tls_config(&testdata("cert.pem"), &testdata("key.pem"), Some(&ca)).unwrap();
+    }
+
+    #[test]
+    fn load_client_ca_empty() {
+        let ca = testdata("key.pem");
+        let err =
+            load_tls_config(&testdata("cert.pem"), &testdata("key.pem"), Some(&ca)).unwrap_err();
+        assert!(err.to_string().starts_with("No certificates found"));
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let err =
+            load_tls_config(&testdata("missing.pem"), &testdata("key.pem"), None).unwrap_err();
+        assert!(err.to_string().starts_with("Opening "));
+    }
+
+    #[test]
+    fn load_no_key() {
+        // the certificate file contains no private key
+        let err = load_tls_config(&testdata("cert.pem"), &testdata("cert.pem"), None).unwrap_err();
+        assert!(err.to_string().starts_with("No private key found"));
+    }
+
+    #[test]
+    fn load_no_cert() {
+        let err = load_tls_config(&testdata("key.pem"), &testdata("key.pem"), None).unwrap_err();
+        assert!(err.to_string().starts_with("No certificates found"));
+    }
+}
@@ -0,0 +1,287 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use taskchampion_sync_server_core::ChangeEvent;
+use uuid::Uuid;
+
+/// The header identifying a delivery, which is the same for each attempt, so that a receiver
+/// can ignore duplicates.
+const DELIVERY_ID_HEADER: &str = "X-Webhook-Id";
+
+/// The header containing the HMAC-SHA256 signature of the request body.
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// The number of events queued for each URL. Further events are dropped until the queue drains.
+const QUEUE_LEN: usize = 1000;
+
+/// The delay before the first retry of a failed delivery, doubling for each further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The time allowed for each attempt to deliver an event.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where to POST sync events, so that operators can trigger backups or send notifications when
+/// clients sync.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookConfig {
+    /// URLs to which each event is POSTed as JSON.
+    pub urls: Vec<String>,
+    /// The secret with which each request body is signed, if any.
+    pub secret: Option<String>,
+    /// The number of times a failed delivery is retried before the event is dropped.
+    pub max_retries: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            secret: None,
+            max_retries: 5,
+        }
+    }
+}
+
+/// An event to be delivered to a webhook.
+struct Delivery {
+    id: Uuid,
+    body: Arc<str>,
+}
+
+/// Delivers events to webhooks. Each URL has a queue and a thread delivering its events in
+/// order, so that a slow or failing endpoint does not delay requests or other endpoints.
+pub(crate) struct Webhooks {
+    queues: Vec<(String, SyncSender<Delivery>)>,
+}
+
+impl Webhooks {
+    /// Start delivering events to the configured URLs.
+    pub(crate) fn start(config: &WebhookConfig) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        let queues = config
+            .urls
+            .iter()
+            .map(|url| {
+                let (sender, receiver) = sync_channel(QUEUE_LEN);
+                let endpoint = Endpoint {
+                    agent: agent.clone(),
+                    url: url.clone(),
+                    secret: config.secret.clone(),
+                    max_retries: config.max_retries,
+                    initial_backoff: INITIAL_BACKOFF,
+                };
+                thread::Builder::new()
+                    .name("webhook".into())
+                    .spawn(move || endpoint.run(receiver))
+                    .expect("could not spawn webhook thread");
+                (url.clone(), sender)
+            })
+            .collect();
+        Self { queues }
+    }
+
+    /// Queue an event for delivery to each URL, dropping it for any URL whose queue is full.
+    pub(crate) fn publish(&self, event: &ChangeEvent) {
+        let body: Arc<str> = serde_json::to_string(event)
+            .expect("events are serializable")
+            .into();
+        let id = Uuid::new_v4();
+        for (url, queue) in &self.queues {
+            let delivery = Delivery {
+                id,
+                body: body.clone(),
+            };
+            if let Err(TrySendError::Full(_)) = queue.try_send(delivery) {
+                log::warn!("Webhook queue for {url} is full; dropping event {id}");
+            }
+        }
+    }
+}
+
+/// A URL to which events are delivered.
+struct Endpoint {
+    agent: ureq::Agent,
+    url: String,
+    secret: Option<String>,
+    max_retries: u32,
+    initial_backoff: Duration,
+}
+
+impl Endpoint {
+    /// Deliver events until the [`Webhooks`] is dropped.
+    fn run(self, receiver: Receiver<Delivery>) {
+        for delivery in receiver {
+            self.deliver(&delivery);
+        }
+    }
+
+    /// Deliver an event, retrying after failures that may be temporary. Returns whether the
+    /// event was delivered.
+    fn deliver(&self, delivery: &Delivery) -> bool {
+        let mut backoff = self.initial_backoff;
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            let mut request = self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/json")
+                .set(DELIVERY_ID_HEADER, &delivery.id.to_string());
+            if let Some(secret) = &self.secret {
+                request = request.set(SIGNATURE_HEADER, &signature(secret, &delivery.body));
+            }
+            match request.send_string(&delivery.body) {
+                Ok(_) => return true,
+                Err(ureq::Error::Status(status, _)) if status != 429 && status < 500 => {
+                    log::warn!(
+                        "Webhook {} rejected event {} with status {status}",
+                        self.url,
+                        delivery.id
+                    );
+                    return false;
+                }
+                Err(err) => {
+                    log::info!(
+                        "Webhook {} failed to receive event {} (attempt {}): {err}",
+                        self.url,
+                        delivery.id,
+                        attempt + 1
+                    );
+                }
+            }
+        }
+        log::warn!(
+            "Giving up on delivering event {} to webhook {}",
+            delivery.id,
+            self.url
+        );
+        false
+    }
+}
+
+/// Sign a request body with HMAC-SHA256, in the form `sha256=<hex digest>`.
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={digest}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn hmac_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    /// Serve one HTTP request with the given status, returning its headers and body.
+    fn serve_one(listener: &TcpListener, status: u16) -> (Vec<String>, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            headers.push(line.to_ascii_lowercase());
+        }
+        let len: usize = headers
+            .iter()
+            .find_map(|h| h.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        (headers, String::from_utf8(body).unwrap())
+    }
+
+    fn test_endpoint(listener: &TcpListener, max_retries: u32) -> Endpoint {
+        Endpoint {
+            agent: ureq::agent(),
+            url: format!("http://{}/hook", listener.local_addr().unwrap()),
+            secret: Some("s3cret".into()),
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+        }
+    }
+
+    fn delivery() -> Delivery {
+        Delivery {
+            id: Uuid::new_v4(),
+            body: r#"{"type":"client_created"}"#.into(),
+        }
+    }
+
+    #[test]
+    fn deliver_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = test_endpoint(&listener, 2);
+        let delivery = delivery();
+        let id = delivery.id;
+        let client = thread::spawn(move || endpoint.deliver(&delivery));
+
+        let (first, _) = serve_one(&listener, 503);
+        let (headers, body) = serve_one(&listener, 200);
+        assert!(client.join().unwrap());
+
+        assert_eq!(body, r#"{"type":"client_created"}"#);
+        let id_header = format!("x-webhook-id: {id}");
+        assert!(first.contains(&id_header));
+        assert!(headers.contains(&id_header));
+        assert!(headers.contains(&format!(
+            "x-webhook-signature: {}",
+            signature("s3cret", &body)
+        )));
+    }
+
+    #[test]
+    fn deliver_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // a client error is not retried
+        let endpoint = test_endpoint(&listener, 2);
+        let client = thread::spawn(move || endpoint.deliver(&delivery()));
+        serve_one(&listener, 400);
+        assert!(!client.join().unwrap());
+
+        // a server error is retried, up to the limit
+        let endpoint = test_endpoint(&listener, 1);
+        let client = thread::spawn(move || endpoint.deliver(&delivery()));
+        serve_one(&listener, 500);
+        serve_one(&listener, 500);
+        assert!(!client.join().unwrap());
+    }
+}
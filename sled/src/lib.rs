@@ -0,0 +1,380 @@
+//! This crate implements a [sled](https://sled.rs) storage backend for the TaskChampion sync
+//! server, for deployments that wish to avoid C dependencies.
+//!
+//! The backend is a [`KvBackend`], storing clients, snapshots, versions, and child-version links
+//! in separate sled trees. Use it with [`KvStorage`]:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use taskchampion_sync_server_storage_sled::{SledKv, SledStorage};
+//! let storage: SledStorage = SledStorage::new(SledKv::new("/var/lib/taskchampion")?);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::Context;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::{Transactional, Tree};
+use std::collections::BTreeMap;
+use std::path::Path;
+use taskchampion_sync_server_core::{kv_keys, KvBackend, KvStorage, KvTxn, StorageError};
+
+/// Storage in sled.
+pub type SledStorage = KvStorage<SledKv>;
+
+/// The tree for each key tag.
+const TREES: [(u8, &str); 7] = [
+    (kv_keys::CLIENT, "clients"),
+    (kv_keys::CLIENT_METADATA, "client_metadata"),
+    (kv_keys::SNAPSHOT, "snapshots"),
+    (kv_keys::RETAINED_SNAPSHOT, "retained_snapshots"),
+    (kv_keys::VERSION, "versions"),
+    (kv_keys::VERSION_TIMESTAMP, "version_timestamps"),
+    (kv_keys::CHILD, "children"),
+];
+
+/// A [`KvBackend`] storing data in a sled database.
+///
+/// Each key is stored in the tree for its tag, without the tag itself. Transactions are
+/// optimistic: reads are performed directly and their results remembered, while writes are
+/// buffered. On commit, a sled transaction across all trees checks that every key read still has
+/// the value that was read, then applies the writes. If any value has changed, nothing is written
+/// and the commit fails with [`StorageError::TryAgainLater`]. Prefix scans are not checked.
+pub struct SledKv {
+    db: sled::Db,
+    trees: [Tree; 7],
+}
+
+impl SledKv {
+    /// Create a new instance using a database in the given directory, creating it if necessary.
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = sled::open(path.as_ref()).with_context(|| {
+            format!(
+                "Error opening sled database at `{}`",
+                path.as_ref().display()
+            )
+        })?;
+        let [clients, client_metadata, snapshots, retained_snapshots, versions, version_timestamps, children] =
+            TREES.map(|(_, name)| db.open_tree(name));
+        let trees = [
+            clients?,
+            client_metadata?,
+            snapshots?,
+            retained_snapshots?,
+            versions?,
+            version_timestamps?,
+            children?,
+        ];
+        Ok(Self { db, trees })
+    }
+}
+
+impl KvBackend for SledKv {
+    fn txn(&self) -> anyhow::Result<Box<dyn KvTxn + '_>> {
+        Ok(Box::new(SledKvTxn {
+            kv: self,
+            reads: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        }))
+    }
+}
+
+/// Get the index into [`TREES`] for the given key tag.
+fn tree_index(tag: u8) -> anyhow::Result<usize> {
+    TREES
+        .iter()
+        .position(|(t, _)| *t == tag)
+        .ok_or_else(|| anyhow::anyhow!("Unknown key tag {tag}"))
+}
+
+/// A key split into the index of its tree and the remainder of the key, with the value read or to
+/// be written, or `None` if the key is absent or to be deleted.
+type TreeEntry<'a> = (usize, &'a [u8], &'a Option<Vec<u8>>);
+
+/// Split a key into the index of the tree for its tag and the remainder of the key.
+fn split(key: &[u8]) -> anyhow::Result<(usize, &[u8])> {
+    let (tag, rest) = key
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("Empty key"))?;
+    Ok((tree_index(*tag)?, rest))
+}
+
+/// The reason a commit was aborted.
+#[derive(Debug)]
+struct Conflict;
+
+struct SledKvTxn<'a> {
+    kv: &'a SledKv,
+    /// Values read from the database in this transaction, which must be unchanged on commit.
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Uncommitted changes, with `None` representing a deletion.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl SledKvTxn<'_> {
+    /// Scan the tree at `index` for keys beginning with `prefix`, adding them to `result` with
+    /// the tag restored.
+    fn scan_tree(
+        &self,
+        index: usize,
+        prefix: &[u8],
+        result: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let (tag, _) = TREES[index];
+        for item in self.kv.trees[index].scan_prefix(prefix) {
+            let (k, v) = item?;
+            let mut key = vec![tag];
+            key.extend_from_slice(&k);
+            result.insert(key, v.to_vec());
+        }
+        Ok(())
+    }
+}
+
+impl KvTxn for SledKvTxn<'_> {
+    fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(key) {
+            return Ok(value.clone());
+        }
+        let (index, rest) = split(key)?;
+        let value = self.kv.trees[index].get(rest)?.map(|v| v.to_vec());
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        split(key)?;
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        split(key)?;
+        self.pending.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut result = BTreeMap::new();
+        match prefix.split_first() {
+            Some((tag, rest)) => self.scan_tree(tree_index(*tag)?, rest, &mut result)?,
+            None => {
+                for index in 0..TREES.len() {
+                    self.scan_tree(index, &[], &mut result)?;
+                }
+            }
+        }
+        for (k, v) in self
+            .pending
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+        {
+            match v {
+                Some(v) => result.insert(k.clone(), v.clone()),
+                None => result.remove(k),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        let reads = std::mem::take(&mut self.reads);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // keys were validated when they were read or written
+        let reads: Vec<TreeEntry> = reads
+            .iter()
+            .map(|(k, v)| (tree_index(k[0]).unwrap(), &k[1..], v))
+            .collect();
+        let writes: Vec<TreeEntry> = pending
+            .iter()
+            .map(|(k, v)| (tree_index(k[0]).unwrap(), &k[1..], v))
+            .collect();
+
+        let [clients, client_metadata, snapshots, retained_snapshots, versions, version_timestamps, children] =
+            &self.kv.trees;
+        let trees = (
+            clients,
+            client_metadata,
+            snapshots,
+            retained_snapshots,
+            versions,
+            version_timestamps,
+            children,
+        );
+        let result = trees.transaction(
+            |(c, m, s, r, v, t, p)| -> ConflictableTransactionResult<(), Conflict> {
+                let views: [&TransactionalTree; 7] = [c, m, s, r, v, t, p];
+                for (index, key, expected) in &reads {
+                    let current = views[*index].get(key)?;
+                    if current.as_deref() != expected.as_deref() {
+                        return Err(ConflictableTransactionError::Abort(Conflict));
+                    }
+                }
+                for (index, key, value) in &writes {
+                    match value {
+                        Some(value) => views[*index].insert(*key, value.as_slice())?,
+                        None => views[*index].remove(*key)?,
+                    };
+                }
+                Ok(())
+            },
+        );
+        match result {
+            Ok(()) => {}
+            Err(TransactionError::Abort(Conflict)) => {
+                return Err(StorageError::TryAgainLater.into())
+            }
+            Err(TransactionError::Storage(err)) => {
+                return Err(err).context("Error committing to sled")
+            }
+        }
+        self.kv.db.flush().context("Error flushing sled database")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_get_put_delete() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = SledKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        txn.put(b"ca", b"1")?;
+        txn.put(b"vb", b"2")?;
+        // reads see uncommitted writes
+        assert_eq!(txn.get(b"ca")?, Some(b"1".to_vec()));
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"ca")?, Some(b"1".to_vec()));
+        // the tag selects the tree
+        assert_eq!(txn.get(b"va")?, None);
+        txn.delete(b"ca")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        assert_eq!(txn.get(b"ca")?, None);
+        assert_eq!(txn.get(b"vb")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_tag() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = SledKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        assert!(txn.put(b"xa", b"1").is_err());
+        assert!(txn.get(b"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = SledKv::new(tmp_dir.path())?;
+        let mut txn = kv.txn()?;
+        txn.put(b"va1", b"x")?;
+        txn.put(b"va2", b"y")?;
+        txn.put(b"vb", b"z")?;
+        txn.put(b"ca", b"w")?;
+        txn.commit()?;
+
+        let mut txn = kv.txn()?;
+        txn.put(b"va3", b"u")?;
+        txn.delete(b"va1")?;
+        assert_eq!(
+            txn.scan_prefix(b"va")?,
+            vec![
+                (b"va2".to_vec(), b"y".to_vec()),
+                (b"va3".to_vec(), b"u".to_vec())
+            ]
+        );
+        assert_eq!(
+            txn.scan_prefix(b"c")?,
+            vec![(b"ca".to_vec(), b"w".to_vec())]
+        );
+        assert_eq!(txn.scan_prefix(b"")?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_changes_discarded() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = SledKv::new(tmp_dir.path())?;
+        {
+            let mut txn = kv.txn()?;
+            txn.put(b"ca", b"1")?;
+        }
+        assert_eq!(kv.txn()?.get(b"ca")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let kv = SledKv::new(tmp_dir.path())?;
+        let mut txn1 = kv.txn()?;
+        assert_eq!(txn1.get(b"ca")?, None);
+
+        let mut txn2 = kv.txn()?;
+        txn2.put(b"ca", b"2")?;
+        txn2.commit()?;
+
+        txn1.put(b"ca", b"1")?;
+        let err = txn1.commit().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::TryAgainLater)
+        ));
+        assert_eq!(kv.txn()?.get(b"ca")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_persists() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        {
+            let storage = SledStorage::new(SledKv::new(tmp_dir.path())?);
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1, 2, 3].into())?;
+            txn.commit()?;
+        }
+
+        let storage = SledStorage::new(SledKv::new(tmp_dir.path())?);
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        let version = txn.get_version_by_parent(NIL_VERSION_ID)?.unwrap();
+        assert_eq!(version.version_id, version_id);
+        assert_eq!(version.history_segment, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let tmp_dir = TempDir::new()?;
+            test(&SledStorage::new(SledKv::new(tmp_dir.path())?))
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
+}
@@ -1,12 +1,18 @@
 //! Tihs crate implements a SQLite storage backend for the TaskChampion sync server.
 use anyhow::Context;
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use rusqlite::types::{FromSql, ToSql};
 use rusqlite::{params, Connection, OptionalExtension};
 use std::path::Path;
-use taskchampion_sync_server_core::{Client, Snapshot, Storage, StorageTxn, Version};
+use std::sync::Arc;
+use taskchampion_sync_server_core::{
+    Bytes, Client, Clock, Quota, Savepoint, Snapshot, Storage, StorageCapabilities, StorageError,
+    StorageTxn, SystemClock, Version,
+};
 use uuid::Uuid;
 
+mod migrations;
+
 /// Newtype to allow implementing `FromSql` for foreign `uuid::Uuid`
 struct StoredUuid(Uuid);
 
@@ -27,17 +33,36 @@ impl ToSql for StoredUuid {
     }
 }
 
+/// Convert a SQLite error, treating a busy or locked database as a transient
+/// [`StorageError::Retryable`] error.
+fn classify(err: rusqlite::Error) -> StorageError {
+    match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => {
+            StorageError::Retryable(err.into())
+        }
+        _ => StorageError::Backend(err.into()),
+    }
+}
+
 /// An on-disk storage backend which uses SQLite.
 ///
 /// A new connection is opened for each transaction, and only one transaction may be active at a
 /// time; a second call to `txn` will block until the first transaction is dropped.
 pub struct SqliteStorage {
     db_file: std::path::PathBuf,
+    /// The SQLCipher passphrase, if the database is encrypted.
+    passphrase: Option<String>,
+    /// The clock used to timestamp new clients and versions.
+    clock: Arc<dyn Clock>,
 }
 
 impl SqliteStorage {
     fn new_connection(&self) -> anyhow::Result<Connection> {
-        Ok(Connection::open(&self.db_file)?)
+        let con = Connection::open(&self.db_file)?;
+        if let Some(passphrase) = &self.passphrase {
+            con.pragma_update(None, "key", passphrase)?;
+        }
+        Ok(con)
     }
 
     /// Create a new instance using a database at the given directory.
@@ -45,65 +70,125 @@ impl SqliteStorage {
     /// The database will be stored in a file named `taskchampion-sync-server.sqlite3` in the given
     /// directory.
     pub fn new<P: AsRef<Path>>(directory: P) -> anyhow::Result<SqliteStorage> {
+        Self::open(directory, None)
+    }
+
+    /// Create a new instance using a database at the given directory, encrypted with SQLCipher
+    /// using the given passphrase.
+    ///
+    /// A new database is encrypted when it is created. An existing database must have been created
+    /// with the same passphrase; an unencrypted database cannot be opened this way.
+    #[cfg(feature = "sqlcipher")]
+    pub fn with_passphrase<P: AsRef<Path>>(
+        directory: P,
+        passphrase: &str,
+    ) -> anyhow::Result<SqliteStorage> {
+        Self::open(directory, Some(passphrase.to_string()))
+    }
+
+    fn open<P: AsRef<Path>>(
+        directory: P,
+        passphrase: Option<String>,
+    ) -> anyhow::Result<SqliteStorage> {
         std::fs::create_dir_all(&directory)
             .with_context(|| format!("Failed to create `{}`.", directory.as_ref().display()))?;
         let db_file = directory.as_ref().join("taskchampion-sync-server.sqlite3");
 
-        let o = SqliteStorage { db_file };
+        let o = SqliteStorage {
+            db_file,
+            passphrase,
+            clock: Arc::new(SystemClock),
+        };
+
+        let mut con = o.new_connection()?;
 
-        let con = o.new_connection()?;
+        // With the wrong passphrase, SQLCipher fails on the first read of the database.
+        con.query_row("SELECT count(*) FROM sqlite_master", [], |_row| Ok(()))
+            .context("Error reading database; is the passphrase correct?")?;
 
         // Use the modern WAL mode.
         con.query_row("PRAGMA journal_mode=WAL", [], |_row| Ok(()))
             .context("Setting journal_mode=WAL")?;
 
-        let queries = vec![
-                "CREATE TABLE IF NOT EXISTS clients (
-                    client_id STRING PRIMARY KEY,
-                    latest_version_id STRING,
-                    snapshot_version_id STRING,
-                    versions_since_snapshot INTEGER,
-                    snapshot_timestamp INTEGER,
-                    snapshot BLOB);",
-                "CREATE TABLE IF NOT EXISTS versions (version_id STRING PRIMARY KEY, client_id STRING, parent_version_id STRING, history_segment BLOB);",
-                "CREATE INDEX IF NOT EXISTS versions_by_parent ON versions (parent_version_id);",
-            ];
-        for q in queries {
-            con.execute(q, [])
-                .context("Error while creating SQLite tables")?;
-        }
+        migrations::run(&mut con)?;
 
         Ok(o)
     }
+
+    /// Use the given clock to timestamp new clients and versions, rather than the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 }
 
 impl Storage for SqliteStorage {
-    fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+    fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, StorageError> {
         let con = self.new_connection()?;
         // Begin the transaction on this new connection. An IMMEDIATE connection is in
         // write (exclusive) mode from the start.
-        con.execute("BEGIN IMMEDIATE", [])?;
-        let txn = Txn { con, client_id };
+        con.execute("BEGIN IMMEDIATE", []).map_err(classify)?;
+        let txn = Txn {
+            con,
+            client_id,
+            savepoints: 0,
+            clock: self.clock.as_ref(),
+        };
         Ok(Box::new(txn))
     }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_client_enumeration: true,
+            supports_rollback: true,
+            supports_compaction: true,
+            supports_savepoints: true,
+            supports_soft_delete: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    fn max_versions_since_snapshot(&self) -> Result<Option<(Uuid, u32)>, StorageError> {
+        let con = self.new_connection()?;
+        let r = con
+            .query_row(
+                "SELECT client_id, versions_since_snapshot FROM clients
+                 WHERE snapshot_version_id IS NOT NULL
+                 ORDER BY versions_since_snapshot DESC
+                 LIMIT 1",
+                [],
+                |r| {
+                    let client_id: StoredUuid = r.get(0)?;
+                    let versions_since: u32 = r.get(1)?;
+                    Ok((client_id.0, versions_since))
+                },
+            )
+            .optional()
+            .context("Error getting max versions_since_snapshot")?;
+        Ok(r)
+    }
 }
 
-struct Txn {
+struct Txn<'a> {
     // SQLite only allows one concurrent transaction per connection, and rusqlite emulates
     // transactions by running `BEGIN ...` and `COMMIT` at appropriate times. So we will do
     // the same.
     con: Connection,
     client_id: Uuid,
+    /// The number of savepoints, named `sp0`, `sp1`, and so on.
+    savepoints: usize,
+    /// The storage's clock, used to timestamp new clients and versions.
+    clock: &'a dyn Clock,
 }
 
-impl Txn {
+impl Txn<'_> {
     /// Implementation for queries from the versions table
     fn get_version_impl(
         &mut self,
         query: &'static str,
         client_id: Uuid,
         version_id_arg: Uuid,
-    ) -> anyhow::Result<Option<Version>> {
+    ) -> Result<Option<Version>, StorageError> {
         let r = self
             .con
             .query_row(
@@ -112,11 +197,13 @@ impl Txn {
                 |r| {
                     let version_id: StoredUuid = r.get("version_id")?;
                     let parent_version_id: StoredUuid = r.get("parent_version_id")?;
+                    let created_at: Option<i64> = r.get("created_at")?;
 
                     Ok(Version {
                         version_id: version_id.0,
                         parent_version_id: parent_version_id.0,
-                        history_segment: r.get("history_segment")?,
+                        history_segment: r.get::<_, Vec<u8>>("history_segment")?.into(),
+                        created_at: created_at.map(|ts| Utc.timestamp_opt(ts, 0).unwrap()),
                     })
                 },
             )
@@ -126,8 +213,8 @@ impl Txn {
     }
 }
 
-impl StorageTxn for Txn {
-    fn get_client(&mut self) -> anyhow::Result<Option<Client>> {
+impl StorageTxn for Txn<'_> {
+    fn get_client(&mut self) -> Result<Option<Client>, StorageError> {
         let result: Option<Client> = self
             .con
             .query_row(
@@ -135,7 +222,13 @@ impl StorageTxn for Txn {
                     latest_version_id,
                     snapshot_timestamp,
                     versions_since_snapshot,
-                    snapshot_version_id
+                    snapshot_version_id,
+                    created_at,
+                    last_sync_at,
+                    user_agent,
+                    quota_max_bytes,
+                    quota_max_versions,
+                    deleted_at
                  FROM clients
                  WHERE client_id = ?
                  LIMIT 1",
@@ -145,6 +238,13 @@ impl StorageTxn for Txn {
                     let snapshot_timestamp: Option<i64> = r.get(1)?;
                     let versions_since_snapshot: Option<u32> = r.get(2)?;
                     let snapshot_version_id: Option<StoredUuid> = r.get(3)?;
+                    let created_at: Option<i64> = r.get(4)?;
+                    let last_sync_at: Option<i64> = r.get(5)?;
+                    let user_agent: Option<String> = r.get(6)?;
+                    // quotas are stored as signed integers, so reinterpret them as unsigned
+                    let quota_max_bytes: Option<i64> = r.get(7)?;
+                    let quota_max_versions: Option<i64> = r.get(8)?;
+                    let deleted_at: Option<i64> = r.get(9)?;
 
                     // if all of the relevant fields are non-NULL, return a snapshot
                     let snapshot = match (
@@ -162,6 +262,14 @@ impl StorageTxn for Txn {
                     Ok(Client {
                         latest_version_id: latest_version_id.0,
                         snapshot,
+                        created_at: created_at.map(|ts| Utc.timestamp_opt(ts, 0).unwrap()),
+                        last_sync_at: last_sync_at.map(|ts| Utc.timestamp_opt(ts, 0).unwrap()),
+                        user_agent,
+                        quota: Quota {
+                            max_bytes: quota_max_bytes.map(|n| n as u64),
+                            max_versions: quota_max_versions.map(|n| n as u64),
+                        },
+                        deleted_at: deleted_at.map(|ts| Utc.timestamp_opt(ts, 0).unwrap()),
                     })
                 },
             )
@@ -171,17 +279,84 @@ impl StorageTxn for Txn {
         Ok(result)
     }
 
-    fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+    fn new_client(&mut self, latest_version_id: Uuid) -> Result<(), StorageError> {
         self.con
             .execute(
-                "INSERT OR REPLACE INTO clients (client_id, latest_version_id) VALUES (?, ?)",
-                params![&StoredUuid(self.client_id), &StoredUuid(latest_version_id)],
+                "INSERT OR REPLACE INTO clients (client_id, latest_version_id, created_at)
+                 VALUES (?, ?, ?)",
+                params![
+                    &StoredUuid(self.client_id),
+                    &StoredUuid(latest_version_id),
+                    self.clock.now().timestamp(),
+                ],
             )
             .context("Error creating/updating client")?;
         Ok(())
     }
 
-    fn set_snapshot(&mut self, snapshot: Snapshot, data: Vec<u8>) -> anyhow::Result<()> {
+    fn record_sync(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+    ) -> Result<(), StorageError> {
+        self.con
+            .execute(
+                "UPDATE clients SET last_sync_at = ?, user_agent = ? WHERE client_id = ?",
+                params![
+                    timestamp.timestamp(),
+                    user_agent,
+                    &StoredUuid(self.client_id)
+                ],
+            )
+            .context("Error recording sync")?;
+        Ok(())
+    }
+
+    fn set_quota(&mut self, quota: Quota) -> Result<(), StorageError> {
+        let updated = self
+            .con
+            .execute(
+                "UPDATE clients SET quota_max_bytes = ?, quota_max_versions = ? WHERE client_id = ?",
+                params![
+                    quota.max_bytes.map(|n| n as i64),
+                    quota.max_versions.map(|n| n as i64),
+                    &StoredUuid(self.client_id)
+                ],
+            )
+            .context("Error setting quota")?;
+        if updated == 0 {
+            return Err(StorageError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn set_deleted(&mut self, deleted_at: Option<DateTime<Utc>>) -> Result<(), StorageError> {
+        let updated = self
+            .con
+            .execute(
+                "UPDATE clients SET deleted_at = ? WHERE client_id = ?",
+                params![
+                    deleted_at.map(|t| t.timestamp()),
+                    &StoredUuid(self.client_id)
+                ],
+            )
+            .context("Error setting client deletion")?;
+        if updated == 0 {
+            return Err(StorageError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot, data: Bytes) -> Result<(), StorageError> {
+        let total_versions: u64 = self
+            .con
+            .query_row(
+                "SELECT COUNT(*) FROM versions WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+                |r| r.get(0),
+            )
+            .context("Error counting versions")?;
+        snapshot.validate(total_versions)?;
         self.con
             .execute(
                 "UPDATE clients
@@ -195,7 +370,7 @@ impl StorageTxn for Txn {
                     &StoredUuid(snapshot.version_id),
                     snapshot.timestamp.timestamp(),
                     snapshot.versions_since,
-                    data,
+                    &data[..],
                     &StoredUuid(self.client_id),
                 ],
             )
@@ -203,7 +378,7 @@ impl StorageTxn for Txn {
         Ok(())
     }
 
-    fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> Result<Option<Bytes>, StorageError> {
         let r = self
             .con
             .query_row(
@@ -219,10 +394,12 @@ impl StorageTxn for Txn {
             .context("Error getting snapshot")?;
         r.map(|(v, d)| {
             if v != version_id {
-                return Err(anyhow::anyhow!("unexpected snapshot_version_id"));
+                return Err(StorageError::Backend(anyhow::anyhow!(
+                    "unexpected snapshot_version_id"
+                )));
             }
 
-            Ok(d)
+            Ok(d.into())
         })
         .transpose()
     }
@@ -231,16 +408,16 @@ impl StorageTxn for Txn {
         &mut self,
 
         parent_version_id: Uuid,
-    ) -> anyhow::Result<Option<Version>> {
+    ) -> Result<Option<Version>, StorageError> {
         self.get_version_impl(
-            "SELECT version_id, parent_version_id, history_segment FROM versions WHERE parent_version_id = ? AND client_id = ?",
+            "SELECT version_id, parent_version_id, history_segment, created_at FROM versions WHERE parent_version_id = ? AND client_id = ?",
             self.client_id,
             parent_version_id)
     }
 
-    fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
+    fn get_version(&mut self, version_id: Uuid) -> Result<Option<Version>, StorageError> {
         self.get_version_impl(
-            "SELECT version_id, parent_version_id, history_segment FROM versions WHERE version_id = ? AND client_id = ?",
+            "SELECT version_id, parent_version_id, history_segment, created_at FROM versions WHERE version_id = ? AND client_id = ?",
             self.client_id,
             version_id)
     }
@@ -250,15 +427,17 @@ impl StorageTxn for Txn {
 
         version_id: Uuid,
         parent_version_id: Uuid,
-        history_segment: Vec<u8>,
-    ) -> anyhow::Result<()> {
+        history_segment: Bytes,
+    ) -> Result<(), StorageError> {
+        self.check_new_version_id(version_id)?;
         self.con.execute(
-            "INSERT INTO versions (version_id, client_id, parent_version_id, history_segment) VALUES(?, ?, ?, ?)",
+            "INSERT INTO versions (version_id, client_id, parent_version_id, history_segment, created_at) VALUES(?, ?, ?, ?, ?)",
             params![
                 StoredUuid(version_id),
                 StoredUuid(self.client_id),
                 StoredUuid(parent_version_id),
-                history_segment
+                &history_segment[..],
+                self.clock.now().timestamp(),
             ]
         )
         .context("Error adding version")?;
@@ -267,7 +446,7 @@ impl StorageTxn for Txn {
                 "UPDATE clients
              SET
                latest_version_id = ?,
-               versions_since_snapshot = versions_since_snapshot + 1
+               versions_since_snapshot = MIN(versions_since_snapshot + 1, 4294967295)
              WHERE client_id = ?",
                 params![StoredUuid(version_id), StoredUuid(self.client_id),],
             )
@@ -276,8 +455,78 @@ impl StorageTxn for Txn {
         Ok(())
     }
 
-    fn commit(&mut self) -> anyhow::Result<()> {
-        self.con.execute("COMMIT", [])?;
+    fn delete_version(&mut self, version_id: Uuid) -> Result<(), StorageError> {
+        self.con
+            .execute(
+                "DELETE FROM versions WHERE version_id = ? AND client_id = ?",
+                params![StoredUuid(version_id), StoredUuid(self.client_id)],
+            )
+            .context("Error deleting version")?;
+        Ok(())
+    }
+
+    fn delete_client(&mut self) -> Result<(), StorageError> {
+        self.con
+            .execute(
+                "DELETE FROM versions WHERE client_id = ?",
+                params![StoredUuid(self.client_id)],
+            )
+            .context("Error deleting versions")?;
+        self.con
+            .execute(
+                "DELETE FROM clients WHERE client_id = ?",
+                params![StoredUuid(self.client_id)],
+            )
+            .context("Error deleting client")?;
+        Ok(())
+    }
+
+    fn list_clients(
+        &mut self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        // every client ID sorts after the empty string
+        let after = after.map(|u| u.to_string()).unwrap_or_default();
+        let mut stmt = self
+            .con
+            .prepare("SELECT client_id FROM clients WHERE client_id > ? ORDER BY client_id LIMIT ?")
+            .context("Error listing clients")?;
+        let rows = stmt
+            .query_map(params![after, limit as i64], |r| {
+                let client_id: StoredUuid = r.get(0)?;
+                Ok(client_id.0)
+            })
+            .context("Error listing clients")?;
+        Ok(rows
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error listing clients")?)
+    }
+
+    fn savepoint(&mut self) -> Result<Savepoint, StorageError> {
+        let savepoint = Savepoint(self.savepoints);
+        self.con
+            .execute(&format!("SAVEPOINT sp{}", savepoint.0), [])
+            .context("Error creating savepoint")?;
+        self.savepoints += 1;
+        Ok(savepoint)
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError> {
+        if savepoint.0 >= self.savepoints {
+            return Err(StorageError::Backend(anyhow::anyhow!(
+                "Savepoint has been discarded"
+            )));
+        }
+        self.con
+            .execute(&format!("ROLLBACK TO SAVEPOINT sp{}", savepoint.0), [])
+            .context("Error rolling back to savepoint")?;
+        self.savepoints = savepoint.0 + 1;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.con.execute("COMMIT", []).map_err(classify)?;
         Ok(())
     }
 }
@@ -285,8 +534,8 @@ impl StorageTxn for Txn {
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::DateTime;
     use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::ManualClock;
     use tempfile::TempDir;
 
     #[test]
@@ -327,7 +576,7 @@ mod test {
         assert!(client.snapshot.is_none());
 
         let latest_version_id = Uuid::new_v4();
-        txn.add_version(latest_version_id, Uuid::new_v4(), vec![1, 1])?;
+        txn.add_version(latest_version_id, Uuid::new_v4(), vec![1, 1].into())?;
 
         let client = txn.get_client()?.unwrap();
         assert_eq!(client.latest_version_id, latest_version_id);
@@ -336,9 +585,9 @@ mod test {
         let snap = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
-            versions_since: 4,
+            versions_since: 1,
         };
-        txn.set_snapshot(snap.clone(), vec![1, 2, 3])?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3].into())?;
 
         let client = txn.get_client()?.unwrap();
         assert_eq!(client.latest_version_id, latest_version_id);
@@ -367,13 +616,17 @@ mod test {
 
         let version_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
-        let history_segment = b"abc".to_vec();
+        let history_segment = Bytes::from_static(b"abc");
+        let before = Utc::now().timestamp();
         txn.add_version(version_id, parent_version_id, history_segment.clone())?;
 
+        let created_at = txn.get_version(version_id)?.unwrap().created_at;
+        assert!(created_at.unwrap().timestamp() >= before);
         let expected = Version {
             version_id,
             parent_version_id,
             history_segment,
+            created_at,
         };
 
         let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
@@ -394,7 +647,7 @@ mod test {
 
         let version_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
-        let history_segment = b"abc".to_vec();
+        let history_segment = Bytes::from_static(b"abc");
         txn.add_version(version_id, parent_version_id, history_segment.clone())?;
         assert!(txn
             .add_version(version_id, parent_version_id, history_segment.clone())
@@ -402,6 +655,48 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_set_snapshot_validates() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::nil())?;
+        txn.add_version(Uuid::new_v4(), Uuid::nil(), Bytes::new())?;
+        let snap = Snapshot::new(Uuid::new_v4(), Utc::now());
+        assert!(txn
+            .set_snapshot(snap.clone().with_versions_since(2), vec![1].into())
+            .is_err());
+        txn.set_snapshot(snap.with_versions_since(1), vec![1].into())?;
+        let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+        assert_eq!(snapshot.versions_since, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_if_latest() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.add_version_if_latest(v1, Uuid::nil(), &mut &b"abc"[..])?;
+        txn.commit()?;
+        drop(txn);
+
+        // a replica which has not seen v1 cannot add a version
+        let mut txn = storage.txn(client_id)?;
+        let err = txn
+            .add_version_if_latest(Uuid::new_v4(), Uuid::nil(), &mut &b"def"[..])
+            .unwrap_err();
+        assert_eq!(err.conflict(), Some(v1));
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+        Ok(())
+    }
+
     #[test]
     fn test_snapshots() -> anyhow::Result<()> {
         let tmp_dir = TempDir::new()?;
@@ -415,9 +710,9 @@ mod test {
         let snap = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
-            versions_since: 3,
+            versions_since: 0,
         };
-        txn.set_snapshot(snap.clone(), vec![9, 8, 9])?;
+        txn.set_snapshot(snap.clone(), vec![9, 8, 9].into())?;
 
         assert_eq!(
             txn.get_snapshot_data(snap.version_id)?.unwrap(),
@@ -428,9 +723,9 @@ mod test {
         let snap2 = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
-            versions_since: 10,
+            versions_since: 0,
         };
-        txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6])?;
+        txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6].into())?;
 
         assert_eq!(
             txn.get_snapshot_data(snap2.version_id)?.unwrap(),
@@ -443,4 +738,310 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_max_versions_since_snapshot() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        assert_eq!(storage.max_versions_since_snapshot()?, None);
+
+        let mut clients = vec![];
+        for versions_since in [3, 17, 5] {
+            let client_id = Uuid::new_v4();
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            let mut parent_version_id = Uuid::nil();
+            for _ in 0..versions_since {
+                let version_id = Uuid::new_v4();
+                txn.add_version(version_id, parent_version_id, Bytes::new())?;
+                parent_version_id = version_id;
+            }
+            txn.set_snapshot(
+                Snapshot::new(Uuid::new_v4(), Utc::now()).with_versions_since(versions_since),
+                vec![1].into(),
+            )?;
+            txn.commit()?;
+            clients.push(client_id);
+        }
+        // a client without a snapshot is not considered
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(Uuid::nil())?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(
+            storage.max_versions_since_snapshot()?,
+            Some((clients[1], 17))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions_since_saturates() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.set_snapshot(Snapshot::new(Uuid::new_v4(), Utc::now()), vec![1].into())?;
+        txn.commit()?;
+        drop(txn);
+        // a count this high cannot be reached through `set_snapshot`, so set it directly
+        storage.new_connection()?.execute(
+            "UPDATE clients SET versions_since_snapshot = ? WHERE client_id = ?",
+            params![u32::MAX - 1, &StoredUuid(client_id)],
+        )?;
+
+        let mut txn = storage.txn(client_id)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, Uuid::nil(), Bytes::new())?;
+        txn.add_version(Uuid::new_v4(), v1, Bytes::new())?;
+
+        let snapshot = txn.get_client()?.unwrap().snapshot.unwrap();
+        assert_eq!(snapshot.versions_since, u32::MAX);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_passphrase() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let client_id = Uuid::new_v4();
+        {
+            let storage = SqliteStorage::with_passphrase(tmp_dir.path(), "secret")?;
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.commit()?;
+        }
+
+        // the data is not readable without the passphrase
+        let db = std::fs::read(tmp_dir.path().join("taskchampion-sync-server.sqlite3"))?;
+        assert!(!db.starts_with(b"SQLite format 3"));
+        assert!(SqliteStorage::new(tmp_dir.path()).is_err());
+        assert!(SqliteStorage::with_passphrase(tmp_dir.path(), "wrong").is_err());
+
+        let storage = SqliteStorage::with_passphrase(tmp_dir.path(), "secret")?;
+        assert!(storage.txn(client_id)?.get_client()?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let mut client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(Uuid::new_v4())?;
+            txn.commit()?;
+        }
+        client_ids.sort();
+
+        let mut txn = storage.txn(client_ids[0])?;
+        assert_eq!(txn.list_clients(None, 10)?, client_ids);
+        assert_eq!(txn.list_clients(None, 1)?, vec![client_ids[0]]);
+        assert_eq!(
+            txn.list_clients(Some(client_ids[0]), 10)?,
+            client_ids[1..].to_vec()
+        );
+        assert_eq!(
+            txn.list_clients(Some(client_ids[2]), 10)?,
+            Vec::<Uuid>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let version_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for (client_id, version_id) in [client_id, other_client_id].into_iter().zip(version_ids) {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(Uuid::nil())?;
+            txn.add_version(version_id, Uuid::nil(), vec![1].into())?;
+            txn.set_snapshot(Snapshot::new(version_id, Utc::now()), vec![2].into())?;
+            txn.commit()?;
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_ids[0])?, None);
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+
+        // the client can be created again, and the other client is unaffected
+        txn.new_client(Uuid::nil())?;
+        txn.commit()?;
+        drop(txn);
+        let mut txn = storage.txn(other_client_id)?;
+        assert!(txn.get_version(version_ids[1])?.is_some());
+        assert_eq!(txn.get_snapshot_data(version_ids[1])?, Some(vec![2].into()));
+        Ok(())
+    }
+    #[test]
+    fn test_prune_versions() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let vids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        let mut parent_version_id = Uuid::nil();
+        for vid in &vids {
+            txn.add_version(*vid, parent_version_id, vec![1].into())?;
+            parent_version_id = *vid;
+        }
+        txn.set_snapshot(Snapshot::new(vids[1], Utc::now()), vec![2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.prune_versions(vids[1])?, 2);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(vids[0])?, None);
+        assert_eq!(txn.get_version(vids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(Uuid::nil())?, None);
+        assert_eq!(
+            txn.get_version_by_parent(vids[1])?.unwrap().version_id,
+            vids[2]
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_record_sync() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        let before = Utc::now().timestamp();
+        txn.new_client(Uuid::nil())?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.created_at.unwrap().timestamp() >= before);
+        assert_eq!(client.last_sync_at, None);
+        assert_eq!(client.user_agent, None);
+
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        txn.record_sync(timestamp, Some("tc/1.0".into()))?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_sync_at, Some(timestamp));
+        assert_eq!(client.user_agent.as_deref(), Some("tc/1.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let storage =
+            SqliteStorage::new(tmp_dir.path())?.with_clock(Arc::new(ManualClock::new(now)));
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        txn.add_version(version_id, Uuid::nil(), Bytes::from_static(b"abc"))?;
+
+        assert_eq!(txn.get_client()?.unwrap().created_at, Some(now));
+        assert_eq!(txn.get_version(version_id)?.unwrap().created_at, Some(now));
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoints() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let v1 = Uuid::new_v4();
+        let v2 = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(Uuid::nil())?;
+        let outer = txn.savepoint()?;
+        txn.add_version(v1, Uuid::nil(), vec![1].into())?;
+        let inner = txn.savepoint()?;
+        txn.add_version(v2, v1, vec![2].into())?;
+
+        txn.rollback_to(inner)?;
+        assert_eq!(txn.get_version(v2)?, None);
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v1);
+        txn.rollback_to(outer)?;
+        assert_eq!(txn.get_version(v1)?, None);
+        // `inner` was discarded by rolling back to `outer`
+        assert!(txn.rollback_to(inner).is_err());
+        txn.add_version(v2, Uuid::nil(), vec![2].into())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, v2);
+        assert_eq!(txn.get_version(v1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_quota() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let quota = Quota {
+            max_bytes: Some(u64::MAX),
+            max_versions: Some(100),
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.set_quota(quota).is_err());
+        txn.new_client(Uuid::nil())?;
+        assert_eq!(txn.get_quota()?, Quota::default());
+        txn.set_quota(quota)?;
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(storage.txn(client_id)?.get_quota()?, quota);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_deleted() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.set_deleted(Some(timestamp)).is_err());
+        txn.new_client(Uuid::nil())?;
+        txn.set_deleted(Some(timestamp))?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().deleted_at, Some(timestamp));
+        txn.set_deleted(None)?;
+        assert_eq!(txn.get_client()?.unwrap().deleted_at, None);
+        Ok(())
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn with_storage(test: &dyn Fn(&dyn Storage) -> anyhow::Result<()>) -> anyhow::Result<()> {
+            let tmp_dir = TempDir::new()?;
+            test(&SqliteStorage::new(tmp_dir.path())?)
+        }
+
+        taskchampion_sync_server_core::storage_conformance_tests!(with_storage);
+    }
 }
@@ -0,0 +1,186 @@
+//! Versioned schema migrations for the SQLite database.
+//!
+//! The schema version is stored in the `meta` table, and is equal to the number of migrations
+//! that have been applied. Migrations must therefore never be removed or reordered; new
+//! migrations are added to the end of [`MIGRATIONS`].
+//!
+//! The version is the `schema_version` key of `meta`, a key-value table, rather than a table of
+//! its own, so that other database-wide values can be added later without another table.
+use anyhow::Context;
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+/// A single migration step, upgrading the schema by one version.
+type Migration = fn(&Transaction) -> anyhow::Result<()>;
+
+/// All migrations, in order.
+const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    client_metadata,
+    client_quota,
+    version_timestamps,
+    client_deletion,
+];
+
+/// Version 1: the original schema. Databases created before migrations were introduced already
+/// have these tables, so this uses `IF NOT EXISTS`.
+fn initial_schema(t: &Transaction) -> anyhow::Result<()> {
+    let queries = [
+        "CREATE TABLE IF NOT EXISTS clients (
+            client_id STRING PRIMARY KEY,
+            latest_version_id STRING,
+            snapshot_version_id STRING,
+            versions_since_snapshot INTEGER,
+            snapshot_timestamp INTEGER,
+            snapshot BLOB);",
+        "CREATE TABLE IF NOT EXISTS versions (version_id STRING PRIMARY KEY, client_id STRING, parent_version_id STRING, history_segment BLOB);",
+        "CREATE INDEX IF NOT EXISTS versions_by_parent ON versions (parent_version_id);",
+    ];
+    for q in queries {
+        t.execute(q, [])?;
+    }
+    Ok(())
+}
+
+/// Version 2: client creation and sync metadata.
+fn client_metadata(t: &Transaction) -> anyhow::Result<()> {
+    let queries = [
+        "ALTER TABLE clients ADD COLUMN created_at INTEGER;",
+        "ALTER TABLE clients ADD COLUMN last_sync_at INTEGER;",
+        "ALTER TABLE clients ADD COLUMN user_agent STRING;",
+    ];
+    for q in queries {
+        t.execute(q, [])?;
+    }
+    Ok(())
+}
+
+/// Version 3: per-client quotas.
+fn client_quota(t: &Transaction) -> anyhow::Result<()> {
+    let queries = [
+        "ALTER TABLE clients ADD COLUMN quota_max_bytes INTEGER;",
+        "ALTER TABLE clients ADD COLUMN quota_max_versions INTEGER;",
+    ];
+    for q in queries {
+        t.execute(q, [])?;
+    }
+    Ok(())
+}
+
+/// Version 4: the time at which each version was added.
+fn version_timestamps(t: &Transaction) -> anyhow::Result<()> {
+    t.execute("ALTER TABLE versions ADD COLUMN created_at INTEGER;", [])?;
+    Ok(())
+}
+
+/// Version 5: the time at which a client was marked deleted.
+fn client_deletion(t: &Transaction) -> anyhow::Result<()> {
+    t.execute("ALTER TABLE clients ADD COLUMN deleted_at INTEGER;", [])?;
+    Ok(())
+}
+
+/// Get the schema version of the database, creating the `meta` table if necessary.
+fn get_schema_version(t: &Transaction) -> anyhow::Result<u32> {
+    t.execute(
+        "CREATE TABLE IF NOT EXISTS meta (key STRING PRIMARY KEY, value INTEGER NOT NULL)",
+        [],
+    )?;
+    Ok(t.query_row(
+        "SELECT value FROM meta WHERE key = 'schema_version'",
+        [],
+        |r| r.get(0),
+    )
+    .optional()?
+    .unwrap_or(0))
+}
+
+/// Bring the database schema up to date, applying any pending migrations in a single
+/// transaction.
+pub(crate) fn run(con: &mut Connection) -> anyhow::Result<()> {
+    run_migrations(con, MIGRATIONS)
+}
+
+fn run_migrations(con: &mut Connection, migrations: &[Migration]) -> anyhow::Result<()> {
+    // An IMMEDIATE transaction ensures that concurrent runners apply each migration only once.
+    let t = con.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let current = get_schema_version(&t).context("Error reading schema version")?;
+    let latest = migrations.len() as u32;
+    if current > latest {
+        anyhow::bail!(
+            "Database schema version {current} is newer than the latest supported version {latest}"
+        );
+    }
+    if current == latest {
+        return Ok(());
+    }
+
+    for (i, migration) in migrations.iter().enumerate().skip(current as usize) {
+        let version = i as u32 + 1;
+        log::info!("Migrating database schema to version {version}");
+        migration(&t).with_context(|| format!("Error migrating to schema version {version}"))?;
+    }
+    t.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?)",
+        [latest],
+    )?;
+    t.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn schema_version(con: &mut Connection) -> anyhow::Result<u32> {
+        let t = con.transaction()?;
+        get_schema_version(&t)
+    }
+
+    fn add_row(t: &Transaction) -> anyhow::Result<()> {
+        t.execute("CREATE TABLE IF NOT EXISTS counter (x INTEGER)", [])?;
+        t.execute("INSERT INTO counter (x) VALUES (1)", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn fresh_database() -> anyhow::Result<()> {
+        let mut con = Connection::open_in_memory()?;
+        run(&mut con)?;
+        assert_eq!(schema_version(&mut con)?, MIGRATIONS.len() as u32);
+        // the original tables exist
+        con.prepare("SELECT * FROM clients")?;
+        con.prepare("SELECT * FROM versions")?;
+        Ok(())
+    }
+
+    #[test]
+    fn rerun_is_noop() -> anyhow::Result<()> {
+        let mut con = Connection::open_in_memory()?;
+        let migrations: &[Migration] = &[initial_schema, add_row];
+        run_migrations(&mut con, migrations)?;
+        run_migrations(&mut con, migrations)?;
+        assert_eq!(schema_version(&mut con)?, 2);
+        let rows: u32 = con.query_row("SELECT COUNT(*) FROM counter", [], |r| r.get(0))?;
+        assert_eq!(rows, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn applies_only_pending() -> anyhow::Result<()> {
+        let mut con = Connection::open_in_memory()?;
+        run_migrations(&mut con, &[initial_schema, add_row])?;
+        run_migrations(&mut con, &[initial_schema, add_row, add_row])?;
+        assert_eq!(schema_version(&mut con)?, 3);
+        let rows: u32 = con.query_row("SELECT COUNT(*) FROM counter", [], |r| r.get(0))?;
+        assert_eq!(rows, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_schema_refused() -> anyhow::Result<()> {
+        let mut con = Connection::open_in_memory()?;
+        run_migrations(&mut con, &[initial_schema, add_row])?;
+        assert!(run_migrations(&mut con, &[initial_schema]).is_err());
+        Ok(())
+    }
+}
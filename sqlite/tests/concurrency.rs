@@ -30,7 +30,7 @@ fn add_version_concurrency() -> anyhow::Result<()> {
             let version_id = Uuid::new_v4();
             let parent_version_id = client.latest_version_id;
             std::thread::yield_now(); // Make failure more likely.
-            txn.add_version(version_id, parent_version_id, b"data".to_vec())?;
+            txn.add_version(version_id, parent_version_id, b"data".to_vec().into())?;
             txn.commit()?;
         }
 